@@ -42,7 +42,7 @@ use std::path::{Path, PathBuf};
 use std::process::Command;
 
 use anyhow::{bail, Context, Result};
-use yts3::{roundtrip, PipelineHook, Yts3Config};
+use yts3::{roundtrip, Credentials, PipelineHook, Yts3Config};
 
 // ---------------------------------------------------------------------------
 // Credentials
@@ -162,7 +162,7 @@ impl YoutubeHook {
         let upload_uri = initiate_output
             .lines()
             .find(|l| l.to_ascii_lowercase().starts_with("location:"))
-            .and_then(|l| l.splitn(2, ':').nth(1))
+            .and_then(|l| l.split_once(':').map(|(_, v)| v))
             .map(|v| v.trim().to_string())
             .context("no Location header in YouTube upload-initiation response — \
                       check that your access token has the youtube.upload scope")?;
@@ -297,7 +297,7 @@ fn main() -> Result<()> {
         Path::new(input),
         encoded,
         Path::new(output),
-        password,
+        &password.map(Credentials::password).unwrap_or_default(),
         &cfg,
         &hook,
     )?;
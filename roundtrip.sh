@@ -3,18 +3,19 @@ set -euo pipefail
 
 INPUT="${1:-hello.txt}"
 ENCODED="${2:-encoded.mkv}"
-PASSWORD="${3:-password}"
+# Passed through the environment so it stays out of `ps`
+export YTS3_PASSWORD="${YTS3_PASSWORD:-${3:-password}}"
 
 echo "==> Hashing original: $INPUT"
 ORIGINAL_HASH=$(shasum -a 256 "$INPUT" | awk '{print $1}')
 echo "    $ORIGINAL_HASH"
 
 echo "==> Encoding..."
-yts3 encode --input "$INPUT" --output "$ENCODED" --password "$PASSWORD"
+yts3 encode --input "$INPUT" --output "$ENCODED" --password-env YTS3_PASSWORD
 
 echo "==> Decoding..."
 mv "$INPUT" "${INPUT}.bak"
-yts3 decode --input "$ENCODED" --output "$INPUT" --password "$PASSWORD"
+yts3 decode --input "$ENCODED" --output "$INPUT" --password-env YTS3_PASSWORD
 
 echo "==> Hashing decoded: $INPUT"
 DECODED_HASH=$(shasum -a 256 "$INPUT" | awk '{print $1}')
@@ -0,0 +1,548 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use byteorder::{ByteOrder, LittleEndian};
+use log::warn;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::config;
+use crate::integrity::Sha256Digest;
+
+#[derive(Error, Debug)]
+pub enum ArchiveError {
+    #[error("archive index too short: need {need} bytes, have {have}")]
+    TooShort { need: usize, have: usize },
+    #[error("unsupported archive index version: {0}")]
+    UnsupportedVersion(u8),
+    #[error("unknown archive entry kind: {0}")]
+    UnknownKind(u8),
+    #[error("invalid archive path {0:?}")]
+    InvalidPath(String),
+    #[error("archive path {0:?} appears more than once")]
+    DuplicatePath(String),
+    #[error("no entry {0:?} in the archive")]
+    NotFound(String),
+    #[error("{path} changed while it was being archived")]
+    Changed { path: PathBuf },
+    #[error("archive I/O error: {0}")]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+}
+
+/// One file or directory in an archive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchiveEntry {
+    /// `/`-separated path relative to the extraction directory.
+    pub path: String,
+    pub kind: EntryKind,
+    /// Length of the file's contents; always 0 for directories.
+    pub size: u64,
+    /// Unix permission bits.
+    pub mode: u32,
+    /// Modification time in seconds since the Unix epoch, if known.
+    pub modified: Option<u64>,
+    /// SHA-256 of the file's contents.
+    pub sha256: Sha256Digest,
+}
+
+/// The files of a multi-file video, stored as a record alongside the chunks.
+///
+/// The video's data is the contents of every file entry back to back, in index
+/// order, so each file's offset is the sum of the sizes before it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ArchiveIndex {
+    pub entries: Vec<ArchiveEntry>,
+}
+
+// Index layout: version (1), entry count (4), then per entry:
+// kind (1), flags (1), mode (4), size (8), modified (8), sha256 (32), path length (2), path
+const INDEX_HEADER_SIZE: usize = 5;
+const ENTRY_FIXED_SIZE: usize = 56;
+
+const KIND_FILE: u8 = 0;
+const KIND_DIRECTORY: u8 = 1;
+const FLAG_HAS_MODIFIED: u8 = 0x01;
+
+impl ArchiveIndex {
+    /// Total length of the file contents, i.e. of the video's data.
+    pub fn total_size(&self) -> u64 {
+        self.entries.iter().map(|e| e.size).sum()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![config::ARCHIVE_VERSION];
+        buf.extend_from_slice(&(self.entries.len() as u32).to_le_bytes());
+        for entry in &self.entries {
+            let mut fixed = [0u8; ENTRY_FIXED_SIZE];
+            fixed[0] = match entry.kind {
+                EntryKind::File => KIND_FILE,
+                EntryKind::Directory => KIND_DIRECTORY,
+            };
+            if entry.modified.is_some() {
+                fixed[1] |= FLAG_HAS_MODIFIED;
+            }
+            LittleEndian::write_u32(&mut fixed[2..], entry.mode);
+            LittleEndian::write_u64(&mut fixed[6..], entry.size);
+            LittleEndian::write_u64(&mut fixed[14..], entry.modified.unwrap_or(0));
+            fixed[22..54].copy_from_slice(&entry.sha256);
+            // Paths are validated when scanned, which bounds their length
+            LittleEndian::write_u16(&mut fixed[54..], entry.path.len() as u16);
+            buf.extend_from_slice(&fixed);
+            buf.extend_from_slice(entry.path.as_bytes());
+        }
+        buf
+    }
+
+    /// Parse an index, rejecting any path that could escape the extraction directory.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ArchiveError> {
+        let need = |need: usize| -> Result<(), ArchiveError> {
+            if data.len() < need {
+                return Err(ArchiveError::TooShort {
+                    need,
+                    have: data.len(),
+                });
+            }
+            Ok(())
+        };
+        need(INDEX_HEADER_SIZE)?;
+        if data[0] != config::ARCHIVE_VERSION {
+            return Err(ArchiveError::UnsupportedVersion(data[0]));
+        }
+        let count = LittleEndian::read_u32(&data[1..]) as usize;
+
+        let mut entries = Vec::new();
+        let mut seen = HashSet::new();
+        let mut pos = INDEX_HEADER_SIZE;
+        for _ in 0..count {
+            need(pos + ENTRY_FIXED_SIZE)?;
+            let fixed = &data[pos..pos + ENTRY_FIXED_SIZE];
+            let path_len = LittleEndian::read_u16(&fixed[54..]) as usize;
+            pos += ENTRY_FIXED_SIZE;
+            need(pos + path_len)?;
+            let path = String::from_utf8(data[pos..pos + path_len].to_vec())
+                .map_err(|e| ArchiveError::InvalidPath(String::from_utf8_lossy(e.as_bytes()).into_owned()))?;
+            pos += path_len;
+
+            validate_path(&path)?;
+            if !seen.insert(path.clone()) {
+                return Err(ArchiveError::DuplicatePath(path));
+            }
+            let kind = match fixed[0] {
+                KIND_FILE => EntryKind::File,
+                KIND_DIRECTORY => EntryKind::Directory,
+                other => return Err(ArchiveError::UnknownKind(other)),
+            };
+            let mut sha256 = [0u8; 32];
+            sha256.copy_from_slice(&fixed[22..54]);
+            entries.push(ArchiveEntry {
+                path,
+                kind,
+                size: match kind {
+                    EntryKind::File => LittleEndian::read_u64(&fixed[6..]),
+                    EntryKind::Directory => 0,
+                },
+                mode: LittleEndian::read_u32(&fixed[2..]),
+                modified: (fixed[1] & FLAG_HAS_MODIFIED != 0)
+                    .then(|| LittleEndian::read_u64(&fixed[14..])),
+                sha256,
+            });
+        }
+        Ok(Self { entries })
+    }
+}
+
+/// Check that an archive path is relative, `/`-separated and stays inside the
+/// directory it is extracted to: no empty, `.` or `..` components, and none
+/// that the platform reads as anything but a plain name (e.g. `C:` on Windows,
+/// which would make the path replace the output directory when joined).
+pub fn validate_path(path: &str) -> Result<(), ArchiveError> {
+    let valid = !path.is_empty()
+        && path.len() <= u16::MAX as usize
+        && !path.contains(['\\', '\0', ':'])
+        && path.split('/').all(|part| {
+            let mut components = Path::new(part).components();
+            matches!(components.next(), Some(Component::Normal(_))) && components.next().is_none()
+        });
+    if valid {
+        Ok(())
+    } else {
+        Err(ArchiveError::InvalidPath(path.to_string()))
+    }
+}
+
+/// Reads the contents of every file in an archive back to back, hashing each
+/// file as it goes.
+///
+/// Each file is read for exactly the size it had when scanned, so the stream
+/// length always matches [`ArchiveIndex::total_size`]; a file that shrank in the
+/// meantime is an error.
+pub struct ArchiveReader {
+    index: ArchiveIndex,
+    /// Source path of each index entry.
+    sources: Vec<PathBuf>,
+    /// Next entry to open; the one before it is being read if `current` is set.
+    next: usize,
+    current: Option<(io::Take<File>, Sha256)>,
+}
+
+impl ArchiveReader {
+    /// Scan `inputs` (files or directory trees) into an index. Each input is
+    /// stored under its own name; directories are walked in name order. Symbolic
+    /// links and special files are skipped.
+    pub fn open(inputs: &[PathBuf]) -> Result<Self, ArchiveError> {
+        let mut reader = Self {
+            index: ArchiveIndex::default(),
+            sources: Vec::new(),
+            next: 0,
+            current: None,
+        };
+        let mut roots = HashSet::new();
+        for input in inputs {
+            let name = fs::canonicalize(input)?
+                .file_name()
+                .and_then(|name| name.to_str())
+                .map(str::to_string)
+                .ok_or_else(|| ArchiveError::InvalidPath(input.display().to_string()))?;
+            if !roots.insert(name.clone()) {
+                return Err(ArchiveError::DuplicatePath(name));
+            }
+            reader.scan(input, name)?;
+        }
+        Ok(reader)
+    }
+
+    fn scan(&mut self, source: &Path, path: String) -> Result<(), ArchiveError> {
+        validate_path(&path)?;
+        let metadata = fs::symlink_metadata(source)?;
+        let kind = if metadata.is_dir() {
+            EntryKind::Directory
+        } else if metadata.is_file() {
+            EntryKind::File
+        } else {
+            warn!("skipping {}: not a regular file or directory", source.display());
+            return Ok(());
+        };
+
+        self.index.entries.push(ArchiveEntry {
+            path: path.clone(),
+            kind,
+            size: if kind == EntryKind::File { metadata.len() } else { 0 },
+            mode: mode(&metadata),
+            modified: metadata
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs()),
+            sha256: [0; 32],
+        });
+        self.sources.push(source.to_path_buf());
+
+        if kind == EntryKind::Directory {
+            let mut children = fs::read_dir(source)?
+                .map(|entry| entry.map(|e| e.file_name()))
+                .collect::<io::Result<Vec<_>>>()?;
+            children.sort();
+            for name in children {
+                let child = source.join(&name);
+                let name = name
+                    .into_string()
+                    .map_err(|_| ArchiveError::InvalidPath(child.display().to_string()))?;
+                self.scan(&child, format!("{path}/{name}"))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn index(&self) -> &ArchiveIndex {
+        &self.index
+    }
+
+    /// The index with every file's SHA-256 filled in. Fails unless the whole
+    /// stream has been read.
+    pub fn finish(self) -> Result<ArchiveIndex, ArchiveError> {
+        if self.current.is_some() || self.index.entries[self.next..].iter().any(|e| e.size > 0) {
+            return Err(io::Error::other("archive stream not fully read").into());
+        }
+        Ok(self.index)
+    }
+}
+
+impl Read for ArchiveReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some((file, hasher)) = &mut self.current {
+                let n = file.read(buf)?;
+                if n > 0 {
+                    hasher.update(&buf[..n]);
+                    return Ok(n);
+                }
+                let i = self.next - 1;
+                if file.limit() > 0 {
+                    let path = self.sources[i].clone();
+                    return Err(io::Error::other(ArchiveError::Changed { path }));
+                }
+                let (_, hasher) = self.current.take().unwrap();
+                self.index.entries[i].sha256 = hasher.finalize().into();
+                continue;
+            }
+
+            let Some(entry) = self.index.entries.get(self.next) else {
+                return Ok(0);
+            };
+            if entry.kind == EntryKind::File {
+                let file = File::open(&self.sources[self.next])?;
+                self.current = Some((file.take(entry.size), Sha256::new()));
+            }
+            self.next += 1;
+        }
+    }
+}
+
+/// Split an archive's data stream back into files under `dir`.
+///
+/// Only entries equal to, or inside, one of the `select` paths are written (all
+/// of them if `select` is empty); the rest of the stream is skipped. Files whose
+/// contents do not match their recorded SHA-256 (e.g. zero-filled damage) are
+/// still written, and returned so the caller can report them. Permissions and
+/// modification times are restored where the platform allows.
+pub fn extract<R: Read>(
+    index: &ArchiveIndex,
+    stream: &mut R,
+    dir: &Path,
+    select: &[String],
+) -> Result<Vec<String>, ArchiveError> {
+    let select: Vec<&str> = select.iter().map(|s| s.trim_end_matches('/')).collect();
+    for wanted in &select {
+        if !index.entries.iter().any(|e| is_selected(&e.path, &[wanted])) {
+            return Err(ArchiveError::NotFound(wanted.to_string()));
+        }
+    }
+
+    let mut damaged = Vec::new();
+    let mut directories = Vec::new();
+    for entry in &index.entries {
+        let mut contents = stream.by_ref().take(entry.size);
+        if !select.is_empty() && !is_selected(&entry.path, &select) {
+            io::copy(&mut contents, &mut io::sink())?;
+            continue;
+        }
+
+        let path = dir.join(&entry.path);
+        match entry.kind {
+            EntryKind::Directory => {
+                fs::create_dir_all(&path)?;
+                directories.push((path, entry));
+            }
+            EntryKind::File => {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let mut out = HashingWriter {
+                    inner: File::create(&path)?,
+                    hasher: Sha256::new(),
+                };
+                let written = io::copy(&mut contents, &mut out)?;
+                if written != entry.size {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+                let sha256: Sha256Digest = out.hasher.finalize().into();
+                if sha256 != entry.sha256 {
+                    warn!("{} does not match its recorded SHA-256", entry.path);
+                    damaged.push(entry.path.clone());
+                }
+                if let Some(secs) = entry.modified {
+                    out.inner.set_modified(UNIX_EPOCH + Duration::from_secs(secs))?;
+                }
+                // Never restore setuid, setgid or sticky bits from a video
+                set_mode(&path, entry.mode & 0o777)?;
+            }
+        }
+    }
+    // Directory permissions last, deepest first, in case they forbid writing
+    for (path, entry) in directories.iter().rev() {
+        set_mode(path, entry.mode & 0o777)?;
+    }
+    Ok(damaged)
+}
+
+fn is_selected(path: &str, select: &[&str]) -> bool {
+    select.iter().any(|wanted| {
+        path.strip_prefix(wanted)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    })
+}
+
+struct HashingWriter {
+    inner: File,
+    hasher: Sha256,
+}
+
+impl Write for HashingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(unix)]
+fn mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode(metadata: &fs::Metadata) -> u32 {
+    match (metadata.is_dir(), metadata.permissions().readonly()) {
+        (true, _) => 0o755,
+        (false, true) => 0o444,
+        (false, false) => 0o644,
+    }
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrity::sha256;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("yts3-archive-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_index_roundtrip_and_path_validation() {
+        let index = ArchiveIndex {
+            entries: vec![
+                ArchiveEntry {
+                    path: "project".to_string(),
+                    kind: EntryKind::Directory,
+                    size: 0,
+                    mode: 0o755,
+                    modified: None,
+                    sha256: [0; 32],
+                },
+                ArchiveEntry {
+                    path: "project/notes é.txt".to_string(),
+                    kind: EntryKind::File,
+                    size: 11,
+                    mode: 0o600,
+                    modified: Some(1_700_000_000),
+                    sha256: sha256(b"hello world"),
+                },
+            ],
+        };
+        assert_eq!(ArchiveIndex::from_bytes(&index.to_bytes()).unwrap(), index);
+        assert!(ArchiveIndex::from_bytes(&index.to_bytes()[..40]).is_err());
+
+        for bad in ["", "/etc/passwd", "a/../../b", "a//b", "./a", "a/", "a\\..\\b", "C:/evil", "C:evil"] {
+            assert!(validate_path(bad).is_err(), "{bad:?} accepted");
+        }
+        let mut evil = index.clone();
+        evil.entries[1].path = "../escape".to_string();
+        assert!(matches!(
+            ArchiveIndex::from_bytes(&evil.to_bytes()),
+            Err(ArchiveError::InvalidPath(_))
+        ));
+    }
+
+    #[test]
+    fn test_archive_stream_extracts_tree_and_selection() {
+        let src = temp_dir("src");
+        fs::create_dir_all(src.join("tree/src/empty")).unwrap();
+        fs::write(src.join("tree/README"), b"read me").unwrap();
+        fs::write(src.join("tree/src/main.rs"), vec![7u8; 5000]).unwrap();
+        fs::write(src.join("single.bin"), b"").unwrap();
+        #[cfg(unix)]
+        set_mode(&src.join("tree/README"), 0o640).unwrap();
+
+        let mut reader =
+            ArchiveReader::open(&[src.join("tree"), src.join("single.bin")]).unwrap();
+        let mut stream = Vec::new();
+        reader.read_to_end(&mut stream).unwrap();
+        let index = reader.finish().unwrap();
+        let paths: Vec<&str> = index.entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            ["tree", "tree/README", "tree/src", "tree/src/empty", "tree/src/main.rs", "single.bin"]
+        );
+        assert_eq!(stream.len() as u64, index.total_size());
+        assert_eq!(index.entries[1].sha256, sha256(b"read me"));
+
+        let out = temp_dir("all");
+        let damaged = extract(&index, &mut stream.as_slice(), &out, &[]).unwrap();
+        assert!(damaged.is_empty());
+        assert_eq!(fs::read(out.join("tree/src/main.rs")).unwrap(), vec![7u8; 5000]);
+        assert!(out.join("tree/src/empty").is_dir());
+        assert!(out.join("single.bin").is_file());
+        #[cfg(unix)]
+        assert_eq!(mode(&fs::metadata(out.join("tree/README")).unwrap()), 0o640);
+
+        let out = temp_dir("some");
+        extract(&index, &mut stream.as_slice(), &out, &["tree/src/".to_string()]).unwrap();
+        assert!(out.join("tree/src/main.rs").is_file());
+        assert!(!out.join("tree/README").exists());
+        assert!(!out.join("single.bin").exists());
+        assert!(matches!(
+            extract(&index, &mut stream.as_slice(), &out, &["tree/sr".to_string()]),
+            Err(ArchiveError::NotFound(_))
+        ));
+
+        // Damaged contents are extracted but reported
+        stream[0] ^= 0xFF;
+        let out = temp_dir("damaged");
+        let damaged = extract(&index, &mut stream.as_slice(), &out, &[]).unwrap();
+        assert_eq!(damaged, ["tree/README"]);
+
+        for dir in [src, out, temp_dir("all"), temp_dir("some")] {
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_special_mode_bits_are_not_restored() {
+        let contents = b"#!/bin/sh\n";
+        let index = ArchiveIndex {
+            entries: vec![ArchiveEntry {
+                path: "run.sh".to_string(),
+                kind: EntryKind::File,
+                size: contents.len() as u64,
+                mode: 0o4755,
+                modified: None,
+                sha256: sha256(contents),
+            }],
+        };
+
+        let out = temp_dir("setuid");
+        extract(&index, &mut contents.as_slice(), &out, &[]).unwrap();
+        assert_eq!(mode(&fs::metadata(out.join("run.sh")).unwrap()), 0o755);
+        fs::remove_dir_all(out).unwrap();
+    }
+}
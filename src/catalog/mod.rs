@@ -0,0 +1,393 @@
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+use log::warn;
+use thiserror::Error;
+
+use crate::config::{Compression, FountainScheme, KdfParams, Yts3Config};
+use crate::pipeline::encode::EncodeSummary;
+
+/// Environment variable overriding the catalog location.
+pub const CATALOG_ENV: &str = "YTS3_CATALOG";
+
+/// Version tag at the start of every catalog line.
+const LINE_VERSION: &str = "yts3-catalog-1";
+
+#[derive(Error, Debug)]
+pub enum CatalogError {
+    #[error("catalog I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("malformed catalog line {line}: {reason}")]
+    Malformed { line: usize, reason: String },
+}
+
+/// Everything we need to know to find, describe and decode a stored file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CatalogEntry {
+    /// Name the file is stored under; later entries with the same key replace earlier ones.
+    pub key: String,
+    /// Absolute path of the input when it was encoded.
+    pub original_path: String,
+    pub size: u64,
+    /// Hex SHA-256 of the input.
+    pub sha256: String,
+    /// Hex file id of the video's packets.
+    pub file_id: String,
+    pub encrypted: bool,
+    /// Where the video was written: a file path or a storage backend id.
+    pub video: String,
+    /// When the entry was recorded, in seconds since the Unix epoch.
+    pub created: u64,
+    /// Parameters the video was encoded with.
+    pub config: Yts3Config,
+}
+
+impl CatalogEntry {
+    /// Describe a finished encode of `input_path` into `video`.
+    pub fn from_summary(
+        key: &str,
+        input_path: &Path,
+        video: &str,
+        summary: &EncodeSummary,
+        cfg: &Yts3Config,
+    ) -> Self {
+        let original_path = fs::canonicalize(input_path).unwrap_or_else(|_| input_path.to_path_buf());
+        Self {
+            key: key.to_string(),
+            original_path: original_path.to_string_lossy().into_owned(),
+            size: summary.manifest.file_size,
+            sha256: summary.manifest.sha256_hex(),
+            file_id: summary.file_id.iter().map(|b| format!("{b:02x}")).collect(),
+            encrypted: summary.encrypted,
+            video: video.to_string(),
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            config: cfg.clone(),
+        }
+    }
+
+    /// Serialize as one tab-separated line of `name=value` fields.
+    fn to_line(&self) -> String {
+        let cfg = &self.config;
+        let fields = [
+            ("key", self.key.clone()),
+            ("path", self.original_path.clone()),
+            ("size", self.size.to_string()),
+            ("sha256", self.sha256.clone()),
+            ("file_id", self.file_id.clone()),
+            ("encrypted", self.encrypted.to_string()),
+            ("video", self.video.clone()),
+            ("created", self.created.to_string()),
+            ("width", cfg.frame_width.to_string()),
+            ("height", cfg.frame_height.to_string()),
+            ("fps", cfg.fps.to_string()),
+            ("bits_per_block", cfg.bits_per_block.to_string()),
+            ("coefficient_strength", cfg.coefficient_strength.to_string()),
+            ("repetition", cfg.repetition.to_string()),
+            ("chunk_size", cfg.chunk_size.to_string()),
+            ("symbol_size", cfg.symbol_size.to_string()),
+            ("repair_overhead", cfg.repair_overhead.to_string()),
+            ("fountain", fountain_name(cfg.fountain).to_string()),
+            ("compression", compression_name(cfg.compression).to_string()),
+            ("compression_level", cfg.compression_level.to_string()),
+            ("kdf_mem_cost", cfg.kdf.mem_cost.to_string()),
+            ("kdf_time_cost", cfg.kdf.time_cost.to_string()),
+            ("kdf_parallelism", cfg.kdf.parallelism.to_string()),
+            ("inflight_chunks", cfg.inflight_chunks.to_string()),
+            ("interleave_depth", cfg.interleave_depth.to_string()),
+            ("ecc_parity", cfg.ecc_parity.to_string()),
+        ];
+        let mut line = LINE_VERSION.to_string();
+        for (name, value) in fields {
+            line.push('\t');
+            line.push_str(name);
+            line.push('=');
+            line.push_str(&escape(&value));
+        }
+        line.push('\n');
+        line
+    }
+
+    /// Parse a line written by [`to_line`](Self::to_line). Parameters added to
+    /// the format later are optional and default, so older lines still load.
+    fn from_line(line: &str) -> Result<Self, String> {
+        let mut parts = line.split('\t');
+        if parts.next() != Some(LINE_VERSION) {
+            return Err("unknown version".to_string());
+        }
+        let fields: BTreeMap<&str, String> = parts
+            .map(|part| {
+                let (name, value) = part.split_once('=').ok_or("field without '='")?;
+                Ok((name, unescape(value)?))
+            })
+            .collect::<Result<_, String>>()?;
+        let text = |name: &str| {
+            fields
+                .get(name)
+                .cloned()
+                .ok_or_else(|| format!("missing {name}"))
+        };
+        fn parse<T: std::str::FromStr>(name: &str, value: String) -> Result<T, String> {
+            value.parse().map_err(|_| format!("bad {name} {value:?}"))
+        }
+        // For parameters added to the format later
+        fn parse_or<T: std::str::FromStr>(
+            fields: &BTreeMap<&str, String>,
+            name: &str,
+            default: T,
+        ) -> Result<T, String> {
+            fields.get(name).map_or(Ok(default), |value| parse(name, value.clone()))
+        }
+
+        let defaults = Yts3Config::default();
+        let fountain = text("fountain")?;
+        let compression = match fields.get("compression") {
+            Some(name) => {
+                Compression::from_str(name, true).map_err(|_| format!("bad compression {name:?}"))?
+            }
+            None => defaults.compression,
+        };
+        let config = Yts3Config {
+            frame_width: parse("width", text("width")?)?,
+            frame_height: parse("height", text("height")?)?,
+            fps: parse("fps", text("fps")?)?,
+            bits_per_block: parse("bits_per_block", text("bits_per_block")?)?,
+            coefficient_strength: parse("coefficient_strength", text("coefficient_strength")?)?,
+            repetition: parse("repetition", text("repetition")?)?,
+            chunk_size: parse("chunk_size", text("chunk_size")?)?,
+            symbol_size: parse("symbol_size", text("symbol_size")?)?,
+            repair_overhead: parse("repair_overhead", text("repair_overhead")?)?,
+            fountain: FountainScheme::from_str(&fountain, true)
+                .map_err(|_| format!("bad fountain {fountain:?}"))?,
+            compression,
+            compression_level: parse_or(&fields, "compression_level", defaults.compression_level)?,
+            kdf: KdfParams {
+                mem_cost: parse_or(&fields, "kdf_mem_cost", defaults.kdf.mem_cost)?,
+                time_cost: parse_or(&fields, "kdf_time_cost", defaults.kdf.time_cost)?,
+                parallelism: parse_or(&fields, "kdf_parallelism", defaults.kdf.parallelism)?,
+            },
+            inflight_chunks: parse_or(&fields, "inflight_chunks", defaults.inflight_chunks)?,
+            interleave_depth: parse_or(&fields, "interleave_depth", defaults.interleave_depth)?,
+            ecc_parity: parse_or(&fields, "ecc_parity", defaults.ecc_parity)?,
+            ..defaults
+        };
+        Ok(Self {
+            key: text("key")?,
+            original_path: text("path")?,
+            size: parse("size", text("size")?)?,
+            sha256: text("sha256")?,
+            file_id: text("file_id")?,
+            encrypted: parse("encrypted", text("encrypted")?)?,
+            video: text("video")?,
+            created: parse("created", text("created")?)?,
+            config,
+        })
+    }
+}
+
+/// An append-only log of everything we have encoded.
+///
+/// Each encode appends one line, so the file is never rewritten and concurrent
+/// encodes cannot lose each other's entries. Readers take the latest line for
+/// each key.
+pub struct Catalog {
+    path: PathBuf,
+}
+
+impl Catalog {
+    pub fn open(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+        }
+    }
+
+    /// `$YTS3_CATALOG` if set, otherwise `~/.yts3/catalog.log`.
+    pub fn default_path() -> Option<PathBuf> {
+        if let Some(path) = std::env::var_os(CATALOG_ENV) {
+            return Some(PathBuf::from(path));
+        }
+        let home = std::env::var_os("HOME")?;
+        Some(Path::new(&home).join(".yts3").join("catalog.log"))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn append(&self, entry: &CatalogEntry) -> Result<(), CatalogError> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // One write per line so appends from concurrent encodes do not interleave
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        file.write_all(entry.to_line().as_bytes())?;
+        Ok(())
+    }
+
+    /// The latest entry for every key, sorted by key. Malformed lines (e.g. from
+    /// an interrupted write) are skipped with a warning.
+    pub fn entries(&self) -> Result<Vec<CatalogEntry>, CatalogError> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut latest = BTreeMap::new();
+        for (i, line) in text.lines().enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            match CatalogEntry::from_line(line) {
+                Ok(entry) => {
+                    latest.insert(entry.key.clone(), entry);
+                }
+                Err(reason) => warn!("{}", CatalogError::Malformed { line: i + 1, reason }),
+            }
+        }
+        Ok(latest.into_values().collect())
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<CatalogEntry>, CatalogError> {
+        Ok(self.entries()?.into_iter().find(|entry| entry.key == key))
+    }
+}
+
+fn fountain_name(scheme: FountainScheme) -> &'static str {
+    match scheme {
+        FountainScheme::Xor => "xor",
+        FountainScheme::RaptorQ => "raptorq",
+    }
+}
+
+fn compression_name(compression: Compression) -> &'static str {
+    match compression {
+        Compression::None => "none",
+        Compression::Zstd => "zstd",
+    }
+}
+
+/// Escape `\`, tab and newlines so a value fits in one field of one line.
+fn escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn unescape(value: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => out.push('\\'),
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            other => return Err(format!("bad escape \\{}", other.unwrap_or(' '))),
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(key: &str, size: u64) -> CatalogEntry {
+        CatalogEntry {
+            key: key.to_string(),
+            original_path: "/home/me/odd\tname\nhere.zip".to_string(),
+            size,
+            sha256: "ab".repeat(32),
+            file_id: "01".repeat(16),
+            encrypted: true,
+            video: "/videos/backup.mkv".to_string(),
+            created: 1_700_000_000,
+            config: Yts3Config {
+                repetition: 4,
+                coefficient_strength: 170.5,
+                fountain: FountainScheme::Xor,
+                ..Yts3Config::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_latest_entry_per_key_wins() {
+        let path = std::env::temp_dir().join(format!("yts3-catalog-{}/catalog.log", std::process::id()));
+        let catalog = Catalog::open(&path);
+        assert!(catalog.entries().unwrap().is_empty());
+
+        catalog.append(&entry("b", 1)).unwrap();
+        catalog.append(&entry("a", 2)).unwrap();
+        catalog.append(&entry("b", 3)).unwrap();
+        // A torn line from an interrupted append is skipped
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"yts3-catalog-1\tkey=c\tsize=")
+            .unwrap();
+
+        let entries = catalog.entries().unwrap();
+        assert_eq!(entries, [entry("a", 2), entry("b", 3)]);
+        assert_eq!(catalog.get("b").unwrap(), Some(entry("b", 3)));
+        assert_eq!(catalog.get("c").unwrap(), None);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_every_encode_parameter_round_trips() {
+        let mut expected = entry("k", 5);
+        expected.config = Yts3Config {
+            frame_width: 1280,
+            frame_height: 720,
+            fps: 24,
+            bits_per_block: 2,
+            coefficient_strength: 99.25,
+            repetition: 3,
+            chunk_size: 65536,
+            symbol_size: 512,
+            repair_overhead: 0.75,
+            fountain: FountainScheme::Xor,
+            compression: Compression::Zstd,
+            compression_level: 9,
+            kdf: KdfParams {
+                mem_cost: 131072,
+                time_cost: 5,
+                parallelism: 2,
+            },
+            inflight_chunks: 7,
+            interleave_depth: 6,
+            ecc_parity: 16,
+            ..Yts3Config::default()
+        };
+        let line = expected.to_line();
+        assert_eq!(CatalogEntry::from_line(line.trim_end()).unwrap(), expected);
+
+        // Lines written before these parameters were recorded load with defaults
+        let old = line.split('\t').take(19).collect::<Vec<_>>().join("\t");
+        let entry = CatalogEntry::from_line(&old).unwrap();
+        assert_eq!(entry.config.compression, Compression::None);
+        assert_eq!(entry.config.ecc_parity, Yts3Config::default().ecc_parity);
+        assert_eq!(entry.config.fountain, FountainScheme::Xor);
+    }
+}
@@ -12,23 +12,55 @@ pub struct Chunk {
     pub is_last: bool,
 }
 
-/// Read a file and split it into fixed-size chunks.
-/// Uses buffered I/O to avoid loading the entire file into memory at once.
-pub fn chunk_file(path: &Path, chunk_size: usize) -> io::Result<Vec<Chunk>> {
-    let file = File::open(path)?;
-    let file_len = file.metadata()?.len() as usize;
-    let mut reader = BufReader::with_capacity(chunk_size, file);
-    let mut chunks = Vec::new();
-    let mut index: u32 = 0;
-    let mut total_read = 0usize;
-
-    loop {
-        let mut buf = vec![0u8; chunk_size];
+/// Streaming reader that yields fixed-size chunks from any byte source.
+///
+/// Only one chunk is buffered ahead of the caller, which is enough to know
+/// whether the chunk being returned is the last one. An empty source yields a
+/// single empty last chunk.
+pub struct ChunkReader<R: Read> {
+    reader: R,
+    chunk_size: usize,
+    next_index: u32,
+    lookahead: Option<Chunk>,
+    started: bool,
+}
+
+impl ChunkReader<BufReader<File>> {
+    /// Open a file for chunked reading.
+    pub fn open(path: &Path, chunk_size: usize) -> io::Result<Self> {
+        let file = File::open(path)?;
+        Ok(Self::new(BufReader::with_capacity(chunk_size, file), chunk_size))
+    }
+}
+
+impl<R: Read> ChunkReader<R> {
+    pub fn new(reader: R, chunk_size: usize) -> Self {
+        Self {
+            reader,
+            chunk_size,
+            next_index: 0,
+            lookahead: None,
+            started: false,
+        }
+    }
+
+    /// Size of every chunk but the last.
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Give back the byte source, e.g. to inspect what it read.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Read exactly chunk_size bytes (or until EOF). Returns `None` at EOF.
+    fn read_chunk(&mut self) -> io::Result<Option<Chunk>> {
+        let mut buf = vec![0u8; self.chunk_size];
         let mut filled = 0;
 
-        // Read exactly chunk_size bytes (or until EOF)
-        while filled < chunk_size {
-            match reader.read(&mut buf[filled..]) {
+        while filled < self.chunk_size {
+            match self.reader.read(&mut buf[filled..]) {
                 Ok(0) => break, // EOF
                 Ok(n) => filled += n,
                 Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
@@ -37,45 +69,64 @@ pub fn chunk_file(path: &Path, chunk_size: usize) -> io::Result<Vec<Chunk>> {
         }
 
         if filled == 0 {
-            // If the file was an exact multiple of chunk_size, mark the previous
-            // chunk as last.
-            if let Some(last) = chunks.last_mut() {
-                let last: &mut Chunk = last;
-                last.is_last = true;
-            }
-            break;
+            return Ok(None);
         }
 
         buf.truncate(filled);
-        total_read += filled;
-        let is_last = total_read >= file_len;
-
-        chunks.push(Chunk {
-            index,
+        let chunk = Chunk {
+            index: self.next_index,
             data: buf,
-            is_last,
-        });
+            is_last: false,
+        };
+        self.next_index += 1;
+        Ok(Some(chunk))
+    }
+}
 
-        index += 1;
+impl<R: Read> Iterator for ChunkReader<R> {
+    type Item = io::Result<Chunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.started {
+            self.started = true;
+            match self.read_chunk() {
+                Ok(Some(chunk)) => self.lookahead = Some(chunk),
+                // Handle empty input
+                Ok(None) => {
+                    return Some(Ok(Chunk {
+                        index: 0,
+                        data: Vec::new(),
+                        is_last: true,
+                    }))
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
 
-        if is_last {
-            break;
+        let mut current = self.lookahead.take()?;
+        match self.read_chunk() {
+            Ok(Some(next)) => self.lookahead = Some(next),
+            Ok(None) => current.is_last = true,
+            Err(e) => return Some(Err(e)),
         }
+        Some(Ok(current))
     }
+}
 
-    // Handle empty file
-    if chunks.is_empty() {
-        chunks.push(Chunk {
-            index: 0,
-            data: Vec::new(),
-            is_last: true,
-        });
-    }
+/// Number of chunks a source of `len` bytes splits into (an empty source is one chunk).
+pub fn chunk_count(len: u64, chunk_size: usize) -> u64 {
+    len.div_ceil(chunk_size as u64).max(1)
+}
 
-    Ok(chunks)
+/// Read a file and split it into fixed-size chunks.
+/// Uses buffered I/O, but collects every chunk; prefer [`ChunkReader`] for large files.
+#[cfg(test)]
+pub fn chunk_file(path: &Path, chunk_size: usize) -> io::Result<Vec<Chunk>> {
+    ChunkReader::open(path, chunk_size)?.collect()
 }
 
 /// Split an in-memory byte buffer into chunks.
+#[cfg(test)]
 pub fn chunk_bytes(data: &[u8], chunk_size: usize) -> Vec<Chunk> {
     if data.is_empty() {
         return vec![Chunk {
@@ -85,7 +136,7 @@ pub fn chunk_bytes(data: &[u8], chunk_size: usize) -> Vec<Chunk> {
         }];
     }
 
-    let num_chunks = (data.len() + chunk_size - 1) / chunk_size;
+    let num_chunks = data.len().div_ceil(chunk_size);
     let mut chunks = Vec::with_capacity(num_chunks);
 
     for (i, slice) in data.chunks(chunk_size).enumerate() {
@@ -176,4 +227,23 @@ mod tests {
         assert!(chunks.last().unwrap().is_last);
         std::fs::remove_dir_all(&dir).ok();
     }
+
+    #[test]
+    fn test_chunk_reader_matches_chunk_bytes() {
+        for len in [0usize, 1, 999, 1000, 1001, 3000] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let streamed: Vec<Chunk> = ChunkReader::new(data.as_slice(), 1000)
+                .collect::<io::Result<_>>()
+                .unwrap();
+            let expected = chunk_bytes(&data, 1000);
+
+            assert_eq!(streamed.len(), expected.len(), "len={len}");
+            assert_eq!(streamed.len() as u64, chunk_count(len as u64, 1000));
+            for (a, b) in streamed.iter().zip(&expected) {
+                assert_eq!(a.index, b.index);
+                assert_eq!(a.data, b.data);
+                assert_eq!(a.is_last, b.is_last);
+            }
+        }
+    }
 }
@@ -0,0 +1,71 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum CompressError {
+    #[error("zstd error: {0}")]
+    Zstd(#[from] std::io::Error),
+    #[error("decompressed chunk is {got} bytes, expected {expected}")]
+    SizeMismatch { expected: usize, got: usize },
+}
+
+/// Compress a chunk with zstd at `level`, or return `None` if that would not
+/// save at least one symbol of `symbol_size` bytes, since only whole symbols
+/// reduce the number of packets. Incompressible data (archives, media,
+/// ciphertext) is therefore stored as is.
+pub fn compress_chunk(data: &[u8], level: i32, symbol_size: usize) -> Result<Option<Vec<u8>>, CompressError> {
+    let compressed = zstd::bulk::compress(data, level)?;
+    let saves_a_symbol = compressed.len().div_ceil(symbol_size) < data.len().div_ceil(symbol_size);
+    Ok(saves_a_symbol.then_some(compressed))
+}
+
+/// Decompress a chunk that must expand to exactly `original_size` bytes.
+pub fn decompress_chunk(data: &[u8], original_size: usize) -> Result<Vec<u8>, CompressError> {
+    let decompressed = zstd::bulk::decompress(data, original_size)?;
+    if decompressed.len() != original_size {
+        return Err(CompressError::SizeMismatch {
+            expected: original_size,
+            got: decompressed.len(),
+        });
+    }
+    Ok(decompressed)
+}
+
+/// Levels accepted by [`compress_chunk`].
+pub fn level_range() -> std::ops::RangeInclusive<i32> {
+    zstd::compression_level_range()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compressible_chunk_roundtrip() {
+        let data: Vec<u8> = b"2024-01-01 12:00:00 INFO request served in 3ms\n"
+            .iter()
+            .copied()
+            .cycle()
+            .take(100_000)
+            .collect();
+        let compressed = compress_chunk(&data, 3, 256).unwrap().unwrap();
+        assert!(compressed.len() < data.len() / 10);
+        assert_eq!(decompress_chunk(&compressed, data.len()).unwrap(), data);
+        assert!(decompress_chunk(&compressed, data.len() - 1).is_err());
+    }
+
+    #[test]
+    fn test_incompressible_chunk_is_skipped() {
+        let mut state = 0x2545F4914F6CDD1Du64;
+        let noise: Vec<u8> = (0..10_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        assert!(compress_chunk(&noise, 3, 256).unwrap().is_none());
+        // Saving fewer bytes than a symbol is not worth it either
+        assert!(compress_chunk(&[0u8; 200], 3, 256).unwrap().is_none());
+    }
+}
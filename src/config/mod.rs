@@ -1,5 +1,12 @@
+use thiserror::Error;
+
 pub const MAGIC: u32 = 0x59545333; // "YTS3"
-pub const PACKET_VERSION: u8 = 2;
+/// V4 packets have the V3 layout, but encrypted chunks and records also
+/// authenticate their packet header fields as associated data.
+pub const PACKET_VERSION: u8 = 4;
+pub const PACKET_VERSION_V3: u8 = 3;
+/// Oldest packet version still accepted by the decoder.
+pub const PACKET_VERSION_V2: u8 = 2;
 
 // Video parameters
 pub const DEFAULT_FRAME_WIDTH: u32 = 3840;
@@ -7,12 +14,66 @@ pub const DEFAULT_FRAME_HEIGHT: u32 = 2160;
 pub const DEFAULT_FPS: u32 = 30;
 pub const BLOCK_SIZE: usize = 8;
 pub const DEFAULT_BITS_PER_BLOCK: usize = 1;
+/// Each embed position can carry one bit, so a block holds at most this many.
+pub const MAX_BITS_PER_BLOCK: usize = EMBED_POSITIONS.len();
 pub const DEFAULT_COEFFICIENT_STRENGTH: f64 = 150.0;
+/// Copies of every data bit spread across a frame; extraction sums their soft values.
+pub const DEFAULT_REPETITION: usize = 1;
+pub const MAX_REPETITION: usize = 16;
+
+// Robust preset for channels that re-encode lossily (e.g. YouTube's H.264/VP9/AV1).
+// 170 is the strongest one-bit embedding that never clips at 0 or 255.
+pub const ROBUST_COEFFICIENT_STRENGTH: f64 = 170.0;
+pub const ROBUST_REPETITION: usize = 4;
+pub const ROBUST_REPAIR_OVERHEAD: f64 = 2.0;
+
+// Calibration header frame: always embedded at one bit per block with this strength
+pub const CALIBRATION_MAGIC: u32 = 0x5943414C; // "YCAL"
+/// Version 4 headers add the parity of the packets' inner Reed–Solomon code.
+pub const CALIBRATION_VERSION: u8 = 4;
+pub const CALIBRATION_HEADER_SIZE: usize = 35;
+/// Version 3 headers have the version 2 layout and mark videos whose data frames
+/// start with a frame header.
+pub const CALIBRATION_VERSION_V3: u8 = 3;
+pub const CALIBRATION_VERSION_V2: u8 = 2;
+pub const CALIBRATION_HEADER_SIZE_V2: usize = 34;
+/// Version 1 headers predate the repetition field.
+pub const CALIBRATION_VERSION_V1: u8 = 1;
+pub const CALIBRATION_HEADER_SIZE_V1: usize = 33;
+pub const CALIBRATION_BITS_PER_BLOCK: usize = 1;
+pub const CALIBRATION_COEFFICIENT_STRENGTH: f64 = DEFAULT_COEFFICIENT_STRENGTH;
+
+// Frame header at the start of every data frame, followed by the same
+// Reed–Solomon parity as the packets; packets never straddle frames
+pub const FRAME_MAGIC: u32 = 0x5946524D; // "YFRM"
+pub const FRAME_VERSION: u8 = 1;
+pub const FRAME_HEADER_SIZE: usize = 25;
+
+// Inner Reed–Solomon code protecting each packet within its frame
+/// Parity bytes per codeword; 0 embeds packets unprotected.
+pub const DEFAULT_ECC_PARITY: usize = 0;
+/// Leaves at least half of every 255-byte codeword for data.
+pub const MAX_ECC_PARITY: usize = 128;
+/// Bytes read with less confidence than this (1.0 being a bit exactly as it was
+/// embedded) are handed to the Reed–Solomon code as erasures, which cost half
+/// the parity of errors.
+pub const ERASURE_CONFIDENCE: f32 = 0.1;
 
 // Data parameters
+pub const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
 pub const DEFAULT_CHUNK_SIZE: usize = 1_048_576; // 1 MiB
+/// Largest chunk an encoder writes. Decoders allocate a chunk's recorded size
+/// before it is authenticated, so larger sizes in packet headers are rejected.
+pub const MAX_CHUNK_SIZE: usize = 64 * 1_048_576;
 pub const SYMBOL_SIZE: usize = 256;
 pub const DEFAULT_REPAIR_OVERHEAD: f64 = 1.0; // 100% redundancy
+/// Chunks read ahead and fountain-coded concurrently while streaming an encode.
+pub const DEFAULT_INFLIGHT_CHUNKS: usize = 8;
+/// Chunks whose packets are interleaved across frames, so a burst of damaged
+/// frames costs each of them only a few symbols.
+pub const DEFAULT_INTERLEAVE_DEPTH: usize = 8;
+/// RFC 6330 limit on source symbols per source block.
+pub const RAPTORQ_MAX_SOURCE_SYMBOLS: usize = 56403;
 
 // Encryption overhead: 16-byte poly1305 tag
 pub const AEAD_TAG_SIZE: usize = 16;
@@ -26,19 +87,64 @@ pub const FILE_ID_SIZE: usize = 16;
 // Nonce size for XChaCha20-Poly1305
 pub const NONCE_SIZE: usize = 24;
 
-// Argon2id parameters
+// Argon2id parameters. The defaults apply to new videos unless overridden in
+// `Yts3Config::kdf`, and to videos written before the key record held them.
 pub const ARGON2_MEM_COST: u32 = 65536; // 64 MiB
 pub const ARGON2_TIME_COST: u32 = 3;
 pub const ARGON2_PARALLELISM: u32 = 4;
 pub const ARGON2_OUTPUT_LEN: usize = 32;
+/// Largest memory cost accepted, so a corrupt or forged key record cannot force
+/// a huge allocation.
+pub const ARGON2_MAX_MEM_COST: u32 = 1024 * 1024; // 1 GiB
+pub const ARGON2_MAX_TIME_COST: u32 = 64;
+pub const ARGON2_MAX_PARALLELISM: u32 = 16;
+/// Largest memory cost times passes accepted, bounding how long a key record
+/// can make the decoder spend deriving the key: 1 GiB for 4 passes, or the
+/// default 64 MiB for 64.
+pub const ARGON2_MAX_WORK: u64 = 4 * 1024 * 1024;
 
-// Packet header sizes
-pub const PACKET_HEADER_SIZE: usize = 50;
+// Packet header sizes. V3 appends the total chunk count and file size to the V2 header.
+pub const PACKET_HEADER_SIZE: usize = 62;
+pub const PACKET_HEADER_SIZE_V2: usize = 50;
 
 // Packet flag bits
 pub const FLAG_REPAIR_SYMBOL: u8 = 0x01;
 pub const FLAG_LAST_CHUNK: u8 = 0x02;
 pub const FLAG_ENCRYPTED: u8 = 0x04;
+pub const FLAG_RAPTORQ: u8 = 0x08;
+/// Flags describing a whole chunk, which V4 encryption authenticates; the
+/// per-symbol repair flag is left out.
+pub const AUTHENTICATED_FLAGS: u8 =
+    FLAG_LAST_CHUNK | FLAG_ENCRYPTED | FLAG_RAPTORQ | FLAG_RECORD | FLAG_COMPRESSED | FLAG_KEY_RECORD;
+/// Set on metadata record packets, whose `chunk_index` holds the record kind.
+pub const FLAG_RECORD: u8 = 0x10;
+/// Set on packets of a chunk that was zstd-compressed before encryption.
+pub const FLAG_COMPRESSED: u8 = 0x20;
+/// Set on encrypted chunk packets whose key is described by the key record: the
+/// Argon2id parameters for the password, or a data key wrapped for recipients.
+/// Without it the key is derived with the default Argon2id parameters, or is a
+/// raw key given by the user.
+pub const FLAG_KEY_RECORD: u8 = 0x40;
+
+// Metadata records carried alongside the chunk packets
+pub const RECORD_KIND_MANIFEST: u32 = 1;
+/// Index of the files in a multi-file archive (an `ArchiveIndex`).
+pub const RECORD_KIND_ARCHIVE: u32 = 2;
+/// How to get the file's key (a `KeyRecord`). Written unencrypted before, among
+/// and after the chunks.
+pub const RECORD_KIND_KEY: u32 = 3;
+/// An Ed25519 signature over the file id, chunk count, size and the other
+/// records. Written unencrypted after them.
+pub const RECORD_KIND_SIGNATURE: u32 = 4;
+/// Each record is written this many times, in separate frames, so one damaged or
+/// lost copy can be patched from another.
+pub const RECORD_COPIES: usize = 3;
+/// Upper bound on a record's length, so a corrupt header cannot force a huge allocation.
+pub const MAX_RECORD_SIZE: usize = 1 << 20;
+pub const MANIFEST_VERSION: u8 = 1;
+pub const ARCHIVE_VERSION: u8 = 1;
+pub const KEY_RECORD_VERSION: u8 = 1;
+pub const SIGNATURE_VERSION: u8 = 1;
 
 /// DCT coefficient positions used for embedding data in 8x8 blocks.
 pub const EMBED_POSITIONS: [(usize, usize); 4] = [(0, 1), (1, 0), (1, 1), (0, 2)];
@@ -48,9 +154,10 @@ pub fn blocks_per_frame(width: u32, height: u32) -> usize {
     (width as usize / BLOCK_SIZE) * (height as usize / BLOCK_SIZE)
 }
 
-/// Compute how many data bytes fit in a single frame.
-pub fn bytes_per_frame(width: u32, height: u32, bits_per_block: usize) -> usize {
-    blocks_per_frame(width, height) * bits_per_block / 8
+/// Compute how many data bytes fit in a single frame when every bit is embedded
+/// `repetition` times.
+pub fn bytes_per_frame(width: u32, height: u32, bits_per_block: usize, repetition: usize) -> usize {
+    blocks_per_frame(width, height) * bits_per_block / repetition / 8
 }
 
 /// Compute the maximum chunk size for encryption (accounting for AEAD overhead).
@@ -58,17 +165,151 @@ pub fn chunk_size_for_encryption(chunk_size: usize) -> usize {
     chunk_size - ENCRYPTION_OVERHEAD
 }
 
+/// Erasure code used to generate each chunk's repair symbols.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum FountainScheme {
+    /// Legacy XOR code with peeling decoder. Only recovers some loss patterns.
+    Xor,
+    /// Systematic RaptorQ (RFC 6330) code. Recovers from any k (+ a few) symbols.
+    #[default]
+    #[value(name = "raptorq")]
+    RaptorQ,
+}
+
+/// Compression applied to each chunk before it is encrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Compression {
+    /// Store chunks as they are.
+    #[default]
+    None,
+    /// Zstandard. Chunks it cannot shrink are stored uncompressed.
+    Zstd,
+}
+
+/// Argon2id cost parameters used to derive a key from a password.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    /// Memory cost in KiB.
+    pub mem_cost: u32,
+    /// Number of passes over the memory.
+    pub time_cost: u32,
+    /// Number of lanes.
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            mem_cost: ARGON2_MEM_COST,
+            time_cost: ARGON2_TIME_COST,
+            parallelism: ARGON2_PARALLELISM,
+        }
+    }
+}
+
+impl KdfParams {
+    /// Check the parameters are ones Argon2id accepts and within our limits.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let valid = (1..=ARGON2_MAX_PARALLELISM).contains(&self.parallelism)
+            && (1..=ARGON2_MAX_TIME_COST).contains(&self.time_cost)
+            && (8 * self.parallelism..=ARGON2_MAX_MEM_COST).contains(&self.mem_cost)
+            && self.mem_cost as u64 * self.time_cost as u64 <= ARGON2_MAX_WORK;
+        if valid {
+            Ok(())
+        } else {
+            Err(ConfigError::Kdf(*self))
+        }
+    }
+}
+
+impl std::fmt::Display for KdfParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "m={} KiB, t={}, p={}", self.mem_cost, self.time_cost, self.parallelism)
+    }
+}
+
+/// What decoding does with the byte ranges of chunks that cannot be recovered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum DamagePolicy {
+    /// Discard the output if any chunk is unrecoverable.
+    #[default]
+    Fail,
+    /// Keep the output and write zeros over unrecoverable ranges.
+    ZeroFill,
+    /// Keep the output and leave unrecoverable ranges as holes in a sparse file.
+    Sparse,
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("bits_per_block must be between 1 and {max}, got {got}")]
+    BitsPerBlock { got: usize, max: usize },
+    #[error("repetition must be between 1 and {max}, got {got}")]
+    Repetition { got: usize, max: usize },
+    #[error("compression level must be between {min} and {max}, got {got}")]
+    CompressionLevel { got: i32, min: i32, max: i32 },
+    #[error(
+        "Argon2id parameters out of range ({0}): need 1-{p} lanes, 1-{t} passes, 8 KiB per lane up to {m} KiB, and at most {w} KiB of memory cost times passes",
+        p = ARGON2_MAX_PARALLELISM, t = ARGON2_MAX_TIME_COST, m = ARGON2_MAX_MEM_COST, w = ARGON2_MAX_WORK
+    )]
+    Kdf(KdfParams),
+    #[error("chunk_size must be between 1 and {max}, got {got}")]
+    ChunkSize { got: usize, max: usize },
+    #[error("symbol_size must be between 1 and {max}, got {got}")]
+    SymbolSize { got: usize, max: usize },
+    #[error("inflight_chunks must be at least 1")]
+    InflightChunks,
+    #[error("interleave_depth must be at least 1")]
+    InterleaveDepth,
+    #[error("ecc_parity must be at most {max}, got {got}")]
+    EccParity { got: usize, max: usize },
+    #[error("chunk of {symbols} symbols exceeds the RaptorQ limit of {max}")]
+    TooManySymbols { symbols: usize, max: usize },
+    #[error("frame size {width}x{height} is too small to hold the calibration header and data")]
+    FrameTooSmall { width: u32, height: u32 },
+    #[error(
+        "a {packet}-byte packet does not fit in the {capacity} bytes a frame holds after its header; use frames of at least {min_width}x{min_height}, a smaller symbol size or less ECC parity"
+    )]
+    PacketTooLarge {
+        packet: usize,
+        capacity: usize,
+        min_width: u32,
+        min_height: u32,
+    },
+}
+
 /// Runtime configuration for an encode/decode operation.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Yts3Config {
     pub frame_width: u32,
     pub frame_height: u32,
     pub fps: u32,
     pub bits_per_block: usize,
     pub coefficient_strength: f64,
+    /// Copies of each bit embedded per frame (inner repetition code).
+    pub repetition: usize,
     pub chunk_size: usize,
     pub symbol_size: usize,
     pub repair_overhead: f64,
+    pub fountain: FountainScheme,
+    pub compression: Compression,
+    /// zstd level used when `compression` is [`Compression::Zstd`].
+    pub compression_level: i32,
+    /// Argon2id parameters for password-encrypted videos; recorded in the video
+    /// so decoding does not need them.
+    pub kdf: KdfParams,
+    /// Chunks held in memory at once by the streaming encoder.
+    pub inflight_chunks: usize,
+    /// Chunks whose packets the encoder spreads across each other's frames; 1
+    /// writes every chunk's packets contiguously. Each group is held in memory
+    /// by the encoder and, until recovered, by the decoder.
+    pub interleave_depth: usize,
+    /// Reed–Solomon parity bytes per codeword added to every packet, so
+    /// scattered byte errors are corrected before the packet's CRC is checked.
+    /// 0 disables it.
+    pub ecc_parity: usize,
+    /// How decoding handles chunks it cannot recover.
+    pub damage_policy: DamagePolicy,
 }
 
 impl Default for Yts3Config {
@@ -79,9 +320,216 @@ impl Default for Yts3Config {
             fps: DEFAULT_FPS,
             bits_per_block: DEFAULT_BITS_PER_BLOCK,
             coefficient_strength: DEFAULT_COEFFICIENT_STRENGTH,
+            repetition: DEFAULT_REPETITION,
             chunk_size: DEFAULT_CHUNK_SIZE,
             symbol_size: SYMBOL_SIZE,
             repair_overhead: DEFAULT_REPAIR_OVERHEAD,
+            fountain: FountainScheme::default(),
+            compression: Compression::default(),
+            compression_level: DEFAULT_COMPRESSION_LEVEL,
+            kdf: KdfParams::default(),
+            inflight_chunks: DEFAULT_INFLIGHT_CHUNKS,
+            interleave_depth: DEFAULT_INTERLEAVE_DEPTH,
+            ecc_parity: DEFAULT_ECC_PARITY,
+            damage_policy: DamagePolicy::default(),
+        }
+    }
+}
+
+impl Yts3Config {
+    /// Preset for videos that will be re-encoded lossily: one bit per block at the
+    /// strongest unclipped strength on the lowest-frequency coefficients, each bit
+    /// repeated [`ROBUST_REPETITION`] times across the frame, and extra repair symbols.
+    ///
+    /// A robust frame holds a quarter of what a default one does, so with a
+    /// smaller frame size, follow with [`fit_symbol_size`](Self::fit_symbol_size).
+    pub fn robust() -> Self {
+        Self {
+            bits_per_block: 1,
+            coefficient_strength: ROBUST_COEFFICIENT_STRENGTH,
+            repetition: ROBUST_REPETITION,
+            repair_overhead: ROBUST_REPAIR_OVERHEAD,
+            ..Self::default()
         }
     }
+
+    /// Shrink `symbol_size`, if needed, to the largest whose packets fit whole in
+    /// a data frame. A frame too small for any packet is left for
+    /// [`validate`](Self::validate) to reject.
+    pub fn fit_symbol_size(self) -> Self {
+        let capacity = self.data_bytes_per_frame().saturating_sub(self.frame_header_slot_size());
+        let fits = |symbol_size| {
+            crate::ecc::protected_len(PACKET_HEADER_SIZE + symbol_size, self.ecc_parity) <= capacity
+        };
+        match (1..=self.symbol_size).rev().find(|&symbol_size| fits(symbol_size)) {
+            Some(symbol_size) => Self { symbol_size, ..self },
+            None => self,
+        }
+    }
+
+    /// Check that the configuration describes a layout the video codec can embed,
+    /// with room for at least one whole packet in every data frame.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.validate_unframed()?;
+        // Packets never straddle frames, so every data frame must hold at least one
+        let capacity = self.data_bytes_per_frame().saturating_sub(self.frame_header_slot_size());
+        let packet = self.packet_slot_size();
+        if packet > capacity {
+            let (min_width, min_height) = self.min_frame_size();
+            return Err(ConfigError::PacketTooLarge {
+                packet,
+                capacity,
+                min_width,
+                min_height,
+            });
+        }
+        Ok(())
+    }
+
+    /// The smallest frame size, in whole blocks and in this frame's aspect ratio,
+    /// that holds the calibration header and a packet in every data frame.
+    fn min_frame_size(&self) -> (u32, u32) {
+        let block = BLOCK_SIZE as u32;
+        (1..)
+            .map(|blocks_high| {
+                let height = blocks_high * block;
+                let width = (height * self.frame_width).div_ceil(self.frame_height);
+                (width.next_multiple_of(block), height)
+            })
+            .find(|&(width, height)| {
+                let cfg = Self { frame_width: width, frame_height: height, ..self.clone() };
+                bytes_per_frame(width, height, CALIBRATION_BITS_PER_BLOCK, 1) >= CALIBRATION_HEADER_SIZE
+                    && cfg.data_bytes_per_frame().saturating_sub(cfg.frame_header_slot_size())
+                        >= cfg.packet_slot_size()
+            })
+            .expect("large enough frames hold any packet")
+    }
+
+    /// Like [`validate`](Self::validate), but for videos written before data
+    /// frames held whole packets, whose packets may straddle frames.
+    pub fn validate_unframed(&self) -> Result<(), ConfigError> {
+        if !(1..=MAX_BITS_PER_BLOCK).contains(&self.bits_per_block) {
+            return Err(ConfigError::BitsPerBlock {
+                got: self.bits_per_block,
+                max: MAX_BITS_PER_BLOCK,
+            });
+        }
+        if !(1..=MAX_REPETITION).contains(&self.repetition) {
+            return Err(ConfigError::Repetition {
+                got: self.repetition,
+                max: MAX_REPETITION,
+            });
+        }
+        let levels = crate::compress::level_range();
+        if self.compression == Compression::Zstd && !levels.contains(&self.compression_level) {
+            return Err(ConfigError::CompressionLevel {
+                got: self.compression_level,
+                min: *levels.start(),
+                max: *levels.end(),
+            });
+        }
+        if !(1..=MAX_CHUNK_SIZE).contains(&self.chunk_size) {
+            return Err(ConfigError::ChunkSize {
+                got: self.chunk_size,
+                max: MAX_CHUNK_SIZE,
+            });
+        }
+        self.header_symbol_size()?;
+        self.kdf.validate()?;
+        if self.inflight_chunks == 0 {
+            return Err(ConfigError::InflightChunks);
+        }
+        if self.interleave_depth == 0 {
+            return Err(ConfigError::InterleaveDepth);
+        }
+        if self.ecc_parity > MAX_ECC_PARITY {
+            return Err(ConfigError::EccParity {
+                got: self.ecc_parity,
+                max: MAX_ECC_PARITY,
+            });
+        }
+        let symbols = self.chunk_size.div_ceil(self.symbol_size);
+        if self.fountain == FountainScheme::RaptorQ && symbols > RAPTORQ_MAX_SOURCE_SYMBOLS {
+            return Err(ConfigError::TooManySymbols {
+                symbols,
+                max: RAPTORQ_MAX_SOURCE_SYMBOLS,
+            });
+        }
+        if bytes_per_frame(self.frame_width, self.frame_height, CALIBRATION_BITS_PER_BLOCK, 1)
+            < CALIBRATION_HEADER_SIZE
+            || self.data_bytes_per_frame() == 0
+        {
+            return Err(ConfigError::FrameTooSmall {
+                width: self.frame_width,
+                height: self.frame_height,
+            });
+        }
+        Ok(())
+    }
+
+    /// The symbol size as packet and calibration headers record it, in 16 bits.
+    pub fn header_symbol_size(&self) -> Result<u16, ConfigError> {
+        u16::try_from(self.symbol_size)
+            .ok()
+            .filter(|&symbol_size| symbol_size > 0)
+            .ok_or(ConfigError::SymbolSize {
+                got: self.symbol_size,
+                max: u16::MAX as usize,
+            })
+    }
+
+    /// Bytes each packet takes in a frame, with its Reed–Solomon parity.
+    pub fn packet_slot_size(&self) -> usize {
+        crate::ecc::protected_len(PACKET_HEADER_SIZE + self.symbol_size, self.ecc_parity)
+    }
+
+    /// Bytes the frame header takes at the start of a data frame, with its
+    /// Reed–Solomon parity.
+    pub fn frame_header_slot_size(&self) -> usize {
+        crate::ecc::protected_len(FRAME_HEADER_SIZE, self.ecc_parity)
+    }
+
+    fn data_bytes_per_frame(&self) -> usize {
+        bytes_per_frame(self.frame_width, self.frame_height, self.bits_per_block, self.repetition)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_robust_symbol_size_fits_small_frames() {
+        let cfg = Yts3Config {
+            frame_width: 640,
+            frame_height: 360,
+            ..Yts3Config::robust()
+        };
+        let err = cfg.validate().unwrap_err();
+        assert!(err.to_string().contains("frames of at least 1128x632"), "{err}");
+
+        // 112 bytes a frame leave 25 for the symbol after both headers
+        let fitted = cfg.fit_symbol_size();
+        assert_eq!(fitted.symbol_size, 25);
+        fitted.validate().unwrap();
+        assert_eq!(Yts3Config::robust().fit_symbol_size().symbol_size, SYMBOL_SIZE);
+    }
+
+    #[test]
+    fn test_kdf_params_are_bounded() {
+        let params = |mem_cost, time_cost, parallelism| KdfParams {
+            mem_cost,
+            time_cost,
+            parallelism,
+        };
+        assert!(KdfParams::default().validate().is_ok());
+        assert!(params(ARGON2_MAX_MEM_COST, 4, 16).validate().is_ok());
+        assert!(params(ARGON2_MEM_COST, 64, 4).validate().is_ok());
+
+        // Each within its own cap, but too slow together
+        assert!(params(ARGON2_MAX_MEM_COST, 5, 4).validate().is_err());
+        assert!(params(ARGON2_MAX_MEM_COST + 1, 1, 4).validate().is_err());
+        assert!(params(ARGON2_MEM_COST, 65, 4).validate().is_err());
+        assert!(params(ARGON2_MEM_COST, 3, 17).validate().is_err());
+    }
 }
@@ -1,12 +1,20 @@
+use std::path::Path;
+
 use argon2::Argon2;
 use chacha20poly1305::{
-    aead::{Aead, KeyInit},
+    aead::{Aead, KeyInit, Payload},
     XChaCha20Poly1305, XNonce,
 };
 use rand::RngCore;
 use thiserror::Error;
 
-use crate::config;
+use crate::config::{self, KdfParams};
+
+mod recipient;
+mod signature;
+
+pub use recipient::{unwrap_key, wrap_key, Identity, Recipient};
+pub use signature::{sign, signed_message, verify_signature, Signer, SigningKey};
 
 #[derive(Error, Debug)]
 pub enum CryptoError {
@@ -16,6 +24,99 @@ pub enum CryptoError {
     Encryption(String),
     #[error("decryption failed: {0}")]
     Decryption(String),
+    #[error("invalid key: {0}")]
+    InvalidKey(String),
+    #[error("invalid key record: {0}")]
+    InvalidKeyRecord(String),
+    #[error("invalid signature: {0}")]
+    InvalidSignature(String),
+    #[error("none of the identities can decrypt this file")]
+    NoMatchingIdentity,
+    #[error("encrypt with only one of a password, a key or recipients")]
+    ConflictingSecrets,
+}
+
+/// Secrets for encrypting or decrypting a video.
+///
+/// Encoding encrypts with `password` (through Argon2id), with a raw `key`, or,
+/// if `recipients` are given, with a random data key wrapped for each of them.
+/// Decoding uses whichever the video needs: the password or key, or any of
+/// `identities`. Given any of them, decoding a video that turns out not to be
+/// encrypted is an error unless `allow_unencrypted` is set, since clearing the
+/// encryption flags in the packet headers would otherwise go unnoticed.
+///
+/// Independently of encryption, encoding signs the video if `signing_key` is
+/// set, and decoding rejects videos not signed by one of `trusted_signers`
+/// when any are given, and keeps no partial output of them.
+///
+/// With `prompt_password`, decoding asks for the password on the terminal if
+/// the video turns out to need one and none was given.
+#[derive(Clone, Default)]
+pub struct Credentials {
+    pub password: Option<String>,
+    /// A 32-byte key used as the AEAD key directly, skipping Argon2id.
+    pub key: Option<[u8; config::ARGON2_OUTPUT_LEN]>,
+    pub recipients: Vec<Recipient>,
+    pub identities: Vec<Identity>,
+    pub signing_key: Option<SigningKey>,
+    pub trusted_signers: Vec<Signer>,
+    pub allow_unencrypted: bool,
+    pub prompt_password: bool,
+}
+
+impl Credentials {
+    pub fn password(password: &str) -> Self {
+        Self {
+            password: Some(password.to_string()),
+            ..Self::default()
+        }
+    }
+
+    pub fn key(key: [u8; config::ARGON2_OUTPUT_LEN]) -> Self {
+        Self {
+            key: Some(key),
+            ..Self::default()
+        }
+    }
+
+    pub fn recipients(recipients: Vec<Recipient>) -> Self {
+        Self {
+            recipients,
+            ..Self::default()
+        }
+    }
+
+    pub fn identities(identities: Vec<Identity>) -> Self {
+        Self {
+            identities,
+            ..Self::default()
+        }
+    }
+
+    /// Whether encoding with these credentials encrypts.
+    pub fn encrypts(&self) -> bool {
+        self.password.is_some() || self.key.is_some() || !self.recipients.is_empty()
+    }
+
+    /// Whether these credentials hold a secret to decrypt with.
+    pub fn decrypts(&self) -> bool {
+        self.password.is_some() || self.key.is_some() || !self.identities.is_empty()
+    }
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .field("key", &self.key.as_ref().map(|_| "<redacted>"))
+            .field("recipients", &self.recipients)
+            .field("identities", &self.identities)
+            .field("signing_key", &self.signing_key)
+            .field("trusted_signers", &self.trusted_signers)
+            .field("allow_unencrypted", &self.allow_unencrypted)
+            .field("prompt_password", &self.prompt_password)
+            .finish()
+    }
 }
 
 /// Generate a cryptographically random 16-byte file ID.
@@ -25,15 +126,37 @@ pub fn generate_file_id() -> [u8; config::FILE_ID_SIZE] {
     id
 }
 
+/// Generate a random 32-byte data key, for videos encrypted to recipients.
+pub fn generate_data_key() -> [u8; config::ARGON2_OUTPUT_LEN] {
+    let mut key = [0u8; config::ARGON2_OUTPUT_LEN];
+    rand::thread_rng().fill_bytes(&mut key);
+    key
+}
+
+/// Read a raw key file: exactly 32 bytes, used as the encryption key as is.
+pub fn read_key_file(path: &Path) -> Result<[u8; config::ARGON2_OUTPUT_LEN], CryptoError> {
+    let bytes = std::fs::read(path)
+        .map_err(|e| CryptoError::InvalidKey(format!("{}: {e}", path.display())))?;
+    bytes.as_slice().try_into().map_err(|_| {
+        CryptoError::InvalidKey(format!(
+            "{} holds {} bytes; a key file must be exactly {}",
+            path.display(),
+            bytes.len(),
+            config::ARGON2_OUTPUT_LEN
+        ))
+    })
+}
+
 /// Derive a 32-byte encryption key from a password and file ID using Argon2id.
 pub fn derive_key(
     password: &[u8],
     file_id: &[u8; config::FILE_ID_SIZE],
+    params: &KdfParams,
 ) -> Result<[u8; config::ARGON2_OUTPUT_LEN], CryptoError> {
     let params = argon2::Params::new(
-        config::ARGON2_MEM_COST,
-        config::ARGON2_TIME_COST,
-        config::ARGON2_PARALLELISM,
+        params.mem_cost,
+        params.time_cost,
+        params.parallelism,
         Some(config::ARGON2_OUTPUT_LEN),
     )
     .map_err(|e| CryptoError::KeyDerivation(e.to_string()))?;
@@ -48,29 +171,175 @@ pub fn derive_key(
     Ok(key)
 }
 
-/// Build a deterministic 24-byte nonce from file_id (16 bytes) + chunk_index (4 bytes) + 4 zero bytes.
-fn build_nonce(file_id: &[u8; config::FILE_ID_SIZE], chunk_index: u32) -> [u8; config::NONCE_SIZE] {
+/// How to get a video's key, stored unencrypted in its key record.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyRecord {
+    /// Derive the key from the password with these Argon2id parameters.
+    Password(KdfParams),
+    /// A random data key wrapped for each recipient, as returned by [`wrap_key`].
+    Recipients(Vec<u8>),
+}
+
+// Key record layout: version (1), method (1), then for a password the Argon2id
+// memory cost, time cost and parallelism (4 each), or the wrapped key stanzas
+const KEY_METHOD_ARGON2ID: u8 = 1;
+const KEY_METHOD_RECIPIENTS: u8 = 2;
+const KEY_RECORD_HEADER_SIZE: usize = 2;
+const KDF_PARAMS_SIZE: usize = 12;
+
+impl KeyRecord {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![config::KEY_RECORD_VERSION];
+        match self {
+            Self::Password(params) => {
+                buf.push(KEY_METHOD_ARGON2ID);
+                buf.extend_from_slice(&params.mem_cost.to_le_bytes());
+                buf.extend_from_slice(&params.time_cost.to_le_bytes());
+                buf.extend_from_slice(&params.parallelism.to_le_bytes());
+            }
+            Self::Recipients(stanzas) => {
+                buf.push(KEY_METHOD_RECIPIENTS);
+                buf.extend_from_slice(stanzas);
+            }
+        }
+        buf
+    }
+
+    /// Parse a key record, rejecting Argon2id parameters outside our limits.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, CryptoError> {
+        if data.len() < KEY_RECORD_HEADER_SIZE || data[0] != config::KEY_RECORD_VERSION {
+            return Err(CryptoError::InvalidKeyRecord("unsupported version".to_string()));
+        }
+        let body = &data[KEY_RECORD_HEADER_SIZE..];
+        match data[1] {
+            KEY_METHOD_ARGON2ID => {
+                if body.len() != KDF_PARAMS_SIZE {
+                    return Err(CryptoError::InvalidKeyRecord("truncated Argon2id parameters".to_string()));
+                }
+                let field = |i: usize| u32::from_le_bytes(body[4 * i..4 * i + 4].try_into().unwrap());
+                let params = KdfParams {
+                    mem_cost: field(0),
+                    time_cost: field(1),
+                    parallelism: field(2),
+                };
+                params
+                    .validate()
+                    .map_err(|e| CryptoError::InvalidKeyRecord(e.to_string()))?;
+                Ok(Self::Password(params))
+            }
+            KEY_METHOD_RECIPIENTS => Ok(Self::Recipients(body.to_vec())),
+            other => Err(CryptoError::InvalidKeyRecord(format!("unknown key method {other}"))),
+        }
+    }
+}
+
+/// The keys in a key file: one per line, with blank lines and `#` comments ignored.
+fn key_file_lines(path: &Path) -> Result<Vec<String>, CryptoError> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| CryptoError::InvalidKey(format!("{}: {e}", path.display())))?;
+    let lines: Vec<String> = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect();
+    if lines.is_empty() {
+        return Err(CryptoError::InvalidKey(format!("no keys in {}", path.display())));
+    }
+    Ok(lines)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(s: &str) -> Option<[u8; 32]> {
+    if s.len() != 64 || !s.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0u8; 32];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// Nonce domains keep chunk and record nonces apart under the same key.
+const NONCE_DOMAIN_CHUNK: u32 = 0;
+const NONCE_DOMAIN_RECORD: u32 = 1;
+
+/// Build a deterministic 24-byte nonce from file_id (16 bytes) + index (4 bytes) + domain (4 bytes).
+/// Chunks use domain 0, which matches the original file_id + chunk_index + 4 zero bytes layout.
+fn build_nonce(
+    file_id: &[u8; config::FILE_ID_SIZE],
+    index: u32,
+    domain: u32,
+) -> [u8; config::NONCE_SIZE] {
     let mut nonce = [0u8; config::NONCE_SIZE];
     nonce[..16].copy_from_slice(file_id);
-    nonce[16..20].copy_from_slice(&chunk_index.to_le_bytes());
+    nonce[16..20].copy_from_slice(&index.to_le_bytes());
+    nonce[20..24].copy_from_slice(&domain.to_le_bytes());
     nonce
 }
 
-/// Encrypt a chunk using XChaCha20-Poly1305.
+/// Encrypt a chunk using XChaCha20-Poly1305, authenticating `aad` (the packet
+/// header fields describing the chunk) along with it.
 /// Returns: [plaintext_size_le(4 bytes)] || [ciphertext + tag]
 pub fn encrypt_chunk(
     key: &[u8; config::ARGON2_OUTPUT_LEN],
     file_id: &[u8; config::FILE_ID_SIZE],
     chunk_index: u32,
+    aad: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+    encrypt(key, &build_nonce(file_id, chunk_index, NONCE_DOMAIN_CHUNK), aad, plaintext)
+}
+
+/// Decrypt a chunk. Input format: [plaintext_size_le(4 bytes)] || [ciphertext + tag]
+pub fn decrypt_chunk(
+    key: &[u8; config::ARGON2_OUTPUT_LEN],
+    file_id: &[u8; config::FILE_ID_SIZE],
+    chunk_index: u32,
+    aad: &[u8],
+    encrypted: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+    decrypt(key, &build_nonce(file_id, chunk_index, NONCE_DOMAIN_CHUNK), aad, encrypted)
+}
+
+/// Encrypt a metadata record of the given kind. Same format as [`encrypt_chunk`].
+pub fn encrypt_record(
+    key: &[u8; config::ARGON2_OUTPUT_LEN],
+    file_id: &[u8; config::FILE_ID_SIZE],
+    kind: u32,
+    aad: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+    encrypt(key, &build_nonce(file_id, kind, NONCE_DOMAIN_RECORD), aad, plaintext)
+}
+
+/// Decrypt a metadata record of the given kind.
+pub fn decrypt_record(
+    key: &[u8; config::ARGON2_OUTPUT_LEN],
+    file_id: &[u8; config::FILE_ID_SIZE],
+    kind: u32,
+    aad: &[u8],
+    encrypted: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+    decrypt(key, &build_nonce(file_id, kind, NONCE_DOMAIN_RECORD), aad, encrypted)
+}
+
+fn encrypt(
+    key: &[u8; config::ARGON2_OUTPUT_LEN],
+    nonce_bytes: &[u8; config::NONCE_SIZE],
+    aad: &[u8],
     plaintext: &[u8],
 ) -> Result<Vec<u8>, CryptoError> {
     let key = chacha20poly1305::Key::from_slice(key);
     let cipher = XChaCha20Poly1305::new(key);
-    let nonce_bytes = build_nonce(file_id, chunk_index);
-    let nonce = XNonce::from_slice(&nonce_bytes);
+    let nonce = XNonce::from_slice(nonce_bytes);
 
     let ciphertext = cipher
-        .encrypt(nonce, plaintext)
+        .encrypt(nonce, Payload { msg: plaintext, aad })
         .map_err(|e| CryptoError::Encryption(e.to_string()))?;
 
     let plaintext_len = plaintext.len() as u32;
@@ -80,11 +349,10 @@ pub fn encrypt_chunk(
     Ok(result)
 }
 
-/// Decrypt a chunk. Input format: [plaintext_size_le(4 bytes)] || [ciphertext + tag]
-pub fn decrypt_chunk(
+fn decrypt(
     key: &[u8; config::ARGON2_OUTPUT_LEN],
-    file_id: &[u8; config::FILE_ID_SIZE],
-    chunk_index: u32,
+    nonce_bytes: &[u8; config::NONCE_SIZE],
+    aad: &[u8],
     encrypted: &[u8],
 ) -> Result<Vec<u8>, CryptoError> {
     if encrypted.len() < config::ENCRYPTED_HEADER_SIZE {
@@ -100,11 +368,10 @@ pub fn decrypt_chunk(
 
     let key = chacha20poly1305::Key::from_slice(key);
     let cipher = XChaCha20Poly1305::new(key);
-    let nonce_bytes = build_nonce(file_id, chunk_index);
-    let nonce = XNonce::from_slice(&nonce_bytes);
+    let nonce = XNonce::from_slice(nonce_bytes);
 
     cipher
-        .decrypt(nonce, ciphertext)
+        .decrypt(nonce, Payload { msg: ciphertext, aad })
         .map_err(|e| CryptoError::Decryption(e.to_string()))
 }
 
@@ -132,49 +399,104 @@ mod tests {
     #[test]
     fn test_key_derivation() {
         let file_id = generate_file_id();
-        let key1 = derive_key(b"password123", &file_id).unwrap();
-        let key2 = derive_key(b"password123", &file_id).unwrap();
+        let key1 = derive_key(b"password123", &file_id, &KdfParams::default()).unwrap();
+        let key2 = derive_key(b"password123", &file_id, &KdfParams::default()).unwrap();
         assert_eq!(key1, key2);
 
-        let key3 = derive_key(b"different", &file_id).unwrap();
+        let key3 = derive_key(b"different", &file_id, &KdfParams::default()).unwrap();
         assert_ne!(key1, key3);
     }
 
+    #[test]
+    fn test_key_record_roundtrip() {
+        let cheap = KdfParams {
+            mem_cost: 64,
+            time_cost: 1,
+            parallelism: 1,
+        };
+        for record in [KeyRecord::Password(cheap), KeyRecord::Recipients(vec![1, 2, 3])] {
+            assert_eq!(KeyRecord::from_bytes(&record.to_bytes()).unwrap(), record);
+        }
+        let file_id = generate_file_id();
+        assert_ne!(
+            derive_key(b"pw", &file_id, &cheap).unwrap(),
+            derive_key(b"pw", &file_id, &KdfParams { time_cost: 2, ..cheap }).unwrap()
+        );
+
+        // A corrupt record cannot ask for an absurd amount of memory
+        let huge = KdfParams {
+            mem_cost: u32::MAX,
+            ..cheap
+        };
+        assert!(matches!(
+            KeyRecord::from_bytes(&KeyRecord::Password(huge).to_bytes()),
+            Err(CryptoError::InvalidKeyRecord(_))
+        ));
+    }
+
     #[test]
     fn test_encrypt_decrypt_roundtrip() {
         let file_id = generate_file_id();
-        let key = derive_key(b"test_password", &file_id).unwrap();
+        let key = derive_key(b"test_password", &file_id, &KdfParams::default()).unwrap();
         let plaintext = b"Hello, YouTube S3!";
 
-        let encrypted = encrypt_chunk(&key, &file_id, 0, plaintext).unwrap();
+        let encrypted = encrypt_chunk(&key, &file_id, 0, b"", plaintext).unwrap();
         assert_ne!(&encrypted[4..], plaintext.as_slice());
 
-        let decrypted = decrypt_chunk(&key, &file_id, 0, &encrypted).unwrap();
+        let decrypted = decrypt_chunk(&key, &file_id, 0, b"", &encrypted).unwrap();
         assert_eq!(decrypted, plaintext);
+
+        // The associated data is authenticated too
+        let encrypted = encrypt_chunk(&key, &file_id, 0, b"header", plaintext).unwrap();
+        assert_eq!(decrypt_chunk(&key, &file_id, 0, b"header", &encrypted).unwrap(), plaintext);
+        assert!(decrypt_chunk(&key, &file_id, 0, b"headex", &encrypted).is_err());
+        assert!(decrypt_chunk(&key, &file_id, 0, b"", &encrypted).is_err());
     }
 
     #[test]
     fn test_wrong_key_fails() {
         let file_id = generate_file_id();
-        let key1 = derive_key(b"correct", &file_id).unwrap();
-        let key2 = derive_key(b"wrong", &file_id).unwrap();
+        let key1 = derive_key(b"correct", &file_id, &KdfParams::default()).unwrap();
+        let key2 = derive_key(b"wrong", &file_id, &KdfParams::default()).unwrap();
 
-        let encrypted = encrypt_chunk(&key1, &file_id, 0, b"secret data").unwrap();
-        let result = decrypt_chunk(&key2, &file_id, 0, &encrypted);
+        let encrypted = encrypt_chunk(&key1, &file_id, 0, b"", b"secret data").unwrap();
+        let result = decrypt_chunk(&key2, &file_id, 0, b"", &encrypted);
         assert!(result.is_err());
     }
 
     #[test]
     fn test_different_chunk_indices() {
         let file_id = generate_file_id();
-        let key = derive_key(b"password", &file_id).unwrap();
+        let key = derive_key(b"password", &file_id, &KdfParams::default()).unwrap();
         let plaintext = b"same data";
 
-        let enc1 = encrypt_chunk(&key, &file_id, 0, plaintext).unwrap();
-        let enc2 = encrypt_chunk(&key, &file_id, 1, plaintext).unwrap();
+        let enc1 = encrypt_chunk(&key, &file_id, 0, b"", plaintext).unwrap();
+        let enc2 = encrypt_chunk(&key, &file_id, 1, b"", plaintext).unwrap();
         assert_ne!(enc1, enc2);
     }
 
+    #[test]
+    fn test_record_nonces_differ_from_chunk_nonces() {
+        let file_id = generate_file_id();
+        let key = [3u8; config::ARGON2_OUTPUT_LEN];
+
+        let record = encrypt_record(&key, &file_id, 1, b"", b"manifest").unwrap();
+        assert_ne!(record, encrypt_chunk(&key, &file_id, 1, b"", b"manifest").unwrap());
+        assert!(decrypt_chunk(&key, &file_id, 1, b"", &record).is_err());
+        assert_eq!(decrypt_record(&key, &file_id, 1, b"", &record).unwrap(), b"manifest");
+    }
+
+    #[test]
+    fn test_read_key_file() {
+        let path = std::env::temp_dir().join(format!("yts3-key-{}", std::process::id()));
+        std::fs::write(&path, [7u8; 32]).unwrap();
+        assert_eq!(read_key_file(&path).unwrap(), [7u8; 32]);
+        // A trailing newline makes it the wrong length
+        std::fs::write(&path, [[7u8; 32].as_slice(), b"\n"].concat()).unwrap();
+        assert!(matches!(read_key_file(&path), Err(CryptoError::InvalidKey(_))));
+        std::fs::remove_file(&path).unwrap();
+    }
+
     #[test]
     fn test_secure_zero() {
         let mut buf = [0xFFu8; 32];
@@ -0,0 +1,216 @@
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+use super::{decrypt, encrypt, from_hex, key_file_lines, to_hex, CryptoError};
+use crate::config;
+
+const RECIPIENT_PREFIX: &str = "yts3-pub-";
+const IDENTITY_PREFIX: &str = "YTS3-SECRET-KEY-";
+const HKDF_INFO: &[u8] = b"yts3 recipient key wrap v1";
+
+// Wrapped key layout: stanza count (2), then per stanza the ephemeral public
+// key (32) and the wrapped data key (4 + 32 + 16)
+const COUNT_SIZE: usize = 2;
+const WRAPPED_KEY_SIZE: usize = config::ENCRYPTED_HEADER_SIZE + config::ARGON2_OUTPUT_LEN + config::AEAD_TAG_SIZE;
+const STANZA_SIZE: usize = 32 + WRAPPED_KEY_SIZE;
+
+/// An X25519 public key a video's data key can be wrapped for, written as
+/// `yts3-pub-` followed by 64 hex digits.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Recipient(PublicKey);
+
+/// The X25519 secret key matching a [`Recipient`], written as
+/// `YTS3-SECRET-KEY-` followed by 64 hex digits.
+#[derive(Clone)]
+pub struct Identity(StaticSecret);
+
+impl Identity {
+    pub fn generate() -> Self {
+        Self(StaticSecret::random_from_rng(OsRng))
+    }
+
+    pub fn recipient(&self) -> Recipient {
+        Recipient(PublicKey::from(&self.0))
+    }
+
+    /// Read every identity in an identity file: one key per line, with blank
+    /// lines and `#` comments ignored.
+    pub fn read_file(path: &Path) -> Result<Vec<Self>, CryptoError> {
+        key_file_lines(path)?.iter().map(|line| line.parse()).collect()
+    }
+
+    /// Contents of an identity file for this key, with the public key in a comment.
+    pub fn to_file_contents(&self) -> String {
+        format!("# public key: {}\n{self}\n", self.recipient())
+    }
+}
+
+impl fmt::Display for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{RECIPIENT_PREFIX}{}", to_hex(self.0.as_bytes()))
+    }
+}
+
+impl fmt::Debug for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Recipient({self})")
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{IDENTITY_PREFIX}{}", to_hex(self.0.as_bytes()).to_uppercase())
+    }
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Identity({})", self.recipient())
+    }
+}
+
+impl FromStr for Recipient {
+    type Err = CryptoError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = s
+            .strip_prefix(RECIPIENT_PREFIX)
+            .and_then(from_hex)
+            .ok_or_else(|| CryptoError::InvalidKey(format!("not a yts3 public key: {s:?}")))?;
+        Ok(Self(PublicKey::from(bytes)))
+    }
+}
+
+impl FromStr for Identity {
+    type Err = CryptoError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = s
+            .strip_prefix(IDENTITY_PREFIX)
+            .and_then(from_hex)
+            .ok_or_else(|| CryptoError::InvalidKey("not a yts3 secret key".to_string()))?;
+        Ok(Self(StaticSecret::from(bytes)))
+    }
+}
+
+/// Wrap `data_key` for every recipient, returning the stanzas for a
+/// [`KeyRecord::Recipients`](super::KeyRecord::Recipients).
+///
+/// Each recipient gets its own ephemeral X25519 key; the shared secret is run
+/// through HKDF-SHA256 (salted with the file id, bound to both public keys) to
+/// get a key that encrypts the data key with XChaCha20-Poly1305.
+pub fn wrap_key(
+    data_key: &[u8; config::ARGON2_OUTPUT_LEN],
+    file_id: &[u8; config::FILE_ID_SIZE],
+    recipients: &[Recipient],
+) -> Result<Vec<u8>, CryptoError> {
+    let count = u16::try_from(recipients.len())
+        .map_err(|_| CryptoError::Encryption("too many recipients".to_string()))?;
+    let mut record = count.to_le_bytes().to_vec();
+    for recipient in recipients {
+        let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+        let ephemeral_public = PublicKey::from(&ephemeral);
+        let shared = ephemeral.diffie_hellman(&recipient.0);
+        if !shared.was_contributory() {
+            return Err(CryptoError::InvalidKey(format!("{recipient} is a low-order point")));
+        }
+        let wrapping_key = wrapping_key(shared.as_bytes(), file_id, &ephemeral_public, &recipient.0)?;
+        record.extend_from_slice(ephemeral_public.as_bytes());
+        record.extend_from_slice(&encrypt(&wrapping_key, &[0; config::NONCE_SIZE], &[], data_key)?);
+    }
+    Ok(record)
+}
+
+/// Recover the data key from wrapped key stanzas with any of `identities`.
+pub fn unwrap_key(
+    record: &[u8],
+    file_id: &[u8; config::FILE_ID_SIZE],
+    identities: &[Identity],
+) -> Result<[u8; config::ARGON2_OUTPUT_LEN], CryptoError> {
+    if record.len() < COUNT_SIZE {
+        return Err(CryptoError::InvalidKeyRecord("no stanza count".to_string()));
+    }
+    let count = u16::from_le_bytes([record[0], record[1]]) as usize;
+    let stanzas = &record[COUNT_SIZE..];
+    if stanzas.len() != count * STANZA_SIZE {
+        return Err(CryptoError::InvalidKeyRecord("truncated key stanzas".to_string()));
+    }
+
+    for stanza in stanzas.chunks_exact(STANZA_SIZE) {
+        let ephemeral_public = PublicKey::from(<[u8; 32]>::try_from(&stanza[..32]).unwrap());
+        for identity in identities {
+            let shared = identity.0.diffie_hellman(&ephemeral_public);
+            if !shared.was_contributory() {
+                continue;
+            }
+            let recipient = PublicKey::from(&identity.0);
+            let wrapping_key = wrapping_key(shared.as_bytes(), file_id, &ephemeral_public, &recipient)?;
+            // A stanza for someone else fails authentication
+            if let Ok(key) = decrypt(&wrapping_key, &[0; config::NONCE_SIZE], &[], &stanza[32..]) {
+                return key
+                    .try_into()
+                    .map_err(|_| CryptoError::Decryption("wrapped key has the wrong length".to_string()));
+            }
+        }
+    }
+    Err(CryptoError::NoMatchingIdentity)
+}
+
+fn wrapping_key(
+    shared: &[u8; 32],
+    file_id: &[u8; config::FILE_ID_SIZE],
+    ephemeral: &PublicKey,
+    recipient: &PublicKey,
+) -> Result<[u8; config::ARGON2_OUTPUT_LEN], CryptoError> {
+    let mut info = HKDF_INFO.to_vec();
+    info.extend_from_slice(ephemeral.as_bytes());
+    info.extend_from_slice(recipient.as_bytes());
+    let mut key = [0u8; config::ARGON2_OUTPUT_LEN];
+    Hkdf::<Sha256>::new(Some(file_id), shared)
+        .expand(&info, &mut key)
+        .map_err(|e| CryptoError::KeyDerivation(e.to_string()))?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_wrapped_for_each_recipient() {
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+        let eve = Identity::generate();
+        let file_id = [9u8; config::FILE_ID_SIZE];
+        let data_key = [42u8; config::ARGON2_OUTPUT_LEN];
+
+        let record = wrap_key(&data_key, &file_id, &[alice.recipient(), bob.recipient()]).unwrap();
+        assert_eq!(unwrap_key(&record, &file_id, std::slice::from_ref(&alice)).unwrap(), data_key);
+        assert_eq!(unwrap_key(&record, &file_id, &[eve.clone(), bob]).unwrap(), data_key);
+        assert!(matches!(
+            unwrap_key(&record, &file_id, &[eve]),
+            Err(CryptoError::NoMatchingIdentity)
+        ));
+        // The stanzas are bound to the file id
+        assert!(unwrap_key(&record, &[0; config::FILE_ID_SIZE], &[alice]).is_err());
+    }
+
+    #[test]
+    fn test_key_text_roundtrip() {
+        let identity = Identity::generate();
+        let recipient = identity.recipient();
+        assert_eq!(recipient.to_string().parse::<Recipient>().unwrap(), recipient);
+        let parsed: Identity = identity.to_string().parse().unwrap();
+        assert_eq!(parsed.recipient(), recipient);
+        assert!(!format!("{identity:?}").contains(IDENTITY_PREFIX));
+
+        assert!("yts3-pub-1234".parse::<Recipient>().is_err());
+        assert!(recipient.to_string().replace("pub", "key").parse::<Recipient>().is_err());
+    }
+}
@@ -0,0 +1,182 @@
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use ed25519_dalek::{Signature, Signer as _, Verifier as _, VerifyingKey};
+use rand::rngs::OsRng;
+
+use super::{from_hex, key_file_lines, to_hex, CryptoError};
+use crate::config;
+
+const SIGNER_PREFIX: &str = "yts3-signer-";
+const SIGNING_KEY_PREFIX: &str = "YTS3-SIGNING-KEY-";
+const SIGNED_MESSAGE_DOMAIN: &[u8] = b"yts3 signature v1";
+
+// Signature record layout: version (1), the signer's public key (32), signature (64)
+const SIGNATURE_RECORD_SIZE: usize = 1 + 32 + 64;
+
+/// An Ed25519 public key videos are signed with, written as `yts3-signer-`
+/// followed by 64 hex digits.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Signer(VerifyingKey);
+
+/// The Ed25519 secret key matching a [`Signer`], written as `YTS3-SIGNING-KEY-`
+/// followed by 64 hex digits.
+#[derive(Clone)]
+pub struct SigningKey(ed25519_dalek::SigningKey);
+
+impl SigningKey {
+    pub fn generate() -> Self {
+        Self(ed25519_dalek::SigningKey::generate(&mut OsRng))
+    }
+
+    pub fn signer(&self) -> Signer {
+        Signer(self.0.verifying_key())
+    }
+
+    /// Read a signing key file: the key on one line, with blank lines and `#`
+    /// comments ignored.
+    pub fn read_file(path: &Path) -> Result<Self, CryptoError> {
+        match key_file_lines(path)?.as_slice() {
+            [line] => line.parse(),
+            _ => Err(CryptoError::InvalidKey(format!(
+                "{} holds more than one key",
+                path.display()
+            ))),
+        }
+    }
+
+    /// Contents of a key file for this key, with the public key in a comment.
+    pub fn to_file_contents(&self) -> String {
+        format!("# public key: {}\n{self}\n", self.signer())
+    }
+}
+
+impl fmt::Display for Signer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{SIGNER_PREFIX}{}", to_hex(self.0.as_bytes()))
+    }
+}
+
+impl fmt::Debug for Signer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Signer({self})")
+    }
+}
+
+impl fmt::Display for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{SIGNING_KEY_PREFIX}{}", to_hex(self.0.as_bytes()).to_uppercase())
+    }
+}
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SigningKey({})", self.signer())
+    }
+}
+
+impl FromStr for Signer {
+    type Err = CryptoError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.strip_prefix(SIGNER_PREFIX)
+            .and_then(from_hex)
+            .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())
+            .map(Self)
+            .ok_or_else(|| CryptoError::InvalidKey(format!("not a yts3 signer key: {s:?}")))
+    }
+}
+
+impl FromStr for SigningKey {
+    type Err = CryptoError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = s
+            .strip_prefix(SIGNING_KEY_PREFIX)
+            .and_then(from_hex)
+            .ok_or_else(|| CryptoError::InvalidKey("not a yts3 signing key".to_string()))?;
+        Ok(Self(ed25519_dalek::SigningKey::from_bytes(&bytes)))
+    }
+}
+
+/// The bytes a video's signature covers: its file id, chunk count and size, and
+/// every other record as stored in the video, which for an encrypted video is
+/// its ciphertext (the manifest holds the file's SHA-256).
+pub fn signed_message(
+    file_id: &[u8; config::FILE_ID_SIZE],
+    total_chunks: u32,
+    file_size: u64,
+    records: &[(u32, &[u8])],
+) -> Vec<u8> {
+    let mut message = SIGNED_MESSAGE_DOMAIN.to_vec();
+    message.extend_from_slice(file_id);
+    message.extend_from_slice(&total_chunks.to_le_bytes());
+    message.extend_from_slice(&file_size.to_le_bytes());
+    for (kind, data) in records {
+        message.extend_from_slice(&kind.to_le_bytes());
+        message.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        message.extend_from_slice(data);
+    }
+    message
+}
+
+/// Sign `message`, returning the signature record.
+pub fn sign(key: &SigningKey, message: &[u8]) -> Vec<u8> {
+    let mut record = vec![config::SIGNATURE_VERSION];
+    record.extend_from_slice(key.0.verifying_key().as_bytes());
+    record.extend_from_slice(&key.0.sign(message).to_bytes());
+    record
+}
+
+/// Check a signature record against `message`, returning who signed it. Whether
+/// that signer is trusted is up to the caller.
+pub fn verify_signature(record: &[u8], message: &[u8]) -> Result<Signer, CryptoError> {
+    if record.len() != SIGNATURE_RECORD_SIZE || record[0] != config::SIGNATURE_VERSION {
+        return Err(CryptoError::InvalidSignature("unsupported signature record".to_string()));
+    }
+    let key = VerifyingKey::from_bytes(record[1..33].try_into().unwrap())
+        .map_err(|e| CryptoError::InvalidSignature(e.to_string()))?;
+    let signature = Signature::from_bytes(record[33..].try_into().unwrap());
+    key.verify(message, &signature)
+        .map_err(|_| CryptoError::InvalidSignature(format!("does not match the video ({})", Signer(key))))?;
+    Ok(Signer(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature_binds_video_metadata() {
+        let key = SigningKey::generate();
+        let file_id = [3u8; config::FILE_ID_SIZE];
+        let message = signed_message(&file_id, 4, 4000, &[(1, b"manifest")]);
+        let record = sign(&key, &message);
+
+        assert_eq!(verify_signature(&record, &message).unwrap(), key.signer());
+        for other in [
+            signed_message(&[4; config::FILE_ID_SIZE], 4, 4000, &[(1, b"manifest")]),
+            signed_message(&file_id, 3, 4000, &[(1, b"manifest")]),
+            signed_message(&file_id, 4, 4000, &[(1, b"manifesx")]),
+            signed_message(&file_id, 4, 4000, &[]),
+        ] {
+            assert!(verify_signature(&record, &other).is_err());
+        }
+
+        // Swapping in another key's public half does not make its signature valid
+        let mut forged = record.clone();
+        forged[1..33].copy_from_slice(SigningKey::generate().signer().0.as_bytes());
+        assert!(verify_signature(&forged, &message).is_err());
+    }
+
+    #[test]
+    fn test_signing_key_text_roundtrip() {
+        let key = SigningKey::generate();
+        let parsed: SigningKey = key.to_string().parse().unwrap();
+        assert_eq!(parsed.signer(), key.signer());
+        assert_eq!(key.signer().to_string().parse::<Signer>().unwrap(), key.signer());
+        assert!(!format!("{key:?}").contains(SIGNING_KEY_PREFIX));
+        assert!("yts3-signer-00".parse::<Signer>().is_err());
+    }
+}
@@ -0,0 +1,415 @@
+//! Reed–Solomon error correction over GF(2^8), applied to each packet before
+//! it is embedded.
+//!
+//! A packet is split across as many interleaved codewords of up to 255 bytes
+//! as it needs, each with `parity` check bytes, so a run of damaged bytes is
+//! shared between codewords. Every codeword corrects up to `parity / 2`
+//! unknown byte errors, or up to `parity` erasures (bytes known to be
+//! unreliable), or any mix with `2 * errors + erasures <= parity`.
+
+use thiserror::Error;
+
+/// Bytes in a full codeword, data and parity together.
+const CODEWORD_SIZE: usize = 255;
+/// x^8 + x^4 + x^3 + x^2 + 1, with 2 as the generator.
+const PRIMITIVE_POLY: u16 = 0x11D;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum EccError {
+    #[error("buffer too short for protected data: need {need} bytes, have {have}")]
+    BufferTooShort { need: usize, have: usize },
+    #[error("too many errors to correct")]
+    Uncorrectable,
+}
+
+struct Tables {
+    exp: [u8; 512],
+    log: [u8; 256],
+}
+
+static TABLES: Tables = build_tables();
+
+const fn build_tables() -> Tables {
+    let mut exp = [0u8; 512];
+    let mut log = [0u8; 256];
+    let mut x: u16 = 1;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = x as u8;
+        log[x as usize] = i as u8;
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= PRIMITIVE_POLY;
+        }
+        i += 1;
+    }
+    // Doubled so a product of two logs needs no reduction
+    while i < 512 {
+        exp[i] = exp[i - 255];
+        i += 1;
+    }
+    Tables { exp, log }
+}
+
+fn mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    TABLES.exp[TABLES.log[a as usize] as usize + TABLES.log[b as usize] as usize]
+}
+
+fn div(a: u8, b: u8) -> u8 {
+    if a == 0 {
+        return 0;
+    }
+    TABLES.exp[TABLES.log[a as usize] as usize + 255 - TABLES.log[b as usize] as usize]
+}
+
+/// `2^power`, for any power.
+fn alpha(power: i64) -> u8 {
+    TABLES.exp[power.rem_euclid(255) as usize]
+}
+
+fn inverse(a: u8) -> u8 {
+    TABLES.exp[255 - TABLES.log[a as usize] as usize]
+}
+
+// Polynomials are stored highest degree first.
+
+fn poly_scale(p: &[u8], x: u8) -> Vec<u8> {
+    p.iter().map(|&c| mul(c, x)).collect()
+}
+
+fn poly_add(p: &[u8], q: &[u8]) -> Vec<u8> {
+    let len = p.len().max(q.len());
+    let mut sum = vec![0u8; len];
+    for (i, &c) in p.iter().enumerate() {
+        sum[i + len - p.len()] = c;
+    }
+    for (i, &c) in q.iter().enumerate() {
+        sum[i + len - q.len()] ^= c;
+    }
+    sum
+}
+
+fn poly_mul(p: &[u8], q: &[u8]) -> Vec<u8> {
+    let mut product = vec![0u8; p.len() + q.len() - 1];
+    for (j, &qc) in q.iter().enumerate() {
+        for (i, &pc) in p.iter().enumerate() {
+            product[i + j] ^= mul(pc, qc);
+        }
+    }
+    product
+}
+
+fn poly_eval(p: &[u8], x: u8) -> u8 {
+    p.iter().fold(0, |acc, &c| mul(acc, x) ^ c)
+}
+
+/// Total length of `data_len` bytes once protected with `parity` check bytes
+/// per codeword.
+pub fn protected_len(data_len: usize, parity: usize) -> usize {
+    if parity == 0 {
+        return data_len;
+    }
+    data_len + codewords(data_len, parity) * parity
+}
+
+fn codewords(data_len: usize, parity: usize) -> usize {
+    data_len.div_ceil(CODEWORD_SIZE - parity).max(1)
+}
+
+/// A systematic Reed–Solomon code with `parity` check bytes per codeword.
+/// With no parity, data passes through unchanged.
+#[derive(Debug, Clone)]
+pub struct ReedSolomon {
+    parity: usize,
+    generator: Vec<u8>,
+}
+
+impl ReedSolomon {
+    /// `parity` must be below 255.
+    pub fn new(parity: usize) -> Self {
+        let generator = (0..parity).fold(vec![1], |g, i| poly_mul(&g, &[1, alpha(i as i64)]));
+        Self { parity, generator }
+    }
+
+    pub fn protected_len(&self, data_len: usize) -> usize {
+        protected_len(data_len, self.parity)
+    }
+
+    /// Append parity to `data`. Byte `i` of the data goes to codeword
+    /// `i % codewords`, and the parity bytes follow the data, interleaved the
+    /// same way.
+    pub fn protect(&self, data: &[u8]) -> Vec<u8> {
+        let mut out = data.to_vec();
+        if self.parity == 0 {
+            return out;
+        }
+        let n = codewords(data.len(), self.parity);
+        out.resize(self.protected_len(data.len()), 0);
+        for j in 0..n {
+            let message: Vec<u8> = data.iter().skip(j).step_by(n).copied().collect();
+            for (k, p) in self.parity_of(&message).into_iter().enumerate() {
+                out[data.len() + k * n + j] = p;
+            }
+        }
+        out
+    }
+
+    /// Correct `block`, the protected form of `data_len` bytes, and return the
+    /// data along with how many bytes were corrected. `erasures` lists offsets
+    /// in `block` known to be unreliable, in ascending order.
+    pub fn correct(
+        &self,
+        block: &[u8],
+        data_len: usize,
+        erasures: &[usize],
+    ) -> Result<(Vec<u8>, usize), EccError> {
+        let need = self.protected_len(data_len);
+        if block.len() < need {
+            return Err(EccError::BufferTooShort { need, have: block.len() });
+        }
+        let mut data = block[..data_len].to_vec();
+        if self.parity == 0 {
+            return Ok((data, 0));
+        }
+
+        let n = codewords(data_len, self.parity);
+        let mut corrected = 0;
+        for j in 0..n {
+            let offsets: Vec<usize> = (j..data_len)
+                .step_by(n)
+                .chain((0..self.parity).map(|k| data_len + k * n + j))
+                .collect();
+            let mut codeword: Vec<u8> = offsets.iter().map(|&q| block[q]).collect();
+            let erased: Vec<usize> = offsets
+                .iter()
+                .enumerate()
+                .filter(|(_, q)| erasures.binary_search(q).is_ok())
+                .map(|(i, _)| i)
+                .collect();
+            corrected += self.correct_codeword(&mut codeword, &erased)?;
+            for (&q, &byte) in offsets.iter().zip(&codeword).take_while(|(&q, _)| q < data_len) {
+                data[q] = byte;
+            }
+        }
+        Ok((data, corrected))
+    }
+
+    /// The remainder of `message * x^parity` divided by the generator.
+    fn parity_of(&self, message: &[u8]) -> Vec<u8> {
+        let mut buf = message.to_vec();
+        buf.resize(message.len() + self.parity, 0);
+        for i in 0..message.len() {
+            let coef = buf[i];
+            if coef != 0 {
+                for (j, &g) in self.generator.iter().enumerate().skip(1) {
+                    buf[i + j] ^= mul(g, coef);
+                }
+            }
+        }
+        buf.split_off(message.len())
+    }
+
+    fn syndromes(&self, codeword: &[u8]) -> Vec<u8> {
+        (0..self.parity).map(|i| poly_eval(codeword, alpha(i as i64))).collect()
+    }
+
+    /// Correct one codeword in place, returning how many bytes changed.
+    fn correct_codeword(&self, codeword: &mut [u8], erasures: &[usize]) -> Result<usize, EccError> {
+        if erasures.len() > self.parity {
+            return Err(EccError::Uncorrectable);
+        }
+        let received = codeword.to_vec();
+        for &e in erasures {
+            codeword[e] = 0;
+        }
+        let synd = self.syndromes(codeword);
+        if synd.iter().all(|&s| s == 0) {
+            return Ok(received.iter().zip(codeword.iter()).filter(|(a, b)| a != b).count());
+        }
+
+        let forney = forney_syndromes(&synd, erasures, codeword.len());
+        let locator = self.error_locator(&forney, erasures.len())?;
+        let mut errata: Vec<usize> = erasures.to_vec();
+        errata.extend(find_errors(&locator, codeword.len())?);
+
+        correct_errata(codeword, &synd, &errata)?;
+        if self.syndromes(codeword).iter().any(|&s| s != 0) {
+            return Err(EccError::Uncorrectable);
+        }
+        Ok(received.iter().zip(codeword.iter()).filter(|(a, b)| a != b).count())
+    }
+
+    /// Berlekamp–Massey on the syndromes with the erasures factored out,
+    /// returning the error locator polynomial.
+    fn error_locator(&self, synd: &[u8], erasure_count: usize) -> Result<Vec<u8>, EccError> {
+        let mut locator = vec![1u8];
+        let mut old = vec![1u8];
+        for k in 0..self.parity - erasure_count {
+            let mut delta = synd[k];
+            for j in 1..locator.len() {
+                delta ^= mul(locator[locator.len() - 1 - j], synd[k - j]);
+            }
+            old.push(0);
+            if delta != 0 {
+                if old.len() > locator.len() {
+                    let new = poly_scale(&old, delta);
+                    old = poly_scale(&locator, inverse(delta));
+                    locator = new;
+                }
+                locator = poly_add(&locator, &poly_scale(&old, delta));
+            }
+        }
+        let leading = locator.iter().take_while(|&&c| c == 0).count();
+        locator.drain(..leading);
+        let errors = locator.len().saturating_sub(1);
+        if errors * 2 + erasure_count > self.parity {
+            return Err(EccError::Uncorrectable);
+        }
+        Ok(locator)
+    }
+}
+
+/// Syndromes with the known erasure positions removed, leaving only the
+/// unknown errors for Berlekamp–Massey to locate.
+fn forney_syndromes(synd: &[u8], erasures: &[usize], len: usize) -> Vec<u8> {
+    let mut fsynd = synd.to_vec();
+    for &e in erasures {
+        let x = alpha((len - 1 - e) as i64);
+        for j in 0..fsynd.len() - 1 {
+            fsynd[j] = mul(fsynd[j], x) ^ fsynd[j + 1];
+        }
+    }
+    fsynd
+}
+
+/// Offsets in a codeword of `len` bytes where the error locator has roots.
+fn find_errors(locator: &[u8], len: usize) -> Result<Vec<usize>, EccError> {
+    let errors = locator.len() - 1;
+    let reversed: Vec<u8> = locator.iter().rev().copied().collect();
+    let positions: Vec<usize> = (0..len)
+        .filter(|&i| poly_eval(&reversed, alpha(i as i64)) == 0)
+        .map(|i| len - 1 - i)
+        .collect();
+    if positions.len() != errors {
+        return Err(EccError::Uncorrectable);
+    }
+    Ok(positions)
+}
+
+/// Fix the bytes at the `errata` offsets with the Forney algorithm.
+fn correct_errata(codeword: &mut [u8], synd: &[u8], errata: &[usize]) -> Result<(), EccError> {
+    let len = codeword.len();
+    let coef_pos: Vec<usize> = errata.iter().map(|&p| len - 1 - p).collect();
+    let locator = coef_pos
+        .iter()
+        .fold(vec![1u8], |loc, &i| poly_mul(&loc, &poly_add(&[1], &[alpha(i as i64), 0])));
+
+    // Error evaluator: syndromes (lowest first, times x) times the locator,
+    // mod x^(errata + 1)
+    let mut shifted_synd: Vec<u8> = synd.iter().rev().copied().collect();
+    shifted_synd.push(0);
+    let product = poly_mul(&shifted_synd, &locator);
+    let evaluator: Vec<u8> = product[product.len().saturating_sub(locator.len())..].to_vec();
+
+    let x: Vec<u8> = coef_pos.iter().map(|&i| alpha(i as i64)).collect();
+    for (i, &xi) in x.iter().enumerate() {
+        let xi_inv = inverse(xi);
+        let locator_prime = x
+            .iter()
+            .enumerate()
+            .filter(|&(j, _)| j != i)
+            .fold(1u8, |acc, (_, &xj)| mul(acc, 1 ^ mul(xi_inv, xj)));
+        if locator_prime == 0 {
+            return Err(EccError::Uncorrectable);
+        }
+        let y = mul(xi, poly_eval(&evaluator, xi_inv));
+        codeword[errata[i]] ^= div(y, locator_prime);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_packet() -> Vec<u8> {
+        (0..318).map(|i| (i * 151 % 256) as u8).collect()
+    }
+
+    #[test]
+    fn test_corrects_errors_and_erasures() {
+        let rs = ReedSolomon::new(16);
+        let data = test_packet();
+        let block = rs.protect(&data);
+        assert_eq!(block.len(), 318 + 2 * 16);
+        assert_eq!(&block[..318], data.as_slice());
+        assert_eq!(rs.correct(&block, 318, &[]).unwrap(), (data.clone(), 0));
+
+        // Eight errors in each of the two codewords: a run of 16 bytes
+        let mut damaged = block.clone();
+        for b in &mut damaged[100..116] {
+            *b ^= 0x5A;
+        }
+        assert_eq!(rs.correct(&damaged, 318, &[]).unwrap(), (data.clone(), 16));
+
+        // Fourteen erasures and one unknown error per codeword
+        let mut damaged = block.clone();
+        let erasures: Vec<usize> = (200..226).chain(330..332).collect();
+        for &e in &erasures {
+            damaged[e] = 0xFF;
+        }
+        damaged[10] ^= 1;
+        damaged[11] ^= 1;
+        let (fixed, _) = rs.correct(&damaged, 318, &erasures).unwrap();
+        assert_eq!(fixed, data);
+
+        // One error too many is never corrected to the original
+        let mut damaged = block;
+        for b in &mut damaged[100..118] {
+            *b ^= 0x5A;
+        }
+        assert!(!rs.correct(&damaged, 318, &[]).is_ok_and(|(fixed, _)| fixed == data));
+    }
+
+    #[test]
+    fn test_random_damage_within_capacity_is_corrected() {
+        let rs = ReedSolomon::new(10);
+        let data = test_packet();
+        let block = rs.protect(&data);
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        let mut next = |n: usize| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 33) as usize % n
+        };
+        for _ in 0..200 {
+            // Up to 10 erasures and (10 - erasures) / 2 errors, in one codeword
+            let erasure_count = next(11);
+            let error_count = (10 - erasure_count) / 2;
+            let mut offsets: Vec<usize> = Vec::new();
+            while offsets.len() < erasure_count + error_count {
+                let offset = next(block.len() / 2) * 2;
+                if !offsets.contains(&offset) {
+                    offsets.push(offset);
+                }
+            }
+            let mut damaged = block.clone();
+            for &offset in &offsets {
+                damaged[offset] ^= 1 + next(255) as u8;
+            }
+            let mut erasures = offsets[..erasure_count].to_vec();
+            erasures.sort_unstable();
+            assert_eq!(rs.correct(&damaged, 318, &erasures).unwrap().0, data);
+        }
+    }
+
+    #[test]
+    fn test_no_parity_passes_data_through() {
+        let rs = ReedSolomon::new(0);
+        let data = test_packet();
+        assert_eq!(rs.protect(&data), data);
+        assert_eq!(rs.correct(&data, 318, &[]).unwrap(), (data, 0));
+    }
+}
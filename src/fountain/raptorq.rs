@@ -0,0 +1,193 @@
+use raptorq::{
+    EncodingPacket, ObjectTransmissionInformation, PayloadId, SourceBlockDecoder,
+    SourceBlockEncoder,
+};
+
+use super::{EncodedSymbol, FountainError};
+use crate::config;
+
+/// Every chunk is coded as a single RaptorQ source block.
+const SOURCE_BLOCK: u8 = 0;
+
+fn transmission_info(symbol_size: usize) -> ObjectTransmissionInformation {
+    ObjectTransmissionInformation::new(0, symbol_size as u16, 0, 1, 1)
+}
+
+/// Encode a data chunk into source + repair symbols using a systematic RaptorQ
+/// (RFC 6330) code.
+///
+/// Source symbols are the chunk split into `symbol_size` pieces (ESIs `0..k`).
+/// Repair symbol ESIs start after the RFC 6330 extended source block size, so
+/// they are not contiguous with the source ESIs.
+pub fn encode_chunk(
+    chunk_data: &[u8],
+    symbol_size: usize,
+    repair_overhead: f64,
+) -> Result<Vec<EncodedSymbol>, FountainError> {
+    if chunk_data.is_empty() {
+        return Ok(vec![EncodedSymbol {
+            esi: 0,
+            data: vec![0u8; symbol_size],
+            is_repair: false,
+        }]);
+    }
+
+    let k = chunk_data.len().div_ceil(symbol_size);
+    if k > config::RAPTORQ_MAX_SOURCE_SYMBOLS {
+        return Err(FountainError::Encode(format!(
+            "{k} source symbols exceeds the RaptorQ limit of {}",
+            config::RAPTORQ_MAX_SOURCE_SYMBOLS
+        )));
+    }
+
+    // Pad the chunk to a multiple of symbol_size
+    let mut padded = vec![0u8; k * symbol_size];
+    padded[..chunk_data.len()].copy_from_slice(chunk_data);
+
+    let num_repair = ((k as f64) * repair_overhead).ceil() as u32;
+    let encoder = SourceBlockEncoder::new2(SOURCE_BLOCK, &transmission_info(symbol_size), &padded);
+
+    let source = encoder.source_packets().into_iter().map(|p| (p, false));
+    let repair = encoder.repair_packets(0, num_repair).into_iter().map(|p| (p, true));
+    Ok(source
+        .chain(repair)
+        .map(|(packet, is_repair)| {
+            let (payload_id, data) = packet.split();
+            EncodedSymbol {
+                esi: payload_id.encoding_symbol_id(),
+                data,
+                is_repair,
+            }
+        })
+        .collect())
+}
+
+/// State for decoding a single RaptorQ-coded chunk via Gaussian elimination.
+///
+/// Decoding succeeds with high probability once any `k` (plus a small margin)
+/// distinct symbols have been received, regardless of which ones were lost.
+pub struct RaptorqDecoder {
+    k: usize,
+    symbol_size: usize,
+    decoder: SourceBlockDecoder,
+    pending: Vec<EncodingPacket>,
+    received_source: Vec<bool>,
+    received_source_count: usize,
+    recovered: Option<Vec<u8>>,
+}
+
+impl RaptorqDecoder {
+    pub fn new(k: usize, symbol_size: usize) -> Self {
+        Self {
+            k,
+            symbol_size,
+            decoder: SourceBlockDecoder::new2(
+                SOURCE_BLOCK,
+                &transmission_info(symbol_size),
+                (k * symbol_size) as u64,
+            ),
+            pending: Vec::new(),
+            received_source: vec![false; k],
+            received_source_count: 0,
+            recovered: None,
+        }
+    }
+
+    /// Feed a received symbol to the decoder.
+    pub fn add_symbol(&mut self, esi: u32, data: Vec<u8>, is_repair: bool) {
+        if data.len() != self.symbol_size {
+            return;
+        }
+        if !is_repair {
+            let idx = esi as usize;
+            if idx >= self.k || self.received_source[idx] {
+                return;
+            }
+            self.received_source[idx] = true;
+            self.received_source_count += 1;
+        }
+        self.pending
+            .push(EncodingPacket::new(PayloadId::new(SOURCE_BLOCK, esi), data));
+    }
+
+    /// Check if we have all source symbols (direct recovery).
+    #[cfg(test)]
+    pub fn is_complete(&self) -> bool {
+        self.received_source_count == self.k
+    }
+
+    /// Attempt to recover the original chunk data.
+    pub fn recover(&mut self, original_size: usize) -> Result<Vec<u8>, FountainError> {
+        if self.recovered.is_none() {
+            self.recovered = self.decoder.decode(self.pending.drain(..));
+        }
+
+        match &self.recovered {
+            Some(data) => {
+                let mut result = data.clone();
+                result.truncate(original_size);
+                Ok(result)
+            }
+            None => Err(FountainError::Decode(format!(
+                "not enough independent symbols to recover {} source symbols",
+                self.k
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recover_from_repair_only() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+        let symbol_size = 16;
+        let k = data.len().div_ceil(symbol_size);
+
+        let symbols = encode_chunk(&data, symbol_size, 1.0).unwrap();
+
+        // Drop every source symbol: repair symbols alone must be enough
+        let mut decoder = RaptorqDecoder::new(k, symbol_size);
+        for sym in symbols.iter().filter(|s| s.is_repair) {
+            decoder.add_symbol(sym.esi, sym.data.clone(), true);
+        }
+        assert!(!decoder.is_complete());
+        assert_eq!(decoder.recover(data.len()).unwrap(), data);
+    }
+
+    #[test]
+    fn test_recover_from_any_k_plus_margin() {
+        let data: Vec<u8> = (0..4096u32).map(|i| (i ^ (i >> 3)) as u8).collect();
+        let symbol_size = 32;
+        let k = data.len().div_ceil(symbol_size);
+
+        let symbols = encode_chunk(&data, symbol_size, 0.5).unwrap();
+
+        // Keep every third symbol plus enough of the rest to reach k + 2
+        let mut decoder = RaptorqDecoder::new(k, symbol_size);
+        let kept = symbols
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| i % 3 != 1)
+            .map(|(_, s)| s)
+            .take(k + 2);
+        for sym in kept {
+            decoder.add_symbol(sym.esi, sym.data.clone(), sym.is_repair);
+        }
+        assert_eq!(decoder.recover(data.len()).unwrap(), data);
+    }
+
+    #[test]
+    fn test_insufficient_symbols() {
+        let data = vec![0x5Au8; 256];
+        let symbols = encode_chunk(&data, 32, 1.0).unwrap();
+
+        let mut decoder = RaptorqDecoder::new(8, 32);
+        for sym in symbols.iter().take(4) {
+            decoder.add_symbol(sym.esi, sym.data.clone(), sym.is_repair);
+        }
+        assert!(decoder.recover(data.len()).is_err());
+    }
+}
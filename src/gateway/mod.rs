@@ -0,0 +1,291 @@
+pub mod s3;
+pub mod store;
+
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::thread;
+
+use anyhow::Result;
+use log::{info, warn};
+use thiserror::Error;
+use tiny_http::{Header, Method, Request, Response, Server, StatusCode};
+
+use s3::{AwsChunkedReader, ListParams};
+use store::{ObjectMeta, ObjectStore};
+
+use crate::storage::StorageError;
+
+#[derive(Error, Debug)]
+pub enum GatewayError {
+    #[error("the specified bucket does not exist: {0}")]
+    NoSuchBucket(String),
+    #[error("the specified key does not exist: {0}")]
+    NoSuchKey(String),
+    #[error("invalid bucket name: {0}")]
+    InvalidBucketName(String),
+    #[error("{0}")]
+    InvalidArgument(String),
+    #[error("{0} is not supported by the yts3 gateway")]
+    NotImplemented(String),
+    #[error("the requested range is not satisfiable for an object of {0} bytes")]
+    InvalidRange(u64),
+    #[error("method not allowed on this resource")]
+    MethodNotAllowed,
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error("{0:#}")]
+    Internal(#[from] anyhow::Error),
+}
+
+impl GatewayError {
+    /// S3 error code and HTTP status for this error.
+    fn code(&self) -> (&'static str, u16) {
+        match self {
+            GatewayError::NoSuchBucket(_) => ("NoSuchBucket", 404),
+            GatewayError::NoSuchKey(_) => ("NoSuchKey", 404),
+            GatewayError::InvalidBucketName(_) => ("InvalidBucketName", 400),
+            GatewayError::InvalidArgument(_) => ("InvalidArgument", 400),
+            GatewayError::NotImplemented(_) => ("NotImplemented", 501),
+            GatewayError::InvalidRange(_) => ("InvalidRange", 416),
+            GatewayError::MethodNotAllowed => ("MethodNotAllowed", 405),
+            GatewayError::Io(_) | GatewayError::Storage(_) | GatewayError::Internal(_) => {
+                ("InternalError", 500)
+            }
+        }
+    }
+}
+
+type GatewayResponse = Response<Box<dyn Read + Send>>;
+
+/// Serve a subset of the S3 REST API on `listen`, storing every object as a
+/// yts3 video in `store`.
+///
+/// Supported: ListBuckets, CreateBucket, HeadBucket, PutObject, GetObject
+/// (including a single byte range), HeadObject, DeleteObject and ListObjectsV2,
+/// with path-style addressing
+/// (`http://host/bucket/key`). Request signatures are not checked, so bind to a
+/// local address. Requests are handled by `workers` threads.
+pub fn serve(listen: &str, store: ObjectStore, workers: usize) -> Result<()> {
+    let server = Server::http(listen)
+        .map_err(|e| anyhow::anyhow!("failed to listen on {listen}: {e}"))?;
+    info!("S3 gateway listening on http://{}", server.server_addr());
+
+    thread::scope(|scope| {
+        for _ in 0..workers.max(1) {
+            scope.spawn(|| {
+                for request in server.incoming_requests() {
+                    handle(&store, request);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+fn handle(store: &ObjectStore, mut request: Request) {
+    let method = request.method().clone();
+    let url = request.url().to_string();
+    let response = match route(store, &mut request) {
+        Ok(response) => response,
+        Err(e) => {
+            let (code, status) = e.code();
+            if status >= 500 {
+                warn!("{method} {url}: {e}");
+            }
+            let path = url.split('?').next().unwrap_or("");
+            let body = s3::error_xml(code, &e.to_string(), path);
+            xml_response(status, body)
+        }
+    };
+    info!("{method} {url} -> {}", response.status_code().0);
+    if let Err(e) = request.respond(response) {
+        warn!("failed to send response for {method} {url}: {e}");
+    }
+}
+
+/// Dispatch a request to the S3 operation it names.
+fn route(store: &ObjectStore, request: &mut Request) -> Result<GatewayResponse, GatewayError> {
+    let (path, query) = request.url().split_once('?').unwrap_or((request.url(), ""));
+    let path = s3::percent_decode(path, false)?;
+    let query = s3::parse_query(query)?;
+    let (bucket, key) = match path.trim_start_matches('/').split_once('/') {
+        Some((bucket, key)) => (bucket.to_string(), key.to_string()),
+        None => (path.trim_start_matches('/').to_string(), String::new()),
+    };
+
+    for unsupported in ["uploads", "uploadId", "acl", "tagging", "versioning", "policy"] {
+        if query.contains_key(unsupported) {
+            return Err(GatewayError::NotImplemented(format!("?{unsupported}")));
+        }
+    }
+
+    let method = request.method().clone();
+    match (&method, bucket.is_empty(), key.is_empty()) {
+        (Method::Get, true, _) => {
+            let body = s3::list_buckets_xml(&store.list_buckets()?);
+            Ok(xml_response(200, body))
+        }
+        (Method::Put, false, true) => {
+            store.create_bucket(&bucket)?;
+            Ok(empty_response(200).with_header(header("Location", &format!("/{bucket}"))))
+        }
+        (Method::Head, false, true) => {
+            store.bucket_exists(&bucket)?;
+            Ok(empty_response(200))
+        }
+        (Method::Get, false, true) => {
+            if query.get("list-type").map(String::as_str) != Some("2") {
+                return Err(GatewayError::NotImplemented("ListObjects (v1)".to_string()));
+            }
+            let params = ListParams::from_query(&query)?;
+            let objects = store.list_objects(&bucket)?;
+            Ok(xml_response(200, s3::list_objects_xml(&bucket, &objects, &params)))
+        }
+        (Method::Put, false, false) => {
+            if request_header(request, "x-amz-copy-source").is_some() {
+                return Err(GatewayError::NotImplemented("CopyObject".to_string()));
+            }
+            let content_type = request_header(request, "Content-Type");
+            let aws_chunked = request_header(request, "Content-Encoding")
+                .is_some_and(|e| e.split(',').any(|e| e.trim() == "aws-chunked"));
+            let meta = if aws_chunked {
+                let mut body = AwsChunkedReader::new(BufReader::new(request.as_reader()));
+                store.put_object(&bucket, &key, &mut body, content_type)?
+            } else {
+                store.put_object(&bucket, &key, request.as_reader(), content_type)?
+            };
+            Ok(empty_response(200).with_header(etag_header(&meta)))
+        }
+        (Method::Head, false, false) => {
+            let meta = store.head_object(&bucket, &key)?;
+            Ok(object_response(&meta, None, Box::new(std::io::empty())))
+        }
+        (Method::Get, false, false) => {
+            let (meta, mut file) = store.get_object(&bucket, &key)?;
+            let range = match request_header(request, "Range") {
+                Some(range) => s3::parse_range(&range, meta.size)?,
+                None => None,
+            };
+            let Some(range) = range else {
+                return Ok(object_response(&meta, None, Box::new(file)));
+            };
+            file.seek(SeekFrom::Start(range.start))?;
+            let body = file.take(range.end - range.start);
+            Ok(object_response(&meta, Some(range), Box::new(body)))
+        }
+        (Method::Delete, false, false) => {
+            store.delete_object(&bucket, &key)?;
+            Ok(empty_response(204))
+        }
+        _ => Err(GatewayError::MethodNotAllowed),
+    }
+}
+
+fn request_header(request: &Request, name: &'static str) -> Option<String> {
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv(name))
+        .map(|h| h.value.to_string())
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("valid header")
+}
+
+fn etag_header(meta: &ObjectMeta) -> Header {
+    header("ETag", &format!("\"{}\"", meta.etag))
+}
+
+fn empty_response(status: u16) -> GatewayResponse {
+    Response::new(StatusCode(status), Vec::new(), Box::new(std::io::empty()), Some(0), None)
+}
+
+fn xml_response(status: u16, body: String) -> GatewayResponse {
+    let len = body.len();
+    Response::new(
+        StatusCode(status),
+        vec![header("Content-Type", "application/xml")],
+        Box::new(Cursor::new(body.into_bytes())),
+        Some(len),
+        None,
+    )
+}
+
+/// GetObject/HeadObject response; `body` is empty for HEAD, which tiny_http
+/// answers without a body but with the object's Content-Length. With `range`,
+/// `body` holds only those bytes and the response is a 206.
+fn object_response(
+    meta: &ObjectMeta,
+    range: Option<Range<u64>>,
+    body: Box<dyn Read + Send>,
+) -> GatewayResponse {
+    let content_type = meta.content_type.as_deref().unwrap_or("application/octet-stream");
+    let mut headers = vec![
+        header("Content-Type", content_type),
+        header("Last-Modified", &httpdate::fmt_http_date(meta.modified)),
+        header("Accept-Ranges", "bytes"),
+        etag_header(meta),
+    ];
+    let (status, len) = match range {
+        Some(range) => {
+            let content_range = format!("bytes {}-{}/{}", range.start, range.end - 1, meta.size);
+            headers.push(header("Content-Range", &content_range));
+            (206, range.end - range.start)
+        }
+        None => (200, meta.size),
+    };
+    Response::new(StatusCode(status), headers, body, Some(len as usize), None)
+    // Always send Content-Length rather than switching to chunked for large bodies
+    .with_chunked_threshold(usize::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use tiny_http::TestRequest;
+
+    use super::*;
+    use crate::config::Yts3Config;
+    use crate::crypto::Credentials;
+    use crate::storage::MemoryBackend;
+
+    fn request(method: Method, path: &str) -> Request {
+        TestRequest::new().with_method(method).with_path(path).into()
+    }
+
+    fn body(response: GatewayResponse) -> String {
+        let mut text = String::new();
+        response.into_reader().read_to_string(&mut text).unwrap();
+        text
+    }
+
+    #[test]
+    fn test_bucket_routes_and_errors() {
+        let store = ObjectStore::new(
+            Box::new(MemoryBackend::new()),
+            Credentials::default(),
+            Yts3Config::default(),
+        )
+        .unwrap();
+
+        let created = route(&store, &mut request(Method::Put, "/my-bucket")).unwrap();
+        assert_eq!(created.status_code().0, 200);
+        let listed = route(&store, &mut request(Method::Get, "/")).unwrap();
+        assert!(body(listed).contains("<Name>my-bucket</Name>"));
+
+        let objects = route(&store, &mut request(Method::Get, "/my-bucket?list-type=2")).unwrap();
+        assert!(body(objects).contains("<KeyCount>0</KeyCount>"));
+
+        let missing = route(&store, &mut request(Method::Head, "/my-bucket/a%20b.txt"));
+        assert!(matches!(missing, Err(GatewayError::NoSuchKey(key)) if key == "a b.txt"));
+        let missing = route(&store, &mut request(Method::Get, "/nope/key"));
+        assert!(matches!(missing, Err(GatewayError::NoSuchBucket(_))));
+        let multipart = route(&store, &mut request(Method::Post, "/my-bucket/big?uploads"));
+        assert!(matches!(multipart, Err(GatewayError::NotImplemented(_))));
+
+        let deleted = route(&store, &mut request(Method::Delete, "/my-bucket/a.txt")).unwrap();
+        assert_eq!(deleted.status_code().0, 204);
+    }
+}
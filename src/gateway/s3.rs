@@ -0,0 +1,440 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, Read};
+use std::ops::Range;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::store::ObjectMeta;
+use super::GatewayError;
+
+const XML_HEADER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>"#;
+const XML_NAMESPACE: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+
+/// Decode `%XX` escapes. With `plus_as_space`, `+` decodes to a space as in
+/// query strings.
+pub fn percent_decode(s: &str, plus_as_space: bool) -> Result<String, GatewayError> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = bytes
+                    .get(i + 1..i + 3)
+                    .and_then(|h| std::str::from_utf8(h).ok())
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())
+                    .ok_or_else(|| GatewayError::InvalidArgument(format!("bad escape in {s:?}")))?;
+                out.push(hex);
+                i += 3;
+            }
+            b'+' if plus_as_space => {
+                out.push(b' ');
+                i += 1;
+            }
+            b => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(out)
+        .map_err(|_| GatewayError::InvalidArgument(format!("{s:?} is not valid UTF-8")))
+}
+
+/// Percent-encode everything except unreserved characters and `/`, as S3 does
+/// for listings requested with `encoding-type=url`.
+pub fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'~' | b'/') {
+            out.push(b as char);
+        } else {
+            let _ = write!(out, "%{b:02X}");
+        }
+    }
+    out
+}
+
+/// Parse a query string into a map. Parameters without a value map to "".
+pub fn parse_query(query: &str) -> Result<HashMap<String, String>, GatewayError> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Ok((percent_decode(name, true)?, percent_decode(value, true)?))
+        })
+        .collect()
+}
+
+/// Parse a `Range` header against an object of `size` bytes. Only a single
+/// byte range is supported; a header that is not a byte range is ignored, as
+/// HTTP allows.
+pub fn parse_range(header: &str, size: u64) -> Result<Option<Range<u64>>, GatewayError> {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Err(GatewayError::NotImplemented("multiple byte ranges".to_string()));
+    }
+    let parse = |n: &str| n.parse::<u64>().ok();
+    let range = match spec.trim().split_once('-') {
+        // The last `n` bytes
+        Some(("", n)) => parse(n).map(|n| size.saturating_sub(n)..size),
+        Some((first, "")) => parse(first).map(|first| first..size),
+        Some((first, last)) => match (parse(first), parse(last)) {
+            (Some(first), Some(last)) if first <= last => Some(first..last.saturating_add(1).min(size)),
+            _ => None,
+        },
+        None => None,
+    };
+    let Some(range) = range else {
+        return Ok(None);
+    };
+    if range.start >= range.end {
+        return Err(GatewayError::InvalidRange(size));
+    }
+    Ok(Some(range))
+}
+
+/// Escape text for use in XML element content.
+pub fn xml_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Format a time as ISO 8601 in UTC with millisecond precision, as used in S3 XML.
+pub fn iso8601(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// `<Error>` body for a failed request.
+pub fn error_xml(code: &str, message: &str, resource: &str) -> String {
+    format!(
+        "{XML_HEADER}<Error><Code>{code}</Code><Message>{}</Message><Resource>{}</Resource></Error>",
+        xml_escape(message),
+        xml_escape(resource)
+    )
+}
+
+/// ListBuckets response body.
+pub fn list_buckets_xml(buckets: &[(String, SystemTime)]) -> String {
+    let mut xml = format!(
+        "{XML_HEADER}<ListAllMyBucketsResult xmlns=\"{XML_NAMESPACE}\">\
+         <Owner><ID>yts3</ID><DisplayName>yts3</DisplayName></Owner><Buckets>"
+    );
+    for (name, created) in buckets {
+        let _ = write!(
+            xml,
+            "<Bucket><Name>{}</Name><CreationDate>{}</CreationDate></Bucket>",
+            xml_escape(name),
+            iso8601(*created)
+        );
+    }
+    xml.push_str("</Buckets></ListAllMyBucketsResult>");
+    xml
+}
+
+/// Parameters of a ListObjectsV2 request.
+#[derive(Debug, Default)]
+pub struct ListParams {
+    pub prefix: String,
+    pub delimiter: Option<String>,
+    pub max_keys: usize,
+    /// Keys at or before this one are skipped (continuation token or start-after).
+    pub after: Option<String>,
+    pub continuation_token: Option<String>,
+    pub url_encode: bool,
+}
+
+impl ListParams {
+    pub fn from_query(query: &HashMap<String, String>) -> Result<Self, GatewayError> {
+        let max_keys = match query.get("max-keys") {
+            Some(v) => v
+                .parse()
+                .map_err(|_| GatewayError::InvalidArgument(format!("bad max-keys {v:?}")))?,
+            None => 1000,
+        };
+        let continuation_token = query.get("continuation-token").cloned();
+        let after = match &continuation_token {
+            Some(token) => Some(percent_decode(token, false)?),
+            None => query.get("start-after").cloned(),
+        };
+        Ok(Self {
+            prefix: query.get("prefix").cloned().unwrap_or_default(),
+            delimiter: query.get("delimiter").filter(|d| !d.is_empty()).cloned(),
+            max_keys,
+            after,
+            continuation_token,
+            url_encode: query.get("encoding-type").is_some_and(|e| e == "url"),
+        })
+    }
+}
+
+/// ListObjectsV2 response body for `objects`, which must be sorted by key.
+pub fn list_objects_xml(bucket: &str, objects: &[ObjectMeta], params: &ListParams) -> String {
+    let encode = |s: &str| {
+        if params.url_encode {
+            percent_encode(s)
+        } else {
+            xml_escape(s)
+        }
+    };
+
+    let mut contents = Vec::new();
+    let mut common_prefixes: Vec<String> = Vec::new();
+    let mut next_token = None;
+    let candidates = objects.iter().filter(|o| {
+        o.key.starts_with(&params.prefix) && params.after.as_ref().is_none_or(|a| o.key > *a)
+    });
+    for object in candidates {
+        // Keys sharing a delimited prefix roll up into one common prefix
+        let rolled_up = params.delimiter.as_ref().and_then(|d| {
+            let rest = &object.key[params.prefix.len()..];
+            rest.find(d.as_str())
+                .map(|i| object.key[..params.prefix.len() + i + d.len()].to_string())
+        });
+        if let Some(prefix) = &rolled_up {
+            if common_prefixes.last() == Some(prefix) {
+                continue;
+            }
+        }
+        if contents.len() + common_prefixes.len() == params.max_keys {
+            next_token = Some(percent_encode(&contents_last_key(&contents, &common_prefixes)));
+            break;
+        }
+        match rolled_up {
+            Some(prefix) => common_prefixes.push(prefix),
+            None => contents.push(object),
+        }
+    }
+
+    let mut xml = format!(
+        "{XML_HEADER}<ListBucketResult xmlns=\"{XML_NAMESPACE}\"><Name>{}</Name>\
+         <Prefix>{}</Prefix><KeyCount>{}</KeyCount><MaxKeys>{}</MaxKeys><IsTruncated>{}</IsTruncated>",
+        xml_escape(bucket),
+        encode(&params.prefix),
+        contents.len() + common_prefixes.len(),
+        params.max_keys,
+        next_token.is_some()
+    );
+    if let Some(delimiter) = &params.delimiter {
+        let _ = write!(xml, "<Delimiter>{}</Delimiter>", encode(delimiter));
+    }
+    if params.url_encode {
+        xml.push_str("<EncodingType>url</EncodingType>");
+    }
+    if let Some(token) = &params.continuation_token {
+        let _ = write!(xml, "<ContinuationToken>{}</ContinuationToken>", xml_escape(token));
+    }
+    if let Some(token) = &next_token {
+        let _ = write!(xml, "<NextContinuationToken>{token}</NextContinuationToken>");
+    }
+    for object in contents {
+        let _ = write!(
+            xml,
+            "<Contents><Key>{}</Key><LastModified>{}</LastModified><ETag>&quot;{}&quot;</ETag>\
+             <Size>{}</Size><StorageClass>STANDARD</StorageClass></Contents>",
+            encode(&object.key),
+            iso8601(object.modified),
+            object.etag,
+            object.size
+        );
+    }
+    for prefix in common_prefixes {
+        let _ = write!(xml, "<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>", encode(&prefix));
+    }
+    xml.push_str("</ListBucketResult>");
+    xml
+}
+
+/// The last key or common prefix listed so far, where the next page resumes.
+fn contents_last_key(contents: &[&ObjectMeta], common_prefixes: &[String]) -> String {
+    let last_key = contents.last().map(|o| o.key.as_str()).unwrap_or("");
+    let last_prefix = common_prefixes.last().map(String::as_str).unwrap_or("");
+    // Resuming after a common prefix must skip every key beneath it
+    if last_prefix > last_key {
+        format!("{last_prefix}\u{10FFFF}")
+    } else {
+        last_key.to_string()
+    }
+}
+
+/// Decodes a body sent with `Content-Encoding: aws-chunked`, as AWS SDKs do for
+/// streaming uploads: `<hex size>[;chunk-signature=...]\r\n<data>\r\n` repeated,
+/// ending with a zero-size chunk and optional trailers. Signatures and trailing
+/// checksums are not verified.
+pub struct AwsChunkedReader<R> {
+    inner: R,
+    remaining: usize,
+    done: bool,
+}
+
+impl<R: BufRead> AwsChunkedReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            remaining: 0,
+            done: false,
+        }
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        self.inner.read_line(&mut line)?;
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    }
+}
+
+impl<R: BufRead> Read for AwsChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.remaining == 0 {
+            if self.done || buf.is_empty() {
+                return Ok(0);
+            }
+            let line = self.read_line()?;
+            if line.is_empty() {
+                continue; // CRLF after the previous chunk's data
+            }
+            let size = line.split(';').next().unwrap_or("");
+            self.remaining = usize::from_str_radix(size.trim(), 16).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("bad aws-chunked header {line:?}"))
+            })?;
+            if self.remaining == 0 {
+                self.done = true;
+            }
+        }
+
+        let limit = buf.len().min(self.remaining);
+        let n = self.inner.read(&mut buf[..limit])?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn meta(key: &str) -> ObjectMeta {
+        ObjectMeta {
+            key: key.to_string(),
+            size: 1,
+            etag: "00".to_string(),
+            modified: UNIX_EPOCH,
+            content_type: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000).unwrap(), Some(0..100));
+        assert_eq!(parse_range("bytes=900-", 1000).unwrap(), Some(900..1000));
+        assert_eq!(parse_range("bytes=-100", 1000).unwrap(), Some(900..1000));
+        assert_eq!(parse_range("bytes=990-2000", 1000).unwrap(), Some(990..1000));
+        assert_eq!(parse_range("bytes=-2000", 1000).unwrap(), Some(0..1000));
+
+        // Anything but a byte range is ignored
+        for ignored in ["items=0-1", "bytes=5-1", "bytes=a-b", "bytes=5"] {
+            assert_eq!(parse_range(ignored, 1000).unwrap(), None, "{ignored}");
+        }
+        assert!(matches!(parse_range("bytes=1000-", 1000), Err(GatewayError::InvalidRange(1000))));
+        assert!(matches!(parse_range("bytes=-0", 1000), Err(GatewayError::InvalidRange(_))));
+        assert!(matches!(parse_range("bytes=0-1,5-9", 1000), Err(GatewayError::NotImplemented(_))));
+    }
+
+    #[test]
+    fn test_percent_coding() {
+        assert_eq!(percent_decode("a%20b%2Fc+d", false).unwrap(), "a b/c+d");
+        assert_eq!(percent_decode("a+b", true).unwrap(), "a b");
+        assert!(percent_decode("bad%2", false).is_err());
+        assert_eq!(percent_encode("dir/a b+c.txt"), "dir/a%20b%2Bc.txt");
+
+        let query = parse_query("list-type=2&prefix=photos%2F&delimiter=%2F&fetch-owner").unwrap();
+        assert_eq!(query["prefix"], "photos/");
+        assert_eq!(query["fetch-owner"], "");
+    }
+
+    #[test]
+    fn test_iso8601() {
+        assert_eq!(iso8601(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let t = UNIX_EPOCH + Duration::from_millis(1_709_251_199_250);
+        assert_eq!(iso8601(t), "2024-02-29T23:59:59.250Z");
+    }
+
+    #[test]
+    fn test_list_objects_with_delimiter_and_pages() {
+        let objects: Vec<_> = ["a.txt", "photos/1.jpg", "photos/2.jpg", "z.txt"]
+            .into_iter()
+            .map(meta)
+            .collect();
+        let params = ListParams {
+            delimiter: Some("/".to_string()),
+            max_keys: 2,
+            ..Default::default()
+        };
+        let xml = list_objects_xml("bucket", &objects, &params);
+        assert!(xml.contains("<Key>a.txt</Key>"));
+        assert!(xml.contains("<CommonPrefixes><Prefix>photos/</Prefix></CommonPrefixes>"));
+        assert!(xml.contains("<IsTruncated>true</IsTruncated>"));
+        assert!(!xml.contains("z.txt"));
+
+        // The next page resumes after the whole common prefix
+        let token = xml.split("<NextContinuationToken>").nth(1).unwrap();
+        let token = token.split('<').next().unwrap();
+        let query = HashMap::from([
+            ("continuation-token".to_string(), token.to_string()),
+            ("delimiter".to_string(), "/".to_string()),
+        ]);
+        let xml = list_objects_xml("bucket", &objects, &ListParams::from_query(&query).unwrap());
+        assert!(xml.contains("<KeyCount>1</KeyCount><MaxKeys>1000</MaxKeys><IsTruncated>false</IsTruncated>"));
+        assert!(xml.contains("<Key>z.txt</Key>"));
+    }
+
+    #[test]
+    fn test_aws_chunked_reader() {
+        let body = "5;chunk-signature=abc\r\nhello\r\n6;chunk-signature=def\r\n world\r\n\
+                    0;chunk-signature=000\r\nx-amz-checksum-crc32:AAAAAA==\r\n\r\n";
+        let mut decoded = String::new();
+        AwsChunkedReader::new(body.as_bytes())
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, "hello world");
+    }
+}
@@ -0,0 +1,411 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+use super::s3::{percent_decode, percent_encode};
+use super::GatewayError;
+use crate::config::Yts3Config;
+use crate::crypto::Credentials;
+use crate::pipeline::{decode, encode};
+use crate::storage::{StorageBackend, StorageError};
+
+const MAX_KEY_LEN: usize = 1024;
+const VIDEO_SUFFIX: &str = ".mkv";
+const META_SUFFIX: &str = ".meta";
+const BUCKET_MARKER: &str = "bucket";
+
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// What the gateway remembers about a stored object without decoding its video.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectMeta {
+    pub key: String,
+    pub size: u64,
+    /// Hex MD5 of the object's contents, the ETag S3 reports for simple uploads.
+    pub etag: String,
+    pub modified: SystemTime,
+    pub content_type: Option<String>,
+}
+
+impl ObjectMeta {
+    /// Serialize as `name=value` lines; the key is percent-encoded so it fits on one line.
+    fn to_text(&self) -> String {
+        let modified = self.modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut text = format!(
+            "key={}\nsize={}\netag={}\nmodified={}\n",
+            percent_encode(&self.key),
+            self.size,
+            self.etag,
+            modified.as_millis()
+        );
+        if let Some(content_type) = &self.content_type {
+            text.push_str(&format!("content-type={}\n", percent_encode(content_type)));
+        }
+        text
+    }
+
+    fn from_text(text: &str) -> Option<Self> {
+        let field = |name: &str| {
+            text.lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix('='))
+        };
+        Some(Self {
+            key: percent_decode(field("key")?, false).ok()?,
+            size: field("size")?.parse().ok()?,
+            etag: field("etag")?.to_string(),
+            modified: UNIX_EPOCH + Duration::from_millis(field("modified")?.parse().ok()?),
+            content_type: field("content-type").and_then(|c| percent_decode(c, false).ok()),
+        })
+    }
+}
+
+/// Objects stored as yts3 videos in a [`StorageBackend`].
+///
+/// A bucket is a marker video id `<bucket>/bucket`. An object is encoded into
+/// `<bucket>/<sha256 of key>.mkv` with its metadata stored next to it as
+/// `<bucket>/<sha256 of key>.meta`, so keys of any length or content map to
+/// valid ids. The metadata is written after the video and removed before it, so
+/// listings never show an object whose video is missing. Puts and deletes of a
+/// key hold its lock exclusively and gets hold it shared, so concurrent requests
+/// never pair one upload's video with another's metadata.
+pub struct ObjectStore {
+    backend: Box<dyn StorageBackend>,
+    credentials: Credentials,
+    cfg: Yts3Config,
+    /// Per-object locks by video id, dropped once no request holds them.
+    locks: Mutex<HashMap<String, Arc<RwLock<()>>>>,
+}
+
+impl ObjectStore {
+    pub fn new(
+        backend: Box<dyn StorageBackend>,
+        credentials: Credentials,
+        cfg: Yts3Config,
+    ) -> anyhow::Result<Self> {
+        cfg.validate()?;
+        Ok(Self {
+            backend,
+            credentials,
+            cfg,
+            locks: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The lock guarding an object's video and metadata.
+    fn object_lock(&self, video_id: &str) -> Arc<RwLock<()>> {
+        let mut locks = self.locks.lock().unwrap();
+        locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+        locks.entry(video_id.to_string()).or_default().clone()
+    }
+
+    pub fn create_bucket(&self, bucket: &str) -> Result<(), GatewayError> {
+        validate_bucket_name(bucket)?;
+        if self.bucket_exists(bucket).is_err() {
+            self.backend.put(&bucket_id(bucket), &mut io::empty())?;
+        }
+        Ok(())
+    }
+
+    pub fn bucket_exists(&self, bucket: &str) -> Result<(), GatewayError> {
+        validate_bucket_name(bucket)?;
+        match self.backend.stat(&bucket_id(bucket)) {
+            Ok(_) => Ok(()),
+            Err(StorageError::NotFound(_)) => Err(GatewayError::NoSuchBucket(bucket.to_string())),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// All buckets with their creation times, sorted by name.
+    pub fn list_buckets(&self) -> Result<Vec<(String, SystemTime)>, GatewayError> {
+        let mut buckets: Vec<_> = self
+            .backend
+            .list("")?
+            .into_iter()
+            .filter_map(|video| {
+                let bucket = video.id.strip_suffix(BUCKET_MARKER)?.strip_suffix('/')?;
+                validate_bucket_name(bucket).ok()?;
+                Some((bucket.to_string(), video.modified))
+            })
+            .collect();
+        buckets.sort();
+        Ok(buckets)
+    }
+
+    /// Encode `body` into a video and store it under `key`, replacing any existing object.
+    pub fn put_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        body: &mut dyn Read,
+        content_type: Option<String>,
+    ) -> Result<ObjectMeta, GatewayError> {
+        self.bucket_exists(bucket)?;
+        validate_key(key)?;
+
+        // Name the scratch input after the key so the manifest records a useful file name
+        let scratch = ScratchDir::new()?;
+        let input = scratch.path.join(input_file_name(key));
+        let (size, etag) = copy_hashed(body, &mut File::create(&input)?)?;
+
+        let (video_id, meta_id) = object_ids(bucket, key);
+        let lock = self.object_lock(&video_id);
+        let _guard = lock.write().unwrap();
+        encode::encode_to_backend(
+            &input,
+            self.backend.as_ref(),
+            &video_id,
+            &self.credentials,
+            &self.cfg,
+        )
+        .with_context(|| format!("failed to encode {bucket}/{key}"))?;
+
+        let meta = ObjectMeta {
+            key: key.to_string(),
+            size,
+            etag,
+            modified: SystemTime::now(),
+            content_type,
+        };
+        self.backend.put(&meta_id, &mut meta.to_text().as_bytes())?;
+        Ok(meta)
+    }
+
+    pub fn head_object(&self, bucket: &str, key: &str) -> Result<ObjectMeta, GatewayError> {
+        self.bucket_exists(bucket)?;
+        let (_, meta_id) = object_ids(bucket, key);
+        let mut text = String::new();
+        match self.backend.get(&meta_id) {
+            Ok(mut reader) => reader.read_to_string(&mut text)?,
+            Err(StorageError::NotFound(_)) => return Err(GatewayError::NoSuchKey(key.to_string())),
+            Err(e) => return Err(e.into()),
+        };
+        ObjectMeta::from_text(&text)
+            .ok_or_else(|| anyhow::anyhow!("corrupt metadata in {meta_id}").into())
+    }
+
+    /// Decode an object's video, returning its metadata and an open handle to the
+    /// decoded contents. The decoded file is unlinked once opened.
+    pub fn get_object(&self, bucket: &str, key: &str) -> Result<(ObjectMeta, File), GatewayError> {
+        let (video_id, _) = object_ids(bucket, key);
+        let lock = self.object_lock(&video_id);
+        let _guard = lock.read().unwrap();
+        let meta = self.head_object(bucket, key)?;
+
+        let scratch = ScratchDir::new()?;
+        let report = decode::decode_from_backend(
+            self.backend.as_ref(),
+            &video_id,
+            &scratch.path.join("object"),
+            &self.credentials,
+            &self.cfg,
+        )
+        .with_context(|| format!("failed to decode {bucket}/{key}"))?;
+        if !report.is_complete() {
+            return Err(anyhow::anyhow!("{bucket}/{key} could not be fully recovered: {report}").into());
+        }
+
+        let file = File::open(&report.output_path)?;
+        Ok((meta, file))
+    }
+
+    /// Remove an object. Removing a key that does not exist is not an error.
+    pub fn delete_object(&self, bucket: &str, key: &str) -> Result<(), GatewayError> {
+        self.bucket_exists(bucket)?;
+        let (video_id, meta_id) = object_ids(bucket, key);
+        let lock = self.object_lock(&video_id);
+        let _guard = lock.write().unwrap();
+        self.backend.delete(&meta_id)?;
+        self.backend.delete(&video_id)?;
+        Ok(())
+    }
+
+    /// Metadata of every object in a bucket, sorted by key.
+    pub fn list_objects(&self, bucket: &str) -> Result<Vec<ObjectMeta>, GatewayError> {
+        self.bucket_exists(bucket)?;
+        let mut objects = Vec::new();
+        for video in self.backend.list(&format!("{bucket}/"))? {
+            if !video.id.ends_with(META_SUFFIX) {
+                continue;
+            }
+            let mut text = String::new();
+            match self.backend.get(&video.id) {
+                Ok(mut reader) => reader.read_to_string(&mut text)?,
+                // Deleted since it was listed
+                Err(StorageError::NotFound(_)) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if let Some(meta) = ObjectMeta::from_text(&text) {
+                objects.push(meta);
+            }
+        }
+        objects.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(objects)
+    }
+}
+
+fn bucket_id(bucket: &str) -> String {
+    format!("{bucket}/{BUCKET_MARKER}")
+}
+
+/// Backend ids of an object's video and metadata.
+fn object_ids(bucket: &str, key: &str) -> (String, String) {
+    let id = format!("{bucket}/{:x}", Sha256::digest(key.as_bytes()));
+    (format!("{id}{VIDEO_SUFFIX}"), format!("{id}{META_SUFFIX}"))
+}
+
+/// A uniquely named directory in the system temp directory, removed on drop.
+struct ScratchDir {
+    path: PathBuf,
+}
+
+impl ScratchDir {
+    fn new() -> io::Result<Self> {
+        let name = format!(
+            "yts3-gateway-{}-{}",
+            std::process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = std::env::temp_dir().join(name);
+        fs::create_dir_all(&path)?;
+        Ok(Self { path })
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Bucket names follow the S3 rules: 3-63 lowercase letters, digits, `.` and `-`,
+/// starting and ending with a letter or digit.
+fn validate_bucket_name(bucket: &str) -> Result<(), GatewayError> {
+    let valid = (3..=63).contains(&bucket.len())
+        && bucket
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'.' || b == b'-')
+        && bucket.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && bucket.ends_with(|c: char| c.is_ascii_alphanumeric());
+    if valid {
+        Ok(())
+    } else {
+        Err(GatewayError::InvalidBucketName(bucket.to_string()))
+    }
+}
+
+fn validate_key(key: &str) -> Result<(), GatewayError> {
+    if key.is_empty() || key.len() > MAX_KEY_LEN {
+        return Err(GatewayError::InvalidArgument(format!(
+            "object keys must be 1-{MAX_KEY_LEN} bytes"
+        )));
+    }
+    Ok(())
+}
+
+/// The last path segment of a key, if it is usable as a file name.
+fn input_file_name(key: &str) -> &str {
+    match key.rsplit('/').next() {
+        Some(name) if !name.is_empty() && name != "." && name != ".." && name.len() <= 255 => name,
+        _ => "object",
+    }
+}
+
+/// Copy `reader` into `writer`, returning the byte count and hex MD5.
+fn copy_hashed(reader: &mut dyn Read, writer: &mut impl Write) -> io::Result<(u64, String)> {
+    let mut hasher = Md5::new();
+    let mut buf = vec![0u8; 65536];
+    let mut size = 0u64;
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        writer.write_all(&buf[..n])?;
+        size += n as u64;
+    }
+    Ok((size, format!("{:x}", hasher.finalize())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryBackend;
+
+    #[test]
+    fn test_metadata_roundtrip_and_listing() {
+        let store = ObjectStore::new(
+            Box::new(MemoryBackend::new()),
+            Credentials::default(),
+            Yts3Config::default(),
+        )
+        .unwrap();
+        store.create_bucket("photos").unwrap();
+        store.create_bucket("photos").unwrap();
+        assert!(matches!(
+            store.create_bucket("Bad_Name"),
+            Err(GatewayError::InvalidBucketName(_))
+        ));
+        assert!(matches!(
+            store.list_objects("missing"),
+            Err(GatewayError::NoSuchBucket(_))
+        ));
+
+        // Write metadata the way put_object does, without needing ffmpeg
+        for key in ["b/with\nnewline", "a.txt"] {
+            let meta = ObjectMeta {
+                key: key.to_string(),
+                size: 5,
+                etag: "5d41402abc4b2a76b9719d911017c592".to_string(),
+                modified: UNIX_EPOCH + Duration::from_millis(1_700_000_000_123),
+                content_type: Some("text/plain; charset=utf-8".to_string()),
+            };
+            let (_, meta_id) = object_ids("photos", key);
+            store.backend.put(&meta_id, &mut meta.to_text().as_bytes()).unwrap();
+            assert_eq!(store.head_object("photos", key).unwrap(), meta);
+        }
+
+        let keys: Vec<_> = store.list_objects("photos").unwrap().into_iter().map(|o| o.key).collect();
+        assert_eq!(keys, ["a.txt", "b/with\nnewline"]);
+        let buckets: Vec<_> = store.list_buckets().unwrap().into_iter().map(|(b, _)| b).collect();
+        assert_eq!(buckets, ["photos"]);
+
+        store.delete_object("photos", "a.txt").unwrap();
+        store.delete_object("photos", "a.txt").unwrap();
+        assert!(matches!(
+            store.head_object("photos", "a.txt"),
+            Err(GatewayError::NoSuchKey(_))
+        ));
+    }
+
+    #[test]
+    fn test_object_locks_are_shared_per_key_and_dropped_when_idle() {
+        let store = ObjectStore::new(
+            Box::new(MemoryBackend::new()),
+            Credentials::default(),
+            Yts3Config::default(),
+        )
+        .unwrap();
+        let (a, _) = object_ids("photos", "a.txt");
+        let (b, _) = object_ids("photos", "b.txt");
+
+        let lock = store.object_lock(&a);
+        let guard = lock.write().unwrap();
+        assert!(Arc::ptr_eq(&lock, &store.object_lock(&a)));
+        assert!(store.object_lock(&a).try_read().is_err());
+        assert!(store.object_lock(&b).try_write().is_ok());
+
+        drop(guard);
+        drop(lock);
+        store.object_lock(&b);
+        assert_eq!(store.locks.lock().unwrap().len(), 1);
+    }
+}
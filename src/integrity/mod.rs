@@ -1,5 +1,4 @@
 use crc::{Crc, CRC_32_MPEG_2};
-use sha2::{Digest, Sha256};
 
 /// CRC-32/MPEG-2 calculator.
 const CRC_MPEG2: Crc<u32> = Crc::<u32>::new(&CRC_32_MPEG_2);
@@ -28,6 +27,7 @@ pub fn packet_crc32(header: &[u8], crc_field_offset: usize, payload: &[u8]) -> u
 }
 
 /// Verify the CRC field in a packet.
+#[cfg(test)]
 pub fn verify_packet_crc(
     header: &[u8],
     crc_field_offset: usize,
@@ -41,7 +41,10 @@ pub fn verify_packet_crc(
 pub type Sha256Digest = [u8; 32];
 
 /// Compute SHA-256 hash of a byte slice.
+#[cfg(test)]
 pub fn sha256(data: &[u8]) -> Sha256Digest {
+    use sha2::{Digest, Sha256};
+
     let mut hasher = Sha256::new();
     hasher.update(data);
     let result = hasher.finalize();
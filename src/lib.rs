@@ -1,14 +1,26 @@
+mod archive;
+pub mod catalog;
 mod chunker;
+mod compress;
 pub mod config;
 mod crypto;
+mod ecc;
 mod fountain;
+pub mod gateway;
 mod integrity;
+mod manifest;
 mod packet;
 pub mod pipeline;
+pub mod storage;
 mod video;
 
+pub use archive::{ArchiveEntry, ArchiveIndex, EntryKind};
 pub use config::Yts3Config;
-pub use pipeline::decode::decode_file;
-pub use pipeline::encode::encode_file;
-pub use pipeline::hook::{NoopHook, PipelineHook};
+pub use crypto::{read_key_file, Credentials, CryptoError, Identity, Recipient, Signer, SigningKey};
+pub use manifest::Manifest;
+pub use packet::frame::FrameStats;
+pub use pipeline::decode::{decode_archive, decode_file, decode_from_backend, verify_file, DecodeReport};
+pub use pipeline::encode::{encode_archive, encode_file, encode_to_backend, EncodeSummary};
+pub use pipeline::hook::{NoopHook, PipelineHook, TranscodeCodec, TranscodeHook};
 pub use pipeline::{roundtrip, RoundtripResult};
+pub use storage::{LocalBackend, MemoryBackend, StorageBackend};
@@ -1,38 +1,61 @@
-use std::path::PathBuf;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
 
-use anyhow::Result;
-use clap::{Parser, Subcommand};
+use anyhow::{Context, Result};
+use clap::{Args, Parser, Subcommand};
+use log::{info, warn};
 
 use yts3::config::{
-    DEFAULT_BITS_PER_BLOCK, DEFAULT_CHUNK_SIZE, DEFAULT_COEFFICIENT_STRENGTH,
-    DEFAULT_FPS, DEFAULT_FRAME_HEIGHT, DEFAULT_FRAME_WIDTH, DEFAULT_REPAIR_OVERHEAD,
+    ARGON2_MEM_COST, ARGON2_PARALLELISM, ARGON2_TIME_COST, DEFAULT_BITS_PER_BLOCK, DEFAULT_CHUNK_SIZE, DEFAULT_COEFFICIENT_STRENGTH, DEFAULT_COMPRESSION_LEVEL,
+    DEFAULT_ECC_PARITY, DEFAULT_FPS, DEFAULT_FRAME_HEIGHT, DEFAULT_INFLIGHT_CHUNKS, DEFAULT_INTERLEAVE_DEPTH, DEFAULT_FRAME_WIDTH, DEFAULT_REPAIR_OVERHEAD,
+    DEFAULT_REPETITION, SYMBOL_SIZE,
+    Compression, DamagePolicy, FountainScheme, KdfParams,
 };
+use yts3::catalog::{Catalog, CatalogEntry};
+use yts3::gateway::{self, store::ObjectStore};
 use yts3::pipeline;
-use yts3::Yts3Config;
+use yts3::{read_key_file, Credentials, Identity, LocalBackend, Recipient, Signer, SigningKey, Yts3Config};
 
 /// yts3 — YouTube as S3: encode arbitrary files into lossless video for cloud storage.
 #[derive(Parser)]
 #[command(name = "yts3", version, about)]
 struct Cli {
+    /// Catalog of encoded files (default: $YTS3_CATALOG or ~/.yts3/catalog.log)
+    #[arg(long, global = true)]
+    catalog: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Commands,
 }
 
 #[derive(Subcommand)]
 enum Commands {
-    /// Encode a file into a video
+    /// Encode a file, or a directory tree or several files as one archive, into a video
     Encode {
-        /// Input file path
-        #[arg(short, long)]
-        input: PathBuf,
+        /// Input file or directory; repeat to archive several
+        #[arg(short, long, num_args = 1.., required = true)]
+        input: Vec<PathBuf>,
 
         /// Output video path (.mkv)
         #[arg(short, long)]
         output: String,
 
-        /// Encrypt the file with a password
-        #[arg(short, long)]
-        password: Option<String>,
+        /// Key to record the file under in the catalog (default: the input name)
+        #[arg(long)]
+        key: Option<String>,
+
+        /// Encrypt the file with a password or key
+        #[command(flatten)]
+        secret: SecretArgs,
+
+        /// Encrypt the file for this public key (from `yts3 keygen`) instead of a
+        /// password; may be repeated
+        #[arg(long, conflicts_with = "secret")]
+        recipient: Vec<Recipient>,
+
+        /// Sign the video with the signing key in this file (from `yts3 keygen --sign`)
+        #[arg(long, value_name = "FILE")]
+        sign: Option<PathBuf>,
 
         /// Frame width (default: 3840)
         #[arg(long, default_value_t = DEFAULT_FRAME_WIDTH)]
@@ -46,7 +69,7 @@ enum Commands {
         #[arg(long, default_value_t = DEFAULT_FPS)]
         fps: u32,
 
-        /// Bits embedded per 8x8 block (default: 1)
+        /// Bits embedded per 8x8 block, 1-4 (default: 1)
         #[arg(long, default_value_t = DEFAULT_BITS_PER_BLOCK)]
         bits_per_block: usize,
 
@@ -54,13 +77,70 @@ enum Commands {
         #[arg(long, default_value_t = DEFAULT_COEFFICIENT_STRENGTH)]
         coefficient_strength: f64,
 
+        /// Copies of each bit spread across the frame, 1-16 (default: 1)
+        #[arg(long, default_value_t = DEFAULT_REPETITION)]
+        repetition: usize,
+
+        /// Use settings that survive lossy re-encoding (e.g. a YouTube upload);
+        /// replaces the embedding and repair settings
+        #[arg(long, conflicts_with_all = [
+            "bits_per_block", "coefficient_strength", "repetition", "repair_overhead",
+        ])]
+        robust: bool,
+
         /// Chunk size in bytes (default: 1048576)
         #[arg(long, default_value_t = DEFAULT_CHUNK_SIZE)]
         chunk_size: usize,
 
+        /// Data bytes per packet; every frame must hold at least one whole packet
+        /// (default: 256, or the largest that fits smaller frames)
+        #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+        symbol_size: Option<u16>,
+
         /// Fountain code repair overhead as a fraction (default: 1.0 = 100%)
         #[arg(long, default_value_t = DEFAULT_REPAIR_OVERHEAD)]
         repair_overhead: f64,
+
+        /// Reed–Solomon parity bytes per 255-byte codeword of every packet,
+        /// correcting half as many byte errors before the CRC check; 0-128
+        /// (default: 0, off)
+        #[arg(long, default_value_t = DEFAULT_ECC_PARITY)]
+        ecc_parity: usize,
+
+        /// Erasure code for repair symbols (default: raptorq)
+        #[arg(long, value_enum, default_value_t = FountainScheme::default())]
+        fountain: FountainScheme,
+
+        /// Compress each chunk before encryption; chunks that do not shrink are
+        /// stored as is (default: none)
+        #[arg(long, value_enum, default_value_t = Compression::default())]
+        compress: Compression,
+
+        /// zstd compression level (default: 3)
+        #[arg(long, default_value_t = DEFAULT_COMPRESSION_LEVEL, allow_negative_numbers = true)]
+        compression_level: i32,
+
+        /// Argon2id memory cost in KiB for password encryption, up to 1 GiB with
+        /// memory times passes at most 4 GiB (default: 65536)
+        #[arg(long, default_value_t = ARGON2_MEM_COST)]
+        kdf_mem_cost: u32,
+
+        /// Argon2id passes for password encryption, 1-64 (default: 3)
+        #[arg(long, default_value_t = ARGON2_TIME_COST)]
+        kdf_time_cost: u32,
+
+        /// Argon2id lanes for password encryption, 1-16 (default: 4)
+        #[arg(long, default_value_t = ARGON2_PARALLELISM)]
+        kdf_parallelism: u32,
+
+        /// Chunks read and encoded concurrently; bounds peak memory (default: 8)
+        #[arg(long, default_value_t = DEFAULT_INFLIGHT_CHUNKS)]
+        inflight_chunks: usize,
+
+        /// Chunks whose packets are interleaved across frames, so a burst of lost
+        /// frames costs each only a few symbols; 1 disables it (default: 8)
+        #[arg(long, default_value_t = DEFAULT_INTERLEAVE_DEPTH)]
+        interleave_depth: usize,
     },
 
     /// Decode a video back into the original file
@@ -69,30 +149,346 @@ enum Commands {
         #[arg(short, long)]
         input: String,
 
-        /// Output file path
+        /// Output file path, or an existing directory to restore the original file
+        /// name or archived tree
         #[arg(short, long)]
         output: PathBuf,
 
-        /// Decryption password (required if file was encrypted)
+        /// Decryption password or key (required if the file was encrypted with one)
+        #[command(flatten)]
+        secret: SecretArgs,
+
+        /// Identity file holding a secret key the file was encrypted for; may be repeated
+        #[arg(long)]
+        identity: Vec<PathBuf>,
+
+        /// Only accept the video if it is signed by this public key; may be repeated
+        #[arg(long)]
+        signer: Vec<Signer>,
+
+        /// Accept a video that is not encrypted even though a password, key or
+        /// identity was given (otherwise an error, as its encryption may have been
+        /// stripped)
+        #[arg(long)]
+        allow_unencrypted: bool,
+
+        /// Only restore this archived path (a file, or a directory and its contents);
+        /// may be repeated. The output is created as a directory.
+        #[arg(long)]
+        extract: Vec<String>,
+
+        /// Frame width (only used for videos without a calibration header)
+        #[arg(long, default_value_t = DEFAULT_FRAME_WIDTH)]
+        width: u32,
+
+        /// Frame height (only used for videos without a calibration header)
+        #[arg(long, default_value_t = DEFAULT_FRAME_HEIGHT)]
+        height: u32,
+
+        /// Bits per block (only used for videos without a calibration header)
+        #[arg(long, default_value_t = DEFAULT_BITS_PER_BLOCK)]
+        bits_per_block: usize,
+
+        /// DCT coefficient strength (only used for videos without a calibration header)
+        #[arg(long, default_value_t = DEFAULT_COEFFICIENT_STRENGTH)]
+        coefficient_strength: f64,
+
+        /// What to do with unrecoverable chunks: discard the output, zero-fill them,
+        /// or leave sparse holes (default: fail)
+        #[arg(long, value_enum, default_value_t = DamagePolicy::default())]
+        on_damage: DamagePolicy,
+    },
+
+    /// Check that a video decodes intact and report who signed it, without
+    /// keeping the output
+    Verify {
+        /// Input video path (.mkv)
         #[arg(short, long)]
-        password: Option<String>,
+        input: String,
 
-        /// Frame width (must match encoding)
+        /// Decryption password or key (required if the file was encrypted with one)
+        #[command(flatten)]
+        secret: SecretArgs,
+
+        /// Identity file holding a secret key the file was encrypted for; may be repeated
+        #[arg(long)]
+        identity: Vec<PathBuf>,
+
+        /// Only accept the video if it is signed by this public key; may be repeated
+        #[arg(long)]
+        signer: Vec<Signer>,
+
+        /// Accept a video that is not encrypted even though a password, key or
+        /// identity was given (otherwise an error, as its encryption may have been
+        /// stripped)
+        #[arg(long)]
+        allow_unencrypted: bool,
+
+        /// Frame width (only used for videos without a calibration header)
         #[arg(long, default_value_t = DEFAULT_FRAME_WIDTH)]
         width: u32,
 
-        /// Frame height (must match encoding)
+        /// Frame height (only used for videos without a calibration header)
         #[arg(long, default_value_t = DEFAULT_FRAME_HEIGHT)]
         height: u32,
 
-        /// Bits per block (must match encoding)
+        /// Bits per block (only used for videos without a calibration header)
         #[arg(long, default_value_t = DEFAULT_BITS_PER_BLOCK)]
         bits_per_block: usize,
 
-        /// DCT coefficient strength (must match encoding)
+        /// DCT coefficient strength (only used for videos without a calibration header)
         #[arg(long, default_value_t = DEFAULT_COEFFICIENT_STRENGTH)]
         coefficient_strength: f64,
     },
+
+    /// Generate an identity (X25519 key pair) for encrypting to recipients, or
+    /// a signing key (Ed25519 key pair) for signing videos
+    Keygen {
+        /// Write the key to this file instead of standard output
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Generate a signing key instead of an identity
+        #[arg(long)]
+        sign: bool,
+    },
+
+    /// List the files recorded in the catalog
+    Ls,
+
+    /// Show everything the catalog records about a file
+    Info {
+        /// Catalog key of the file
+        key: String,
+    },
+
+    /// Decode a file recorded in the catalog from its video
+    Get {
+        /// Catalog key of the file
+        key: String,
+
+        /// Output file path, or an existing directory to restore the original file
+        /// name or archived tree
+        #[arg(short, long, default_value = ".")]
+        output: PathBuf,
+
+        /// Decryption password or key (required if the file was encrypted with one)
+        #[command(flatten)]
+        secret: SecretArgs,
+
+        /// Identity file holding a secret key the file was encrypted for; may be repeated
+        #[arg(long)]
+        identity: Vec<PathBuf>,
+
+        /// Only accept the video if it is signed by this public key; may be repeated
+        #[arg(long)]
+        signer: Vec<Signer>,
+
+        /// Accept a video that is not encrypted even though a password, key or
+        /// identity was given (otherwise an error, as its encryption may have been
+        /// stripped)
+        #[arg(long)]
+        allow_unencrypted: bool,
+
+        /// Only restore this archived path; may be repeated
+        #[arg(long)]
+        extract: Vec<String>,
+
+        /// What to do with unrecoverable chunks (default: fail)
+        #[arg(long, value_enum, default_value_t = DamagePolicy::default())]
+        on_damage: DamagePolicy,
+    },
+
+    /// Serve an S3-compatible HTTP gateway that stores every object as a video
+    Serve {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:9000")]
+        listen: String,
+
+        /// Directory holding the buckets and their encoded videos
+        #[arg(long)]
+        root: PathBuf,
+
+        /// Encrypt every stored object with a password or key
+        #[command(flatten)]
+        secret: SecretArgs,
+
+        /// Encrypt every stored object for the keys in this identity file, and
+        /// decrypt with them
+        #[arg(long, conflicts_with = "secret")]
+        identity: Option<PathBuf>,
+
+        /// Frame width (default: 3840)
+        #[arg(long, default_value_t = DEFAULT_FRAME_WIDTH)]
+        width: u32,
+
+        /// Frame height (default: 2160)
+        #[arg(long, default_value_t = DEFAULT_FRAME_HEIGHT)]
+        height: u32,
+
+        /// Encode objects with settings that survive lossy re-encoding
+        #[arg(long)]
+        robust: bool,
+
+        /// Requests handled concurrently (default: 4)
+        #[arg(long, default_value_t = 4)]
+        workers: usize,
+    },
+}
+
+/// Where the password or key comes from. At most one may be given.
+#[derive(Args)]
+#[group(id = "secret", multiple = false)]
+struct SecretArgs {
+    /// Password; visible in shell history and `ps`, so prefer the options below
+    #[arg(short, long)]
+    password: Option<String>,
+
+    /// Read the password from the first line of this file
+    #[arg(long)]
+    password_file: Option<PathBuf>,
+
+    /// Read the password from this environment variable
+    #[arg(long, value_name = "VAR")]
+    password_env: Option<String>,
+
+    /// Prompt for the password even if no other secret is given; this happens
+    /// anyway when standard input is a terminal
+    #[arg(long)]
+    ask_password: bool,
+
+    /// Use the 32 bytes in this file as the key directly, skipping Argon2id
+    #[arg(long)]
+    key_file: Option<PathBuf>,
+}
+
+impl SecretArgs {
+    fn is_set(&self) -> bool {
+        self.password.is_some()
+            || self.password_file.is_some()
+            || self.password_env.is_some()
+            || self.ask_password
+            || self.key_file.is_some()
+    }
+
+    /// Read the password or key from wherever it was given. A prompted password
+    /// is asked for twice if `confirm` is set, since a typo would make what is
+    /// encrypted with it unrecoverable.
+    fn credentials(self, confirm: bool) -> Result<Credentials> {
+        let password = if let Some(password) = self.password {
+            Some(password)
+        } else if let Some(path) = &self.password_file {
+            let text = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            Some(text.lines().next().unwrap_or_default().to_string())
+        } else if let Some(var) = &self.password_env {
+            Some(std::env::var(var).with_context(|| format!("${var} is not set"))?)
+        } else if self.ask_password {
+            Some(prompt_password(confirm)?)
+        } else {
+            None
+        };
+        if password.as_deref() == Some("") {
+            anyhow::bail!("the password is empty");
+        }
+        let key = self.key_file.as_deref().map(read_key_file).transpose()?;
+        Ok(Credentials {
+            password,
+            key,
+            ..Default::default()
+        })
+    }
+}
+
+fn prompt_password(confirm: bool) -> Result<String> {
+    if !std::io::stdin().is_terminal() {
+        anyhow::bail!("cannot prompt for a password without a terminal; use --password-file or --password-env");
+    }
+    let password = rpassword::prompt_password("Password: ")?;
+    if confirm && !password.is_empty() && rpassword::prompt_password("Repeat password: ")? != password {
+        anyhow::bail!("the passwords do not match");
+    }
+    Ok(password)
+}
+
+fn open_catalog(path: Option<&Path>) -> Result<Catalog> {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => Catalog::default_path()
+            .context("no catalog location: pass --catalog or set YTS3_CATALOG")?,
+    };
+    Ok(Catalog::open(&path))
+}
+
+fn find_entry(catalog: Option<&Path>, key: &str) -> Result<CatalogEntry> {
+    let catalog = open_catalog(catalog)?;
+    catalog
+        .get(key)?
+        .with_context(|| format!("no file {key:?} in {}", catalog.path().display()))
+}
+
+/// Decode a video, restoring only the `extract` paths of an archive if any are given.
+fn decode(
+    video: &str,
+    output: &Path,
+    credentials: &Credentials,
+    cfg: &Yts3Config,
+    extract: &[String],
+) -> Result<pipeline::decode::DecodeReport> {
+    if extract.is_empty() {
+        pipeline::decode::decode_file(video, output, credentials, cfg)
+    } else {
+        pipeline::decode::decode_archive(video, output, credentials, cfg, extract)
+    }
+}
+
+/// Decryption credentials from a password or key and identity files, prompting
+/// for a password if none is given and standard input is a terminal, trusting
+/// only videos signed by `signers` if any are given and, with
+/// `allow_unencrypted`, accepting unencrypted videos.
+fn decode_credentials(
+    secret: SecretArgs,
+    identity_files: &[PathBuf],
+    signers: Vec<Signer>,
+    allow_unencrypted: bool,
+) -> Result<Credentials> {
+    let prompt = !secret.is_set() && std::io::stdin().is_terminal();
+    let mut credentials = secret.credentials(false)?;
+    // Asked for only once the video turns out to need a password
+    credentials.prompt_password = prompt;
+    for path in identity_files {
+        credentials.identities.extend(Identity::read_file(path)?);
+    }
+    credentials.trusted_signers = signers;
+    credentials.allow_unencrypted = allow_unencrypted;
+    Ok(credentials)
+}
+
+/// Write a new identity or signing key to `path` (readable only by its owner)
+/// or print it.
+fn keygen(path: Option<&Path>, sign: bool) -> Result<()> {
+    let (contents, public_key) = if sign {
+        let key = SigningKey::generate();
+        (key.to_file_contents(), key.signer().to_string())
+    } else {
+        let identity = Identity::generate();
+        (identity.to_file_contents(), identity.recipient().to_string())
+    };
+    let Some(path) = path else {
+        print!("{contents}");
+        return Ok(());
+    };
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("failed to create {}", path.display()))?;
+    file.write_all(contents.as_bytes())?;
+    println!("public key: {public_key}");
+    Ok(())
 }
 
 fn main() -> Result<()> {
@@ -104,57 +500,278 @@ fn main() -> Result<()> {
         Commands::Encode {
             input,
             output,
-            password,
+            key,
+            secret,
+            recipient,
+            sign,
             width,
             height,
             fps,
             bits_per_block,
             coefficient_strength,
+            repetition,
+            robust,
             chunk_size,
+            symbol_size,
             repair_overhead,
+            ecc_parity,
+            fountain,
+            compress,
+            compression_level,
+            kdf_mem_cost,
+            kdf_time_cost,
+            kdf_parallelism,
+            inflight_chunks,
+            interleave_depth,
         } => {
+            let embedding = if robust {
+                Yts3Config::robust()
+            } else {
+                Yts3Config {
+                    bits_per_block,
+                    coefficient_strength,
+                    repetition,
+                    repair_overhead,
+                    ..Default::default()
+                }
+            };
             let cfg = Yts3Config {
                 frame_width: width,
                 frame_height: height,
                 fps,
-                bits_per_block,
-                coefficient_strength,
                 chunk_size,
-                repair_overhead,
-                ..Default::default()
+                symbol_size: symbol_size.map_or(SYMBOL_SIZE, usize::from),
+                ecc_parity,
+                fountain,
+                compression: compress,
+                compression_level,
+                kdf: KdfParams {
+                    mem_cost: kdf_mem_cost,
+                    time_cost: kdf_time_cost,
+                    parallelism: kdf_parallelism,
+                },
+                inflight_chunks,
+                interleave_depth,
+                ..embedding
+            };
+            let cfg = match symbol_size {
+                Some(_) => cfg,
+                None => cfg.fit_symbol_size(),
+            };
+
+            let prompt = !secret.is_set() && recipient.is_empty() && std::io::stdin().is_terminal();
+            let mut credentials = Credentials {
+                recipients: recipient,
+                signing_key: sign.as_deref().map(SigningKey::read_file).transpose()?,
+                ..secret.credentials(true)?
+            };
+            if prompt {
+                eprintln!("Leave the password empty to encode without encryption.");
+                credentials.password = Some(prompt_password(true)?).filter(|p| !p.is_empty());
+            }
+
+            let summary = match input.as_slice() {
+                [file] if file.is_file() => {
+                    pipeline::encode::encode_file(file, &output, &credentials, &cfg)?
+                }
+                inputs => pipeline::encode::encode_archive(inputs, &output, &credentials, &cfg)?,
+            };
+
+            let key = key.unwrap_or_else(|| summary.manifest.file_name.clone());
+            let video = std::fs::canonicalize(&output)
+                .map(|p| p.to_string_lossy().into_owned())
+                .unwrap_or(output);
+            match open_catalog(cli.catalog.as_deref()) {
+                Ok(catalog) => {
+                    let entry = CatalogEntry::from_summary(&key, &input[0], &video, &summary, &cfg);
+                    catalog.append(&entry)?;
+                    info!("recorded {key:?} in {}", catalog.path().display());
+                }
+                Err(e) => warn!("not recording the encode in the catalog: {e}"),
+            }
+        }
+
+        Commands::Keygen { output, sign } => keygen(output.as_deref(), sign)?,
+
+        Commands::Ls => {
+            let catalog = open_catalog(cli.catalog.as_deref())?;
+            for entry in catalog.entries()? {
+                println!(
+                    "{:>14}  {}  {}  {}",
+                    entry.size,
+                    if entry.encrypted { "enc" } else { "   " },
+                    entry.key,
+                    entry.video
+                );
+            }
+        }
+
+        Commands::Info { key } => {
+            let entry = find_entry(cli.catalog.as_deref(), &key)?;
+            let cfg = &entry.config;
+            println!("key:              {}", entry.key);
+            println!("original path:    {}", entry.original_path);
+            println!("size:             {}", entry.size);
+            println!("sha256:           {}", entry.sha256);
+            println!("file id:          {}", entry.file_id);
+            println!("encrypted:        {}", entry.encrypted);
+            println!("video:            {}", entry.video);
+            println!("recorded:         {} (Unix time)", entry.created);
+            println!("frame:            {}x{} @ {} fps", cfg.frame_width, cfg.frame_height, cfg.fps);
+            println!(
+                "embedding:        {} bits/block, strength {}, repetition {}",
+                cfg.bits_per_block, cfg.coefficient_strength, cfg.repetition
+            );
+            println!(
+                "erasure coding:   {:?}, {} byte chunks, {} byte symbols, {}% repair",
+                cfg.fountain,
+                cfg.chunk_size,
+                cfg.symbol_size,
+                cfg.repair_overhead * 100.0
+            );
+            println!(
+                "packets:          interleave depth {}, {} parity bytes",
+                cfg.interleave_depth, cfg.ecc_parity
+            );
+            println!("compression:      {:?} (level {})", cfg.compression, cfg.compression_level);
+            println!("key derivation:   {}", cfg.kdf);
+        }
+
+        Commands::Get {
+            key,
+            output,
+            mut secret,
+            identity,
+            signer,
+            allow_unencrypted,
+            extract,
+            on_damage,
+        } => {
+            let entry = find_entry(cli.catalog.as_deref(), &key)?;
+            if entry.encrypted && !secret.is_set() && identity.is_empty() {
+                if !std::io::stdin().is_terminal() {
+                    anyhow::bail!("{key:?} is encrypted; pass a password, key file or --identity");
+                }
+                secret.ask_password = true;
+            }
+            let credentials = decode_credentials(secret, &identity, signer, allow_unencrypted)?;
+            let cfg = Yts3Config {
+                damage_policy: on_damage,
+                ..entry.config
             };
 
-            pipeline::encode::encode_file(
-                &input,
-                &output,
-                password.as_deref(),
-                &cfg,
-            )?;
+            let report = decode(&entry.video, &output, &credentials, &cfg, &extract)?;
+            if !report.is_complete() {
+                anyhow::bail!("decode incomplete: {report}");
+            }
         }
 
         Commands::Decode {
             input,
             output,
-            password,
+            secret,
+            identity,
+            signer,
+            allow_unencrypted,
+            extract,
             width,
             height,
             bits_per_block,
             coefficient_strength,
+            on_damage,
         } => {
             let cfg = Yts3Config {
                 frame_width: width,
                 frame_height: height,
                 bits_per_block,
                 coefficient_strength,
+                damage_policy: on_damage,
                 ..Default::default()
             };
 
-            pipeline::decode::decode_file(
-                &input,
-                &output,
-                password.as_deref(),
-                &cfg,
-            )?;
+            let credentials = decode_credentials(secret, &identity, signer, allow_unencrypted)?;
+            let report = decode(&input, &output, &credentials, &cfg, &extract)?;
+            if !report.is_complete() {
+                anyhow::bail!("decode incomplete: {report}");
+            }
+        }
+
+        Commands::Verify {
+            input,
+            secret,
+            identity,
+            signer,
+            allow_unencrypted,
+            width,
+            height,
+            bits_per_block,
+            coefficient_strength,
+        } => {
+            let cfg = Yts3Config {
+                frame_width: width,
+                frame_height: height,
+                bits_per_block,
+                coefficient_strength,
+                ..Default::default()
+            };
+
+            let trusted = !signer.is_empty();
+            let credentials = decode_credentials(secret, &identity, signer, allow_unencrypted)?;
+            let report = pipeline::decode::verify_file(&input, &credentials, &cfg)?;
+            if !report.is_complete() {
+                anyhow::bail!("decode incomplete: {report}");
+            }
+            if let Some(manifest) = &report.manifest {
+                println!("file:    {} ({} bytes)", manifest.file_name, manifest.file_size);
+                println!("sha256:  {}", manifest.sha256_hex());
+            }
+            if let Some(frames) = &report.frames {
+                println!("frames:  {frames}");
+            }
+            match report.signer {
+                Some(signer) if trusted => println!("signer:  {signer} (trusted)"),
+                Some(signer) => println!("signer:  {signer}"),
+                // With --signer, decoding has already refused an unsigned video
+                None => println!("signer:  none"),
+            }
+        }
+
+        Commands::Serve {
+            listen,
+            root,
+            secret,
+            identity,
+            width,
+            height,
+            robust,
+            workers,
+        } => {
+            let embedding = if robust {
+                Yts3Config::robust()
+            } else {
+                Yts3Config::default()
+            };
+            let cfg = Yts3Config {
+                frame_width: width,
+                frame_height: height,
+                ..embedding
+            }
+            .fit_symbol_size();
+
+            let backend = LocalBackend::open(&root)?;
+            let credentials = match identity {
+                Some(path) => {
+                    let identities = Identity::read_file(&path)?;
+                    Credentials {
+                        recipients: identities.iter().map(Identity::recipient).collect(),
+                        identities,
+                        ..Default::default()
+                    }
+                }
+                None => secret.credentials(true)?,
+            };
+            let store = ObjectStore::new(Box::new(backend), credentials, cfg)?;
+            gateway::serve(&listen, store, workers)?;
         }
     }
 
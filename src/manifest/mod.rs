@@ -0,0 +1,145 @@
+use std::path::Path;
+
+use byteorder::{ByteOrder, LittleEndian};
+use thiserror::Error;
+
+use crate::config;
+use crate::integrity::Sha256Digest;
+
+#[derive(Error, Debug)]
+pub enum ManifestError {
+    #[error("manifest too short: need {need} bytes, have {have}")]
+    TooShort { need: usize, have: usize },
+    #[error("unsupported manifest version: {0}")]
+    UnsupportedVersion(u8),
+    #[error("manifest file name is not valid UTF-8")]
+    InvalidFileName,
+}
+
+/// Description of the original input, embedded in the video as a record so a
+/// decode can verify its output and restore the file name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Manifest {
+    /// SHA-256 of the whole plaintext input.
+    pub sha256: Sha256Digest,
+    pub file_size: u64,
+    /// File name of the input, without any directory components.
+    pub file_name: String,
+    /// Modification time of the input in seconds since the Unix epoch, if known.
+    pub modified: Option<u64>,
+}
+
+// Manifest field offsets (52 bytes + file name)
+const OFF_VERSION: usize = 0;
+const OFF_FLAGS: usize = 1;
+const OFF_SHA256: usize = 2;
+const OFF_FILE_SIZE: usize = 34;
+const OFF_MODIFIED: usize = 42;
+const OFF_NAME_LEN: usize = 50;
+const OFF_NAME: usize = 52;
+
+const FLAG_HAS_MODIFIED: u8 = 0x01;
+
+impl Manifest {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let name = self.file_name.as_bytes();
+        let name_len = name.len().min(u16::MAX as usize);
+
+        let mut buf = vec![0u8; OFF_NAME + name_len];
+        buf[OFF_VERSION] = config::MANIFEST_VERSION;
+        if self.modified.is_some() {
+            buf[OFF_FLAGS] |= FLAG_HAS_MODIFIED;
+        }
+        buf[OFF_SHA256..OFF_FILE_SIZE].copy_from_slice(&self.sha256);
+        LittleEndian::write_u64(&mut buf[OFF_FILE_SIZE..], self.file_size);
+        LittleEndian::write_u64(&mut buf[OFF_MODIFIED..], self.modified.unwrap_or(0));
+        LittleEndian::write_u16(&mut buf[OFF_NAME_LEN..], name_len as u16);
+        buf[OFF_NAME..].copy_from_slice(&name[..name_len]);
+        buf
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ManifestError> {
+        if data.len() < OFF_NAME {
+            return Err(ManifestError::TooShort {
+                need: OFF_NAME,
+                have: data.len(),
+            });
+        }
+        let version = data[OFF_VERSION];
+        if version != config::MANIFEST_VERSION {
+            return Err(ManifestError::UnsupportedVersion(version));
+        }
+
+        let name_len = LittleEndian::read_u16(&data[OFF_NAME_LEN..]) as usize;
+        if data.len() < OFF_NAME + name_len {
+            return Err(ManifestError::TooShort {
+                need: OFF_NAME + name_len,
+                have: data.len(),
+            });
+        }
+        let file_name = std::str::from_utf8(&data[OFF_NAME..OFF_NAME + name_len])
+            .map_err(|_| ManifestError::InvalidFileName)?
+            .to_string();
+
+        let mut sha256 = [0u8; 32];
+        sha256.copy_from_slice(&data[OFF_SHA256..OFF_FILE_SIZE]);
+        let modified = (data[OFF_FLAGS] & FLAG_HAS_MODIFIED != 0)
+            .then(|| LittleEndian::read_u64(&data[OFF_MODIFIED..]));
+
+        Ok(Self {
+            sha256,
+            file_size: LittleEndian::read_u64(&data[OFF_FILE_SIZE..]),
+            file_name,
+            modified,
+        })
+    }
+
+    pub fn sha256_hex(&self) -> String {
+        self.sha256.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// The recorded file name if it is safe to create inside an output directory:
+    /// a single path component that is not `.` or `..`.
+    pub fn safe_file_name(&self) -> Option<&str> {
+        let name = Path::new(&self.file_name).file_name()?.to_str()?;
+        (name == self.file_name).then_some(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_roundtrip() {
+        let manifest = Manifest {
+            sha256: [0xAB; 32],
+            file_size: 123_456_789,
+            file_name: "holiday photos.zip".to_string(),
+            modified: Some(1_700_000_000),
+        };
+        let parsed = Manifest::from_bytes(&manifest.to_bytes()).unwrap();
+        assert_eq!(parsed, manifest);
+
+        let no_mtime = Manifest {
+            modified: None,
+            ..manifest
+        };
+        assert_eq!(Manifest::from_bytes(&no_mtime.to_bytes()).unwrap(), no_mtime);
+    }
+
+    #[test]
+    fn test_safe_file_name_rejects_paths() {
+        let named = |name: &str| Manifest {
+            sha256: [0; 32],
+            file_size: 0,
+            file_name: name.to_string(),
+            modified: None,
+        };
+        assert_eq!(named("report.pdf").safe_file_name(), Some("report.pdf"));
+        assert_eq!(named("../etc/passwd").safe_file_name(), None);
+        assert_eq!(named("/tmp/x").safe_file_name(), None);
+        assert_eq!(named("..").safe_file_name(), None);
+        assert_eq!(named("").safe_file_name(), None);
+    }
+}
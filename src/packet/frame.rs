@@ -0,0 +1,486 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::ops::Range;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::config;
+use crate::ecc::ReedSolomon;
+use crate::integrity;
+use crate::packet::{deserialize_packet, Packet, PacketError};
+
+/// Header at the start of every data frame of a framed video.
+///
+/// Packets are laid out whole after the header, so a lost, duplicated or
+/// corrupted frame only affects its own packets, and the decoder finds them at
+/// fixed offsets instead of scanning for the packet magic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameHeader {
+    /// Identifies the video the frame belongs to; see [`stream_id`].
+    pub stream_id: u32,
+    /// Position of the frame among the data frames, from 0.
+    pub index: u32,
+    /// Number of data frames in the video. Frames are rendered as packets
+    /// arrive, so this is only known, and only nonzero, in the final frame.
+    pub total_frames: u32,
+    /// Bytes of packets after the header; the rest of the frame is padding.
+    pub payload_len: u32,
+}
+
+// Header field offsets (25 bytes total)
+const OFF_MAGIC: usize = 0;
+const OFF_VERSION: usize = 4;
+const OFF_STREAM_ID: usize = 5;
+const OFF_INDEX: usize = 9;
+const OFF_TOTAL_FRAMES: usize = 13;
+const OFF_PAYLOAD_LEN: usize = 17;
+const OFF_CRC: usize = 21;
+
+/// Stream id of the video whose packets carry `file_id`.
+pub fn stream_id(file_id: &[u8; config::FILE_ID_SIZE]) -> u32 {
+    LittleEndian::read_u32(file_id)
+}
+
+impl FrameHeader {
+    /// Serialize the header. The CRC covers the header only; every packet in the
+    /// frame carries its own.
+    pub fn to_bytes(&self) -> [u8; config::FRAME_HEADER_SIZE] {
+        let mut buf = [0u8; config::FRAME_HEADER_SIZE];
+        LittleEndian::write_u32(&mut buf[OFF_MAGIC..], config::FRAME_MAGIC);
+        buf[OFF_VERSION] = config::FRAME_VERSION;
+        LittleEndian::write_u32(&mut buf[OFF_STREAM_ID..], self.stream_id);
+        LittleEndian::write_u32(&mut buf[OFF_INDEX..], self.index);
+        LittleEndian::write_u32(&mut buf[OFF_TOTAL_FRAMES..], self.total_frames);
+        LittleEndian::write_u32(&mut buf[OFF_PAYLOAD_LEN..], self.payload_len);
+        let crc = integrity::crc32_mpeg2(&buf[..OFF_CRC]);
+        LittleEndian::write_u32(&mut buf[OFF_CRC..], crc);
+        buf
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, PacketError> {
+        if data.len() < config::FRAME_HEADER_SIZE {
+            return Err(PacketError::BufferTooShort {
+                need: config::FRAME_HEADER_SIZE,
+                have: data.len(),
+            });
+        }
+        let magic = LittleEndian::read_u32(&data[OFF_MAGIC..]);
+        if magic != config::FRAME_MAGIC {
+            return Err(PacketError::InvalidMagic {
+                expected: config::FRAME_MAGIC,
+                got: magic,
+            });
+        }
+        if data[OFF_VERSION] != config::FRAME_VERSION {
+            return Err(PacketError::UnsupportedVersion(data[OFF_VERSION]));
+        }
+        let expected = LittleEndian::read_u32(&data[OFF_CRC..]);
+        let computed = integrity::crc32_mpeg2(&data[..OFF_CRC]);
+        if expected != computed {
+            return Err(PacketError::CrcMismatch { expected, computed });
+        }
+
+        Ok(Self {
+            stream_id: LittleEndian::read_u32(&data[OFF_STREAM_ID..]),
+            index: LittleEndian::read_u32(&data[OFF_INDEX..]),
+            total_frames: LittleEndian::read_u32(&data[OFF_TOTAL_FRAMES..]),
+            payload_len: LittleEndian::read_u32(&data[OFF_PAYLOAD_LEN..]),
+        })
+    }
+}
+
+/// Bytes of whole packet slots of `slot_size` that fit in a frame after its
+/// header of `header_size`.
+pub fn frame_capacity(bytes_per_frame: usize, header_size: usize, slot_size: usize) -> usize {
+    bytes_per_frame.saturating_sub(header_size) / slot_size * slot_size
+}
+
+/// Confidences of the bytes of slot `index`, or none if they are unknown.
+fn slot_confidence(confidence: &[f32], index: usize, slot_size: usize) -> &[f32] {
+    confidence
+        .get(index * slot_size..(index + 1) * slot_size)
+        .unwrap_or_default()
+}
+
+/// What a decode saw of a framed video's data frames.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FrameStats {
+    /// Data frames read, including duplicates and frames from other videos.
+    pub read: u64,
+    /// Frame count recorded in the final frame, if it was read.
+    pub total_frames: Option<u32>,
+    /// Frames whose index had already been read. Only repeats of a frame with
+    /// damaged packets are used, to fill in what the earlier copy lost.
+    pub duplicates: u64,
+    /// Frames that arrived after a frame with a higher index.
+    pub reordered: u64,
+    /// Frames whose header was unreadable; their packets are still tried.
+    pub damaged_headers: u64,
+    /// Frames from another video, which are skipped.
+    pub foreign: u64,
+    /// Packets that failed their CRC in frames with an intact header.
+    pub damaged_packets: u64,
+    /// Packets whose bytes were repaired by their Reed–Solomon parity.
+    pub corrected_packets: u64,
+    /// Frame indices never read with an intact header, sorted and merged: up
+    /// to the recorded frame count, or the highest index read if the final
+    /// frame was lost.
+    pub missing: Vec<Range<u32>>,
+}
+
+impl fmt::Display for FrameStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = match self.total_frames {
+            Some(n) => n.to_string(),
+            None => "?".to_string(),
+        };
+        write!(f, "read {} of {} frames", self.read, total)?;
+        let counts = [
+            (self.duplicates, "duplicated"),
+            (self.reordered, "out of order"),
+            (self.damaged_headers, "with damaged headers"),
+            (self.foreign, "from other videos"),
+        ];
+        for (count, what) in counts {
+            if count > 0 {
+                write!(f, "; {count} {what}")?;
+            }
+        }
+        if self.damaged_packets > 0 {
+            write!(f, "; {} damaged packets", self.damaged_packets)?;
+        }
+        if self.corrected_packets > 0 {
+            write!(f, "; {} packets corrected", self.corrected_packets)?;
+        }
+        if !self.missing.is_empty() {
+            let ranges: Vec<String> = self
+                .missing
+                .iter()
+                .map(|r| format!("{}..{}", r.start, r.end))
+                .collect();
+            write!(f, "; missing frames: {}", ranges.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the packets of a framed video, one frame at a time, in whatever order
+/// the frames arrive.
+///
+/// Frames from another stream and exact repeats of a frame already read are
+/// skipped. A frame whose header is damaged still has every packet slot tried,
+/// since packets sit at fixed offsets and carry their own CRCs. The header and
+/// each slot are corrected with their Reed–Solomon parity, if any, before the
+/// CRC is checked, with the bytes read least confidently marked as erasures
+/// when that helps.
+pub struct FrameReader {
+    packet_size: usize,
+    ecc: ReedSolomon,
+    header_size: usize,
+    slot_size: usize,
+    stream_id: Option<u32>,
+    seen: HashSet<u32>,
+    /// Frames read with damaged packets, whose repeats are worth reading.
+    incomplete: HashSet<u32>,
+    max_index: Option<u32>,
+    stats: FrameStats,
+}
+
+impl FrameReader {
+    /// A reader for packets of `packet_size` bytes, each followed by
+    /// `ecc_parity` Reed–Solomon parity bytes per codeword.
+    pub fn new(packet_size: usize, ecc_parity: usize) -> Self {
+        let ecc = ReedSolomon::new(ecc_parity);
+        Self {
+            packet_size,
+            header_size: ecc.protected_len(config::FRAME_HEADER_SIZE),
+            slot_size: ecc.protected_len(packet_size),
+            ecc,
+            stream_id: None,
+            seen: HashSet::new(),
+            incomplete: HashSet::new(),
+            max_index: None,
+            stats: FrameStats::default(),
+        }
+    }
+
+    /// Return every intact packet in one frame's data, given the confidence each
+    /// byte was read with (empty if unknown).
+    pub fn push(&mut self, frame: &[u8], confidence: &[f32]) -> Vec<Packet> {
+        self.stats.read += 1;
+        let body = frame.get(self.header_size..).unwrap_or_default();
+        let body_confidence = confidence.get(self.header_size..).unwrap_or_default();
+        let header_confidence = confidence.get(..self.header_size).unwrap_or_default();
+        let header = self.correct(frame, config::FRAME_HEADER_SIZE, header_confidence, |bytes| {
+            FrameHeader::from_bytes(bytes)
+        });
+        let header = match header {
+            Ok((header, _)) => header,
+            Err(_) => {
+                self.stats.damaged_headers += 1;
+                return body
+                    .chunks_exact(self.slot_size)
+                    .enumerate()
+                    .filter_map(|(i, slot)| {
+                        self.read_slot(slot, slot_confidence(body_confidence, i, slot.len()))
+                            .ok()
+                    })
+                    .collect();
+            }
+        };
+
+        // The first intact header seen determines the stream
+        if header.stream_id != *self.stream_id.get_or_insert(header.stream_id) {
+            self.stats.foreign += 1;
+            return Vec::new();
+        }
+        let first_copy = self.seen.insert(header.index);
+        if !first_copy {
+            self.stats.duplicates += 1;
+            if !self.incomplete.contains(&header.index) {
+                return Vec::new();
+            }
+        } else if self.max_index.is_some_and(|max| header.index < max) {
+            self.stats.reordered += 1;
+        }
+        self.max_index = self.max_index.max(Some(header.index));
+        if header.total_frames != 0 {
+            self.stats.total_frames = Some(header.total_frames);
+        }
+
+        let payload = &body[..(header.payload_len as usize).min(body.len())];
+        let mut packets = Vec::new();
+        let mut damaged = 0;
+        for (i, slot) in payload.chunks_exact(self.slot_size).enumerate() {
+            match self.read_slot(slot, slot_confidence(body_confidence, i, slot.len())) {
+                Ok(packet) => packets.push(packet),
+                Err(_) => damaged += 1,
+            }
+        }
+        if first_copy {
+            self.stats.damaged_packets += damaged;
+            if damaged > 0 {
+                self.incomplete.insert(header.index);
+            }
+        } else if damaged == 0 {
+            self.incomplete.remove(&header.index);
+        }
+        packets
+    }
+
+    /// Correct one packet slot and parse the packet in it.
+    fn read_slot(&mut self, slot: &[u8], confidence: &[f32]) -> Result<Packet, PacketError> {
+        let (packet, corrected) = self.correct(slot, self.packet_size, confidence, |bytes| {
+            deserialize_packet(bytes).map(|(packet, _)| packet)
+        })?;
+        if corrected > 0 {
+            self.stats.corrected_packets += 1;
+        }
+        Ok(packet)
+    }
+
+    /// Correct the protected form of `data_len` bytes at the start of `block`
+    /// and parse them, returning how many bytes were corrected too. Bytes read
+    /// with low confidence are tried as erasures first, then the block is
+    /// corrected as if every byte were sure, in case too many were flagged or
+    /// they misled it.
+    fn correct<T>(
+        &self,
+        block: &[u8],
+        data_len: usize,
+        confidence: &[f32],
+        parse: impl Fn(&[u8]) -> Result<T, PacketError>,
+    ) -> Result<(T, usize), PacketError> {
+        let attempt = |erasures: &[usize]| -> Result<(T, usize), PacketError> {
+            let (bytes, corrected) = self.ecc.correct(block, data_len, erasures)?;
+            Ok((parse(&bytes)?, corrected))
+        };
+        let protected_len = self.ecc.protected_len(data_len);
+        let erasures: Vec<usize> = if protected_len > data_len {
+            (0..confidence.len().min(protected_len))
+                .filter(|&i| confidence[i] < config::ERASURE_CONFIDENCE)
+                .collect()
+        } else {
+            Vec::new()
+        };
+        if !erasures.is_empty() {
+            if let Ok(parsed) = attempt(&erasures) {
+                return Ok(parsed);
+            }
+        }
+        attempt(&[])
+    }
+
+    /// Statistics for the frames read so far.
+    pub fn stats(&self) -> FrameStats {
+        let end = self
+            .stats
+            .total_frames
+            .or(self.max_index.map(|i| i + 1))
+            .unwrap_or(0);
+        let mut missing: Vec<Range<u32>> = Vec::new();
+        for index in (0..end).filter(|i| !self.seen.contains(i)) {
+            match missing.last_mut() {
+                Some(prev) if prev.end == index => prev.end = index + 1,
+                _ => missing.push(index..index + 1),
+            }
+        }
+        FrameStats {
+            missing,
+            ..self.stats.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::serialize_packet;
+
+    const PACKET_SIZE: usize = config::PACKET_HEADER_SIZE + 64;
+
+    fn frame(header: FrameHeader, esis: std::ops::Range<u32>) -> Vec<u8> {
+        let mut data = header.to_bytes().to_vec();
+        for esi in esis {
+            data.extend(serialize_packet(&[1; 16], 1, 200, 0, 256, 200, 64, 4, esi, 0, &[esi as u8; 64]));
+        }
+        data.resize(config::FRAME_HEADER_SIZE + 3 * PACKET_SIZE + 10, 0);
+        data
+    }
+
+    fn esis(packets: Vec<Packet>) -> Vec<u32> {
+        packets.iter().map(|p| p.header.esi).collect()
+    }
+
+    #[test]
+    fn test_frame_reader_skips_duplicate_and_foreign_frames() {
+        let header = |index, total_frames| FrameHeader {
+            stream_id: 7,
+            index,
+            total_frames,
+            payload_len: 3 * PACKET_SIZE as u32,
+        };
+        let mut reader = FrameReader::new(PACKET_SIZE, 0);
+
+        assert_eq!(esis(reader.push(&frame(header(0, 0), 0..3), &[])), [0, 1, 2]);
+        assert!(reader.push(&frame(header(0, 0), 0..3), &[]).is_empty());
+        assert!(reader.push(&frame(FrameHeader { stream_id: 8, ..header(1, 0) }, 3..6), &[]).is_empty());
+
+        // A damaged header does not lose the frame's packets
+        let mut damaged = frame(header(2, 0), 6..9);
+        damaged[OFF_INDEX] ^= 0xFF;
+        assert_eq!(esis(reader.push(&damaged, &[])), [6, 7, 8]);
+
+        // Only the payload length of the final, short frame is read
+        let last = frame(FrameHeader { payload_len: PACKET_SIZE as u32, ..header(6, 7) }, 18..21);
+        assert_eq!(esis(reader.push(&last, &[])), [18]);
+        // A frame arriving after a later one is still read
+        assert_eq!(esis(reader.push(&frame(header(4, 0), 12..15), &[])), [12, 13, 14]);
+
+        let stats = reader.stats();
+        assert_eq!(stats.read, 6);
+        assert_eq!(stats.total_frames, Some(7));
+        assert_eq!((stats.duplicates, stats.reordered), (1, 1));
+        assert_eq!((stats.damaged_headers, stats.foreign), (1, 1));
+        assert_eq!(stats.missing, [1..4, 5..6]);
+    }
+
+    #[test]
+    fn test_repeat_of_damaged_frame_fills_in_packets() {
+        let header = FrameHeader {
+            stream_id: 7,
+            index: 0,
+            total_frames: 1,
+            payload_len: 3 * PACKET_SIZE as u32,
+        };
+        let intact = frame(header, 0..3);
+        let mut damaged = intact.clone();
+        damaged[config::FRAME_HEADER_SIZE + PACKET_SIZE + 70] ^= 0x01;
+
+        let mut reader = FrameReader::new(PACKET_SIZE, 0);
+        assert_eq!(esis(reader.push(&damaged, &[])), [0, 2]);
+        assert_eq!(esis(reader.push(&intact, &[])), [0, 1, 2]);
+        // Once a copy was intact, further repeats add nothing
+        assert!(reader.push(&damaged, &[]).is_empty());
+
+        let stats = reader.stats();
+        assert_eq!((stats.duplicates, stats.damaged_packets), (2, 1));
+        assert!(stats.missing.is_empty());
+    }
+
+    #[test]
+    fn test_parity_corrects_damaged_packets() {
+        let ecc = ReedSolomon::new(8);
+        let slot_size = ecc.protected_len(PACKET_SIZE);
+        let header = FrameHeader {
+            stream_id: 7,
+            index: 0,
+            total_frames: 1,
+            payload_len: 2 * slot_size as u32,
+        };
+        let mut data = ecc.protect(&header.to_bytes());
+        for esi in 0..2 {
+            let packet = serialize_packet(&[1; 16], 1, 200, 0, 256, 200, 64, 4, esi, 0, &[7; 64]);
+            data.extend(ecc.protect(&packet));
+        }
+
+        // Four byte errors are within the first packet's parity, nine are not,
+        // and the header corrects its own
+        let first = ecc.protected_len(config::FRAME_HEADER_SIZE);
+        for offset in [3, 40, 41, 100] {
+            data[first + offset] ^= 0xA5;
+        }
+        for offset in 0..9 {
+            data[first + slot_size + 20 + offset] ^= 0xA5;
+        }
+        for offset in [OFF_MAGIC, OFF_INDEX, OFF_PAYLOAD_LEN, OFF_CRC] {
+            data[offset] ^= 0x5A;
+        }
+
+        let mut reader = FrameReader::new(PACKET_SIZE, 8);
+        assert_eq!(esis(reader.push(&data, &[])), [0]);
+        let stats = reader.stats();
+        assert_eq!((stats.corrected_packets, stats.damaged_packets), (1, 1));
+        assert_eq!(stats.damaged_headers, 0);
+        assert!(stats.missing.is_empty());
+    }
+
+    #[test]
+    fn test_unsure_bytes_are_corrected_as_erasures() {
+        let ecc = ReedSolomon::new(8);
+        let slot_size = ecc.protected_len(PACKET_SIZE);
+        let header = FrameHeader {
+            stream_id: 7,
+            index: 0,
+            total_frames: 1,
+            payload_len: 2 * slot_size as u32,
+        };
+        let mut data = ecc.protect(&header.to_bytes());
+        for esi in 0..2 {
+            let packet = serialize_packet(&[1; 16], 1, 200, 0, 256, 200, 64, 4, esi, 0, &[7; 64]);
+            data.extend(ecc.protect(&packet));
+        }
+
+        // Seven errors are beyond what eight parity bytes correct blind, but not
+        // once the reader knows where they are. The second packet has two errors
+        // among twenty unsure bytes, too many to erase, and falls back on parity.
+        let first = ecc.protected_len(config::FRAME_HEADER_SIZE);
+        let second = first + slot_size;
+        let mut confidence = vec![1.0; data.len()];
+        for offset in [3, 10, 40, 41, 42, 100, 120] {
+            data[first + offset] ^= 0xA5;
+            confidence[first + offset] = 0.05;
+        }
+        confidence[first + 50] = 0.08;
+        for offset in 0..20 {
+            confidence[second + offset] = 0.0;
+        }
+        data[second + 5] ^= 0x01;
+        data[second + 30] ^= 0x80;
+
+        assert_eq!(esis(FrameReader::new(PACKET_SIZE, 8).push(&data, &[])), [1]);
+        let mut reader = FrameReader::new(PACKET_SIZE, 8);
+        assert_eq!(esis(reader.push(&data, &confidence)), [0, 1]);
+        assert_eq!(reader.stats().corrected_packets, 2);
+    }
+}
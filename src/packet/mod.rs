@@ -1,8 +1,13 @@
 use byteorder::{ByteOrder, LittleEndian};
 use thiserror::Error;
 
-use crate::config;
+use crate::config::{self, FountainScheme};
+use crate::ecc::EccError;
 use crate::integrity;
+use frame::{FrameReader, FrameStats};
+
+pub mod frame;
+pub mod record;
 
 #[derive(Error, Debug)]
 pub enum PacketError {
@@ -16,12 +21,17 @@ pub enum PacketError {
     BufferTooShort { need: usize, have: usize },
     #[error("payload length mismatch")]
     PayloadLengthMismatch,
+    #[error("implausible chunk size: {stored} bytes stored, {original} bytes decompressed")]
+    ImplausibleChunkSize { stored: u32, original: u32 },
+    #[error("implausible symbol layout: {k} symbols of {symbol_size} bytes for {stored} bytes")]
+    ImplausibleSymbolCount { k: u32, symbol_size: u16, stored: u32 },
+    #[error(transparent)]
+    Ecc(#[from] EccError),
 }
 
 /// Parsed packet header fields.
 #[derive(Debug, Clone)]
 pub struct PacketHeader {
-    pub magic: u32,
     pub version: u8,
     pub flags: u8,
     pub file_id: [u8; config::FILE_ID_SIZE],
@@ -31,8 +41,10 @@ pub struct PacketHeader {
     pub symbol_size: u16,
     pub k: u32,
     pub esi: u32,
-    pub payload_length: u16,
-    pub crc: u32,
+    /// Number of chunks in the file. Absent in V2 packets.
+    pub total_chunks: Option<u32>,
+    /// Plaintext length of the whole file. Absent in V2 packets.
+    pub file_size: Option<u64>,
 }
 
 /// A complete packet: header + payload.
@@ -42,7 +54,7 @@ pub struct Packet {
     pub payload: Vec<u8>,
 }
 
-// Header field offsets (V2, 50 bytes total)
+// Header field offsets (V2 prefix, 50 bytes)
 const OFF_MAGIC: usize = 0;
 const OFF_VERSION: usize = 4;
 const OFF_FLAGS: usize = 5;
@@ -55,6 +67,9 @@ const OFF_K: usize = 36;
 const OFF_ESI: usize = 40;
 const OFF_PAYLOAD_LEN: usize = 44;
 const OFF_CRC: usize = 46;
+// V3 extension (62 bytes total)
+const OFF_TOTAL_CHUNKS: usize = 50;
+const OFF_FILE_SIZE: usize = 54;
 
 impl PacketHeader {
     pub fn is_repair(&self) -> bool {
@@ -68,11 +83,81 @@ impl PacketHeader {
     pub fn is_encrypted(&self) -> bool {
         self.flags & config::FLAG_ENCRYPTED != 0
     }
+
+    /// Whether the chunk's key is described by the key record.
+    pub fn has_key_record(&self) -> bool {
+        self.flags & config::FLAG_KEY_RECORD != 0
+    }
+
+    /// Whether the chunk's plaintext was compressed before encryption.
+    pub fn is_compressed(&self) -> bool {
+        self.flags & config::FLAG_COMPRESSED != 0
+    }
+
+    /// Whether encrypting the packet's chunk or record authenticated its header
+    /// fields (V4 and later).
+    pub fn authenticates_header(&self) -> bool {
+        self.version >= config::PACKET_VERSION
+    }
+
+    /// The associated data an encrypted V4 chunk or record was sealed with.
+    /// A record's length is only known once it is encrypted, so it is left out.
+    pub fn associated_data(&self) -> Vec<u8> {
+        associated_data(
+            &self.file_id,
+            self.chunk_index,
+            self.total_chunks.unwrap_or(0),
+            self.file_size.unwrap_or(0),
+            if self.is_record() { 0 } else { self.original_size },
+            self.flags,
+        )
+    }
+
+    /// Whether this packet carries a metadata record segment rather than a chunk symbol.
+    pub fn is_record(&self) -> bool {
+        self.flags & config::FLAG_RECORD != 0
+    }
+
+    /// The erasure code used for this packet's chunk. Packets written before
+    /// RaptorQ support carry no scheme flag and are XOR-coded.
+    pub fn fountain_scheme(&self) -> FountainScheme {
+        if self.flags & config::FLAG_RAPTORQ != 0 {
+            FountainScheme::RaptorQ
+        } else {
+            FountainScheme::Xor
+        }
+    }
+}
+
+/// Associated data binding an encrypted chunk (or record) to the header fields
+/// that say where it goes: its index (or record kind), the file's chunk count
+/// and size, its plaintext size and its flags. A spliced, reordered or edited
+/// header then fails authentication instead of misplacing data.
+pub fn associated_data(
+    file_id: &[u8; config::FILE_ID_SIZE],
+    chunk_index: u32,
+    total_chunks: u32,
+    file_size: u64,
+    original_size: u32,
+    flags: u8,
+) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(config::FILE_ID_SIZE + 22);
+    aad.push(config::PACKET_VERSION);
+    aad.push(flags & config::AUTHENTICATED_FLAGS);
+    aad.extend_from_slice(file_id);
+    aad.extend_from_slice(&chunk_index.to_le_bytes());
+    aad.extend_from_slice(&total_chunks.to_le_bytes());
+    aad.extend_from_slice(&file_size.to_le_bytes());
+    aad.extend_from_slice(&original_size.to_le_bytes());
+    aad
 }
 
 /// Serialize a packet header + payload into bytes.
+#[allow(clippy::too_many_arguments)]
 pub fn serialize_packet(
     file_id: &[u8; config::FILE_ID_SIZE],
+    total_chunks: u32,
+    file_size: u64,
     chunk_index: u32,
     chunk_size: u32,
     original_size: u32,
@@ -95,6 +180,8 @@ pub fn serialize_packet(
     LittleEndian::write_u32(&mut header[OFF_K..], k);
     LittleEndian::write_u32(&mut header[OFF_ESI..], esi);
     LittleEndian::write_u16(&mut header[OFF_PAYLOAD_LEN..], payload.len() as u16);
+    LittleEndian::write_u32(&mut header[OFF_TOTAL_CHUNKS..], total_chunks);
+    LittleEndian::write_u64(&mut header[OFF_FILE_SIZE..], file_size);
 
     // Compute CRC over header (with CRC field zeroed) + payload
     let crc = integrity::packet_crc32(&header, OFF_CRC, payload);
@@ -107,17 +194,17 @@ pub fn serialize_packet(
 }
 
 /// Deserialize a packet from a byte buffer. Returns the packet and the number of bytes consumed.
+///
+/// V3 packets and the shorter V2 packets written by earlier releases are also accepted.
 pub fn deserialize_packet(data: &[u8]) -> Result<(Packet, usize), PacketError> {
-    if data.len() < config::PACKET_HEADER_SIZE {
+    if data.len() < config::PACKET_HEADER_SIZE_V2 {
         return Err(PacketError::BufferTooShort {
-            need: config::PACKET_HEADER_SIZE,
+            need: config::PACKET_HEADER_SIZE_V2,
             have: data.len(),
         });
     }
 
-    let header_bytes = &data[..config::PACKET_HEADER_SIZE];
-
-    let magic = LittleEndian::read_u32(&header_bytes[OFF_MAGIC..]);
+    let magic = LittleEndian::read_u32(&data[OFF_MAGIC..]);
     if magic != config::MAGIC {
         return Err(PacketError::InvalidMagic {
             expected: config::MAGIC,
@@ -125,11 +212,21 @@ pub fn deserialize_packet(data: &[u8]) -> Result<(Packet, usize), PacketError> {
         });
     }
 
-    let version = header_bytes[OFF_VERSION];
-    if version != config::PACKET_VERSION {
-        return Err(PacketError::UnsupportedVersion(version));
+    let version = data[OFF_VERSION];
+    let header_size = match version {
+        config::PACKET_VERSION | config::PACKET_VERSION_V3 => config::PACKET_HEADER_SIZE,
+        config::PACKET_VERSION_V2 => config::PACKET_HEADER_SIZE_V2,
+        _ => return Err(PacketError::UnsupportedVersion(version)),
+    };
+    if data.len() < header_size {
+        return Err(PacketError::BufferTooShort {
+            need: header_size,
+            have: data.len(),
+        });
     }
 
+    let header_bytes = &data[..header_size];
+
     let flags = header_bytes[OFF_FLAGS];
     let mut file_id = [0u8; config::FILE_ID_SIZE];
     file_id.copy_from_slice(&header_bytes[OFF_FILE_ID..OFF_FILE_ID + config::FILE_ID_SIZE]);
@@ -141,8 +238,23 @@ pub fn deserialize_packet(data: &[u8]) -> Result<(Packet, usize), PacketError> {
     let esi = LittleEndian::read_u32(&header_bytes[OFF_ESI..]);
     let payload_length = LittleEndian::read_u16(&header_bytes[OFF_PAYLOAD_LEN..]);
     let crc = LittleEndian::read_u32(&header_bytes[OFF_CRC..]);
+    let (total_chunks, file_size) = if version == config::PACKET_VERSION_V2 {
+        (None, None)
+    } else {
+        (
+            Some(LittleEndian::read_u32(&header_bytes[OFF_TOTAL_CHUNKS..])),
+            Some(LittleEndian::read_u64(&header_bytes[OFF_FILE_SIZE..])),
+        )
+    };
+
+    // Every symbol fills its payload exactly. Rejecting anything else up front
+    // keeps a header torn across a lost frame from stalling a streaming scan
+    // while it waits for a bogus payload length.
+    if payload_length != symbol_size {
+        return Err(PacketError::PayloadLengthMismatch);
+    }
 
-    let total_len = config::PACKET_HEADER_SIZE + payload_length as usize;
+    let total_len = header_size + payload_length as usize;
     if data.len() < total_len {
         return Err(PacketError::BufferTooShort {
             need: total_len,
@@ -150,7 +262,7 @@ pub fn deserialize_packet(data: &[u8]) -> Result<(Packet, usize), PacketError> {
         });
     }
 
-    let payload = data[config::PACKET_HEADER_SIZE..total_len].to_vec();
+    let payload = data[header_size..total_len].to_vec();
 
     // Verify CRC
     let computed_crc = integrity::packet_crc32(header_bytes, OFF_CRC, &payload);
@@ -162,7 +274,6 @@ pub fn deserialize_packet(data: &[u8]) -> Result<(Packet, usize), PacketError> {
     }
 
     let header = PacketHeader {
-        magic,
         version,
         flags,
         file_id,
@@ -172,38 +283,143 @@ pub fn deserialize_packet(data: &[u8]) -> Result<(Packet, usize), PacketError> {
         symbol_size,
         k,
         esi,
-        payload_length,
-        crc,
+        total_chunks,
+        file_size,
     };
 
+    // The decoder allocates these sizes, so a forged header must not name more
+    // than its symbols hold, or a chunk larger than any encoder writes
+    if chunk_size as u64 > k as u64 * symbol_size as u64
+        || (!header.is_record() && original_size as usize > config::MAX_CHUNK_SIZE)
+    {
+        return Err(PacketError::ImplausibleChunkSize {
+            stored: chunk_size,
+            original: original_size,
+        });
+    }
+    // A chunk is split into exactly as many symbols as it fills, and RaptorQ
+    // cannot code more source symbols than RFC 6330 allows
+    if symbol_size == 0
+        || (header.fountain_scheme() == FountainScheme::RaptorQ
+            && k as usize > config::RAPTORQ_MAX_SOURCE_SYMBOLS)
+        || (!header.is_record() && k as usize != (chunk_size as usize).div_ceil(symbol_size as usize))
+    {
+        return Err(PacketError::ImplausibleSymbolCount {
+            k,
+            symbol_size,
+            stored: chunk_size,
+        });
+    }
+
     Ok((Packet { header, payload }, total_len))
 }
 
 /// Scan a byte buffer for packets by looking for the magic number.
+#[cfg(test)]
 pub fn scan_for_packets(data: &[u8]) -> Vec<Packet> {
-    let mut packets = Vec::new();
-    let mut offset = 0;
-    let magic_bytes = config::MAGIC.to_le_bytes();
+    let mut scanner = PacketScanner::new();
+    scanner.push(data)
+}
 
-    while offset + config::PACKET_HEADER_SIZE <= data.len() {
-        // Search for magic number
-        if let Some(pos) = find_magic(&data[offset..], &magic_bytes) {
+/// Interleave the serialized packets of several chunks, each `packet_size`
+/// bytes, taking one packet from every chunk in turn until all are used.
+///
+/// Consecutive packets of a chunk end up `chunks.len()` packets apart, so
+/// frames lost in a burst take a few symbols from each chunk rather than most
+/// of one. Decoding needs no matching step: every packet names its chunk.
+pub fn interleave_packets(chunks: &[Vec<u8>], packet_size: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(chunks.iter().map(Vec::len).sum());
+    let mut iters: Vec<_> = chunks.iter().map(|c| c.chunks_exact(packet_size)).collect();
+    loop {
+        let before = out.len();
+        for packets in &mut iters {
+            if let Some(packet) = packets.next() {
+                out.extend_from_slice(packet);
+            }
+        }
+        if out.len() == before {
+            return out;
+        }
+    }
+}
+
+/// Incremental packet scanner for a byte stream that arrives in pieces.
+///
+/// Bytes that may still hold the start of a packet are carried over to the next
+/// [`PacketScanner::push`], so packets split across frames are still found, while
+/// everything before them is discarded to keep memory bounded.
+#[derive(Default)]
+pub struct PacketScanner {
+    buffer: Vec<u8>,
+}
+
+impl PacketScanner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append `data` to the stream and return every complete packet found.
+    pub fn push(&mut self, data: &[u8]) -> Vec<Packet> {
+        self.buffer.extend_from_slice(data);
+
+        let mut packets = Vec::new();
+        let mut offset = 0;
+        let magic_bytes = config::MAGIC.to_le_bytes();
+
+        loop {
+            // Search for magic number
+            let Some(pos) = find_magic(&self.buffer[offset..], &magic_bytes) else {
+                // Keep a possible partial magic at the end
+                offset = self.buffer.len().saturating_sub(magic_bytes.len() - 1).max(offset);
+                break;
+            };
             let abs_pos = offset + pos;
-            match deserialize_packet(&data[abs_pos..]) {
+            match deserialize_packet(&self.buffer[abs_pos..]) {
                 Ok((packet, consumed)) => {
                     packets.push(packet);
                     offset = abs_pos + consumed;
                 }
+                Err(PacketError::BufferTooShort { .. }) => {
+                    // Wait for the rest of this packet
+                    offset = abs_pos;
+                    break;
+                }
                 Err(_) => {
                     offset = abs_pos + 1; // Skip past this false magic match
                 }
             }
-        } else {
-            break;
         }
+
+        self.buffer.drain(..offset);
+        packets
     }
+}
 
-    packets
+/// Reads packets from a video's data frames: at fixed offsets after each frame
+/// header in a framed video, or by scanning the byte stream of an older one.
+pub enum PacketReader {
+    Framed(Box<FrameReader>),
+    Stream(PacketScanner),
+}
+
+impl PacketReader {
+    /// Return every complete packet found in the next frame's data, given the
+    /// confidence each byte was read with (empty if unknown). Framed videos with
+    /// parity treat unsure bytes as erasures.
+    pub fn push(&mut self, frame: &[u8], confidence: &[f32]) -> Vec<Packet> {
+        match self {
+            Self::Framed(reader) => reader.push(frame, confidence),
+            Self::Stream(scanner) => scanner.push(frame),
+        }
+    }
+
+    /// Statistics on the frames read, for framed videos.
+    pub fn frame_stats(&self) -> Option<FrameStats> {
+        match self {
+            Self::Framed(reader) => Some(reader.stats()),
+            Self::Stream(_) => None,
+        }
+    }
 }
 
 fn find_magic(data: &[u8], magic: &[u8; 4]) -> Option<usize> {
@@ -229,6 +445,8 @@ mod tests {
 
         let data = serialize_packet(
             &file_id,
+            5,     // total_chunks
+            4500,  // file_size
             3,     // chunk_index
             1024,  // chunk_size
             900,   // original_size
@@ -241,16 +459,39 @@ mod tests {
 
         let (packet, consumed) = deserialize_packet(&data).unwrap();
         assert_eq!(consumed, config::PACKET_HEADER_SIZE + 256);
-        assert_eq!(packet.header.magic, config::MAGIC);
         assert_eq!(packet.header.version, config::PACKET_VERSION);
         assert_eq!(packet.header.chunk_index, 3);
         assert_eq!(packet.header.chunk_size, 1024);
         assert_eq!(packet.header.original_size, 900);
         assert_eq!(packet.header.k, 4);
         assert_eq!(packet.header.esi, 3);
+        assert_eq!(packet.header.total_chunks, Some(5));
+        assert_eq!(packet.header.file_size, Some(4500));
         assert!(packet.header.is_last_chunk());
         assert!(!packet.header.is_repair());
         assert!(!packet.header.is_encrypted());
+        assert_eq!(packet.header.fountain_scheme(), FountainScheme::Xor);
+        assert_eq!(packet.payload, payload);
+    }
+
+    #[test]
+    fn test_deserialize_v2_packet() {
+        let file_id = make_test_file_id();
+        let payload = [0xCC; 64];
+        let v3 = serialize_packet(&file_id, 1, 200, 0, 256, 200, 64, 4, 2, 0, &payload);
+
+        // A V2 packet is the V3 header without its extension
+        let mut v2 = v3[..config::PACKET_HEADER_SIZE_V2].to_vec();
+        v2[OFF_VERSION] = config::PACKET_VERSION_V2;
+        let crc = integrity::packet_crc32(&v2, OFF_CRC, &payload);
+        LittleEndian::write_u32(&mut v2[OFF_CRC..], crc);
+        v2.extend_from_slice(&payload);
+
+        let (packet, consumed) = deserialize_packet(&v2).unwrap();
+        assert_eq!(consumed, config::PACKET_HEADER_SIZE_V2 + 64);
+        assert_eq!(packet.header.esi, 2);
+        assert_eq!(packet.header.total_chunks, None);
+        assert_eq!(packet.header.file_size, None);
         assert_eq!(packet.payload, payload);
     }
 
@@ -258,7 +499,7 @@ mod tests {
     fn test_crc_tamper_detection() {
         let file_id = make_test_file_id();
         let payload = vec![0xBB; 128];
-        let mut data = serialize_packet(&file_id, 0, 512, 512, 128, 4, 0, 0, &payload);
+        let mut data = serialize_packet(&file_id, 1, 512, 0, 512, 512, 128, 4, 0, 0, &payload);
 
         // Tamper with the payload
         data[config::PACKET_HEADER_SIZE + 10] ^= 0xFF;
@@ -267,11 +508,50 @@ mod tests {
         assert!(matches!(result, Err(PacketError::CrcMismatch { .. })));
     }
 
+    #[test]
+    fn test_implausible_chunk_sizes_are_rejected() {
+        let file_id = make_test_file_id();
+        let payload = [0xDD; 64];
+
+        // More stored bytes than 4 symbols hold, and a decompressed size no encoder writes
+        for (chunk_size, original_size) in [(257, 200), (256, config::MAX_CHUNK_SIZE as u32 + 1)] {
+            let data = serialize_packet(&file_id, 1, 200, 0, chunk_size, original_size, 64, 4, 0, 0, &payload);
+            let result = deserialize_packet(&data);
+            assert!(matches!(result, Err(PacketError::ImplausibleChunkSize { .. })));
+        }
+    }
+
+    #[test]
+    fn test_implausible_symbol_counts_are_rejected() {
+        let file_id = make_test_file_id();
+        let payload = [0xDD; 64];
+        let k = config::RAPTORQ_MAX_SOURCE_SYMBOLS as u32 + 1;
+        let raptorq = config::FLAG_RAPTORQ;
+
+        // More RaptorQ source symbols than RFC 6330 allows, more symbols than the
+        // chunk fills, and empty symbols
+        let cases: [(u32, u32, u8, &[u8]); 4] = [
+            (k * 64, k, raptorq, &payload),
+            (100, 3, 0, &payload),
+            (200, 5, raptorq, &payload),
+            (0, 0, 0, &[]),
+        ];
+        for (chunk_size, k, flags, payload) in cases {
+            let symbol_size = payload.len() as u16;
+            let data =
+                serialize_packet(&file_id, 1, 200, 0, chunk_size, 200, symbol_size, k, 0, flags, payload);
+            let result = deserialize_packet(&data);
+            assert!(matches!(result, Err(PacketError::ImplausibleSymbolCount { .. })));
+        }
+        let data = serialize_packet(&file_id, 1, 200, 0, 200, 200, 64, 4, 0, raptorq, &payload);
+        assert!(deserialize_packet(&data).is_ok());
+    }
+
     #[test]
     fn test_scan_for_packets() {
         let file_id = make_test_file_id();
-        let p1 = serialize_packet(&file_id, 0, 256, 200, 64, 4, 0, 0, &vec![1u8; 64]);
-        let p2 = serialize_packet(&file_id, 0, 256, 200, 64, 4, 1, 0, &vec![2u8; 64]);
+        let p1 = serialize_packet(&file_id, 1, 200, 0, 256, 200, 64, 4, 0, 0, &[1u8; 64]);
+        let p2 = serialize_packet(&file_id, 1, 200, 0, 256, 200, 64, 4, 1, 0, &[2u8; 64]);
 
         // Concatenate with some garbage in between
         let mut stream = Vec::new();
@@ -286,4 +566,32 @@ mod tests {
         assert_eq!(packets[0].header.esi, 0);
         assert_eq!(packets[1].header.esi, 1);
     }
+
+    #[test]
+    fn test_interleave_packets() {
+        let chunks = [b"a1a2a3".to_vec(), b"b1".to_vec(), b"c1c2".to_vec()];
+        assert_eq!(interleave_packets(&chunks, 2), b"a1b1c1a2c2a3");
+        assert_eq!(interleave_packets(&chunks[..1], 2), chunks[0]);
+    }
+
+    #[test]
+    fn test_scanner_finds_packets_split_across_pushes() {
+        let file_id = make_test_file_id();
+        let mut stream = vec![0x59u8; 7]; // garbage including a partial magic byte
+        for esi in 0..5 {
+            stream.extend(serialize_packet(&file_id, 2, 512, 1, 256, 256, 64, 4, esi, 0, &[esi as u8; 64]));
+            stream.extend_from_slice(&[0x00; 3]);
+        }
+
+        for piece_len in [1, 5, 50, 113, stream.len()] {
+            let mut scanner = PacketScanner::new();
+            let esis: Vec<u32> = stream
+                .chunks(piece_len)
+                .flat_map(|piece| scanner.push(piece))
+                .map(|p| p.header.esi)
+                .collect();
+            assert_eq!(esis, vec![0, 1, 2, 3, 4], "piece_len={piece_len}");
+            assert!(scanner.buffer.len() < config::PACKET_HEADER_SIZE);
+        }
+    }
 }
@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+
+use crate::config;
+use crate::packet::{serialize_packet, Packet};
+
+/// Serialize one copy of a metadata record into packets.
+///
+/// The record is split into `symbol_size` segments carried by packets flagged with
+/// [`config::FLAG_RECORD`]: `chunk_index` holds the record kind, `k` the segment
+/// count, `esi` the segment index and `chunk_size` the record length. Records are
+/// small, so [`config::RECORD_COPIES`] copies in separate frames stand in for
+/// fountain coding.
+#[allow(clippy::too_many_arguments)]
+pub fn serialize_record(
+    file_id: &[u8; config::FILE_ID_SIZE],
+    total_chunks: u32,
+    file_size: u64,
+    kind: u32,
+    data: &[u8],
+    symbol_size: u16,
+    encrypted: bool,
+) -> Vec<u8> {
+    let symbol_size = symbol_size as usize;
+    let segments = data.len().div_ceil(symbol_size).max(1);
+    let mut flags = config::FLAG_RECORD;
+    if encrypted {
+        flags |= config::FLAG_ENCRYPTED;
+    }
+
+    let mut copy = Vec::with_capacity(segments * (config::PACKET_HEADER_SIZE + symbol_size));
+    for esi in 0..segments {
+        let start = (esi * symbol_size).min(data.len());
+        let end = (start + symbol_size).min(data.len());
+        let mut payload = vec![0u8; symbol_size];
+        payload[..end - start].copy_from_slice(&data[start..end]);
+
+        copy.extend(serialize_packet(
+            file_id,
+            total_chunks,
+            file_size,
+            kind,
+            data.len() as u32,
+            data.len() as u32,
+            symbol_size as u16,
+            segments as u32,
+            esi as u32,
+            flags,
+            &payload,
+        ));
+    }
+    copy
+}
+
+/// A fully reassembled record.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub data: Vec<u8>,
+    pub encrypted: bool,
+    /// Associated data an encrypted V4 record was sealed with; `None` before V4.
+    pub associated_data: Option<Vec<u8>>,
+    /// Chunk count and file size recorded in the record's packet headers.
+    pub total_chunks: Option<u32>,
+    pub file_size: Option<u64>,
+}
+
+struct PartialRecord {
+    len: usize,
+    encrypted: bool,
+    associated_data: Option<Vec<u8>>,
+    total_chunks: Option<u32>,
+    file_size: Option<u64>,
+    segments: Vec<Option<Vec<u8>>>,
+}
+
+/// Collects record segments from any copy until each record is complete.
+#[derive(Default)]
+pub struct RecordAssembler {
+    records: HashMap<u32, PartialRecord>,
+}
+
+impl RecordAssembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a record packet. Packets that disagree with the first segment seen for
+    /// their kind, or describe an oversized record, are ignored. An encrypted copy
+    /// replaces an unencrypted one collected so far, and unencrypted packets never
+    /// join an encrypted record, so a spliced-in plaintext copy cannot shadow the
+    /// authenticated one.
+    pub fn add(&mut self, pkt: Packet) {
+        let header = &pkt.header;
+        let len = header.chunk_size as usize;
+        let segments = header.k as usize;
+        if len > config::MAX_RECORD_SIZE
+            || segments != len.div_ceil(pkt.payload.len().max(1)).max(1)
+            || header.esi as usize >= segments
+        {
+            return;
+        }
+        if let Some(record) = self.records.get(&header.chunk_index) {
+            match (record.encrypted, header.is_encrypted()) {
+                (true, false) => return,
+                (false, true) => {
+                    self.records.remove(&header.chunk_index);
+                }
+                _ => {}
+            }
+        }
+
+        let record = self
+            .records
+            .entry(header.chunk_index)
+            .or_insert_with(|| PartialRecord {
+                len,
+                encrypted: header.is_encrypted(),
+                associated_data: header.authenticates_header().then(|| header.associated_data()),
+                total_chunks: header.total_chunks,
+                file_size: header.file_size,
+                segments: vec![None; segments],
+            });
+        if record.len != len || record.segments.len() != segments {
+            return;
+        }
+        record.segments[header.esi as usize].get_or_insert(pkt.payload);
+    }
+
+    /// The record of the given kind, if every one of its segments has arrived.
+    pub fn get(&self, kind: u32) -> Option<Record> {
+        let record = self.records.get(&kind)?;
+        let mut data = Vec::with_capacity(record.len);
+        for segment in &record.segments {
+            data.extend_from_slice(segment.as_ref()?);
+        }
+        data.truncate(record.len);
+        Some(Record {
+            data,
+            encrypted: record.encrypted,
+            associated_data: record.associated_data.clone(),
+            total_chunks: record.total_chunks,
+            file_size: record.file_size,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::scan_for_packets;
+
+    #[test]
+    fn test_record_survives_losing_segments_of_each_copy() {
+        let data: Vec<u8> = (0..300u32).map(|i| i as u8).collect();
+        let stream =
+            serialize_record(&[9; 16], 2, 5000, 7, &data, 64, false).repeat(config::RECORD_COPIES);
+
+        let packets = scan_for_packets(&stream);
+        assert_eq!(packets.len(), 5 * config::RECORD_COPIES);
+        assert!(packets.iter().all(|p| p.header.flags & config::FLAG_RECORD != 0));
+
+        // Each copy loses a different segment
+        let mut assembler = RecordAssembler::new();
+        for (i, pkt) in packets.into_iter().enumerate() {
+            if i % 5 != i / 5 {
+                assembler.add(pkt);
+            }
+        }
+        let record = assembler.get(7).unwrap();
+        assert_eq!(record.data, data);
+        assert!(!record.encrypted);
+        assert!(assembler.get(8).is_none());
+    }
+
+    #[test]
+    fn test_encrypted_copy_wins_over_plaintext() {
+        let plain = serialize_record(&[9; 16], 2, 5000, 7, b"forged", 64, false);
+        let sealed = serialize_record(&[9; 16], 2, 5000, 7, b"sealed record", 64, true);
+
+        for stream in [[plain.clone(), sealed.clone()].concat(), [sealed, plain].concat()] {
+            let mut assembler = RecordAssembler::new();
+            for pkt in scan_for_packets(&stream) {
+                assembler.add(pkt);
+            }
+            let record = assembler.get(7).unwrap();
+            assert!(record.encrypted);
+            assert_eq!(record.data, b"sealed record");
+        }
+    }
+}
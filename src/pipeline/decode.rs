@@ -1,134 +1,1697 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs::File;
-use std::io::Write;
-use std::path::Path;
+use std::io::{BufReader, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
 
 use anyhow::{Context, Result};
 use indicatif::{ProgressBar, ProgressStyle};
-use log::info;
+use log::{info, warn};
 use rayon::prelude::*;
 
-use crate::config::Yts3Config;
-use crate::crypto;
-use crate::fountain;
-use crate::packet;
+use crate::archive::{self, ArchiveIndex};
+use crate::compress;
+use crate::config::{self, DamagePolicy, KdfParams, Yts3Config};
+use crate::crypto::{self, Credentials, KeyRecord, Signer};
+use crate::fountain::ChunkDecoder;
+use crate::manifest::Manifest;
+use crate::packet::frame::FrameStats;
+use crate::packet::record::RecordAssembler;
+use crate::packet::Packet;
+use crate::storage::StorageBackend;
 use crate::video::decoder::VideoDecoder;
 
+use super::TempPath;
+
 /// Full decode pipeline: video -> packets -> fountain decode -> [decrypt] -> reassemble file.
+///
+/// The pipeline is streamed: packets are scanned from each batch of frames as it
+/// is extracted and fed to per-chunk fountain decoders, and every chunk is written
+/// to its offset in the output as soon as it can be recovered, after which its
+/// buffers are freed. Memory stays bounded by the chunks still in flight.
+///
+/// Chunks that cannot be recovered or fail authentication do not abort the
+/// decode; they are listed in the returned [`DecodeReport`] and their byte ranges
+/// are handled according to `cfg.damage_policy`. With [`DamagePolicy::Fail`] an
+/// incomplete output is deleted.
+///
+/// A complete output is checked against the video's manifest record (size and
+/// SHA-256) and given the modification time it records; an output the decode
+/// fails on is removed. If `output_path` is a directory, the file is written
+/// inside it under the file name recorded in the manifest, or, for a video made
+/// by [`encode_archive`](super::encode::encode_archive), every archived file and
+/// directory is restored inside it.
 pub fn decode_file(
     input_path: &str,
     output_path: &Path,
-    password: Option<&str>,
+    credentials: &Credentials,
+    cfg: &Yts3Config,
+) -> Result<DecodeReport> {
+    decode_video(input_path, output_path, credentials, cfg, &[])
+}
+
+/// Decode a multi-file video and restore its tree under `output_dir`, or only
+/// the entries equal to or inside the `select` paths if any are given.
+pub fn decode_archive(
+    input_path: &str,
+    output_dir: &Path,
+    credentials: &Credentials,
     cfg: &Yts3Config,
-) -> Result<()> {
-    // Step 1: Decode video frames into raw packet data
+    select: &[String],
+) -> Result<DecodeReport> {
+    std::fs::create_dir_all(output_dir).context("failed to create output directory")?;
+    decode_video(input_path, output_dir, credentials, cfg, select)
+}
+
+/// Decode a video only to check it: the output is verified against the manifest
+/// as usual and then discarded, and the report says whether the video is
+/// complete and who signed it. With [`Credentials::trusted_signers`] set, a
+/// video not signed by one of them is an error.
+pub fn verify_file(input_path: &str, credentials: &Credentials, cfg: &Yts3Config) -> Result<DecodeReport> {
+    let output = TempPath::new("bin");
+    let cfg = Yts3Config {
+        damage_policy: DamagePolicy::Fail,
+        ..cfg.clone()
+    };
+    decode_video(input_path, &output.path, credentials, &cfg, &[])
+}
+
+fn decode_video(
+    input_path: &str,
+    output_path: &Path,
+    credentials: &Credentials,
+    cfg: &Yts3Config,
+    select: &[String],
+) -> Result<DecodeReport> {
+    // The video may predate frame headers, so `cfg` need not fit a whole packet
+    cfg.validate_unframed()?;
+
+    // Step 1: Open the video and detect its encoding parameters
     info!("decoding video: {}", input_path);
-    let decoder = VideoDecoder::new(cfg);
-    let raw_data = decoder.decode_from_file(input_path)?;
+    let decoder = VideoDecoder::open(input_path, cfg)?;
 
-    // Step 2: Scan for and parse packets
-    info!("scanning for packets...");
-    let packets = packet::scan_for_packets(&raw_data);
-    info!("found {} valid packets", packets.len());
+    // The file name is only known once the manifest at the end of the stream is
+    // read, so decode into a temporary file when restoring it
+    let output_dir = output_path.is_dir().then_some(output_path);
+    let write_path = match output_dir {
+        Some(dir) => dir.join(format!(".yts3-decode-{}.partial", std::process::id())),
+        None => output_path.to_path_buf(),
+    };
 
-    if packets.is_empty() {
-        anyhow::bail!("no valid packets found in video");
+    let outfile = File::create(&write_path).context("failed to create output file")?;
+    // Until it is verified and in place, the output is removed on any error
+    let write_path = TempPath::at(write_path);
+    let mut assembler = ChunkAssembler::new(outfile, credentials, cfg.damage_policy);
+    let mut reader = decoder.packet_reader();
+
+    // Steps 2-5: Read the packets in each batch of frames, fountain-decode chunks
+    // as they become recoverable, decrypt and write them out.
+    decoder.decode_stream(input_path, |frames| {
+        for frame in &frames {
+            for pkt in reader.push(&frame.data, &frame.confidence) {
+                assembler.add_packet(pkt)?;
+            }
+        }
+        assembler.recover_ready()
+    })?;
+
+    let (outfile, mut report) = assembler.finish()?;
+    drop(outfile);
+    report.frames = reader.frame_stats();
+    match &report.frames {
+        Some(frames) if !frames.missing.is_empty() || frames.damaged_headers > 0 => warn!("frames: {frames}"),
+        Some(frames) => info!("frames: {frames}"),
+        None => {}
     }
 
-    // Extract file ID from first packet
-    let file_id = packets[0].header.file_id;
-    let encrypted = packets[0].header.is_encrypted();
+    if let Some(signer) = &report.signer {
+        info!("signed by {signer}");
+    }
+    let trusted = &credentials.trusted_signers;
+    if !trusted.is_empty() && !report.signer.is_some_and(|signer| trusted.contains(&signer)) {
+        match report.signer {
+            Some(signer) => anyhow::bail!("video is signed by {signer}, which is not trusted"),
+            None => anyhow::bail!("video has no valid signature from a trusted signer"),
+        }
+    }
 
-    // Derive encryption key if needed
-    let key = if encrypted {
-        let pw = password.ok_or_else(|| {
-            anyhow::anyhow!("file is encrypted but no password provided")
-        })?;
-        Some(crypto::derive_key(pw.as_bytes(), &file_id)?)
+    if !report.is_complete() {
+        warn!("decode incomplete: {report}");
+        if cfg.damage_policy == DamagePolicy::Fail {
+            warn!("partial output discarded");
+            return Ok(report);
+        }
+        // The signature vouches for the manifest, but a partial output cannot be
+        // checked against it, and unencrypted chunks are protected only by CRCs
+        if !trusted.is_empty() {
+            anyhow::bail!(
+                "decode incomplete, so the output cannot be verified against the signed manifest: {report}"
+            );
+        }
+    } else if let Some(manifest) = &report.manifest {
+        // Step 6: Verify the output against the manifest
+        verify_output(&write_path.path, manifest)?;
+        info!("verified SHA-256: {}", manifest.sha256_hex());
     } else {
-        None
+        warn!("video has no manifest; output not verified");
+    }
+
+    report.output_path = match (output_dir, &report.archive) {
+        (Some(dir), Some(index)) => {
+            // Step 7: Split the archive stream into its files
+            let stream = File::open(&write_path.path).context("failed to open archive stream")?;
+            let damaged = archive::extract(index, &mut BufReader::new(stream), dir, select)
+                .context("failed to extract archive")?;
+            if !damaged.is_empty() {
+                warn!("damaged files: {}", damaged.join(", "));
+            }
+            dir.to_path_buf()
+        }
+        (Some(dir), None) => {
+            if !select.is_empty() {
+                anyhow::bail!("the video holds a single file, not an archive");
+            }
+            let name = report
+                .manifest
+                .as_ref()
+                .and_then(Manifest::safe_file_name)
+                .unwrap_or("decoded.bin");
+            let final_path = dir.join(name);
+            std::fs::rename(&write_path.path, &final_path)
+                .context("failed to move output into place")?;
+            write_path.keep();
+            restore_modified(&final_path, report.manifest.as_ref());
+            final_path
+        }
+        (None, _) => {
+            let path = write_path.keep();
+            restore_modified(&path, report.manifest.as_ref());
+            path
+        }
     };
+    info!("decode complete! output: {}", report.output_path.display());
+    Ok(report)
+}
+
+/// Decode the video stored in `backend` under `id`.
+///
+/// Backends that keep videos as local files are read in place; otherwise the
+/// video is copied to a temporary file first, since ffmpeg needs to seek in it.
+pub fn decode_from_backend(
+    backend: &dyn StorageBackend,
+    id: &str,
+    output_path: &Path,
+    credentials: &Credentials,
+    cfg: &Yts3Config,
+) -> Result<DecodeReport> {
+    if let Some(path) = backend.local_path(id) {
+        let path = path
+            .to_str()
+            .with_context(|| format!("video path is not valid UTF-8: {}", path.display()))?;
+        return decode_file(path, output_path, credentials, cfg);
+    }
+
+    let video = TempPath::new("mkv");
+    let mut reader = backend
+        .get(id)
+        .with_context(|| format!("failed to fetch video {id}"))?;
+    std::io::copy(&mut reader, &mut File::create(&video.path)?)?;
+    decode_file(video.to_str()?, output_path, credentials, cfg)
+}
+
+/// Set a decoded file's modification time to the one the manifest records, if any.
+fn restore_modified(path: &Path, manifest: Option<&Manifest>) {
+    let Some(secs) = manifest.and_then(|manifest| manifest.modified) else {
+        return;
+    };
+    let restored = File::options()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_modified(UNIX_EPOCH + Duration::from_secs(secs)));
+    if let Err(err) = restored {
+        warn!("could not restore the modification time of {}: {err}", path.display());
+    }
+}
+
+/// Check a decoded file's size and SHA-256 against the manifest.
+fn verify_output(path: &Path, manifest: &Manifest) -> Result<()> {
+    let size = std::fs::metadata(path)?.len();
+    if size != manifest.file_size {
+        anyhow::bail!(
+            "decoded file is {size} bytes but the manifest records {}",
+            manifest.file_size
+        );
+    }
+    let hash = super::sha256_file(path)?;
+    if hash != manifest.sha256_hex() {
+        anyhow::bail!(
+            "SHA-256 mismatch: decoded {hash}, manifest {}",
+            manifest.sha256_hex()
+        );
+    }
+    Ok(())
+}
+
+/// Per-chunk outcome of a decode.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DecodeReport {
+    /// Number of chunks in the file. Only unknown for V2 videos whose last chunk
+    /// was lost entirely.
+    pub total_chunks: Option<u32>,
+    /// Plaintext length of the file, as recorded in V3 packets.
+    pub file_size: Option<u64>,
+    /// Chunks recovered and written to the output.
+    pub recovered: Vec<u32>,
+    /// Chunks with too few (or no) symbols to recover, or whose place in the
+    /// file could not be determined.
+    pub missing: Vec<u32>,
+    /// Chunks that were recovered but failed decryption/authentication or decompression.
+    pub auth_failed: Vec<u32>,
+    /// Output byte ranges without recovered data, sorted and merged. Ranges whose
+    /// position or length could not be determined are omitted.
+    pub damaged_ranges: Vec<Range<u64>>,
+    /// The manifest record describing the original file, if it was recovered.
+    pub manifest: Option<Manifest>,
+    /// The files of a multi-file video, if its archive index was recovered.
+    pub archive: Option<ArchiveIndex>,
+    /// Who signed the video, if it carries a valid signature. Whether the
+    /// signer is trusted is checked against [`Credentials::trusted_signers`].
+    pub signer: Option<Signer>,
+    /// What was read of the video's frames; `None` for videos without frame
+    /// headers.
+    pub frames: Option<FrameStats>,
+    /// Where the output was written.
+    pub output_path: PathBuf,
+}
+
+impl DecodeReport {
+    /// `true` if every chunk of the file was recovered.
+    pub fn is_complete(&self) -> bool {
+        self.total_chunks.is_some() && self.missing.is_empty() && self.auth_failed.is_empty()
+    }
+}
+
+impl fmt::Display for DecodeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = match self.total_chunks {
+            Some(n) => n.to_string(),
+            None => "?".to_string(),
+        };
+        write!(f, "recovered {} of {} chunks", self.recovered.len(), total)?;
+        if !self.missing.is_empty() {
+            write!(f, "; missing chunks: {}", join(&self.missing))?;
+        }
+        if !self.auth_failed.is_empty() {
+            write!(f, "; failed authentication: {}", join(&self.auth_failed))?;
+        }
+        if !self.damaged_ranges.is_empty() {
+            let ranges: Vec<String> = self
+                .damaged_ranges
+                .iter()
+                .map(|r| format!("{}..{}", r.start, r.end))
+                .collect();
+            write!(f, "; damaged bytes: {}", ranges.join(", "))?;
+        }
+        if self.total_chunks.is_none() {
+            write!(f, "; chunk count unknown, output may be truncated")?;
+        }
+        Ok(())
+    }
+}
+
+fn join(indices: &[u32]) -> String {
+    indices
+        .iter()
+        .map(u32::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// A chunk whose symbols are still being collected.
+struct PendingChunk {
+    decoder: ChunkDecoder,
+    k: usize,
+    chunk_size: usize,
+    original_size: usize,
+    is_last: bool,
+    compressed: bool,
+    encrypted: bool,
+    /// Associated data to authenticate when decrypting; `None` before V4.
+    associated_data: Option<Vec<u8>>,
+    /// Chunk count and file size in the chunk's headers, authenticated with it in V4.
+    layout: (Option<u32>, Option<u64>),
+    received: usize,
+    /// Received-symbol count at which to next attempt recovery.
+    next_attempt: usize,
+}
+
+/// Routes packets to per-chunk decoders and writes recovered chunks to `output`.
+struct ChunkAssembler<W: Write + Seek> {
+    output: W,
+    credentials: Credentials,
+    file_id: Option<[u8; config::FILE_ID_SIZE]>,
+    key: Option<[u8; config::ARGON2_OUTPUT_LEN]>,
+    /// Set once a chunk says its key is described by the key record, until
+    /// that record arrives; chunks stay pending meanwhile.
+    awaiting_key_record: bool,
+    policy: DamagePolicy,
+    records: RecordAssembler,
+    pending: HashMap<u32, PendingChunk>,
+    ready: HashSet<u32>,
+    written: HashSet<u32>,
+    auth_failed: HashSet<u32>,
+    /// Chunk counts and file sizes that passed authentication along with a
+    /// chunk or record; anything but the one the decoder uses means tampering.
+    authenticated_layouts: HashSet<(Option<u32>, Option<u64>)>,
+    /// Chunk count and plaintext length recorded in the packets (V3 and later).
+    total_chunks: Option<u32>,
+    file_size: Option<u64>,
+    /// Highest chunk index seen in any packet.
+    max_index: Option<u32>,
+    /// Index and plaintext size of the last chunk, once any of its packets is seen.
+    last_chunk: Option<(u32, u64)>,
+    /// Plaintext size of every non-last chunk, learned from the first one seen.
+    nominal_chunk_size: Option<u64>,
+    /// A recovered last chunk waiting for `nominal_chunk_size` to place it.
+    deferred_last: Option<(u32, Vec<u8>)>,
+    /// End of the furthest byte written so far.
+    output_len: u64,
+    foreign_packets: u64,
+    out_of_range_packets: u64,
+    progress: ProgressBar,
+}
+
+impl<W: Write + Seek> ChunkAssembler<W> {
+    fn new(output: W, credentials: &Credentials, policy: DamagePolicy) -> Self {
+        let progress = ProgressBar::new_spinner();
+        progress.set_style(
+            ProgressStyle::default_spinner()
+                .template("[{elapsed_precise}] {spinner:.green} {pos} chunks decoded")
+                .unwrap(),
+        );
+
+        Self {
+            output,
+            credentials: credentials.clone(),
+            file_id: None,
+            key: None,
+            awaiting_key_record: false,
+            policy,
+            records: RecordAssembler::new(),
+            pending: HashMap::new(),
+            ready: HashSet::new(),
+            written: HashSet::new(),
+            auth_failed: HashSet::new(),
+            authenticated_layouts: HashSet::new(),
+            total_chunks: None,
+            file_size: None,
+            max_index: None,
+            last_chunk: None,
+            nominal_chunk_size: None,
+            deferred_last: None,
+            output_len: 0,
+            foreign_packets: 0,
+            out_of_range_packets: 0,
+            progress,
+        }
+    }
+
+    /// Feed one packet. The first packet seen determines the file being decoded.
+    fn add_packet(&mut self, pkt: Packet) -> Result<()> {
+        let header = &pkt.header;
+
+        let file_id = *self.file_id.get_or_insert(header.file_id);
+        if header.file_id != file_id {
+            self.foreign_packets += 1;
+            return Ok(());
+        }
+
+        if header.is_record() {
+            self.records.add(pkt);
+            return self.read_key_record();
+        }
+        if header.is_encrypted() && self.key.is_none() && !self.awaiting_key_record {
+            self.start_decryption(header.has_key_record())?;
+        }
+        if !header.is_encrypted()
+            && self.key.is_none()
+            && self.credentials.decrypts()
+            && !self.credentials.allow_unencrypted
+        {
+            anyhow::bail!(
+                "the video is not encrypted, but a decryption secret was given; its packet headers may have been altered to strip the encryption"
+            );
+        }
+        if self.total_chunks.is_none() {
+            self.total_chunks = header.total_chunks;
+            self.file_size = header.file_size;
+        }
+        let ci = header.chunk_index;
+        if self.total_chunks.is_some_and(|total| ci >= total) {
+            self.out_of_range_packets += 1;
+            return Ok(());
+        }
+        self.max_index = self.max_index.max(Some(ci));
+        if header.is_last_chunk() && self.last_chunk.is_none() {
+            self.last_chunk = Some((ci, header.original_size as u64));
+        }
+        if self.written.contains(&ci) || self.auth_failed.contains(&ci) {
+            return Ok(());
+        }
 
-    // Step 3: Group packets by chunk index
-    let mut chunk_packets: HashMap<u32, Vec<&packet::Packet>> = HashMap::new();
-    let mut chunk_metadata: HashMap<u32, (u32, u32, u32, bool)> = HashMap::new(); // (k, chunk_size, original_size, is_last)
+        let chunk = self.pending.entry(ci).or_insert_with(|| PendingChunk {
+            decoder: ChunkDecoder::new(
+                header.fountain_scheme(),
+                header.k as usize,
+                header.symbol_size as usize,
+            ),
+            k: header.k as usize,
+            chunk_size: header.chunk_size as usize,
+            original_size: header.original_size as usize,
+            is_last: header.is_last_chunk(),
+            compressed: header.is_compressed(),
+            encrypted: header.is_encrypted(),
+            associated_data: header.authenticates_header().then(|| header.associated_data()),
+            layout: (header.total_chunks, header.file_size),
+            received: 0,
+            next_attempt: header.k as usize,
+        });
+        if !chunk.is_last && self.nominal_chunk_size.is_none() {
+            self.nominal_chunk_size = Some(chunk.original_size as u64);
+        }
 
-    for pkt in &packets {
-        let ci = pkt.header.chunk_index;
-        chunk_packets.entry(ci).or_default().push(pkt);
-        chunk_metadata.entry(ci).or_insert((
-            pkt.header.k,
-            pkt.header.chunk_size,
-            pkt.header.original_size,
-            pkt.header.is_last_chunk(),
-        ));
+        let is_repair = header.is_repair();
+        chunk.decoder.add_symbol(header.esi, pkt.payload, is_repair);
+        chunk.received += 1;
+        if chunk.received >= chunk.next_attempt {
+            self.ready.insert(ci);
+        }
+        Ok(())
     }
 
-    let num_chunks = chunk_packets.len();
-    info!("found {} chunks to decode", num_chunks);
+    /// Try to recover every chunk that has received enough symbols, in parallel,
+    /// and write the ones that succeed.
+    fn recover_ready(&mut self) -> Result<()> {
+        if self.ready.is_empty() || self.awaiting_key_record {
+            return Ok(());
+        }
 
-    let progress = ProgressBar::new(num_chunks as u64);
-    progress.set_style(
-        ProgressStyle::default_bar()
-            .template("[{elapsed_precise}] {bar:40.green/black} {pos}/{len} chunks ({eta})")
-            .unwrap()
-            .progress_chars("##-"),
-    );
+        let mut attempts: Vec<(u32, PendingChunk)> = self
+            .ready
+            .drain()
+            .filter_map(|ci| self.pending.remove(&ci).map(|chunk| (ci, chunk)))
+            .collect();
 
-    // Step 4: Fountain-decode each chunk
-    let mut chunk_indices: Vec<u32> = chunk_packets.keys().copied().collect();
-    chunk_indices.sort();
+        let key = self.key;
+        let file_id = self.file_id.unwrap_or_default();
+        let results: Vec<Option<Result<Vec<u8>>>> = attempts
+            .par_iter_mut()
+            .map(|(ci, chunk)| {
+                let recovered = chunk.decoder.recover(chunk.chunk_size).ok()?;
+                // Decrypt and decompress if needed
+                let plaintext = match (key, &chunk.associated_data) {
+                    // Only a flipped flag makes a V4 chunk plaintext in an encrypted video
+                    (Some(_), Some(_)) if !chunk.encrypted => Err(anyhow::anyhow!(
+                        "chunk {ci} is marked unencrypted in an encrypted video; its headers were altered"
+                    )),
+                    (Some(ref k), aad) => {
+                        let aad = aad.as_deref().unwrap_or_default();
+                        crypto::decrypt_chunk(k, &file_id, *ci, aad, &recovered).with_context(|| {
+                            format!("chunk {ci} failed authentication: its data or packet headers were altered")
+                        })
+                    }
+                    (None, _) => Ok(recovered),
+                };
+                Some(plaintext.and_then(|data| {
+                    if !chunk.compressed {
+                        return Ok(data);
+                    }
+                    compress::decompress_chunk(&data, chunk.original_size)
+                        .with_context(|| format!("decompression failed for chunk {ci}"))
+                }))
+            })
+            .collect();
 
-    let decoded_chunks: Vec<(u32, Vec<u8>)> = chunk_indices
-        .par_iter()
-        .map(|&ci| {
-            let pkts = &chunk_packets[&ci];
-            let (k, chunk_size, _original_size, _is_last) = chunk_metadata[&ci];
+        for ((ci, mut chunk), result) in attempts.into_iter().zip(results) {
+            match result {
+                Some(Ok(data)) => {
+                    if key.is_some() && chunk.associated_data.is_some() {
+                        self.authenticated_layouts.insert(chunk.layout);
+                    }
+                    self.write_chunk(ci, data)?
+                }
+                Some(Err(err)) => {
+                    warn!("{err:#}");
+                    self.auth_failed.insert(ci);
+                }
+                None => {
+                    // Not enough independent symbols yet; retry after more arrive
+                    chunk.next_attempt = chunk.received + (chunk.k / 32).max(1);
+                    self.pending.insert(ci, chunk);
+                }
+            }
+        }
+        Ok(())
+    }
 
-            let mut fdecoder = fountain::ChunkDecoder::new(k as usize, pkts[0].header.symbol_size as usize);
+    /// Make a final recovery attempt on every remaining chunk, apply the damage
+    /// policy to whatever is still missing, flush the output and return it along
+    /// with the per-chunk report.
+    fn finish(mut self) -> Result<(W, DecodeReport)> {
+        if self.file_id.is_none() {
+            anyhow::bail!("no valid packets found in video");
+        }
+        if self.foreign_packets > 0 {
+            warn!("ignored {} packets from other files", self.foreign_packets);
+        }
+        if self.out_of_range_packets > 0 {
+            warn!(
+                "ignored {} packets with a chunk index beyond the recorded chunk count",
+                self.out_of_range_packets
+            );
+        }
 
-            for pkt in pkts {
-                fdecoder.add_symbol(pkt.header.esi, pkt.payload.clone(), pkt.header.is_repair());
+        if self.awaiting_key_record {
+            // Password videos are most likely made with the default parameters;
+            // if not, their chunks fail authentication and are reported
+            if self.credentials.password.is_none() && !self.credentials.prompt_password {
+                anyhow::bail!("the key record describing the file's key was not recovered");
             }
+            warn!("the key record was not recovered; trying the default Argon2id parameters");
+            self.derive_password_key(&KdfParams::default())?;
+            self.awaiting_key_record = false;
+        }
+        if self.key.is_none() && self.credentials.decrypts() {
+            warn!("the video is not encrypted, so its contents are not authenticated");
+        }
+        self.ready.extend(self.pending.keys().copied());
+        self.recover_ready()?;
+        // Whatever is still pending never gathered enough symbols
+        self.pending.clear();
 
-            let recovered = fdecoder
-                .recover(chunk_size as usize)
-                .expect("fountain decoding failed for chunk");
+        self.place_deferred_at_end()?;
 
-            // Decrypt if needed
-            let chunk_data = if let Some(ref k) = key {
-                crypto::decrypt_chunk(k, &file_id, ci, &recovered)
-                    .expect("decryption failed for chunk")
+        let mut report = self.report();
+        report.manifest = self
+            .record(config::RECORD_KIND_MANIFEST)?
+            .and_then(|data| {
+                Manifest::from_bytes(&data)
+                    .map_err(|err| warn!("ignoring invalid manifest record: {err}"))
+                    .ok()
+            });
+        report.archive = self
+            .record(config::RECORD_KIND_ARCHIVE)?
+            .and_then(|data| {
+                ArchiveIndex::from_bytes(&data)
+                    .map_err(|err| warn!("ignoring invalid archive index: {err}"))
+                    .ok()
+            });
+        report.signer = self.signer();
+        // Authenticated chunks and records vouch for the real chunk count and
+        // size; headers saying otherwise were edited or spliced in
+        let layout = (self.total_chunks, self.file_size);
+        if let Some((total, size)) = self.authenticated_layouts.iter().find(|l| **l != layout) {
+            anyhow::bail!(
+                "packet headers were altered: authenticated data says {} chunks and {} bytes, headers say {} chunks and {} bytes",
+                total.unwrap_or(0),
+                size.unwrap_or(0),
+                layout.0.unwrap_or(0),
+                layout.1.unwrap_or(0)
+            );
+        }
+        if report.is_complete() {
+            if let Some(size) = self.file_size {
+                if self.output_len != size {
+                    anyhow::bail!(
+                        "decoded {} bytes but the video records a file size of {size}",
+                        self.output_len
+                    );
+                }
+            }
+        }
+        match self.policy {
+            DamagePolicy::Fail => {}
+            DamagePolicy::ZeroFill => {
+                for range in &report.damaged_ranges {
+                    self.zero_fill(range.clone())?;
+                }
+            }
+            DamagePolicy::Sparse => {
+                // Holes between written chunks are left unallocated by seeking past
+                // them; only a trailing hole needs the file extended.
+                if let Some(end) = report.damaged_ranges.last().map(|r| r.end) {
+                    if end > self.output_len {
+                        self.write_at(end - 1, &[0])?;
+                    }
+                }
+            }
+        }
+
+        self.output.flush()?;
+        self.progress.finish_with_message("decoding complete");
+
+        // Securely zero the key
+        if let Some(mut k) = self.key {
+            crypto::secure_zero(&mut k);
+        }
+        Ok((self.output, report))
+    }
+
+    /// Classify every chunk in `0..total_chunks`. Without a recorded chunk count
+    /// (V2 packets) the count comes from the last chunk's flag, and if that was lost
+    /// too only chunks up to the highest index seen can be checked.
+    fn report(&self) -> DecodeReport {
+        let total_chunks = self
+            .total_chunks
+            .or(self.last_chunk.map(|(ci, _)| ci + 1));
+        let end = total_chunks.or(self.max_index.map(|ci| ci + 1)).unwrap_or(0);
+
+        let mut report = DecodeReport {
+            total_chunks,
+            file_size: self.file_size,
+            ..Default::default()
+        };
+        for ci in 0..end {
+            if self.written.contains(&ci) {
+                report.recovered.push(ci);
+                continue;
+            }
+            if self.auth_failed.contains(&ci) {
+                report.auth_failed.push(ci);
             } else {
-                recovered
-            };
+                report.missing.push(ci);
+            }
+            if let Some(range) = self.chunk_range(ci) {
+                match report.damaged_ranges.last_mut() {
+                    Some(prev) if prev.end == range.start => prev.end = range.end,
+                    _ => report.damaged_ranges.push(range),
+                }
+            }
+        }
+        report
+    }
 
-            progress.inc(1);
-            (ci, chunk_data)
+    /// Decrypt a metadata record of the given kind, if it was recovered. Once a
+    /// key is in use every record but the key record and signature must be
+    /// encrypted, since a plaintext one could not be authenticated.
+    fn record(&mut self, kind: u32) -> Result<Option<Vec<u8>>> {
+        let Some(record) = self.records.get(kind) else {
+            return Ok(None);
+        };
+        Ok(match (record.encrypted, &self.key) {
+            (false, Some(_))
+                if kind != config::RECORD_KIND_KEY && kind != config::RECORD_KIND_SIGNATURE =>
+            {
+                anyhow::bail!(
+                    "record {kind} of an encrypted video is not encrypted, so it cannot be authenticated; it was likely spliced in"
+                );
+            }
+            (false, _) => Some(record.data),
+            (true, Some(k)) => {
+                let file_id = self.file_id.unwrap_or_default();
+                let aad = record.associated_data.as_deref().unwrap_or_default();
+                match crypto::decrypt_record(k, &file_id, kind, aad, &record.data) {
+                    Ok(data) => {
+                        if record.associated_data.is_some() {
+                            self.authenticated_layouts.insert((record.total_chunks, record.file_size));
+                        }
+                        Some(data)
+                    }
+                    Err(err) => {
+                        warn!("record {kind} failed authentication; it or its packet headers were altered: {err}");
+                        None
+                    }
+                }
+            }
+            (true, None) => None,
         })
+    }
+
+    /// Check the signature record, if any, against the file id, chunk count,
+    /// size and records as stored in the video.
+    fn signer(&self) -> Option<Signer> {
+        let signature = self.records.get(config::RECORD_KIND_SIGNATURE)?;
+        let records: Vec<(u32, Vec<u8>)> = [
+            config::RECORD_KIND_KEY,
+            config::RECORD_KIND_MANIFEST,
+            config::RECORD_KIND_ARCHIVE,
+        ]
+        .into_iter()
+        .filter_map(|kind| Some((kind, self.records.get(kind)?.data)))
         .collect();
+        let records: Vec<(u32, &[u8])> = records.iter().map(|(kind, data)| (*kind, data.as_slice())).collect();
+        let message = crypto::signed_message(
+            &self.file_id.unwrap_or_default(),
+            self.total_chunks.unwrap_or_default(),
+            self.file_size.unwrap_or_default(),
+            &records,
+        );
+        crypto::verify_signature(&signature.data, &message)
+            .map_err(|err| warn!("ignoring signature: {err}"))
+            .ok()
+    }
 
-    progress.finish_with_message("decoding complete");
+    /// Byte range a chunk occupies in the output, if it can be determined.
+    fn chunk_range(&self, ci: u32) -> Option<Range<u64>> {
+        let start = match (ci, self.nominal_chunk_size) {
+            (0, _) => 0,
+            (_, Some(nominal)) => ci as u64 * nominal,
+            (_, None) => return None,
+        };
+        let len = match self.last_chunk {
+            Some((last, size)) if last == ci => size,
+            _ if self.total_chunks == Some(ci + 1) => self.file_size?.checked_sub(start)?,
+            _ => self.nominal_chunk_size?,
+        };
+        Some(start..start + len)
+    }
 
-    // Step 5: Reassemble file in chunk order
-    info!("reassembling file: {}", output_path.display());
-    let mut sorted_chunks = decoded_chunks;
-    sorted_chunks.sort_by_key(|(idx, _)| *idx);
+    /// Get the key for an encrypted file: wait for the key record to say how,
+    /// or, for videos without one, take the raw key or derive it from the
+    /// password with the default Argon2id parameters.
+    fn start_decryption(&mut self, has_key_record: bool) -> Result<()> {
+        if has_key_record {
+            self.awaiting_key_record = true;
+            return self.read_key_record();
+        }
+        if let Some(key) = self.credentials.key {
+            self.key = Some(key);
+            return Ok(());
+        }
+        self.derive_password_key(&KdfParams::default())
+    }
 
-    let mut outfile =
-        File::create(output_path).context("failed to create output file")?;
-    for (_, data) in &sorted_chunks {
-        outfile
+    fn derive_password_key(&mut self, params: &KdfParams) -> Result<()> {
+        let file_id = self.file_id.unwrap_or_default();
+        let key = crypto::derive_key(self.password()?.as_bytes(), &file_id, params)?;
+        self.key = Some(key);
+        Ok(())
+    }
+
+    /// Get the key once the key record is complete: derive it from the password
+    /// with the recorded parameters, or unwrap it with an identity.
+    fn read_key_record(&mut self) -> Result<()> {
+        if !self.awaiting_key_record {
+            return Ok(());
+        }
+        let Some(record) = self.records.get(config::RECORD_KIND_KEY) else {
+            return Ok(());
+        };
+        let file_id = self.file_id.unwrap_or_default();
+        let key = match KeyRecord::from_bytes(&record.data)? {
+            KeyRecord::Password(params) => {
+                info!("deriving the file key with Argon2id ({params})");
+                crypto::derive_key(self.password()?.as_bytes(), &file_id, &params)?
+            }
+            KeyRecord::Recipients(stanzas) => {
+                if self.credentials.identities.is_empty() {
+                    anyhow::bail!("file is encrypted for recipients but no identity provided");
+                }
+                let key = crypto::unwrap_key(&stanzas, &file_id, &self.credentials.identities)?;
+                info!("unwrapped the file key with an identity");
+                key
+            }
+        };
+        self.key = Some(key);
+        self.awaiting_key_record = false;
+        Ok(())
+    }
+
+    /// The password, asked for on the terminal the first time it is needed if
+    /// none was given and the credentials allow prompting.
+    fn password(&mut self) -> Result<&str> {
+        if self.credentials.password.is_none() && self.credentials.prompt_password {
+            let password = self.progress.suspend(|| rpassword::prompt_password("Password: "))?;
+            self.credentials.password = Some(password);
+        }
+        self.credentials
+            .password
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("file is encrypted but no password or key provided"))
+    }
+
+    /// Write a recovered chunk at its offset, deferring a last chunk whose offset
+    /// is not yet known.
+    fn write_chunk(&mut self, ci: u32, data: Vec<u8>) -> Result<()> {
+        self.written.insert(ci);
+        self.progress.inc(1);
+
+        let offset = match (ci, self.nominal_chunk_size) {
+            (0, _) => 0,
+            (_, Some(nominal)) => ci as u64 * nominal,
+            (_, None) => {
+                self.deferred_last = Some((ci, data));
+                return Ok(());
+            }
+        };
+        self.write_at(offset, &data)?;
+        self.place_deferred()
+    }
+
+    fn place_deferred(&mut self) -> Result<()> {
+        let Some(nominal) = self.nominal_chunk_size else {
+            return Ok(());
+        };
+        if let Some((ci, data)) = self.deferred_last.take() {
+            self.write_at(ci as u64 * nominal, &data)?;
+        }
+        Ok(())
+    }
+
+    /// Place a last chunk no other chunk revealed the offset of: it ends the
+    /// file, so with a recorded file size it starts `len` bytes before the end,
+    /// which also gives the nominal chunk size. Otherwise it is reported missing.
+    fn place_deferred_at_end(&mut self) -> Result<()> {
+        if let (Some((ci, data)), Some(size)) = (&self.deferred_last, self.file_size) {
+            let ci = *ci as u64;
+            match size.checked_sub(data.len() as u64) {
+                Some(start) if self.nominal_chunk_size.is_none() && start % ci == 0 => {
+                    self.nominal_chunk_size = Some(start / ci);
+                }
+                _ => {}
+            }
+        }
+        self.place_deferred()?;
+        if let Some((ci, _)) = self.deferred_last.take() {
+            warn!("cannot place last chunk {ci}: its offset in the file is unknown");
+            self.written.remove(&ci);
+        }
+        Ok(())
+    }
+
+    fn zero_fill(&mut self, range: Range<u64>) -> Result<()> {
+        let zeros = vec![0u8; (range.end - range.start).min(1 << 16) as usize];
+        let mut offset = range.start;
+        while offset < range.end {
+            let len = (range.end - offset).min(zeros.len() as u64);
+            self.write_at(offset, &zeros[..len as usize])?;
+            offset += len;
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        self.output.seek(SeekFrom::Start(offset))?;
+        self.output
             .write_all(data)
             .context("failed to write output data")?;
+        self.output_len = self.output_len.max(offset + data.len() as u64);
+        Ok(())
     }
-    outfile.flush()?;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
 
-    // Securely zero the key
-    if let Some(mut k) = key {
-        crypto::secure_zero(&mut k);
+    use super::*;
+    use crate::chunker::{chunk_count, effective_chunk_size, ChunkReader};
+    use crate::pipeline::encode::{encode_chunk_packets, encode_record_packets, write_stream};
+    use crate::video::encoder::{FrameWriter, VideoEncoder};
+    use crate::video::header::CalibrationHeader;
+
+    const FILE_ID: [u8; config::FILE_ID_SIZE] = [7; config::FILE_ID_SIZE];
+
+    fn test_config() -> Yts3Config {
+        Yts3Config {
+            frame_width: 512,
+            frame_height: 128,
+            bits_per_block: 2,
+            chunk_size: 1000,
+            symbol_size: 64,
+            ..Default::default()
+        }
     }
 
-    info!("decode complete! output: {}", output_path.display());
-    Ok(())
+    fn test_data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 131 % 251) as u8).collect()
+    }
+
+    /// Serialized packets of every chunk of `data`, in chunk order.
+    fn chunk_packets(data: &[u8], cfg: &Yts3Config) -> Vec<Vec<u8>> {
+        let total = chunk_count(data.len() as u64, cfg.chunk_size) as u32;
+        ChunkReader::new(data, cfg.chunk_size)
+            .map(|chunk| {
+                let chunk = chunk.unwrap();
+                encode_chunk_packets(&chunk, &FILE_ID, total, data.len() as u64, None, false, cfg)
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    /// Raw frames of a video of `data` laid out as `encode_file` lays it out.
+    fn render_video(data: &[u8], credentials: &Credentials, cfg: &Yts3Config) -> Vec<u8> {
+        let encoder = VideoEncoder::new(cfg).unwrap();
+        let mut writer = FrameWriter::new(&encoder, Vec::new(), 1).unwrap();
+        let chunk_size = effective_chunk_size(cfg.chunk_size, credentials.encrypts());
+        let file_size = data.len() as u64;
+        let reader = ChunkReader::new(data, chunk_size);
+        write_stream(reader, FILE_ID, file_size, &mut writer, credentials, cfg, |_, sha256| {
+            let manifest = Manifest {
+                sha256,
+                file_size,
+                file_name: "data.bin".to_string(),
+                modified: None,
+            };
+            Ok((manifest, Vec::new()))
+        })
+        .unwrap();
+        writer.finish().unwrap()
+    }
+
+    /// Decode raw frames without the data frames `lost` picks, by index.
+    fn decode_frames_without(
+        video: &[u8],
+        credentials: &Credentials,
+        cfg: &Yts3Config,
+        lost: impl Fn(usize) -> bool,
+    ) -> Result<(Vec<u8>, DecodeReport)> {
+        let frame_size = (cfg.frame_width * cfg.frame_height) as usize;
+        let kept: Vec<u8> = video
+            .chunks(frame_size)
+            .enumerate()
+            .filter(|(i, _)| *i == 0 || !lost(i - 1))
+            .flat_map(|(_, frame)| frame.to_vec())
+            .collect();
+
+        let decoder = VideoDecoder::with_header(CalibrationHeader::from_config(cfg)?, cfg)?;
+        let mut assembler = ChunkAssembler::new(Cursor::new(Vec::new()), credentials, DamagePolicy::Fail);
+        let mut reader = decoder.packet_reader();
+        decoder.decode_frames(&mut kept.as_slice(), |frames| {
+            for frame in &frames {
+                for pkt in reader.push(&frame.data, &frame.confidence) {
+                    assembler.add_packet(pkt)?;
+                }
+            }
+            assembler.recover_ready()
+        })?;
+        let (output, report) = assembler.finish()?;
+        Ok((output.into_inner(), report))
+    }
+
+    /// Indices of the data frames holding packets of the given record kind.
+    fn record_frames(video: &[u8], cfg: &Yts3Config, kind: u32) -> Vec<usize> {
+        let frame_size = (cfg.frame_width * cfg.frame_height) as usize;
+        let decoder = VideoDecoder::with_header(CalibrationHeader::from_config(cfg).unwrap(), cfg).unwrap();
+        let mut reader = decoder.packet_reader();
+        (0..video.len() / frame_size - 1)
+            .filter(|i| {
+                let frame = decoder.extract_frame(&video[(i + 1) * frame_size..(i + 2) * frame_size]);
+                reader
+                    .push(&frame, &[])
+                    .iter()
+                    .any(|pkt| pkt.header.is_record() && pkt.header.chunk_index == kind)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_stream_decode_survives_dropped_frames() {
+        let cfg = test_config();
+        let data = test_data(4321);
+
+        let encoder = VideoEncoder::new(&cfg).unwrap();
+        let mut writer = FrameWriter::new(&encoder, Vec::new(), 1).unwrap();
+        for packets in chunk_packets(&data, &cfg) {
+            writer.write(&packets).unwrap();
+        }
+        let video = writer.finish().unwrap();
+
+        // Drop every 9th data frame and repeat every 7th (the calibration frame is frame 0)
+        let frame_size = 512 * 128;
+        let damaged: Vec<u8> = video
+            .chunks(frame_size)
+            .enumerate()
+            .filter(|(i, _)| *i == 0 || i % 9 != 0)
+            .flat_map(|(i, frame)| frame.repeat(if i > 0 && i % 7 == 0 { 2 } else { 1 }))
+            .collect();
+
+        let decoder =
+            VideoDecoder::with_header(CalibrationHeader::from_config(&cfg).unwrap(), &cfg).unwrap();
+        let mut assembler = ChunkAssembler::new(
+            Cursor::new(Vec::new()),
+            &Credentials::default(),
+            DamagePolicy::Fail,
+        );
+        let mut reader = decoder.packet_reader();
+        decoder
+            .decode_frames(&mut damaged.as_slice(), |frames| {
+                for frame in &frames {
+                    for pkt in reader.push(&frame.data, &frame.confidence) {
+                        assembler.add_packet(pkt)?;
+                    }
+                }
+                assembler.recover_ready()
+            })
+            .unwrap();
+
+        // Every chunk is written before the stream ends
+        assert!(assembler.pending.is_empty());
+        let (output, report) = assembler.finish().unwrap();
+        assert!(report.is_complete());
+        assert_eq!(output.into_inner(), data);
+
+        // Data frame `i` is video frame `i + 1`
+        let frames = reader.frame_stats().unwrap();
+        assert!(frames.duplicates > 0);
+        assert!(!frames.missing.is_empty());
+        assert!(frames.missing.iter().all(|r| r.len() == 1 && (r.start + 1) % 9 == 0));
+    }
+
+    #[test]
+    fn test_interleaving_survives_burst_of_lost_frames() {
+        let cfg = test_config();
+        let data = test_data(8000);
+        let chunks = chunk_packets(&data, &cfg);
+        let packet_size = config::PACKET_HEADER_SIZE + cfg.symbol_size;
+
+        let decode_with_burst = |stream: &[u8]| {
+            let encoder = VideoEncoder::new(&cfg).unwrap();
+            let mut writer = FrameWriter::new(&encoder, Vec::new(), 1).unwrap();
+            writer.write(stream).unwrap();
+            let video = writer.finish().unwrap();
+
+            // Lose 24 consecutive frames of one packet each
+            let frame_size = 512 * 128;
+            let mut damaged = video[..frame_size * 40].to_vec();
+            damaged.extend_from_slice(&video[frame_size * 64..]);
+
+            let decoder =
+                VideoDecoder::with_header(CalibrationHeader::from_config(&cfg).unwrap(), &cfg).unwrap();
+            let mut assembler = ChunkAssembler::new(
+                Cursor::new(Vec::new()),
+                &Credentials::default(),
+                DamagePolicy::Fail,
+            );
+            let mut reader = decoder.packet_reader();
+            decoder
+                .decode_frames(&mut damaged.as_slice(), |frames| {
+                    for frame in &frames {
+                        for pkt in reader.push(&frame.data, &frame.confidence) {
+                            assembler.add_packet(pkt)?;
+                        }
+                    }
+                    assembler.recover_ready()
+                })
+                .unwrap();
+            assembler.finish().unwrap()
+        };
+
+        // In chunk order the burst takes most of one chunk's symbols
+        let (_, report) = decode_with_burst(&chunks.concat());
+        assert_eq!(report.missing, [1]);
+
+        // Spread over all eight chunks it takes three from each
+        let (output, report) = decode_with_burst(&crate::packet::interleave_packets(&chunks, packet_size));
+        assert!(report.is_complete(), "{report}");
+        assert_eq!(output.into_inner(), data);
+    }
+
+    #[test]
+    fn test_last_chunk_recovered_first_is_placed_later() {
+        let cfg = test_config();
+        let data = test_data(2500);
+
+        let mut assembler = ChunkAssembler::new(
+            Cursor::new(Vec::new()),
+            &Credentials::default(),
+            DamagePolicy::Fail,
+        );
+        for packets in chunk_packets(&data, &cfg).iter().rev() {
+            for pkt in crate::packet::scan_for_packets(packets) {
+                assembler.add_packet(pkt).unwrap();
+            }
+            assembler.recover_ready().unwrap();
+        }
+        assert_eq!(assembler.finish().unwrap().0.into_inner(), data);
+    }
+
+    #[test]
+    fn test_lone_last_chunk_is_placed_by_file_size() {
+        let cfg = test_config();
+        let data = test_data(4321);
+
+        for file_size in [Some(4321), None] {
+            let mut assembler = ChunkAssembler::new(
+                Cursor::new(Vec::new()),
+                &Credentials::default(),
+                DamagePolicy::ZeroFill,
+            );
+            for pkt in crate::packet::scan_for_packets(&chunk_packets(&data, &cfg)[4]) {
+                assembler.add_packet(pkt).unwrap();
+            }
+            assembler.recover_ready().unwrap();
+            // V2 packets record no file size
+            assembler.file_size = file_size;
+            let (output, report) = assembler.finish().unwrap();
+
+            assert_eq!(report.total_chunks, Some(5));
+            if file_size.is_some() {
+                assert_eq!(report.recovered, vec![4]);
+                assert_eq!(report.missing, vec![0, 1, 2, 3]);
+                assert_eq!(report.damaged_ranges, vec![0..4000]);
+                let output = output.into_inner();
+                assert_eq!(output[..4000], [0; 4000]);
+                assert_eq!(output[4000..], data[4000..]);
+            } else {
+                // The chunk cannot be placed, and neither can the damage
+                assert!(report.recovered.is_empty());
+                assert_eq!(report.missing, vec![0, 1, 2, 3, 4]);
+                assert!(report.damaged_ranges.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn test_missing_chunk_is_reported_and_zero_filled() {
+        let cfg = test_config();
+        let data = test_data(4321);
+
+        let mut assembler = ChunkAssembler::new(
+            Cursor::new(Vec::new()),
+            &Credentials::default(),
+            DamagePolicy::ZeroFill,
+        );
+        for (ci, packets) in chunk_packets(&data, &cfg).iter().enumerate() {
+            // Chunk 2 loses all but a few of its symbols
+            let keep = if ci == 2 { 3 } else { usize::MAX };
+            for pkt in crate::packet::scan_for_packets(packets).into_iter().take(keep) {
+                assembler.add_packet(pkt).unwrap();
+            }
+            assembler.recover_ready().unwrap();
+        }
+        let (output, report) = assembler.finish().unwrap();
+
+        assert!(!report.is_complete());
+        assert_eq!(report.total_chunks, Some(5));
+        assert_eq!(report.recovered, vec![0, 1, 3, 4]);
+        assert_eq!(report.missing, vec![2]);
+        assert_eq!(report.damaged_ranges, vec![2000..3000]);
+
+        let mut expected = data.clone();
+        expected[2000..3000].fill(0);
+        assert_eq!(output.into_inner(), expected);
+    }
+
+    #[test]
+    fn test_auth_failure_is_reported_not_fatal() {
+        let cfg = test_config();
+        let data = test_data(2500);
+        let key = [1u8; config::ARGON2_OUTPUT_LEN];
+
+        let mut assembler = ChunkAssembler::new(
+            Cursor::new(Vec::new()),
+            &Credentials::default(),
+            DamagePolicy::Sparse,
+        );
+        assembler.file_id = Some(FILE_ID);
+        assembler.key = Some([2u8; config::ARGON2_OUTPUT_LEN]);
+        let chunk_size = effective_chunk_size(cfg.chunk_size, true);
+        for chunk in ChunkReader::new(data.as_slice(), chunk_size) {
+            let packets =
+                encode_chunk_packets(&chunk.unwrap(), &FILE_ID, 3, 2500, Some(&key), false, &cfg)
+                    .unwrap();
+            for pkt in crate::packet::scan_for_packets(&packets) {
+                assembler.add_packet(pkt).unwrap();
+            }
+            assembler.recover_ready().unwrap();
+        }
+        let (output, report) = assembler.finish().unwrap();
+
+        assert!(report.recovered.is_empty());
+        assert_eq!(report.auth_failed, vec![0, 1, 2]);
+        assert_eq!(report.damaged_ranges, vec![0..2500]);
+        // The sparse output still spans the whole file
+        assert_eq!(output.into_inner().len(), 2500);
+    }
+
+    #[test]
+    fn test_lost_tail_is_detected() {
+        let cfg = test_config();
+        let data = test_data(4321);
+
+        // The video is cut after chunk 2: no packet flags the last chunk
+        let mut assembler = ChunkAssembler::new(
+            Cursor::new(Vec::new()),
+            &Credentials::default(),
+            DamagePolicy::ZeroFill,
+        );
+        for packets in &chunk_packets(&data, &cfg)[..3] {
+            for pkt in crate::packet::scan_for_packets(packets) {
+                assembler.add_packet(pkt).unwrap();
+            }
+            assembler.recover_ready().unwrap();
+        }
+        let (output, report) = assembler.finish().unwrap();
+
+        assert!(!report.is_complete());
+        assert_eq!(report.total_chunks, Some(5));
+        assert_eq!(report.file_size, Some(4321));
+        assert_eq!(report.missing, vec![3, 4]);
+        assert_eq!(report.damaged_ranges, vec![3000..4321]);
+        assert_eq!(output.into_inner().len(), 4321);
+    }
+
+    #[test]
+    fn test_encrypted_manifest_record_is_recovered() {
+        let cfg = test_config();
+        let data = test_data(1500);
+        let key = [5u8; config::ARGON2_OUTPUT_LEN];
+        let manifest = Manifest {
+            sha256: crate::integrity::sha256(&data),
+            file_size: data.len() as u64,
+            file_name: "data.bin".to_string(),
+            modified: Some(1_700_000_000),
+        };
+
+        let mut stream = Vec::new();
+        let chunk_size = effective_chunk_size(cfg.chunk_size, true);
+        for chunk in ChunkReader::new(data.as_slice(), chunk_size) {
+            let chunk = chunk.unwrap();
+            let packets = encode_chunk_packets(&chunk, &FILE_ID, 2, 1500, Some(&key), false, &cfg);
+            stream.extend(packets.unwrap());
+        }
+        let kind = config::RECORD_KIND_MANIFEST;
+        let record = manifest.to_bytes();
+        let packets = encode_record_packets(kind, &record, &FILE_ID, 2, 1500, Some(&key), &cfg);
+        stream.extend(packets.unwrap());
+
+        let mut assembler = ChunkAssembler::new(
+            Cursor::new(Vec::new()),
+            &Credentials::default(),
+            DamagePolicy::Fail,
+        );
+        assembler.file_id = Some(FILE_ID);
+        assembler.key = Some(key);
+        for pkt in crate::packet::scan_for_packets(&stream) {
+            assembler.add_packet(pkt).unwrap();
+        }
+        let (output, report) = assembler.finish().unwrap();
+
+        assert!(report.is_complete());
+        assert_eq!(report.manifest, Some(manifest));
+        assert_eq!(output.into_inner(), data);
+    }
+
+    #[test]
+    fn test_plaintext_manifest_in_encrypted_video_is_rejected() {
+        let cfg = test_config();
+        let data = test_data(1500);
+        let key = [5u8; config::ARGON2_OUTPUT_LEN];
+        let manifest = |file_name: &str| Manifest {
+            sha256: crate::integrity::sha256(&data),
+            file_size: data.len() as u64,
+            file_name: file_name.to_string(),
+            modified: None,
+        };
+
+        // A forged plaintext manifest is read ahead of the real, encrypted one
+        let kind = config::RECORD_KIND_MANIFEST;
+        let forged = manifest("evil.sh").to_bytes();
+        let mut stream = encode_record_packets(kind, &forged, &FILE_ID, 2, 1500, None, &cfg).unwrap();
+        let chunk_size = effective_chunk_size(cfg.chunk_size, true);
+        for chunk in ChunkReader::new(data.as_slice(), chunk_size) {
+            let chunk = chunk.unwrap();
+            let packets = encode_chunk_packets(&chunk, &FILE_ID, 2, 1500, Some(&key), false, &cfg);
+            stream.extend(packets.unwrap());
+        }
+
+        let decode = |stream: &[u8]| {
+            let mut assembler =
+                ChunkAssembler::new(Cursor::new(Vec::new()), &Credentials::default(), DamagePolicy::Fail);
+            assembler.file_id = Some(FILE_ID);
+            assembler.key = Some(key);
+            for pkt in crate::packet::scan_for_packets(stream) {
+                assembler.add_packet(pkt)?;
+            }
+            assembler.finish().map(|(_, report)| report)
+        };
+
+        let err = decode(&stream).unwrap_err();
+        assert!(err.to_string().contains("not encrypted"), "{err}");
+
+        // The real manifest takes its place once it is read
+        let real = manifest("data.bin");
+        let packets = encode_record_packets(kind, &real.to_bytes(), &FILE_ID, 2, 1500, Some(&key), &cfg);
+        stream.extend(packets.unwrap());
+        assert_eq!(decode(&stream).unwrap().manifest, Some(real));
+    }
+
+    #[test]
+    fn test_compressed_chunks_decode_transparently() {
+        let cfg = Yts3Config {
+            compression: config::Compression::Zstd,
+            ..test_config()
+        };
+        let key = [5u8; config::ARGON2_OUTPUT_LEN];
+        let chunk_size = effective_chunk_size(cfg.chunk_size, true);
+        // One chunk of text that compresses well, one of noise that does not
+        let mut data: Vec<u8> = b"GET /index.html 200\n".iter().copied().cycle().take(chunk_size).collect();
+        let mut state = 0x9E3779B97F4A7C15u64;
+        data.extend((0..700).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        }));
+        let total = chunk_count(data.len() as u64, chunk_size) as u32;
+
+        let mut stream = Vec::new();
+        let mut flags = Vec::new();
+        for chunk in ChunkReader::new(data.as_slice(), chunk_size) {
+            let chunk = chunk.unwrap();
+            let packets =
+                encode_chunk_packets(&chunk, &FILE_ID, total, data.len() as u64, Some(&key), false, &cfg)
+                    .unwrap();
+            let first = crate::packet::scan_for_packets(&packets).remove(0);
+            flags.push(first.header.is_compressed());
+            stream.extend(packets);
+        }
+        assert_eq!(flags, [true, false]);
+
+        let mut assembler = ChunkAssembler::new(
+            Cursor::new(Vec::new()),
+            &Credentials::default(),
+            DamagePolicy::Fail,
+        );
+        assembler.file_id = Some(FILE_ID);
+        assembler.key = Some(key);
+        for pkt in crate::packet::scan_for_packets(&stream) {
+            assembler.add_packet(pkt).unwrap();
+        }
+        let (output, report) = assembler.finish().unwrap();
+        assert!(report.is_complete());
+        assert_eq!(output.into_inner(), data);
+    }
+
+    #[test]
+    fn test_recipient_encrypted_chunks_wait_for_key_record() {
+        let cfg = test_config();
+        let data = test_data(2500);
+        let identity = crypto::Identity::generate();
+        let key = crypto::generate_data_key();
+        let stanzas = crypto::wrap_key(&key, &FILE_ID, &[identity.recipient()]).unwrap();
+        let record = KeyRecord::Recipients(stanzas).to_bytes();
+
+        let chunk_size = effective_chunk_size(cfg.chunk_size, true);
+        let total = chunk_count(data.len() as u64, chunk_size) as u32;
+        let mut chunks = Vec::new();
+        for chunk in ChunkReader::new(data.as_slice(), chunk_size) {
+            let chunk = chunk.unwrap();
+            let size = data.len() as u64;
+            let packets = encode_chunk_packets(&chunk, &FILE_ID, total, size, Some(&key), true, &cfg);
+            chunks.extend(packets.unwrap());
+        }
+        let kind = config::RECORD_KIND_KEY;
+        let record = encode_record_packets(kind, &record, &FILE_ID, total, 2500, None, &cfg).unwrap();
+
+        let decode = |stream: &[u8], credentials: &Credentials| {
+            let mut assembler =
+                ChunkAssembler::new(Cursor::new(Vec::new()), credentials, DamagePolicy::Fail);
+            for pkt in crate::packet::scan_for_packets(stream) {
+                assembler.add_packet(pkt)?;
+                assembler.recover_ready()?;
+            }
+            assembler.finish()
+        };
+        let with_identity = Credentials::identities(vec![identity]);
+
+        // The record normally comes first, but chunks seen before it are held back
+        for stream in [[&record[..], &chunks[..]].concat(), [&chunks[..], &record[..]].concat()] {
+            let (output, report) = decode(&stream, &with_identity).unwrap();
+            assert!(report.is_complete());
+            assert_eq!(output.into_inner(), data);
+        }
+
+        let stranger = Credentials::identities(vec![crypto::Identity::generate()]);
+        assert!(decode(&[&record[..], &chunks[..]].concat(), &stranger).is_err());
+        // A password is tried with the default parameters, which cannot decrypt it
+        let (_, report) = decode(&chunks, &Credentials::password("pw")).unwrap();
+        assert_eq!(report.auth_failed, [0, 1, 2]);
+        // Without the record the key can never be recovered
+        assert!(decode(&chunks, &with_identity).is_err());
+    }
+
+    #[test]
+    fn test_tampered_headers_fail_authentication() {
+        let cfg = test_config();
+        let data = test_data(2500);
+        let key = [6u8; config::ARGON2_OUTPUT_LEN];
+        let chunk_size = effective_chunk_size(cfg.chunk_size, true);
+        let total = chunk_count(data.len() as u64, chunk_size) as u32;
+        let chunks: Vec<Vec<crate::packet::Packet>> = ChunkReader::new(data.as_slice(), chunk_size)
+            .map(|chunk| {
+                let packets = encode_chunk_packets(&chunk.unwrap(), &FILE_ID, total, 2500, Some(&key), false, &cfg);
+                crate::packet::scan_for_packets(&packets.unwrap())
+            })
+            .collect();
+
+        // Re-serialize every packet with a valid CRC after `edit` changes its header
+        let decode = |edit: &dyn Fn(&mut crate::packet::PacketHeader)| {
+            let mut assembler = ChunkAssembler::new(
+                Cursor::new(Vec::new()),
+                &Credentials::key(key),
+                DamagePolicy::ZeroFill,
+            );
+            for pkt in chunks.iter().flatten() {
+                let mut h = pkt.header.clone();
+                edit(&mut h);
+                let bytes = crate::packet::serialize_packet(
+                    &h.file_id,
+                    h.total_chunks.unwrap(),
+                    h.file_size.unwrap(),
+                    h.chunk_index,
+                    h.chunk_size,
+                    h.original_size,
+                    h.symbol_size,
+                    h.k,
+                    h.esi,
+                    h.flags,
+                    &pkt.payload,
+                );
+                for pkt in crate::packet::scan_for_packets(&bytes) {
+                    assembler.add_packet(pkt)?;
+                }
+                assembler.recover_ready()?;
+            }
+            assembler.finish()
+        };
+
+        let (_, report) = decode(&|_| {}).unwrap();
+        assert!(report.is_complete());
+
+        // Chunks swapped into each other's place
+        let (_, report) = decode(&|h| h.chunk_index = [1, 0, 2][h.chunk_index as usize]).unwrap();
+        assert_eq!(report.auth_failed, [0, 1]);
+
+        // The encryption flag cleared on one chunk
+        let (output, report) = decode(&|h| {
+            if h.chunk_index == 2 {
+                h.flags &= !config::FLAG_ENCRYPTED;
+            }
+        })
+        .unwrap();
+        assert_eq!(report.auth_failed, [2]);
+        assert_eq!(&output.into_inner()[2 * chunk_size..], vec![0; 2500 - 2 * chunk_size]);
+
+        // The encryption flag cleared on every packet, so nothing is decrypted
+        let err = decode(&|h| h.flags &= !config::FLAG_ENCRYPTED).unwrap_err();
+        assert!(err.to_string().contains("not encrypted"), "{err}");
+
+        // A different chunk count on the first chunk's packets
+        let err = decode(&|h| {
+            if h.chunk_index == 0 {
+                h.total_chunks = Some(total + 1);
+            }
+        })
+        .unwrap_err();
+        assert!(err.to_string().contains("packet headers were altered"), "{err}");
+    }
+
+    #[test]
+    fn test_unencrypted_video_needs_opt_in_with_credentials() {
+        let cfg = test_config();
+        let data = test_data(2500);
+        let stream = chunk_packets(&data, &cfg).concat();
+        let decode = |credentials: &Credentials| {
+            let mut assembler =
+                ChunkAssembler::new(Cursor::new(Vec::new()), credentials, DamagePolicy::Fail);
+            for pkt in crate::packet::scan_for_packets(&stream) {
+                assembler.add_packet(pkt)?;
+            }
+            assembler.finish()
+        };
+
+        assert!(decode(&Credentials::default()).unwrap().1.is_complete());
+        assert!(decode(&Credentials::password("pw")).is_err());
+        let allowed = Credentials {
+            allow_unencrypted: true,
+            ..Credentials::password("pw")
+        };
+        let (output, report) = decode(&allowed).unwrap();
+        assert!(report.is_complete());
+        assert_eq!(output.into_inner(), data);
+    }
+
+    #[test]
+    fn test_password_key_uses_recorded_kdf_params() {
+        let cfg = test_config();
+        let data = test_data(1500);
+        let params = KdfParams {
+            mem_cost: 256,
+            time_cost: 1,
+            parallelism: 2,
+        };
+        let key = crypto::derive_key(b"pw", &FILE_ID, &params).unwrap();
+
+        let chunk_size = effective_chunk_size(cfg.chunk_size, true);
+        let total = chunk_count(data.len() as u64, chunk_size) as u32;
+        let kind = config::RECORD_KIND_KEY;
+        let record = KeyRecord::Password(params).to_bytes();
+        let mut stream = encode_record_packets(kind, &record, &FILE_ID, total, 1500, None, &cfg).unwrap();
+        for chunk in ChunkReader::new(data.as_slice(), chunk_size) {
+            let packets = encode_chunk_packets(&chunk.unwrap(), &FILE_ID, total, 1500, Some(&key), true, &cfg);
+            stream.extend(packets.unwrap());
+        }
+
+        let mut assembler = ChunkAssembler::new(
+            Cursor::new(Vec::new()),
+            &Credentials::password("pw"),
+            DamagePolicy::Fail,
+        );
+        for pkt in crate::packet::scan_for_packets(&stream) {
+            assembler.add_packet(pkt).unwrap();
+            assembler.recover_ready().unwrap();
+        }
+        let (output, report) = assembler.finish().unwrap();
+        assert!(report.is_complete());
+        assert_eq!(output.into_inner(), data);
+    }
+
+    #[test]
+    fn test_password_video_survives_losing_key_record_frames() {
+        let cfg = test_config();
+        let data = test_data(3000);
+        let password = Credentials::password("pw");
+        let video = render_video(&data, &password, &cfg);
+
+        // Losing the first data frame loses only the first copy of the key record
+        let key_frames = record_frames(&video, &cfg, config::RECORD_KIND_KEY);
+        assert_eq!(key_frames.len(), config::RECORD_COPIES);
+        assert_eq!(key_frames[0], 0);
+        let (output, report) = decode_frames_without(&video, &password, &cfg, |i| i == 0).unwrap();
+        assert!(report.is_complete(), "{report}");
+        assert_eq!(output, data);
+
+        // Without any copy the default Argon2id parameters are tried
+        let (output, report) =
+            decode_frames_without(&video, &password, &cfg, |i| key_frames.contains(&i)).unwrap();
+        assert!(report.is_complete(), "{report}");
+        assert_eq!(output, data);
+    }
+
+    #[test]
+    fn test_input_changed_while_encoding_fails_the_encode() {
+        let cfg = test_config();
+        let data = test_data(3000);
+        let encoder = VideoEncoder::new(&cfg).unwrap();
+
+        // The input grew after its size was taken, or shrank
+        for (file_size, read) in [(2000, 3000), (5000, 3000)] {
+            let mut writer = FrameWriter::new(&encoder, Vec::new(), 1).unwrap();
+            let reader = ChunkReader::new(data.as_slice(), cfg.chunk_size);
+            let credentials = Credentials::default();
+            let err = write_stream(reader, FILE_ID, file_size, &mut writer, &credentials, &cfg, |_, _| {
+                panic!("the input was described despite changing")
+            })
+            .unwrap_err();
+            assert!(
+                format!("{err:#}").contains(&format!("expected {file_size} bytes, read {read}")),
+                "{err:#}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_manifest_survives_losing_last_frame() {
+        let cfg = test_config();
+        let data = test_data(3000);
+        let video = render_video(&data, &Credentials::default(), &cfg);
+
+        let manifest_frames = record_frames(&video, &cfg, config::RECORD_KIND_MANIFEST);
+        assert_eq!(manifest_frames.len(), config::RECORD_COPIES);
+        let last = video.len() / (cfg.frame_width * cfg.frame_height) as usize - 2;
+        assert_eq!(manifest_frames.last(), Some(&last));
+
+        let (output, report) =
+            decode_frames_without(&video, &Credentials::default(), &cfg, |i| i == last).unwrap();
+        assert!(report.is_complete(), "{report}");
+        assert_eq!(report.manifest.unwrap().sha256, crate::integrity::sha256(&data));
+        assert_eq!(output, data);
+    }
+
+    #[test]
+    fn test_signature_is_checked_against_records() {
+        let cfg = test_config();
+        let data = test_data(1500);
+        let signing_key = crypto::SigningKey::generate();
+        let manifest = Manifest {
+            sha256: crate::integrity::sha256(&data),
+            file_size: data.len() as u64,
+            file_name: "data.bin".to_string(),
+            modified: None,
+        }
+        .to_bytes();
+
+        let decode = |signed_manifest: &[u8]| {
+            let kind = config::RECORD_KIND_MANIFEST;
+            let mut stream: Vec<u8> = chunk_packets(&data, &cfg).concat();
+            stream.extend(encode_record_packets(kind, &manifest, &FILE_ID, 2, 1500, None, &cfg).unwrap());
+            let message = crypto::signed_message(&FILE_ID, 2, 1500, &[(kind, signed_manifest)]);
+            let signature = crypto::sign(&signing_key, &message);
+            let kind = config::RECORD_KIND_SIGNATURE;
+            stream.extend(encode_record_packets(kind, &signature, &FILE_ID, 2, 1500, None, &cfg).unwrap());
+
+            let mut assembler =
+                ChunkAssembler::new(Cursor::new(Vec::new()), &Credentials::default(), DamagePolicy::Fail);
+            for pkt in crate::packet::scan_for_packets(&stream) {
+                assembler.add_packet(pkt).unwrap();
+            }
+            assembler.finish().unwrap().1
+        };
+
+        assert_eq!(decode(&manifest).signer, Some(signing_key.signer()));
+        // A signature over a different manifest is not reported
+        let mut other = manifest.clone();
+        other[0] ^= 1;
+        let report = decode(&other);
+        assert!(report.is_complete());
+        assert_eq!(report.signer, None);
+    }
+
+    #[test]
+    fn test_verify_output_detects_mismatch() {
+        let dir = std::env::temp_dir().join("yts3_test_verify_output");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("output.bin");
+        let data = test_data(1000);
+        std::fs::write(&path, &data).unwrap();
+
+        let mut manifest = Manifest {
+            sha256: crate::integrity::sha256(&data),
+            file_size: 1000,
+            file_name: "output.bin".to_string(),
+            modified: None,
+        };
+        verify_output(&path, &manifest).unwrap();
+
+        manifest.sha256[0] ^= 1;
+        assert!(verify_output(&path, &manifest).is_err());
+        manifest.file_size = 999;
+        assert!(verify_output(&path, &manifest).is_err());
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
 }
@@ -1,44 +1,195 @@
-use std::path::Path;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::UNIX_EPOCH;
 
 use anyhow::{Context, Result};
 use indicatif::{ProgressBar, ProgressStyle};
 use log::info;
 use rayon::prelude::*;
+use sha2::{Digest, Sha256};
 
-use crate::chunker;
-use crate::config::{self, Yts3Config};
-use crate::crypto;
+use crate::archive::ArchiveReader;
+use crate::chunker::{self, Chunk, ChunkReader};
+use crate::compress;
+use crate::config::{self, Compression, FountainScheme, Yts3Config};
+use crate::crypto::{self, Credentials, CryptoError, KeyRecord};
 use crate::fountain;
+use crate::integrity::Sha256Digest;
+use crate::manifest::Manifest;
 use crate::packet;
-use crate::video::encoder::VideoEncoder;
+use crate::storage::{self, StorageBackend, VideoInfo};
+use crate::video::encoder::{FrameWriter, VideoEncoder};
+
+use super::TempPath;
+
+/// What an encode produced, for callers that keep track of their videos.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncodeSummary {
+    /// Random id shared by every packet of the video (and the key derivation salt).
+    pub file_id: [u8; config::FILE_ID_SIZE],
+    /// The manifest record embedded in the video.
+    pub manifest: Manifest,
+    pub total_chunks: u32,
+    pub encrypted: bool,
+}
 
 /// Full encode pipeline: file -> chunks -> [encrypt] -> fountain -> packets -> video.
+///
+/// The pipeline is streamed: a reader thread reads chunks ahead into a bounded
+/// channel, the main thread fountain-codes windows of `cfg.inflight_chunks` chunks
+/// in parallel, and the packets of each `cfg.interleave_depth` chunks are
+/// interleaved and rendered into frames. Peak memory is a few chunks and frames
+/// regardless of the input size.
+///
+/// The SHA-256 of the input is computed along the way and written, together
+/// with the file name, size and modification time, as a manifest record after
+/// the last chunk, each copy in frames of its own.
 pub fn encode_file(
     input_path: &Path,
     output_path: &str,
-    password: Option<&str>,
+    credentials: &Credentials,
     cfg: &Yts3Config,
-) -> Result<()> {
-    let file_id = crypto::generate_file_id();
-    let encrypted = password.is_some();
+) -> Result<EncodeSummary> {
+    cfg.validate()?;
 
-    // Derive encryption key if needed
-    let key = if let Some(pw) = password {
-        Some(crypto::derive_key(pw.as_bytes(), &file_id)?)
-    } else {
-        None
+    // Step 1: Open the input for chunked reading
+    info!("chunking input file: {}", input_path.display());
+    let metadata = std::fs::metadata(input_path).context("failed to stat input file")?;
+    let chunk_size = chunker::effective_chunk_size(cfg.chunk_size, credentials.encrypts());
+    let reader =
+        ChunkReader::open(input_path, chunk_size).context("failed to open input file")?;
+
+    encode_stream(reader, metadata.len(), output_path, credentials, cfg, |_, sha256| {
+        let manifest = Manifest {
+            sha256,
+            file_size: metadata.len(),
+            file_name: input_path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            modified: metadata
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs()),
+        };
+        Ok((manifest, Vec::new()))
+    })
+}
+
+/// Encode a directory tree, or several files and trees, into one video.
+///
+/// The files' contents are streamed back to back as if they were one input, and
+/// an archive index recording each path, size, mode, modification time and
+/// SHA-256 is written as a record after the manifest. Paths are relative to each
+/// input's parent, so encoding `photos/` stores `photos/2024/beach.jpg`.
+pub fn encode_archive(
+    inputs: &[PathBuf],
+    output_path: &str,
+    credentials: &Credentials,
+    cfg: &Yts3Config,
+) -> Result<EncodeSummary> {
+    cfg.validate()?;
+
+    info!("scanning {} archive inputs", inputs.len());
+    let archive = ArchiveReader::open(inputs).context("failed to scan archive inputs")?;
+    let file_len = archive.index().total_size();
+    info!(
+        "archiving {} entries, {file_len} bytes",
+        archive.index().entries.len()
+    );
+    // A single tree is named after its root; several inputs have no common name
+    let file_name = match (inputs, archive.index().entries.first()) {
+        ([_], Some(root)) => root.path.clone(),
+        _ => "archive".to_string(),
     };
 
-    let effective_chunk_size = chunker::effective_chunk_size(cfg.chunk_size, encrypted);
+    let chunk_size = chunker::effective_chunk_size(cfg.chunk_size, credentials.encrypts());
+    let reader = ChunkReader::new(archive, chunk_size);
+    encode_stream(reader, file_len, output_path, credentials, cfg, |archive, sha256| {
+        let index = archive.finish().context("failed to read archive inputs")?;
+        let manifest = Manifest {
+            sha256,
+            file_size: file_len,
+            file_name,
+            modified: None,
+        };
+        Ok((manifest, vec![(config::RECORD_KIND_ARCHIVE, index.to_bytes())]))
+    })
+}
 
-    // Step 1: Chunk the file
-    info!("chunking input file: {}", input_path.display());
-    let chunks = chunker::chunk_file(input_path, effective_chunk_size)
-        .context("failed to chunk input file")?;
-    let num_chunks = chunks.len();
-    info!("split into {} chunks", num_chunks);
+/// Stream `reader`'s chunks into a video at `output_path`, followed by the
+/// manifest and any other records `describe` builds once the whole input has
+/// been read. `describe` is given the underlying reader back and the SHA-256 of
+/// everything it produced.
+fn encode_stream<R: Read + Send>(
+    reader: ChunkReader<R>,
+    file_len: u64,
+    output_path: &str,
+    credentials: &Credentials,
+    cfg: &Yts3Config,
+    describe: impl FnOnce(R, Sha256Digest) -> Result<(Manifest, Vec<(u32, Vec<u8>)>)>,
+) -> Result<EncodeSummary> {
+    let file_id = crypto::generate_file_id();
+    let encoder = VideoEncoder::new(cfg)?;
+    info!("encoding to video: {}", output_path);
+    let mut video = encoder.start(output_path, packet::frame::stream_id(&file_id))?;
+    let summary = match write_stream(reader, file_id, file_len, video.frames(), credentials, cfg, describe) {
+        Ok(summary) => summary,
+        Err(err) => {
+            video.abort();
+            return Err(err);
+        }
+    };
+    video.finish()?;
+
+    info!("encode complete!");
+    Ok(summary)
+}
+
+/// Write the packets of `reader`'s chunks and of the video's records into
+/// `video`, as [`encode_stream`] does for a video file.
+pub(crate) fn write_stream<R: Read + Send, W: Write>(
+    reader: ChunkReader<R>,
+    file_id: [u8; config::FILE_ID_SIZE],
+    file_len: u64,
+    video: &mut FrameWriter<'_, W>,
+    credentials: &Credentials,
+    cfg: &Yts3Config,
+    describe: impl FnOnce(R, Sha256Digest) -> Result<(Manifest, Vec<(u32, Vec<u8>)>)>,
+) -> Result<EncodeSummary> {
+    let encrypted = credentials.encrypts();
+
+    // Derive the encryption key from the password, take the raw key as is, or
+    // generate one and wrap it for the recipients. The key record tells the
+    // decoder which it was, and with what parameters.
+    let (key, key_record) = match (&credentials.password, credentials.key, credentials.recipients.as_slice()) {
+        (Some(_), Some(_), _) | (Some(_), _, [_, ..]) | (_, Some(_), [_, ..]) => {
+            return Err(CryptoError::ConflictingSecrets.into())
+        }
+        (Some(pw), None, []) => {
+            let key = crypto::derive_key(pw.as_bytes(), &file_id, &cfg.kdf)?;
+            (Some(key), Some(KeyRecord::Password(cfg.kdf)))
+        }
+        (None, Some(key), []) => (Some(key), None),
+        (None, None, []) => (None, None),
+        (None, None, recipients) => {
+            let key = crypto::generate_data_key();
+            let record = crypto::wrap_key(&key, &file_id, recipients)?;
+            info!("encrypting for {} recipients", recipients.len());
+            (Some(key), Some(KeyRecord::Recipients(record)))
+        }
+    };
+    let has_key_record = key_record.is_some();
+
+    let num_chunks = chunker::chunk_count(file_len, reader.chunk_size());
+    let total_chunks = u32::try_from(num_chunks).context("input file has too many chunks")?;
+    info!("splitting into {} chunks", num_chunks);
 
-    let progress = ProgressBar::new(num_chunks as u64);
+    let progress = ProgressBar::new(num_chunks);
     progress.set_style(
         ProgressStyle::default_bar()
             .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} chunks ({eta})")
@@ -46,79 +197,364 @@ pub fn encode_file(
             .progress_chars("##-"),
     );
 
-    // Step 2 & 3: Encrypt (if needed) and fountain-encode each chunk, then serialize packets.
-    // Process chunks in parallel.
-    let all_chunk_packets: Vec<Vec<Vec<u8>>> = chunks
-        .par_iter()
-        .map(|chunk| {
-            let chunk_data = if let Some(ref k) = key {
-                crypto::encrypt_chunk(k, &file_id, chunk.index, &chunk.data)
-                    .expect("encryption failed")
-            } else {
-                chunk.data.clone()
-            };
-
-            let symbols =
-                fountain::encode_chunk(&chunk_data, cfg.symbol_size, cfg.repair_overhead)
-                    .expect("fountain encoding failed");
-
-            let k = ((chunk_data.len() + cfg.symbol_size - 1) / cfg.symbol_size) as u32;
-
-            let mut flags = 0u8;
-            if encrypted {
-                flags |= config::FLAG_ENCRYPTED;
-            }
-            if chunk.is_last {
-                flags |= config::FLAG_LAST_CHUNK;
-            }
+    // Records as stored in the video, for the signature to cover
+    let mut stored_records = Vec::new();
 
-            let mut chunk_packets = Vec::new();
-            for sym in &symbols {
-                let mut sym_flags = flags;
-                if sym.is_repair {
-                    sym_flags |= config::FLAG_REPAIR_SYMBOL;
+    // The decoder needs the key record before it can decrypt any chunk, so the
+    // first copy goes first. The others are spread over the chunks, so no single
+    // lost frame or burst of them loses every copy.
+    let mut key_copies = KeyRecordCopies::default();
+    if let Some(record) = &key_record {
+        let kind = config::RECORD_KIND_KEY;
+        let record = record.to_bytes();
+        key_copies.packets =
+            encode_record_packets(kind, &record, &file_id, total_chunks, file_len, None, cfg)?;
+        stored_records.push((kind, record));
+    }
+    key_copies.write_due(video, 0, num_chunks)?;
+
+    let mut hasher = Sha256::new();
+    let source = thread::scope(|scope| -> Result<R> {
+        // Read ahead at most one window of chunks while the previous window is coded.
+        // Every packet records the file's size, so reading more or less than that
+        // fails the encode as soon as it is noticed.
+        let (tx, rx) = mpsc::sync_channel(cfg.inflight_chunks);
+        let read_ahead = scope.spawn(move || {
+            let mut reader = reader;
+            let mut bytes_read = 0u64;
+            for chunk in reader.by_ref() {
+                let chunk = chunk.and_then(|chunk| {
+                    bytes_read += chunk.data.len() as u64;
+                    if bytes_read > file_len {
+                        return Err(changed_input(file_len, bytes_read));
+                    }
+                    Ok(chunk)
+                });
+                let failed = chunk.is_err();
+                if tx.send(chunk).is_err() || failed {
+                    break;
                 }
+            }
+            // After an error this goes unread, as the encode has stopped
+            if bytes_read < file_len {
+                let _ = tx.send(Err(changed_input(file_len, bytes_read)));
+            }
+            reader.into_inner()
+        });
 
-                let pkt = packet::serialize_packet(
-                    &file_id,
-                    chunk.index,
-                    chunk_data.len() as u32,
-                    chunk.data.len() as u32,
-                    cfg.symbol_size as u16,
-                    k,
-                    sym.esi,
-                    sym_flags,
-                    &sym.data,
-                );
-                chunk_packets.push(pkt);
+        let mut chunks = rx.iter();
+        let mut chunks_written = 0u64;
+        let packet_size = config::PACKET_HEADER_SIZE + cfg.symbol_size;
+        let mut group = Vec::with_capacity(cfg.interleave_depth);
+        loop {
+            let window = chunks
+                .by_ref()
+                .take(cfg.inflight_chunks)
+                .collect::<std::io::Result<Vec<Chunk>>>()
+                .context("failed to read input file")?;
+            if window.is_empty() {
+                break;
+            }
+            for chunk in &window {
+                hasher.update(&chunk.data);
             }
 
-            progress.inc(1);
-            chunk_packets
-        })
-        .collect();
+            // Step 2 & 3: Encrypt (if needed) and fountain-encode each chunk in the
+            // window in parallel, then serialize its packets.
+            let window_packets: Vec<Vec<u8>> = window
+                .par_iter()
+                .map(|chunk| {
+                    let packets = encode_chunk_packets(
+                        chunk,
+                        &file_id,
+                        total_chunks,
+                        file_len,
+                        key.as_ref(),
+                        has_key_record,
+                        cfg,
+                    )?;
+                    progress.inc(1);
+                    Ok(packets)
+                })
+                .collect::<Result<_>>()?;
+
+            // Step 4: Render the packets into frames, interleaving each group of
+            // `cfg.interleave_depth` chunks
+            for packets in window_packets {
+                group.push(packets);
+                if group.len() == cfg.interleave_depth {
+                    video.write(&packet::interleave_packets(&group, packet_size))?;
+                    chunks_written += group.len() as u64;
+                    group.clear();
+                    key_copies.write_due(video, chunks_written, num_chunks)?;
+                }
+            }
+        }
+        video.write(&packet::interleave_packets(&group, packet_size))?;
+        key_copies.write_due(video, num_chunks, num_chunks)?;
+        let source = read_ahead
+            .join()
+            .map_err(|_| anyhow::anyhow!("input reader thread panicked"))?;
+        Ok(source)
+    })?;
 
     progress.finish_with_message("chunking complete");
 
-    // Flatten all packets into a single byte stream
-    let mut packet_stream = Vec::new();
-    for chunk_pkts in &all_chunk_packets {
-        for pkt in chunk_pkts {
-            packet_stream.extend_from_slice(pkt);
-        }
+    // Step 5: Append the manifest record describing the original file, then any
+    // records specific to the input
+    let (manifest, records) = describe(source, hasher.finalize().into())?;
+    info!("input SHA-256: {}", manifest.sha256_hex());
+    let manifest_record = (config::RECORD_KIND_MANIFEST, manifest.to_bytes());
+    let mut trailing_records = Vec::new();
+    for (kind, data) in std::iter::once(&manifest_record).chain(&records) {
+        let record = seal_record(*kind, data, &file_id, total_chunks, file_len, key.as_ref())?;
+        trailing_records.extend(packet::record::serialize_record(
+            &file_id,
+            total_chunks,
+            file_len,
+            *kind,
+            &record,
+            cfg.header_symbol_size()?,
+            key.is_some(),
+        ));
+        stored_records.push((*kind, record));
     }
-    info!("total packet data: {} bytes", packet_stream.len());
 
-    // Step 4: Encode packets into video
-    info!("encoding to video: {}", output_path);
-    let encoder = VideoEncoder::new(cfg);
-    encoder.encode_to_file(output_path, &packet_stream)?;
+    // Step 6: Sign the file id, chunk count, size and every record as stored,
+    // so the signature can be checked without decrypting anything
+    if let Some(signing_key) = &credentials.signing_key {
+        let records: Vec<(u32, &[u8])> = stored_records
+            .iter()
+            .map(|(kind, record)| (*kind, record.as_slice()))
+            .collect();
+        let message = crypto::signed_message(&file_id, total_chunks, file_len, &records);
+        trailing_records.extend(encode_record_packets(
+            config::RECORD_KIND_SIGNATURE,
+            &crypto::sign(signing_key, &message),
+            &file_id,
+            total_chunks,
+            file_len,
+            None,
+            cfg,
+        )?);
+        info!("signed by {}", signing_key.signer());
+    }
+
+    // Each copy of the records gets frames of its own, so losing the last frame
+    // or any other costs at most one copy
+    for _ in 0..config::RECORD_COPIES {
+        video.end_frame();
+        video.write(&trailing_records)?;
+    }
 
     // Securely zero the key
     if let Some(mut k) = key {
         crypto::secure_zero(&mut k);
     }
 
-    info!("encode complete!");
-    Ok(())
+    Ok(EncodeSummary {
+        file_id,
+        manifest,
+        total_chunks,
+        encrypted,
+    })
+}
+
+/// The error for an input that no longer has the size it was encoded with.
+fn changed_input(file_len: u64, bytes_read: u64) -> std::io::Error {
+    std::io::Error::other(format!(
+        "input file changed while encoding: expected {file_len} bytes, read {bytes_read}"
+    ))
+}
+
+/// Encode a file and store the video in `backend` under `id`.
+///
+/// The video is written to a temporary file first, since ffmpeg needs a seekable
+/// output, then streamed into the backend.
+pub fn encode_to_backend(
+    input_path: &Path,
+    backend: &dyn StorageBackend,
+    id: &str,
+    credentials: &Credentials,
+    cfg: &Yts3Config,
+) -> Result<(EncodeSummary, VideoInfo)> {
+    storage::validate_id(id)?;
+    let video = TempPath::new("mkv");
+    let summary = encode_file(input_path, video.to_str()?, credentials, cfg)?;
+
+    let info = backend
+        .put(id, &mut File::open(&video.path)?)
+        .with_context(|| format!("failed to store video {id}"))?;
+    info!("stored {} bytes as {id}", info.size);
+    Ok((summary, info))
+}
+
+/// The copies of the key record, each written in frames of its own once its
+/// share of the chunks has been: the first before any chunk, the last after all
+/// of them and the rest evenly in between.
+#[derive(Default)]
+struct KeyRecordCopies {
+    /// One copy of the record's packets; empty without a key record.
+    packets: Vec<u8>,
+    written: usize,
+}
+
+impl KeyRecordCopies {
+    fn write_due<W: Write>(
+        &mut self,
+        video: &mut FrameWriter<'_, W>,
+        chunks_written: u64,
+        num_chunks: u64,
+    ) -> Result<()> {
+        let spacing = config::RECORD_COPIES as u64 - 1;
+        while !self.packets.is_empty()
+            && self.written < config::RECORD_COPIES
+            && chunks_written * spacing >= self.written as u64 * num_chunks
+        {
+            video.end_frame();
+            video.write(&self.packets)?;
+            video.end_frame();
+            self.written += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Compress (if enabled and worthwhile), encrypt (if `key` is set) and
+/// fountain-encode one chunk, returning its serialized packets back to back.
+/// `total_chunks` and `file_size` describe the whole file and are recorded in
+/// every packet; `has_key_record` marks a key described by the key record.
+pub(crate) fn encode_chunk_packets(
+    chunk: &Chunk,
+    file_id: &[u8; config::FILE_ID_SIZE],
+    total_chunks: u32,
+    file_size: u64,
+    key: Option<&[u8; config::ARGON2_OUTPUT_LEN]>,
+    has_key_record: bool,
+    cfg: &Yts3Config,
+) -> Result<Vec<u8>> {
+    let compressed = match cfg.compression {
+        Compression::None => None,
+        Compression::Zstd => {
+            compress::compress_chunk(&chunk.data, cfg.compression_level, cfg.symbol_size)
+                .with_context(|| format!("compression failed for chunk {}", chunk.index))?
+        }
+    };
+    let plaintext = compressed.as_deref().unwrap_or(&chunk.data);
+
+    let mut flags = 0u8;
+    if key.is_some() {
+        flags |= config::FLAG_ENCRYPTED;
+    }
+    if chunk.is_last {
+        flags |= config::FLAG_LAST_CHUNK;
+    }
+    if cfg.fountain == FountainScheme::RaptorQ {
+        flags |= config::FLAG_RAPTORQ;
+    }
+    if compressed.is_some() {
+        flags |= config::FLAG_COMPRESSED;
+    }
+    if has_key_record {
+        flags |= config::FLAG_KEY_RECORD;
+    }
+
+    // The header fields describing the chunk are authenticated along with it
+    let chunk_data = if let Some(k) = key {
+        let aad = packet::associated_data(
+            file_id,
+            chunk.index,
+            total_chunks,
+            file_size,
+            chunk.data.len() as u32,
+            flags,
+        );
+        crypto::encrypt_chunk(k, file_id, chunk.index, &aad, plaintext)
+            .with_context(|| format!("encryption failed for chunk {}", chunk.index))?
+    } else {
+        plaintext.to_vec()
+    };
+
+    let symbols = fountain::encode_chunk(
+        cfg.fountain,
+        &chunk_data,
+        cfg.symbol_size,
+        cfg.repair_overhead,
+    )
+    .with_context(|| format!("fountain encoding failed for chunk {}", chunk.index))?;
+
+    let symbol_size = cfg.header_symbol_size()?;
+    let k = chunk_data.len().div_ceil(cfg.symbol_size) as u32;
+
+    let mut chunk_packets =
+        Vec::with_capacity(symbols.len() * (config::PACKET_HEADER_SIZE + cfg.symbol_size));
+    for sym in &symbols {
+        let mut sym_flags = flags;
+        if sym.is_repair {
+            sym_flags |= config::FLAG_REPAIR_SYMBOL;
+        }
+
+        let pkt = packet::serialize_packet(
+            file_id,
+            total_chunks,
+            file_size,
+            chunk.index,
+            chunk_data.len() as u32,
+            chunk.data.len() as u32,
+            symbol_size,
+            k,
+            sym.esi,
+            sym_flags,
+            &sym.data,
+        );
+        chunk_packets.extend_from_slice(&pkt);
+    }
+
+    Ok(chunk_packets)
+}
+
+/// Encrypt (if `key` is set) a metadata record and serialize its packets.
+pub(crate) fn encode_record_packets(
+    kind: u32,
+    data: &[u8],
+    file_id: &[u8; config::FILE_ID_SIZE],
+    total_chunks: u32,
+    file_size: u64,
+    key: Option<&[u8; config::ARGON2_OUTPUT_LEN]>,
+    cfg: &Yts3Config,
+) -> Result<Vec<u8>> {
+    let record = seal_record(kind, data, file_id, total_chunks, file_size, key)?;
+    Ok(packet::record::serialize_record(
+        file_id,
+        total_chunks,
+        file_size,
+        kind,
+        &record,
+        cfg.header_symbol_size()?,
+        key.is_some(),
+    ))
+}
+
+/// Encrypt (if `key` is set) a metadata record, returning it as stored in the video.
+fn seal_record(
+    kind: u32,
+    data: &[u8],
+    file_id: &[u8; config::FILE_ID_SIZE],
+    total_chunks: u32,
+    file_size: u64,
+    key: Option<&[u8; config::ARGON2_OUTPUT_LEN]>,
+) -> Result<Vec<u8>> {
+    let record = match key {
+        Some(k) => {
+            let flags = config::FLAG_RECORD | config::FLAG_ENCRYPTED;
+            let aad = packet::associated_data(file_id, kind, total_chunks, file_size, 0, flags);
+            crypto::encrypt_record(k, file_id, kind, &aad, data)
+                .with_context(|| format!("encryption failed for record {kind}"))?
+        }
+        None => data.to_vec(),
+    };
+    if record.len() > config::MAX_RECORD_SIZE {
+        anyhow::bail!("record {kind} is too large: {} bytes", record.len());
+    }
+    Ok(record)
 }
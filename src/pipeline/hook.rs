@@ -1,6 +1,8 @@
+use std::ffi::OsString;
 use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 
 /// A hook invoked between encoding and decoding in a [`roundtrip`](super::roundtrip).
 ///
@@ -43,3 +45,194 @@ impl PipelineHook for NoopHook {
         Ok(encoded_path.to_path_buf())
     }
 }
+
+/// Lossy codec used by [`TranscodeHook`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TranscodeCodec {
+    /// H.264 via libx264, in MP4.
+    H264,
+    /// VP9 via libvpx-vp9, in WebM.
+    Vp9,
+    /// AV1 via libaom-av1, in WebM.
+    Av1,
+}
+
+impl TranscodeCodec {
+    fn encoder(self) -> &'static str {
+        match self {
+            TranscodeCodec::H264 => "libx264",
+            TranscodeCodec::Vp9 => "libvpx-vp9",
+            TranscodeCodec::Av1 => "libaom-av1",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            TranscodeCodec::H264 => "mp4",
+            TranscodeCodec::Vp9 | TranscodeCodec::Av1 => "webm",
+        }
+    }
+}
+
+/// A hook that runs the encoded video through a local ffmpeg chain mimicking
+/// what a video platform does to uploads: downscaling, 4:2:0 chroma subsampling,
+/// a lossy codec at a target bitrate or quality, and a frame-rate change.
+///
+/// This lets a [`roundtrip`](super::roundtrip) check whether data survives
+/// realistic processing without any network access. The transcoded copy is
+/// written next to the encoded file as `<name>.transcoded.<ext>`.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use std::path::Path;
+/// use yts3::{roundtrip, Credentials, TranscodeHook, Yts3Config};
+///
+/// let result = roundtrip(
+///     Path::new("input.txt"),
+///     "encoded.mkv",
+///     Path::new("output.txt"),
+///     &Credentials::default(),
+///     &Yts3Config::robust(),
+///     &TranscodeHook::youtube_1080p(),
+/// ).unwrap();
+/// println!("survived: {}", result.matched);
+/// ```
+#[derive(Debug, Clone)]
+pub struct TranscodeHook {
+    /// Lossy codec to re-encode with.
+    pub codec: TranscodeCodec,
+    /// Scale to this height, keeping the aspect ratio. `None` keeps the size.
+    pub height: Option<u32>,
+    /// Target bitrate in kbit/s. When `None`, `crf` sets a constant quality instead.
+    pub bitrate_kbps: Option<u32>,
+    /// Constant rate factor used when no bitrate is set.
+    pub crf: u32,
+    /// Resample to this frame rate, dropping or duplicating frames. `None` keeps it.
+    pub fps: Option<u32>,
+}
+
+impl Default for TranscodeHook {
+    fn default() -> Self {
+        Self {
+            codec: TranscodeCodec::H264,
+            height: None,
+            bitrate_kbps: None,
+            crf: 23,
+            fps: None,
+        }
+    }
+}
+
+impl TranscodeHook {
+    /// Roughly what YouTube serves for a 1080p upload: H.264 at 8 Mbit/s.
+    pub fn youtube_1080p() -> Self {
+        Self {
+            height: Some(1080),
+            bitrate_kbps: Some(8000),
+            ..Self::default()
+        }
+    }
+
+    /// Roughly what YouTube serves for a 720p upload: H.264 at 5 Mbit/s.
+    pub fn youtube_720p() -> Self {
+        Self {
+            height: Some(720),
+            bitrate_kbps: Some(5000),
+            ..Self::default()
+        }
+    }
+
+    /// Path the transcoded copy of `encoded_path` is written to.
+    pub fn output_path(&self, encoded_path: &Path) -> PathBuf {
+        encoded_path.with_extension(format!("transcoded.{}", self.codec.extension()))
+    }
+
+    /// The ffmpeg arguments that transcode `input` into `output`.
+    fn ffmpeg_args(&self, input: &Path, output: &Path) -> Vec<OsString> {
+        let mut filters = Vec::new();
+        if let Some(height) = self.height {
+            filters.push(format!("scale=-2:{height}"));
+        }
+        if let Some(fps) = self.fps {
+            filters.push(format!("fps={fps}"));
+        }
+        filters.push("format=yuv420p".to_string());
+
+        let mut args: Vec<OsString> = vec!["-y".into(), "-i".into(), input.into()];
+        args.extend(["-vf".into(), filters.join(",").into()]);
+        args.extend(["-c:v".into(), self.codec.encoder().into()]);
+        match self.bitrate_kbps {
+            Some(kbps) => args.extend(["-b:v".into(), format!("{kbps}k").into()]),
+            None => {
+                // libvpx and libaom only honour -crf in constant-quality mode
+                if self.codec != TranscodeCodec::H264 {
+                    args.extend(["-b:v".into(), "0".into()]);
+                }
+                args.extend(["-crf".into(), self.crf.to_string().into()]);
+            }
+        }
+        args.push(output.into());
+        args
+    }
+}
+
+impl PipelineHook for TranscodeHook {
+    fn after_encode(&self, encoded_path: &Path) -> Result<PathBuf> {
+        let output = self.output_path(encoded_path);
+        let status = Command::new("ffmpeg")
+            .args(self.ffmpeg_args(encoded_path, &output))
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .context("failed to spawn ffmpeg for transcoding")?;
+        if !status.success() {
+            anyhow::bail!("ffmpeg transcode exited with status: {}", status);
+        }
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transcode_args() {
+        let hook = TranscodeHook {
+            fps: Some(24),
+            ..TranscodeHook::youtube_720p()
+        };
+        let input = Path::new("/tmp/encoded.mkv");
+        let output = hook.output_path(input);
+        assert_eq!(output, Path::new("/tmp/encoded.transcoded.mp4"));
+
+        let args = hook.ffmpeg_args(input, &output);
+        let args: Vec<&str> = args.iter().map(|a| a.to_str().unwrap()).collect();
+        assert_eq!(
+            args,
+            [
+                "-y", "-i", "/tmp/encoded.mkv",
+                "-vf", "scale=-2:720,fps=24,format=yuv420p",
+                "-c:v", "libx264", "-b:v", "5000k",
+                "/tmp/encoded.transcoded.mp4",
+            ]
+        );
+
+        let vp9 = TranscodeHook {
+            codec: TranscodeCodec::Vp9,
+            crf: 31,
+            ..TranscodeHook::default()
+        };
+        let args = vp9.ffmpeg_args(input, &vp9.output_path(input));
+        let args: Vec<&str> = args.iter().map(|a| a.to_str().unwrap()).collect();
+        assert_eq!(
+            args[3..],
+            [
+                "-vf", "format=yuv420p",
+                "-c:v", "libvpx-vp9", "-b:v", "0", "-crf", "31",
+                "/tmp/encoded.transcoded.webm",
+            ]
+        );
+    }
+}
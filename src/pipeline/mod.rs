@@ -4,12 +4,14 @@ pub mod hook;
 
 use std::fs::File;
 use std::io::Read;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use sha2::{Digest, Sha256};
 
 use crate::config::Yts3Config;
+use crate::crypto::Credentials;
 use hook::PipelineHook;
 
 /// Result of a full encode → hook → decode roundtrip.
@@ -35,13 +37,13 @@ pub struct RoundtripResult {
 ///
 /// ```rust,no_run
 /// use std::path::Path;
-/// use yts3::{roundtrip, NoopHook, Yts3Config};
+/// use yts3::{roundtrip, Credentials, NoopHook, Yts3Config};
 ///
 /// let result = roundtrip(
 ///     Path::new("input.txt"),
 ///     "encoded.mkv",
 ///     Path::new("output.txt"),
-///     Some("my-password"),
+///     &Credentials::password("my-password"),
 ///     &Yts3Config::default(),
 ///     &NoopHook,
 /// ).unwrap();
@@ -52,24 +54,27 @@ pub fn roundtrip<H: PipelineHook>(
     input: &Path,
     encoded_path: &str,
     output: &Path,
-    password: Option<&str>,
+    credentials: &Credentials,
     cfg: &Yts3Config,
     hook: &H,
 ) -> Result<RoundtripResult> {
     let original_hash = sha256_file(input)?;
 
-    encode::encode_file(input, encoded_path, password, cfg)?;
+    encode::encode_file(input, encoded_path, credentials, cfg)?;
 
     let decode_from = hook.after_encode(Path::new(encoded_path))?;
 
-    decode::decode_file(
+    let report = decode::decode_file(
         decode_from.to_str().unwrap(),
         output,
-        password,
+        credentials,
         cfg,
     )?;
+    if !report.is_complete() {
+        anyhow::bail!("decode incomplete: {report}");
+    }
 
-    let decoded_hash = sha256_file(output)?;
+    let decoded_hash = sha256_file(&report.output_path)?;
     let matched = original_hash == decoded_hash;
 
     Ok(RoundtripResult {
@@ -92,3 +97,46 @@ fn sha256_file(path: &Path) -> Result<String> {
     }
     Ok(format!("{:x}", hasher.finalize()))
 }
+
+/// A file path removed on drop: by default a uniquely named one in the system
+/// temp directory.
+pub(crate) struct TempPath {
+    pub path: PathBuf,
+}
+
+impl TempPath {
+    pub fn new(extension: &str) -> Self {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let name = format!(
+            "yts3-{}-{}.{extension}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        Self {
+            path: std::env::temp_dir().join(name),
+        }
+    }
+
+    /// Remove the file at `path` on drop, unless it is [kept](Self::keep).
+    pub fn at(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Keep the file, returning its path.
+    pub fn keep(mut self) -> PathBuf {
+        std::mem::take(&mut self.path)
+    }
+
+    /// The path as a string, for APIs that hand it to ffmpeg.
+    pub fn to_str(&self) -> Result<&str> {
+        self.path
+            .to_str()
+            .with_context(|| format!("temp path is not valid UTF-8: {}", self.path.display()))
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
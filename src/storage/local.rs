@@ -0,0 +1,124 @@
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::{validate_id, StorageBackend, StorageError, VideoInfo};
+
+static PARTIAL_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Keeps videos as files under a root directory; `/` in an id is a subdirectory.
+///
+/// Writes go to a hidden `.partial` file in the same directory and are renamed
+/// into place, so a video is never seen half-written.
+pub struct LocalBackend {
+    root: PathBuf,
+}
+
+impl LocalBackend {
+    pub fn open(root: &Path) -> Result<Self, StorageError> {
+        fs::create_dir_all(root)?;
+        Ok(Self {
+            root: root.to_path_buf(),
+        })
+    }
+
+    fn path(&self, id: &str) -> Result<PathBuf, StorageError> {
+        validate_id(id)?;
+        Ok(self.root.join(id))
+    }
+
+    fn info(id: &str, path: &Path) -> Result<VideoInfo, StorageError> {
+        let metadata = match fs::metadata(path) {
+            Ok(m) if m.is_file() => m,
+            Ok(_) => return Err(StorageError::NotFound(id.to_string())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(StorageError::NotFound(id.to_string()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Ok(VideoInfo {
+            id: id.to_string(),
+            size: metadata.len(),
+            modified: metadata.modified()?,
+        })
+    }
+
+    /// Collect every video under `dir` (whose id prefix is `dir_id`) into `out`.
+    fn walk(dir: &Path, dir_id: &str, out: &mut Vec<VideoInfo>) -> Result<(), StorageError> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let id = format!("{dir_id}{name}");
+            // Partial writes and anything else not addressable by an id are skipped
+            if validate_id(&id).is_err() {
+                continue;
+            }
+            if entry.file_type()?.is_dir() {
+                Self::walk(&entry.path(), &format!("{id}/"), out)?;
+            } else {
+                out.push(Self::info(&id, &entry.path())?);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StorageBackend for LocalBackend {
+    fn put(&self, id: &str, video: &mut dyn Read) -> Result<VideoInfo, StorageError> {
+        let path = self.path(id)?;
+        let dir = path.parent().unwrap_or(&self.root);
+        fs::create_dir_all(dir)?;
+
+        let partial = dir.join(format!(
+            ".{}.{}-{}.partial",
+            path.file_name().unwrap_or_default().to_string_lossy(),
+            std::process::id(),
+            PARTIAL_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let written = File::create(&partial).and_then(|mut file| {
+            io::copy(video, &mut file)?;
+            file.sync_all()
+        });
+        if let Err(e) = written.and_then(|()| fs::rename(&partial, &path)) {
+            let _ = fs::remove_file(&partial);
+            return Err(e.into());
+        }
+        Self::info(id, &path)
+    }
+
+    fn get(&self, id: &str) -> Result<Box<dyn Read + Send>, StorageError> {
+        let path = self.path(id)?;
+        match File::open(&path) {
+            Ok(file) if file.metadata()?.is_file() => Ok(Box::new(file)),
+            Ok(_) => Err(StorageError::NotFound(id.to_string())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(StorageError::NotFound(id.to_string())),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn stat(&self, id: &str) -> Result<VideoInfo, StorageError> {
+        Self::info(id, &self.path(id)?)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<VideoInfo>, StorageError> {
+        let mut videos = Vec::new();
+        Self::walk(&self.root, "", &mut videos)?;
+        videos.retain(|v| v.id.starts_with(prefix));
+        videos.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(videos)
+    }
+
+    fn delete(&self, id: &str) -> Result<(), StorageError> {
+        match fs::remove_file(self.path(id)?) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn local_path(&self, id: &str) -> Option<PathBuf> {
+        let path = self.path(id).ok()?;
+        path.is_file().then_some(path)
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::BTreeMap;
+use std::io::{Cursor, Read};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use super::{validate_id, StorageBackend, StorageError, VideoInfo};
+
+/// Keeps videos in memory. Meant for tests; everything is lost on drop.
+#[derive(Default)]
+pub struct MemoryBackend {
+    videos: Mutex<BTreeMap<String, StoredVideo>>,
+}
+
+struct StoredVideo {
+    data: Arc<Vec<u8>>,
+    modified: SystemTime,
+}
+
+impl StoredVideo {
+    fn info(&self, id: &str) -> VideoInfo {
+        VideoInfo {
+            id: id.to_string(),
+            size: self.data.len() as u64,
+            modified: self.modified,
+        }
+    }
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// Reads a shared video buffer without copying it.
+struct SharedReader {
+    data: Arc<Vec<u8>>,
+    pos: usize,
+}
+
+impl Read for SharedReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = Cursor::new(&self.data[self.pos..]).read(buf)?;
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn put(&self, id: &str, video: &mut dyn Read) -> Result<VideoInfo, StorageError> {
+        validate_id(id)?;
+        let mut data = Vec::new();
+        video.read_to_end(&mut data)?;
+        let stored = StoredVideo {
+            data: Arc::new(data),
+            modified: SystemTime::now(),
+        };
+        let info = stored.info(id);
+        self.videos.lock().unwrap().insert(id.to_string(), stored);
+        Ok(info)
+    }
+
+    fn get(&self, id: &str) -> Result<Box<dyn Read + Send>, StorageError> {
+        validate_id(id)?;
+        let videos = self.videos.lock().unwrap();
+        let stored = videos
+            .get(id)
+            .ok_or_else(|| StorageError::NotFound(id.to_string()))?;
+        Ok(Box::new(SharedReader {
+            data: Arc::clone(&stored.data),
+            pos: 0,
+        }))
+    }
+
+    fn stat(&self, id: &str) -> Result<VideoInfo, StorageError> {
+        validate_id(id)?;
+        let videos = self.videos.lock().unwrap();
+        videos
+            .get(id)
+            .map(|stored| stored.info(id))
+            .ok_or_else(|| StorageError::NotFound(id.to_string()))
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<VideoInfo>, StorageError> {
+        let videos = self.videos.lock().unwrap();
+        Ok(videos
+            .range(prefix.to_string()..)
+            .take_while(|(id, _)| id.starts_with(prefix))
+            .map(|(id, stored)| stored.info(id))
+            .collect())
+    }
+
+    fn delete(&self, id: &str) -> Result<(), StorageError> {
+        validate_id(id)?;
+        self.videos.lock().unwrap().remove(id);
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+mod local;
+mod memory;
+
+use std::io::Read;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use thiserror::Error;
+
+pub use local::LocalBackend;
+pub use memory::MemoryBackend;
+
+const MAX_ID_LEN: usize = 1024;
+
+#[derive(Error, Debug)]
+pub enum StorageError {
+    #[error("no video stored under {0:?}")]
+    NotFound(String),
+    #[error("invalid video id {0:?}: use /-separated segments of letters, digits, '.', '_' and '-' not starting with '.'")]
+    InvalidId(String),
+    #[error("storage I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// What a backend knows about a stored video without reading it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VideoInfo {
+    pub id: String,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+/// Somewhere encoded videos are kept, addressed by an id.
+///
+/// Ids are `/`-separated segments of ASCII letters, digits, `.`, `_` and `-`,
+/// so every backend can map them to paths or object keys without escaping.
+/// Videos are streamed in and out; a `put` replaces any video with the same id
+/// and is never visible half-written.
+pub trait StorageBackend: Send + Sync {
+    /// Store a video read from `video` under `id`.
+    fn put(&self, id: &str, video: &mut dyn Read) -> Result<VideoInfo, StorageError>;
+
+    /// Open the video stored under `id`.
+    fn get(&self, id: &str) -> Result<Box<dyn Read + Send>, StorageError>;
+
+    fn stat(&self, id: &str) -> Result<VideoInfo, StorageError>;
+
+    /// All videos whose id starts with `prefix`, sorted by id.
+    fn list(&self, prefix: &str) -> Result<Vec<VideoInfo>, StorageError>;
+
+    /// Remove the video stored under `id`. Removing a missing id is not an error.
+    fn delete(&self, id: &str) -> Result<(), StorageError>;
+
+    /// A local file holding the video, if the backend keeps one, so decoding can
+    /// read it in place instead of copying it out through [`get`](Self::get).
+    fn local_path(&self, id: &str) -> Option<PathBuf> {
+        let _ = id;
+        None
+    }
+}
+
+/// Check that `id` is a valid video id.
+pub fn validate_id(id: &str) -> Result<(), StorageError> {
+    let valid = !id.is_empty()
+        && id.len() <= MAX_ID_LEN
+        && id.split('/').all(|segment| {
+            !segment.is_empty()
+                && !segment.starts_with('.')
+                && segment
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'_' | b'-'))
+        });
+    if valid {
+        Ok(())
+    } else {
+        Err(StorageError::InvalidId(id.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Behaviour every backend must share.
+    fn exercise_backend(backend: &dyn StorageBackend) {
+        for bad in ["", "a//b", "../escape", "dir/.hidden", "spa ce", "/abs"] {
+            assert!(matches!(backend.put(bad, &mut &b""[..]), Err(StorageError::InvalidId(_))), "{bad}");
+        }
+        assert!(matches!(backend.stat("missing.mkv"), Err(StorageError::NotFound(_))));
+        assert!(matches!(backend.get("missing.mkv"), Err(StorageError::NotFound(_))));
+
+        let info = backend.put("photos/b.mkv", &mut &b"second"[..]).unwrap();
+        assert_eq!((info.id.as_str(), info.size), ("photos/b.mkv", 6));
+        backend.put("photos/a.mkv", &mut &b"first"[..]).unwrap();
+        backend.put("other.mkv", &mut &b"x"[..]).unwrap();
+        backend.put("photos/a.mkv", &mut &b"replaced"[..]).unwrap();
+
+        let mut data = String::new();
+        backend.get("photos/a.mkv").unwrap().read_to_string(&mut data).unwrap();
+        assert_eq!(data, "replaced");
+        assert_eq!(backend.stat("photos/a.mkv").unwrap().size, 8);
+
+        let ids: Vec<_> = backend.list("photos/").unwrap().into_iter().map(|v| v.id).collect();
+        assert_eq!(ids, ["photos/a.mkv", "photos/b.mkv"]);
+        assert_eq!(backend.list("").unwrap().len(), 3);
+
+        backend.delete("photos/a.mkv").unwrap();
+        backend.delete("photos/a.mkv").unwrap();
+        assert!(matches!(backend.stat("photos/a.mkv"), Err(StorageError::NotFound(_))));
+        assert_eq!(backend.list("").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_memory_backend() {
+        exercise_backend(&MemoryBackend::new());
+    }
+
+    #[test]
+    fn test_local_backend() {
+        let root = std::env::temp_dir().join(format!("yts3-local-backend-{}", std::process::id()));
+        let backend = LocalBackend::open(&root).unwrap();
+        exercise_backend(&backend);
+        assert_eq!(backend.local_path("other.mkv"), Some(root.join("other.mkv")));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
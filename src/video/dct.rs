@@ -3,62 +3,153 @@ use std::f64::consts::PI;
 use crate::config;
 
 /// Precomputed 8x8 DCT basis functions for embedding and extraction.
+///
+/// With one bit per block, the bit drives all [`config::EMBED_POSITIONS`] coefficients
+/// together for maximum margin. With `n > 1` bits per block, each of the first `n`
+/// positions carries its own bit (MSB first), and the orthogonality of the DCT basis
+/// lets every bit be recovered independently by projecting onto its own coefficient.
 pub struct DctTables {
-    /// For each possible bit value (0 or 1), the 8x8 pixel block pattern.
-    pub embed_blocks: [[u8; 64]; 2],
-    /// Projection vector for extracting a single bit from an 8x8 block via dot product.
-    pub projection: [f64; 64],
+    bits_per_block: usize,
+    /// Pixel pattern for every possible block value (`2^bits_per_block` entries),
+    /// indexed by the bits carried by the block.
+    pub embed_blocks: Vec<[u8; 64]>,
+    /// Projection vectors, one per embedded bit, for recovering bits via dot product.
+    pub projections: Vec<[f64; 64]>,
+    /// Smallest projection magnitude of each bit over every embedded block, after
+    /// clipping and rounding to pixels: what a cleanly read bit measures.
+    nominal: Vec<f64>,
 }
 
 impl DctTables {
-    pub fn new(coefficient_strength: f64) -> Self {
-        // Compute the DCT basis vectors for the embed positions
-        let mut projection = [0.0f64; 64];
-        let mut embed_pattern = [0.0f64; 64]; // pattern for bit=1
-
-        for &(u, v) in &config::EMBED_POSITIONS {
-            let basis = dct_basis(u, v);
-            for i in 0..64 {
-                projection[i] += basis[i];
-                embed_pattern[i] += basis[i];
+    pub fn new(coefficient_strength: f64, bits_per_block: usize) -> Self {
+        assert!(
+            (1..=config::MAX_BITS_PER_BLOCK).contains(&bits_per_block),
+            "bits_per_block must be between 1 and {}",
+            config::MAX_BITS_PER_BLOCK
+        );
+
+        // One basis pattern per embedded bit. A single bit uses the sum of all
+        // embed positions; multiple bits get one coefficient each.
+        let bit_patterns: Vec<[f64; 64]> = if bits_per_block == 1 {
+            let mut combined = [0.0f64; 64];
+            for &(u, v) in &config::EMBED_POSITIONS {
+                let basis = dct_basis(u, v);
+                for i in 0..64 {
+                    combined[i] += basis[i];
+                }
             }
-        }
+            vec![combined]
+        } else {
+            config::EMBED_POSITIONS[..bits_per_block]
+                .iter()
+                .map(|&(u, v)| dct_basis(u, v))
+                .collect()
+        };
+
+        // Normalized projections
+        let projections = bit_patterns
+            .iter()
+            .map(|pattern| {
+                let mut projection = *pattern;
+                let norm: f64 = projection.iter().map(|x| x * x).sum::<f64>().sqrt();
+                if norm > 0.0 {
+                    for p in projection.iter_mut() {
+                        *p /= norm;
+                    }
+                }
+                projection
+            })
+            .collect();
+
+        // Generate one block pattern per block value. Each bit adds its basis
+        // pattern with a positive (bit=1) or negative (bit=0) sign.
+        let embed_blocks = (0..1usize << bits_per_block)
+            .map(|value| {
+                let mut signal = [0.0f64; 64];
+                for (bit, pattern) in bit_patterns.iter().enumerate() {
+                    let sign = if (value >> (bits_per_block - 1 - bit)) & 1 == 1 {
+                        1.0
+                    } else {
+                        -1.0
+                    };
+                    for i in 0..64 {
+                        signal[i] += sign * pattern[i];
+                    }
+                }
+
+                let mut block = [0u8; 64];
+                for i in 0..64 {
+                    // Baseline is 128 (mid-gray)
+                    let val = 128.0 + coefficient_strength * signal[i];
+                    block[i] = val.clamp(0.0, 255.0) as u8;
+                }
+                block
+            })
+            .collect();
+
+        let mut tables = Self {
+            bits_per_block,
+            embed_blocks,
+            projections,
+            nominal: Vec::new(),
+        };
+        tables.nominal = (0..bits_per_block)
+            .map(|bit| {
+                tables
+                    .embed_blocks
+                    .iter()
+                    .map(|block| tables.correlate(block, bit).abs())
+                    .fold(f64::INFINITY, f64::min)
+            })
+            .collect();
+        tables
+    }
 
-        // Normalize projection
-        let norm: f64 = projection.iter().map(|x| x * x).sum::<f64>().sqrt();
-        if norm > 0.0 {
-            for p in projection.iter_mut() {
-                *p /= norm;
-            }
-        }
+    pub fn bits_per_block(&self) -> usize {
+        self.bits_per_block
+    }
 
-        // Generate the two block patterns (bit=0 and bit=1)
-        let mut block_0 = [0u8; 64];
-        let mut block_1 = [0u8; 64];
+    /// Pixel pattern for a block carrying `value` (the low `bits_per_block` bits, MSB first).
+    pub fn embed_block(&self, value: u8) -> &[u8; 64] {
+        &self.embed_blocks[value as usize]
+    }
 
-        for i in 0..64 {
-            // Baseline is 128 (mid-gray)
-            let val_0 = 128.0 - coefficient_strength * embed_pattern[i];
-            let val_1 = 128.0 + coefficient_strength * embed_pattern[i];
-            block_0[i] = val_0.clamp(0.0, 255.0) as u8;
-            block_1[i] = val_1.clamp(0.0, 255.0) as u8;
-        }
+    /// Extract a single bit from an 8x8 block using the first projection vector.
+    #[cfg(test)]
+    pub fn extract_bit(&self, block: &[u8; 64]) -> u8 {
+        self.extract_projection(block, 0)
+    }
 
-        Self {
-            embed_blocks: [block_0, block_1],
-            projection,
-        }
+    /// Extract all bits carried by an 8x8 block, packed MSB first into the low
+    /// `bits_per_block` bits of the result.
+    #[cfg(test)]
+    pub fn extract_bits(&self, block: &[u8; 64]) -> u8 {
+        (0..self.bits_per_block).fold(0u8, |value, bit| {
+            (value << 1) | self.extract_projection(block, bit)
+        })
     }
 
-    /// Extract a single bit from an 8x8 block using the projection vector.
-    pub fn extract_bit(&self, block: &[u8; 64]) -> u8 {
-        let dot: f64 = block
+    /// Soft value of one embedded bit: the block's projection onto that bit's
+    /// pattern. Positive means 1, and the magnitude is the confidence, so copies of
+    /// a bit can be summed before deciding.
+    pub fn correlate(&self, block: &[u8; 64], bit: usize) -> f64 {
+        block
             .iter()
-            .zip(self.projection.iter())
+            .zip(self.projections[bit].iter())
             .map(|(&pixel, &proj)| (pixel as f64 - 128.0) * proj)
-            .sum();
+            .sum()
+    }
+
+    /// Soft value of one embedded bit, scaled so a block read exactly as it was
+    /// embedded gives at least 1.0 in magnitude. The sign is the bit and the
+    /// magnitude its confidence: near 0 the bit could be either.
+    pub fn soft_bit(&self, block: &[u8; 64], bit: usize) -> f64 {
+        self.correlate(block, bit) / self.nominal[bit]
+    }
 
-        if dot > 0.0 { 1 } else { 0 }
+    #[cfg(test)]
+    fn extract_projection(&self, block: &[u8; 64], bit: usize) -> u8 {
+        if self.correlate(block, bit) > 0.0 { 1 } else { 0 }
     }
 }
 
@@ -94,7 +185,7 @@ mod tests {
 
     #[test]
     fn test_embed_extract_roundtrip() {
-        let tables = DctTables::new(config::DEFAULT_COEFFICIENT_STRENGTH);
+        let tables = DctTables::new(config::DEFAULT_COEFFICIENT_STRENGTH, 1);
 
         // Bit 0
         let mut block_0 = [0u8; 64];
@@ -107,6 +198,41 @@ mod tests {
         assert_eq!(tables.extract_bit(&block_1), 1);
     }
 
+    #[test]
+    fn test_multi_bit_embed_extract_roundtrip() {
+        for bits in 1..=config::MAX_BITS_PER_BLOCK {
+            let tables = DctTables::new(config::DEFAULT_COEFFICIENT_STRENGTH, bits);
+            assert_eq!(tables.embed_blocks.len(), 1 << bits);
+
+            for value in 0..(1u8 << bits) {
+                let block = tables.embed_block(value);
+                assert_eq!(tables.extract_bits(block), value, "bits={bits} value={value}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_soft_bit_measures_confidence() {
+        for bits in 1..=config::MAX_BITS_PER_BLOCK {
+            let tables = DctTables::new(config::DEFAULT_COEFFICIENT_STRENGTH, bits);
+            let ones = tables.embed_block((1u8 << bits) - 1);
+            let zeros = tables.embed_block(0);
+            // Halfway between the two patterns is flat gray: no information
+            let faded: Vec<u8> = ones
+                .iter()
+                .zip(zeros)
+                .map(|(&a, &b)| ((3 * a as u16 + b as u16) / 4) as u8)
+                .collect();
+            for bit in 0..bits {
+                assert!(tables.soft_bit(ones, bit) >= 1.0 - 1e-9);
+                assert!(tables.soft_bit(zeros, bit) <= -1.0 + 1e-9);
+                assert_eq!(tables.soft_bit(&[128; 64], bit), 0.0);
+                let soft = tables.soft_bit(faded.as_slice().try_into().unwrap(), bit);
+                assert!((0.4..0.6).contains(&soft), "bits={bits} bit={bit}: {soft}");
+            }
+        }
+    }
+
     #[test]
     fn test_dct_basis_dc() {
         let basis = dct_basis(0, 0);
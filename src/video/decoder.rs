@@ -1,58 +1,191 @@
+use std::process::{Command, Stdio};
+
 use anyhow::{Context, Result};
-use log::info;
+use log::{info, warn};
 use rayon::prelude::*;
 
 use crate::config::{self, Yts3Config};
+use crate::packet::frame::FrameReader;
+use crate::packet::{PacketReader, PacketScanner};
 use crate::video::dct::DctTables;
+use crate::video::header::CalibrationHeader;
+
+/// Frame heights videos are commonly made at, tried in turn when a video's
+/// calibration header cannot be read at its probed size.
+const COMMON_HEIGHTS: [u32; 8] = [360, 480, 540, 720, 1080, 1440, 2160, 4320];
+
+/// Data extracted from one frame, with how confidently each byte was read.
+pub struct ExtractedFrame {
+    pub data: Vec<u8>,
+    /// Confidence of each byte's least certain bit, averaged over its copies: 1.0
+    /// or more reads as cleanly as it was embedded, near 0.0 could be either.
+    pub confidence: Vec<f32>,
+}
 
 /// Decode an FFV1/MKV video file back into raw packet bytes.
 pub struct VideoDecoder {
     width: u32,
     height: u32,
     dct: DctTables,
+    repetition: usize,
     blocks_x: usize,
     blocks_y: usize,
     bytes_per_frame: usize,
+    symbol_size: usize,
+    ecc_parity: usize,
+    header: Option<CalibrationHeader>,
 }
 
 impl VideoDecoder {
     pub fn new(cfg: &Yts3Config) -> Self {
-        let dct = DctTables::new(cfg.coefficient_strength);
+        let dct = DctTables::new(cfg.coefficient_strength, cfg.bits_per_block);
         let blocks_x = cfg.frame_width as usize / config::BLOCK_SIZE;
         let blocks_y = cfg.frame_height as usize / config::BLOCK_SIZE;
-        let bytes_per_frame =
-            config::bytes_per_frame(cfg.frame_width, cfg.frame_height, cfg.bits_per_block);
+        let bytes_per_frame = config::bytes_per_frame(
+            cfg.frame_width,
+            cfg.frame_height,
+            cfg.bits_per_block,
+            cfg.repetition,
+        );
 
         Self {
             width: cfg.frame_width,
             height: cfg.frame_height,
             dct,
+            repetition: cfg.repetition,
             blocks_x,
             blocks_y,
             bytes_per_frame,
+            symbol_size: cfg.symbol_size,
+            ecc_parity: cfg.ecc_parity,
+            header: None,
         }
     }
 
+    /// Open a video and detect how it was encoded.
+    ///
+    /// The frame geometry is probed with ffprobe and the first frame is checked for a
+    /// calibration header. When one is found its parameters take precedence over
+    /// `fallback`; videos written without a header are decoded with `fallback` as-is.
+    ///
+    /// A video that was downscaled after encoding (e.g. by a video platform) has no
+    /// readable header at its probed size, so the first frame is also tried scaled
+    /// back up to the `fallback` geometry and to common sizes of the same shape
+    /// (1920x1080 for a 1280x720 copy, say) until the header reads. Data frames are
+    /// always scaled to the geometry the header records.
+    pub fn open(input_path: &str, fallback: &Yts3Config) -> Result<Self> {
+        let probed = match probe_geometry(input_path) {
+            Ok(geometry) => Some(geometry),
+            Err(e) => {
+                warn!("could not probe video geometry ({e:#}); using configured size");
+                None
+            }
+        };
+        let configured = (fallback.frame_width, fallback.frame_height);
+
+        for (width, height) in header_sizes(probed, configured) {
+            let calibration_cfg = Yts3Config {
+                frame_width: width,
+                frame_height: height,
+                bits_per_block: config::CALIBRATION_BITS_PER_BLOCK,
+                coefficient_strength: config::CALIBRATION_COEFFICIENT_STRENGTH,
+                repetition: 1,
+                ..fallback.clone()
+            };
+            let calibration = Self::new(&calibration_cfg);
+            let Some(frame) = read_first_frame(input_path, width, height)? else {
+                break;
+            };
+            let Ok(header) = CalibrationHeader::from_repeated(&calibration.extract_frame(&frame))
+            else {
+                continue;
+            };
+            if probed.is_some_and(|p| p != (header.frame_width, header.frame_height)) {
+                info!(
+                    "video was rescaled; decoding at the original {}x{}",
+                    header.frame_width, header.frame_height
+                );
+            }
+            return Self::with_header(header, fallback);
+        }
+
+        info!("no calibration header found; using configured parameters");
+        Ok(Self::new(fallback))
+    }
+
+    /// Build a decoder for a video whose calibration header has already been read.
+    pub fn with_header(header: CalibrationHeader, fallback: &Yts3Config) -> Result<Self> {
+        info!(
+            "calibration header: {}x{} @ {} fps, {} bits/block, strength {}, repetition {}, symbol size {}, ECC parity {}",
+            header.frame_width,
+            header.frame_height,
+            header.fps,
+            header.bits_per_block,
+            header.coefficient_strength,
+            header.repetition,
+            header.symbol_size,
+            header.ecc_parity
+        );
+        if header.packet_version > config::PACKET_VERSION {
+            anyhow::bail!(
+                "video uses packet version {}, newer than supported version {}",
+                header.packet_version,
+                config::PACKET_VERSION
+            );
+        }
+
+        let cfg = header.apply_to(fallback);
+        let valid = if header.framed {
+            cfg.validate()
+        } else {
+            cfg.validate_unframed()
+        };
+        valid.context("calibration header describes an invalid layout")?;
+
+        Ok(Self {
+            header: Some(header),
+            ..Self::new(&cfg)
+        })
+    }
+
+    #[cfg(test)]
     pub fn bytes_per_frame(&self) -> usize {
         self.bytes_per_frame
     }
 
-    /// Decode all frames from a video file and return the concatenated packet data.
-    pub fn decode_from_file(&self, input_path: &str) -> Result<Vec<u8>> {
-        use std::process::{Command, Stdio};
+    /// A reader for the packets in this video's extracted frames: at fixed
+    /// offsets if its calibration header says the frames are framed, otherwise
+    /// by scanning the concatenated frame data.
+    pub fn packet_reader(&self) -> PacketReader {
+        if self.header.as_ref().is_some_and(|header| header.framed) {
+            PacketReader::Framed(Box::new(FrameReader::new(
+                config::PACKET_HEADER_SIZE + self.symbol_size,
+                self.ecc_parity,
+            )))
+        } else {
+            PacketReader::Stream(PacketScanner::new())
+        }
+    }
 
+    /// Decode a video file frame by frame, passing each batch of extracted frame
+    /// data to `on_batch` in order. Returns the number of data frames decoded.
+    pub fn decode_stream(
+        &self,
+        input_path: &str,
+        on_batch: impl FnMut(Vec<ExtractedFrame>) -> Result<()>,
+    ) -> Result<u64> {
         info!("decoding video: {}", input_path);
 
         let mut child = Command::new("ffmpeg")
             .args([
                 "-i",
                 input_path,
+                "-vf",
+                &format!("scale={}:{}", self.width, self.height),
                 "-f",
                 "rawvideo",
                 "-pixel_format",
                 "gray",
-                "-video_size",
-                &format!("{}x{}", self.width, self.height),
                 "pipe:1",
             ])
             .stdin(Stdio::null())
@@ -62,72 +195,86 @@ impl VideoDecoder {
             .context("failed to spawn ffmpeg for decoding")?;
 
         let stdout = child.stdout.as_mut().unwrap();
+        let frame_count = self.decode_frames(stdout, on_batch)?;
+
+        let status = child.wait().context("ffmpeg decode process failed")?;
+        if !status.success() {
+            anyhow::bail!("ffmpeg decode exited with status: {}", status);
+        }
+
+        info!("decoded {} frames", frame_count);
+        Ok(frame_count)
+    }
+
+    /// Read raw grayscale frames from `reader` and extract their data.
+    ///
+    /// Frames are read in batches (I/O must be sequential) and bits are extracted
+    /// from each batch in parallel. Batch size matches the rayon thread pool so all
+    /// cores stay busy while we keep memory bounded to `threads * frame_size`.
+    pub(crate) fn decode_frames(
+        &self,
+        reader: &mut impl std::io::Read,
+        mut on_batch: impl FnMut(Vec<ExtractedFrame>) -> Result<()>,
+    ) -> Result<u64> {
         let frame_size = self.width as usize * self.height as usize;
-        let mut all_data = Vec::new();
+        let batch_size = rayon::current_num_threads();
         let mut frame_count = 0u64;
 
-        // Read frames in batches from ffmpeg (I/O must be sequential) and extract
-        // bits from each batch in parallel. Batch size matches the rayon thread pool
-        // so all cores stay busy while we keep memory bounded to `threads * frame_size`.
-        let batch_size = rayon::current_num_threads();
-        let mut batch: Vec<Vec<u8>> = Vec::with_capacity(batch_size);
+        // The calibration frame carries no packet data
+        if self.header.is_some() {
+            let mut header_frame = vec![0u8; frame_size];
+            read_exact_or_eof(reader, &mut header_frame)?;
+        }
 
         loop {
-            let mut frame_buf = vec![0u8; frame_size];
-            match read_exact_or_eof(stdout, &mut frame_buf) {
-                Ok(true) => {
-                    batch.push(frame_buf);
-                    frame_count += 1;
-
-                    if batch.len() >= batch_size {
-                        let extracted: Vec<Vec<u8>> = batch
-                            .par_iter()
-                            .map(|f| self.extract_frame(f))
-                            .collect();
-                        for frame_data in extracted {
-                            all_data.extend_from_slice(&frame_data);
-                        }
-                        batch.clear();
-                    }
+            let mut batch: Vec<Vec<u8>> = Vec::with_capacity(batch_size);
+            while batch.len() < batch_size {
+                let mut frame_buf = vec![0u8; frame_size];
+                if !read_exact_or_eof(reader, &mut frame_buf)? {
+                    break; // EOF
                 }
-                Ok(false) => break, // EOF
-                Err(e) => return Err(e.into()),
+                batch.push(frame_buf);
+            }
+            if batch.is_empty() {
+                break;
             }
-        }
 
-        // Process any remaining frames in the last (partial) batch
-        if !batch.is_empty() {
-            let extracted: Vec<Vec<u8>> = batch
+            frame_count += batch.len() as u64;
+            let at_eof = batch.len() < batch_size;
+            let extracted: Vec<ExtractedFrame> = batch
                 .par_iter()
-                .map(|f| self.extract_frame(f))
+                .map(|f| self.extract_frame_soft(f))
                 .collect();
-            for frame_data in extracted {
-                all_data.extend_from_slice(&frame_data);
-            }
-        }
+            on_batch(extracted)?;
 
-        let status = child.wait().context("ffmpeg decode process failed")?;
-        if !status.success() {
-            anyhow::bail!("ffmpeg decode exited with status: {}", status);
+            if at_eof {
+                break;
+            }
         }
 
-        info!("decoded {} frames, {} bytes total", frame_count, all_data.len());
-        Ok(all_data)
+        Ok(frame_count)
     }
 
     /// Extract data bytes from a single grayscale frame.
-    fn extract_frame(&self, pixels: &[u8]) -> Vec<u8> {
-        let total_bits = self.blocks_x * self.blocks_y;
-        let total_bytes = total_bits / 8;
-        let mut data = vec![0u8; total_bytes];
-        let mut bit_index = 0usize;
+    pub(crate) fn extract_frame(&self, pixels: &[u8]) -> Vec<u8> {
+        self.extract_frame_soft(pixels).data
+    }
+
+    /// Extract data bytes from a single grayscale frame along with the confidence
+    /// each was read with.
+    ///
+    /// Every bit slot is read as a soft value, and the `repetition` copies of each
+    /// data bit (laid out as in `VideoEncoder::render_with`) are summed before the
+    /// sign decides the bit, so a weak or flipped copy is outvoted by confident ones.
+    /// What is left of the sum after the vote is the bit's confidence.
+    pub(crate) fn extract_frame_soft(&self, pixels: &[u8]) -> ExtractedFrame {
+        let bits_per_block = self.dct.bits_per_block();
+        let total_slots = self.blocks_x * self.blocks_y * bits_per_block;
+        let span = total_slots / self.repetition;
+        let mut soft = Vec::with_capacity(total_slots);
 
         for by in 0..self.blocks_y {
             for bx in 0..self.blocks_x {
-                if bit_index / 8 >= total_bytes {
-                    break;
-                }
-
                 // Extract the 8x8 block from the frame
                 let px = bx * config::BLOCK_SIZE;
                 let py = by * config::BLOCK_SIZE;
@@ -139,23 +286,113 @@ impl VideoDecoder {
                         .copy_from_slice(&pixels[frame_offset..frame_offset + config::BLOCK_SIZE]);
                 }
 
-                // Extract bit using DCT projection
-                let bit = self.dct.extract_bit(&block);
-
-                // Pack into output bytes (MSB first)
-                let byte_idx = bit_index / 8;
-                let bit_pos = 7 - (bit_index % 8);
-                if byte_idx < data.len() {
-                    data[byte_idx] |= bit << bit_pos;
+                // Soft values of this block's bits via DCT projection (MSB first)
+                for bit in 0..bits_per_block {
+                    soft.push(self.dct.soft_bit(&block, bit));
                 }
-                bit_index += 1;
             }
         }
 
-        // Trim to bytes_per_frame since not all block bits may carry data
-        data.truncate(self.bytes_per_frame);
-        data
+        // Combine the copies of each bit and pack into output bytes (MSB first)
+        let mut data = vec![0u8; self.bytes_per_frame];
+        let mut confidence = vec![f32::INFINITY; self.bytes_per_frame];
+        for bit_index in 0..self.bytes_per_frame * 8 {
+            let sum: f64 = (0..self.repetition)
+                .map(|copy| soft[copy * span + bit_index])
+                .sum();
+            if sum > 0.0 {
+                data[bit_index / 8] |= 1 << (7 - (bit_index % 8));
+            }
+            let byte_confidence = &mut confidence[bit_index / 8];
+            *byte_confidence = byte_confidence.min((sum.abs() / self.repetition as f64) as f32);
+        }
+        ExtractedFrame { data, confidence }
+    }
+}
+
+/// Sizes to try reading a video's calibration header at, in order: the probed
+/// size, the configured one, then common larger sizes of the probed shape,
+/// rounded to whole blocks, in case the video was downscaled.
+pub(super) fn header_sizes(probed: Option<(u32, u32)>, configured: (u32, u32)) -> Vec<(u32, u32)> {
+    let upscaled = probed
+        .filter(|&(_, height)| height > 0)
+        .into_iter()
+        .flat_map(|(width, height)| {
+            COMMON_HEIGHTS.iter().filter(move |&&h| h > height).map(move |&h| {
+                let block = config::BLOCK_SIZE as u64;
+                let scaled = width as u64 * h as u64 / height as u64;
+                (((scaled + block / 2) / block * block) as u32, h)
+            })
+        });
+    let mut sizes = Vec::new();
+    for size in probed.into_iter().chain([configured]).chain(upscaled) {
+        if !sizes.contains(&size) {
+            sizes.push(size);
+        }
+    }
+    sizes
+}
+
+/// Query the frame size of the first video stream with ffprobe.
+fn probe_geometry(input_path: &str) -> Result<(u32, u32)> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-select_streams",
+            "v:0",
+            "-show_entries",
+            "stream=width,height",
+            "-of",
+            "csv=s=x:p=0",
+            input_path,
+        ])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .context("failed to spawn ffprobe")?;
+    if !output.status.success() {
+        anyhow::bail!("ffprobe exited with status: {}", output.status);
+    }
+
+    let text = String::from_utf8_lossy(&output.stdout);
+    let (w, h) = text
+        .trim()
+        .split_once('x')
+        .context("unexpected ffprobe output")?;
+    Ok((w.parse()?, h.parse()?))
+}
+
+/// Decode only the first frame of a video, scaled to `width`x`height`, returning
+/// `None` if it has no frames.
+fn read_first_frame(input_path: &str, width: u32, height: u32) -> Result<Option<Vec<u8>>> {
+    let output = Command::new("ffmpeg")
+        .args([
+            "-i",
+            input_path,
+            "-frames:v",
+            "1",
+            "-vf",
+            &format!("scale={}:{}", width, height),
+            "-f",
+            "rawvideo",
+            "-pixel_format",
+            "gray",
+            "pipe:1",
+        ])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .context("failed to spawn ffmpeg for decoding")?;
+    if !output.status.success() {
+        anyhow::bail!("ffmpeg decode exited with status: {}", output.status);
+    }
+
+    let frame_size = width as usize * height as usize;
+    if output.stdout.len() < frame_size {
+        return Ok(None);
     }
+    Ok(Some(output.stdout[..frame_size].to_vec()))
 }
 
 /// Read exactly `buf.len()` bytes, returning Ok(false) on clean EOF.
@@ -1,61 +1,94 @@
 use std::io::Write;
-use std::process::{Command, Stdio};
+use std::ops::Range;
+use std::process::{Child, ChildStdin, Command, Stdio};
 
 use anyhow::{Context, Result};
-use log::info;
+use log::{info, warn};
 use rayon::prelude::*;
 
 use crate::config::{self, Yts3Config};
+use crate::ecc::ReedSolomon;
+use crate::packet::frame::{self, FrameHeader};
 use crate::video::dct::DctTables;
+use crate::video::header::CalibrationHeader;
 
 /// Encode a sequence of packet byte streams into an FFV1/MKV video file.
 ///
 /// Each frame is a grayscale 8-bit image where data is embedded in 8x8 DCT blocks.
+/// The first frame is a calibration header describing the encoding parameters;
+/// every data frame starts with a [`FrameHeader`] followed by whole packets.
 /// Uses the ffmpeg CLI to produce the final video.
 pub struct VideoEncoder {
     width: u32,
     height: u32,
     fps: u32,
     dct: DctTables,
+    repetition: usize,
+    header_dct: DctTables,
+    header: CalibrationHeader,
     blocks_x: usize,
     blocks_y: usize,
     bytes_per_frame: usize,
+    /// Serialized packet size, before Reed–Solomon protection.
+    packet_size: usize,
+    /// Inner code applied to every packet, if any parity is configured.
+    ecc: Option<ReedSolomon>,
+    /// Bytes of whole protected packets each data frame holds after its frame header.
+    frame_capacity: usize,
 }
 
 impl VideoEncoder {
-    pub fn new(cfg: &Yts3Config) -> Self {
-        let dct = DctTables::new(cfg.coefficient_strength);
+    pub fn new(cfg: &Yts3Config) -> Result<Self> {
+        let dct = DctTables::new(cfg.coefficient_strength, cfg.bits_per_block);
+        let header_dct = DctTables::new(
+            config::CALIBRATION_COEFFICIENT_STRENGTH,
+            config::CALIBRATION_BITS_PER_BLOCK,
+        );
         let blocks_x = cfg.frame_width as usize / config::BLOCK_SIZE;
         let blocks_y = cfg.frame_height as usize / config::BLOCK_SIZE;
-        let bytes_per_frame =
-            config::bytes_per_frame(cfg.frame_width, cfg.frame_height, cfg.bits_per_block);
+        let bytes_per_frame = config::bytes_per_frame(
+            cfg.frame_width,
+            cfg.frame_height,
+            cfg.bits_per_block,
+            cfg.repetition,
+        );
 
-        Self {
+        Ok(Self {
             width: cfg.frame_width,
             height: cfg.frame_height,
             fps: cfg.fps,
             dct,
+            repetition: cfg.repetition,
+            header_dct,
+            header: CalibrationHeader::from_config(cfg)?,
             blocks_x,
             blocks_y,
             bytes_per_frame,
-        }
+            packet_size: config::PACKET_HEADER_SIZE + cfg.symbol_size,
+            ecc: (cfg.ecc_parity > 0).then(|| ReedSolomon::new(cfg.ecc_parity)),
+            frame_capacity: frame::frame_capacity(
+                bytes_per_frame,
+                cfg.frame_header_slot_size(),
+                cfg.packet_slot_size(),
+            ),
+        })
     }
 
+    #[cfg(test)]
     pub fn bytes_per_frame(&self) -> usize {
         self.bytes_per_frame
     }
 
-    /// Encode all packet data into a video file.
-    /// `packet_data` is the concatenation of all serialized packets.
-    pub fn encode_to_file(&self, output_path: &str, packet_data: &[u8]) -> Result<()> {
-        let num_frames = (packet_data.len() + self.bytes_per_frame - 1) / self.bytes_per_frame;
+    /// Start streaming packet data into a video file, with `stream_id` in every
+    /// frame header.
+    ///
+    /// Spawns ffmpeg and writes the calibration frame. Packet bytes passed to
+    /// [`VideoStream::write`] are rendered into frames as soon as enough data has
+    /// arrived, so memory stays bounded regardless of the total data size.
+    pub fn start(&self, output_path: &str, stream_id: u32) -> Result<VideoStream<'_>> {
         info!(
-            "encoding {} bytes into {} frames ({}x{} @ {} fps)",
-            packet_data.len(),
-            num_frames,
-            self.width,
-            self.height,
-            self.fps
+            "encoding video {} ({}x{} @ {} fps, {} bytes/frame)",
+            output_path, self.width, self.height, self.fps, self.bytes_per_frame
         );
 
         // Scale FFV1 slice count to available threads for better intra-frame parallelism
@@ -91,68 +124,70 @@ impl VideoEncoder {
             .spawn()
             .context("failed to spawn ffmpeg process — is ffmpeg installed?")?;
 
-        let stdin = child.stdin.as_mut().unwrap();
-
-        // Render frames in parallel batches, then write each batch to ffmpeg in order.
-        // Batch size matches the rayon thread pool so we keep all cores busy without
-        // holding more than `threads * frame_size` bytes of rendered pixel data at once.
-        let batch_size = rayon::current_num_threads();
-        let mut frame_idx = 0;
-        while frame_idx < num_frames {
-            let batch_end = (frame_idx + batch_size).min(num_frames);
-            let frames: Vec<Vec<u8>> = (frame_idx..batch_end)
-                .into_par_iter()
-                .map(|idx| {
-                    let data_offset = idx * self.bytes_per_frame;
-                    let data_end = (data_offset + self.bytes_per_frame).min(packet_data.len());
-                    let frame_data = if data_offset < packet_data.len() {
-                        &packet_data[data_offset..data_end]
-                    } else {
-                        &[]
-                    };
-                    self.render_frame(frame_data)
-                })
-                .collect();
-
-            for frame_pixels in &frames {
-                stdin
-                    .write_all(frame_pixels)
-                    .context("failed to write frame data to ffmpeg")?;
-            }
-            frame_idx = batch_end;
-        }
-
-        drop(child.stdin.take());
-        let status = child.wait().context("ffmpeg process failed")?;
-        if !status.success() {
-            anyhow::bail!("ffmpeg exited with status: {}", status);
-        }
+        let stdin = child.stdin.take().unwrap();
+        Ok(VideoStream {
+            frames: FrameWriter::new(self, stdin, stream_id)?,
+            child,
+            output_path: output_path.to_string(),
+        })
+    }
 
-        info!("video encoding complete: {}", output_path);
-        Ok(())
+    /// Render the calibration frame: the header repeated across every block at the
+    /// fixed calibration setting.
+    pub(crate) fn render_calibration_frame(&self) -> Vec<u8> {
+        let capacity = config::bytes_per_frame(
+            self.width,
+            self.height,
+            config::CALIBRATION_BITS_PER_BLOCK,
+            1,
+        );
+        self.render_with(&self.header_dct, 1, &self.header.repeated(capacity))
     }
 
     /// Render a single frame: embed data bytes into 8x8 DCT blocks.
     /// Returns a flat array of grayscale pixels (width * height).
-    fn render_frame(&self, data: &[u8]) -> Vec<u8> {
+    pub(crate) fn render_frame(&self, data: &[u8]) -> Vec<u8> {
+        self.render_with(&self.dct, self.repetition, data)
+    }
+
+    /// Embed `data` using `dct`, each bit `repetition` times.
+    ///
+    /// The frame's bit slots (`bits_per_block` per block, in block order) are split
+    /// into `repetition` equal spans, and every span carries a full copy of the
+    /// data bits (MSB first), so the copies of a bit land in different regions of
+    /// the frame. Blocks that carry no data are left mid-gray.
+    fn render_with(&self, dct: &DctTables, repetition: usize, data: &[u8]) -> Vec<u8> {
         let frame_size = self.width as usize * self.height as usize;
         let mut pixels = vec![128u8; frame_size]; // mid-gray background
 
-        let mut bit_index = 0usize;
+        let bits_per_block = dct.bits_per_block();
         let total_bits = data.len() * 8;
+        let span = self.blocks_x * self.blocks_y * bits_per_block / repetition;
+        let mut slot = 0usize;
 
         for by in 0..self.blocks_y {
             for bx in 0..self.blocks_x {
-                if bit_index >= total_bits {
-                    break;
+                // Gather this block's bits, zero-padding past the end of the data
+                let mut value = 0u8;
+                let mut carries_data = false;
+                for _ in 0..bits_per_block {
+                    let bit_index = slot % span;
+                    let bit = if slot < span * repetition && bit_index < total_bits {
+                        carries_data = true;
+                        let byte_idx = bit_index / 8;
+                        let bit_pos = 7 - (bit_index % 8); // MSB first
+                        (data[byte_idx] >> bit_pos) & 1
+                    } else {
+                        0
+                    };
+                    value = (value << 1) | bit;
+                    slot += 1;
+                }
+                if !carries_data {
+                    continue;
                 }
 
-                let byte_idx = bit_index / 8;
-                let bit_pos = 7 - (bit_index % 8); // MSB first
-                let bit = (data[byte_idx] >> bit_pos) & 1;
-                bit_index += 1;
-
-                let block = &self.dct.embed_blocks[bit as usize];
+                let block = dct.embed_block(value);
 
                 let px = bx * config::BLOCK_SIZE;
                 let py = by * config::BLOCK_SIZE;
@@ -168,3 +203,217 @@ impl VideoEncoder {
         pixels
     }
 }
+
+/// Renders a stream of packets into raw grayscale frames written to `sink`.
+///
+/// Each packet is protected with the configured Reed–Solomon parity, and each
+/// data frame gets a [`FrameHeader`] and as many whole packets as fit after it,
+/// so no packet straddles two frames. Packets are buffered until a full
+/// batch of frames is available; each batch is rendered in parallel and written
+/// in order. Batch size matches the rayon thread pool so we keep all cores busy
+/// without holding more than `threads * frame_size` bytes of rendered pixel data
+/// at once.
+pub struct FrameWriter<'a, W: Write> {
+    encoder: &'a VideoEncoder,
+    sink: W,
+    stream_id: u32,
+    /// Whole protected packets not yet rendered.
+    pending: Vec<u8>,
+    /// Offsets in `pending` where a frame ends early, in order.
+    breaks: Vec<usize>,
+    /// The start of a packet still waiting for the rest of its bytes.
+    partial: Vec<u8>,
+    batch_size: usize,
+    frames_written: u32,
+    bytes_written: u64,
+}
+
+impl<'a, W: Write> FrameWriter<'a, W> {
+    /// Create a writer and emit the calibration frame.
+    pub fn new(encoder: &'a VideoEncoder, mut sink: W, stream_id: u32) -> Result<Self> {
+        sink.write_all(&encoder.render_calibration_frame())
+            .context("failed to write calibration frame")?;
+
+        let batch_size = rayon::current_num_threads();
+        Ok(Self {
+            encoder,
+            sink,
+            stream_id,
+            pending: Vec::with_capacity(batch_size * encoder.frame_capacity),
+            breaks: Vec::new(),
+            partial: Vec::new(),
+            batch_size,
+            frames_written: 0,
+            bytes_written: 0,
+        })
+    }
+
+    /// Append serialized packets, rendering every complete batch of frames.
+    /// Packets may be split across calls, but every packet of a video must have
+    /// the configured symbol size.
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        let start = self.pending.len();
+        match &self.encoder.ecc {
+            Some(ecc) => {
+                // Only whole packets can be protected
+                self.partial.extend_from_slice(data);
+                let whole = self.partial.len() / self.encoder.packet_size * self.encoder.packet_size;
+                for packet in self.partial[..whole].chunks_exact(self.encoder.packet_size) {
+                    self.pending.extend(ecc.protect(packet));
+                }
+                self.partial.drain(..whole);
+            }
+            None => self.pending.extend_from_slice(data),
+        }
+        self.bytes_written += (self.pending.len() - start) as u64;
+
+        // Keep at least one frame's packets back so `finish` can mark the final frame
+        let payloads = self.payloads();
+        if payloads.len() > self.batch_size {
+            let full = (payloads.len() - 1) / self.batch_size * self.batch_size;
+            self.render_pending(&payloads[..full], false)?;
+        }
+        Ok(())
+    }
+
+    /// End the current frame after the packets written so far, so the next
+    /// packet starts a new frame.
+    pub fn end_frame(&mut self) {
+        let len = self.pending.len();
+        if len > 0 && self.breaks.last() != Some(&len) {
+            self.breaks.push(len);
+        }
+    }
+
+    /// Render the remaining packets and return the sink, for rendering into memory.
+    #[cfg(test)]
+    pub fn finish(mut self) -> Result<W> {
+        self.render_remaining()?;
+        Ok(self.sink)
+    }
+
+    /// Render the remaining packets, recording the frame count in the final
+    /// frame's header.
+    fn render_remaining(&mut self) -> Result<()> {
+        if !self.partial.is_empty() {
+            anyhow::bail!("{} bytes of an incomplete packet were written", self.partial.len());
+        }
+        let mut payloads = self.payloads();
+        if payloads.is_empty() && self.frames_written == 0 {
+            // Even an empty video gets a final frame
+            payloads.push(0..0);
+        }
+        self.render_pending(&payloads, true)?;
+        self.sink.flush().context("failed to flush frame data")
+    }
+
+    /// Byte ranges of `pending` each frame carries: as many whole packets as fit,
+    /// up to the next frame break.
+    fn payloads(&self) -> Vec<Range<usize>> {
+        let capacity = self.encoder.frame_capacity;
+        let mut payloads = Vec::new();
+        let mut start = 0;
+        let ends = self.breaks.iter().copied().chain([self.pending.len()]);
+        for end in ends {
+            while start < end {
+                let len = (end - start).min(capacity);
+                payloads.push(start..start + len);
+                start += len;
+            }
+        }
+        payloads
+    }
+
+    /// Render the pending bytes of consecutive `payloads`, starting from the
+    /// first pending byte, into frames and write them out.
+    fn render_pending(&mut self, payloads: &[Range<usize>], last: bool) -> Result<()> {
+        let pending = std::mem::take(&mut self.pending);
+        let len = payloads.last().map_or(0, |payload| payload.end);
+        let payloads: Vec<&[u8]> = payloads
+            .iter()
+            .map(|payload| &pending[payload.clone()])
+            .collect();
+        let total_frames = self.frames_written + payloads.len() as u32;
+
+        for batch in payloads.chunks(self.batch_size) {
+            let first = self.frames_written;
+            let frames: Vec<Vec<u8>> = batch
+                .par_iter()
+                .enumerate()
+                .map(|(i, payload)| {
+                    let index = first + i as u32;
+                    let header = FrameHeader {
+                        stream_id: self.stream_id,
+                        index,
+                        total_frames: if last && index + 1 == total_frames { total_frames } else { 0 },
+                        payload_len: payload.len() as u32,
+                    };
+                    let mut frame_data = match &self.encoder.ecc {
+                        Some(ecc) => ecc.protect(&header.to_bytes()),
+                        None => header.to_bytes().to_vec(),
+                    };
+                    frame_data.extend_from_slice(payload);
+                    self.encoder.render_frame(&frame_data)
+                })
+                .collect();
+
+            for frame_pixels in &frames {
+                self.sink
+                    .write_all(frame_pixels)
+                    .context("failed to write frame data")?;
+            }
+            self.frames_written += batch.len() as u32;
+        }
+        self.pending = pending;
+        self.pending.drain(..len);
+        self.breaks.retain_mut(|end| {
+            *end = end.saturating_sub(len);
+            *end > 0
+        });
+        Ok(())
+    }
+}
+
+/// An in-progress video file fed by a [`FrameWriter`] piping into ffmpeg.
+pub struct VideoStream<'a> {
+    frames: FrameWriter<'a, ChildStdin>,
+    child: Child,
+    output_path: String,
+}
+
+impl<'a> VideoStream<'a> {
+    /// The writer rendering packets into the video's frames.
+    pub fn frames(&mut self) -> &mut FrameWriter<'a, ChildStdin> {
+        &mut self.frames
+    }
+
+    /// Flush the final frame and wait for ffmpeg to finish writing the file.
+    pub fn finish(mut self) -> Result<()> {
+        self.frames.render_remaining()?;
+        let bytes = self.frames.bytes_written;
+        let data_frames = self.frames.frames_written;
+
+        // Closing stdin signals end of input to ffmpeg
+        drop(self.frames);
+        let status = self.child.wait().context("ffmpeg process failed")?;
+        if !status.success() {
+            anyhow::bail!("ffmpeg exited with status: {}", status);
+        }
+
+        info!(
+            "video encoding complete: {} ({} bytes in {} data frames)",
+            self.output_path, bytes, data_frames
+        );
+        Ok(())
+    }
+
+    /// Stop ffmpeg and remove the unfinished video file.
+    pub fn abort(mut self) {
+        drop(self.frames);
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        if let Err(err) = std::fs::remove_file(&self.output_path) {
+            warn!("failed to remove unfinished video {}: {err}", self.output_path);
+        }
+    }
+}
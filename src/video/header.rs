@@ -0,0 +1,285 @@
+use byteorder::{ByteOrder, LittleEndian};
+use thiserror::Error;
+
+use crate::config::{self, ConfigError, Yts3Config};
+use crate::integrity;
+
+#[derive(Error, Debug)]
+pub enum HeaderError {
+    #[error("buffer too short for calibration header: need {need} bytes, have {have}")]
+    BufferTooShort { need: usize, have: usize },
+    #[error("invalid calibration magic: 0x{0:08X}")]
+    InvalidMagic(u32),
+    #[error("unsupported calibration header version: {0}")]
+    UnsupportedVersion(u8),
+    #[error("calibration header CRC mismatch")]
+    CrcMismatch,
+}
+
+/// Encoding parameters recorded in the leading calibration frame of a video.
+///
+/// The header frame is always embedded at one bit per block with
+/// [`config::CALIBRATION_COEFFICIENT_STRENGTH`], and the header bytes are repeated
+/// across the whole frame so the decoder can recover them by majority vote
+/// without knowing anything about how the rest of the video was encoded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CalibrationHeader {
+    pub frame_width: u32,
+    pub frame_height: u32,
+    pub fps: u32,
+    pub bits_per_block: u8,
+    pub coefficient_strength: f64,
+    pub symbol_size: u16,
+    pub packet_version: u8,
+    pub repetition: u8,
+    /// Whether every data frame starts with a frame header and holds whole
+    /// packets (version 3 headers and later), rather than a slice of one
+    /// packet stream.
+    pub framed: bool,
+    /// Reed–Solomon parity bytes per codeword of every packet; 0 before
+    /// version 4.
+    pub ecc_parity: u8,
+}
+
+// Header field offsets (35 bytes total; versions 2 and 3 end with the CRC at 30,
+// and version 1 has no repetition and its CRC at 29)
+const OFF_MAGIC: usize = 0;
+const OFF_VERSION: usize = 4;
+const OFF_WIDTH: usize = 5;
+const OFF_HEIGHT: usize = 9;
+const OFF_FPS: usize = 13;
+const OFF_BITS_PER_BLOCK: usize = 17;
+const OFF_STRENGTH: usize = 18;
+const OFF_SYMBOL_SIZE: usize = 26;
+const OFF_PACKET_VERSION: usize = 28;
+const OFF_REPETITION: usize = 29;
+const OFF_ECC_PARITY: usize = 30;
+const OFF_CRC: usize = 31;
+const OFF_CRC_V2: usize = 30;
+const OFF_CRC_V1: usize = 29;
+
+impl CalibrationHeader {
+    pub fn from_config(cfg: &Yts3Config) -> Result<Self, ConfigError> {
+        Ok(Self {
+            frame_width: cfg.frame_width,
+            frame_height: cfg.frame_height,
+            fps: cfg.fps,
+            bits_per_block: cfg.bits_per_block as u8,
+            coefficient_strength: cfg.coefficient_strength,
+            symbol_size: cfg.header_symbol_size()?,
+            packet_version: config::PACKET_VERSION,
+            repetition: cfg.repetition as u8,
+            framed: true,
+            ecc_parity: cfg.ecc_parity as u8,
+        })
+    }
+
+    /// Overlay the recorded parameters onto `cfg`, keeping any fields the header
+    /// does not describe.
+    pub fn apply_to(&self, cfg: &Yts3Config) -> Yts3Config {
+        Yts3Config {
+            frame_width: self.frame_width,
+            frame_height: self.frame_height,
+            fps: self.fps,
+            bits_per_block: self.bits_per_block as usize,
+            coefficient_strength: self.coefficient_strength,
+            symbol_size: self.symbol_size as usize,
+            repetition: self.repetition as usize,
+            ecc_parity: self.ecc_parity as usize,
+            ..cfg.clone()
+        }
+    }
+
+    /// Serialize the header: the current version for a framed video, or a
+    /// version 2 header for an unframed one.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (version, size, crc_offset) = if self.framed {
+            (config::CALIBRATION_VERSION, config::CALIBRATION_HEADER_SIZE, OFF_CRC)
+        } else {
+            (config::CALIBRATION_VERSION_V2, config::CALIBRATION_HEADER_SIZE_V2, OFF_CRC_V2)
+        };
+        let mut buf = vec![0u8; size];
+        LittleEndian::write_u32(&mut buf[OFF_MAGIC..], config::CALIBRATION_MAGIC);
+        buf[OFF_VERSION] = version;
+        LittleEndian::write_u32(&mut buf[OFF_WIDTH..], self.frame_width);
+        LittleEndian::write_u32(&mut buf[OFF_HEIGHT..], self.frame_height);
+        LittleEndian::write_u32(&mut buf[OFF_FPS..], self.fps);
+        buf[OFF_BITS_PER_BLOCK] = self.bits_per_block;
+        LittleEndian::write_f64(&mut buf[OFF_STRENGTH..], self.coefficient_strength);
+        LittleEndian::write_u16(&mut buf[OFF_SYMBOL_SIZE..], self.symbol_size);
+        buf[OFF_PACKET_VERSION] = self.packet_version;
+        buf[OFF_REPETITION] = self.repetition;
+        if self.framed {
+            buf[OFF_ECC_PARITY] = self.ecc_parity;
+        }
+        let crc = integrity::crc32_mpeg2(&buf[..crc_offset]);
+        LittleEndian::write_u32(&mut buf[crc_offset..], crc);
+        buf
+    }
+
+    /// Parse a header of the current version, a version 3 header (framed,
+    /// without ECC), a version 2 header (unframed) or a version 1 header, which
+    /// also implies a repetition of 1.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, HeaderError> {
+        if data.len() < config::CALIBRATION_HEADER_SIZE_V1 {
+            return Err(HeaderError::BufferTooShort {
+                need: config::CALIBRATION_HEADER_SIZE_V1,
+                have: data.len(),
+            });
+        }
+
+        let magic = LittleEndian::read_u32(&data[OFF_MAGIC..]);
+        if magic != config::CALIBRATION_MAGIC {
+            return Err(HeaderError::InvalidMagic(magic));
+        }
+        let version = data[OFF_VERSION];
+        let (size, crc_offset) = match version {
+            config::CALIBRATION_VERSION => (config::CALIBRATION_HEADER_SIZE, OFF_CRC),
+            config::CALIBRATION_VERSION_V3 | config::CALIBRATION_VERSION_V2 => {
+                (config::CALIBRATION_HEADER_SIZE_V2, OFF_CRC_V2)
+            }
+            config::CALIBRATION_VERSION_V1 => (config::CALIBRATION_HEADER_SIZE_V1, OFF_CRC_V1),
+            _ => return Err(HeaderError::UnsupportedVersion(version)),
+        };
+        if data.len() < size {
+            return Err(HeaderError::BufferTooShort {
+                need: size,
+                have: data.len(),
+            });
+        }
+        if LittleEndian::read_u32(&data[crc_offset..]) != integrity::crc32_mpeg2(&data[..crc_offset])
+        {
+            return Err(HeaderError::CrcMismatch);
+        }
+        let repetition = if version == config::CALIBRATION_VERSION_V1 {
+            1
+        } else {
+            data[OFF_REPETITION]
+        };
+
+        Ok(Self {
+            frame_width: LittleEndian::read_u32(&data[OFF_WIDTH..]),
+            frame_height: LittleEndian::read_u32(&data[OFF_HEIGHT..]),
+            fps: LittleEndian::read_u32(&data[OFF_FPS..]),
+            bits_per_block: data[OFF_BITS_PER_BLOCK],
+            coefficient_strength: LittleEndian::read_f64(&data[OFF_STRENGTH..]),
+            symbol_size: LittleEndian::read_u16(&data[OFF_SYMBOL_SIZE..]),
+            packet_version: data[OFF_PACKET_VERSION],
+            repetition,
+            framed: version >= config::CALIBRATION_VERSION_V3,
+            ecc_parity: if version == config::CALIBRATION_VERSION {
+                data[OFF_ECC_PARITY]
+            } else {
+                0
+            },
+        })
+    }
+
+    /// Fill `capacity` bytes with back-to-back copies of the serialized header.
+    pub fn repeated(&self, capacity: usize) -> Vec<u8> {
+        let bytes = self.to_bytes();
+        let copies = capacity / bytes.len();
+        bytes.repeat(copies)
+    }
+
+    /// Recover a header from a buffer of back-to-back copies by bitwise majority
+    /// vote, falling back to the first individually valid copy. Copies of any
+    /// header size are accepted.
+    pub fn from_repeated(data: &[u8]) -> Result<Self, HeaderError> {
+        Self::from_copies(data, config::CALIBRATION_HEADER_SIZE)
+            .or_else(|err| Self::from_copies(data, config::CALIBRATION_HEADER_SIZE_V2).map_err(|_| err))
+            .or_else(|err| Self::from_copies(data, config::CALIBRATION_HEADER_SIZE_V1).map_err(|_| err))
+    }
+
+    fn from_copies(data: &[u8], size: usize) -> Result<Self, HeaderError> {
+        let copies: Vec<&[u8]> = data.chunks_exact(size).collect();
+        if copies.is_empty() {
+            return Err(HeaderError::BufferTooShort {
+                need: config::CALIBRATION_HEADER_SIZE,
+                have: data.len(),
+            });
+        }
+
+        let mut voted = vec![0u8; size];
+        for (i, byte) in voted.iter_mut().enumerate() {
+            for bit in 0..8 {
+                let ones = copies.iter().filter(|c| (c[i] >> bit) & 1 == 1).count();
+                if ones * 2 > copies.len() {
+                    *byte |= 1 << bit;
+                }
+            }
+        }
+
+        Self::from_bytes(&voted).or_else(|err| {
+            copies
+                .iter()
+                .find_map(|c| Self::from_bytes(c).ok())
+                .ok_or(err)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_roundtrip() {
+        let cfg = Yts3Config {
+            bits_per_block: 3,
+            coefficient_strength: 175.5,
+            repetition: 4,
+            ecc_parity: 24,
+            ..Default::default()
+        };
+        let header = CalibrationHeader::from_config(&cfg).unwrap();
+        let parsed = CalibrationHeader::from_bytes(&header.to_bytes()).unwrap();
+        assert_eq!(parsed, header);
+        // Headers of unframed videos keep version 2
+        let unframed = CalibrationHeader { framed: false, ecc_parity: 0, ..header.clone() };
+        assert_eq!(unframed.to_bytes().len(), config::CALIBRATION_HEADER_SIZE_V2);
+        assert_eq!(CalibrationHeader::from_bytes(&unframed.to_bytes()).unwrap(), unframed);
+
+        let applied = parsed.apply_to(&Yts3Config::default());
+        assert_eq!(applied.bits_per_block, 3);
+        assert_eq!(applied.coefficient_strength, 175.5);
+        assert_eq!(applied.repetition, 4);
+        assert_eq!(applied.ecc_parity, 24);
+    }
+
+    #[test]
+    fn test_v3_header_is_framed_without_ecc() {
+        let header = CalibrationHeader::from_config(&Yts3Config::default()).unwrap();
+        let mut v3 = header.to_bytes()[..config::CALIBRATION_HEADER_SIZE_V2].to_vec();
+        v3[OFF_VERSION] = config::CALIBRATION_VERSION_V3;
+        let crc = integrity::crc32_mpeg2(&v3[..OFF_CRC_V2]);
+        LittleEndian::write_u32(&mut v3[OFF_CRC_V2..], crc);
+
+        let parsed = CalibrationHeader::from_repeated(&v3.repeat(5)).unwrap();
+        assert_eq!(parsed, header);
+    }
+
+    #[test]
+    fn test_v1_header_is_accepted() {
+        let header = CalibrationHeader::from_config(&Yts3Config::default()).unwrap();
+        let mut v1 = header.to_bytes()[..config::CALIBRATION_HEADER_SIZE_V1].to_vec();
+        v1[OFF_VERSION] = config::CALIBRATION_VERSION_V1;
+        let crc = integrity::crc32_mpeg2(&v1[..OFF_CRC_V1]);
+        LittleEndian::write_u32(&mut v1[OFF_CRC_V1..], crc);
+
+        let parsed = CalibrationHeader::from_repeated(&v1.repeat(5)).unwrap();
+        assert_eq!(parsed, CalibrationHeader { framed: false, ..header });
+    }
+
+    #[test]
+    fn test_header_majority_vote_survives_corruption() {
+        let header = CalibrationHeader::from_config(&Yts3Config::default()).unwrap();
+        let mut data = header.repeated(config::CALIBRATION_HEADER_SIZE * 5);
+
+        // Corrupt two of five copies, including every magic byte of the first
+        data[0..4].copy_from_slice(&[0, 0, 0, 0]);
+        data[config::CALIBRATION_HEADER_SIZE + 10] ^= 0xFF;
+
+        assert_eq!(CalibrationHeader::from_repeated(&data).unwrap(), header);
+    }
+}
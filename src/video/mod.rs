@@ -1,3 +1,283 @@
 pub mod dct;
 pub mod encoder;
 pub mod decoder;
+pub mod header;
+
+#[cfg(test)]
+mod tests {
+    use super::decoder::{header_sizes, VideoDecoder};
+    use super::encoder::{FrameWriter, VideoEncoder};
+    use super::header::CalibrationHeader;
+    use crate::config::{self, Yts3Config};
+    use crate::packet::frame::FrameHeader;
+    use crate::packet::serialize_packet;
+
+    #[test]
+    fn test_frame_roundtrip_all_bit_depths() {
+        for bits_per_block in 1..=config::MAX_BITS_PER_BLOCK {
+            let cfg = Yts3Config {
+                frame_width: 128,
+                frame_height: 64,
+                bits_per_block,
+                ..Default::default()
+            };
+            let encoder = VideoEncoder::new(&cfg).unwrap();
+            let decoder = VideoDecoder::new(&cfg);
+            assert_eq!(encoder.bytes_per_frame(), 128 * bits_per_block / 8);
+            assert_eq!(decoder.bytes_per_frame(), encoder.bytes_per_frame());
+
+            let data: Vec<u8> = (0..encoder.bytes_per_frame())
+                .map(|i| (i * 37 + 11) as u8)
+                .collect();
+            let pixels = encoder.render_frame(&data);
+            assert_eq!(decoder.extract_frame(&pixels), data, "bits_per_block={bits_per_block}");
+        }
+    }
+
+    #[test]
+    fn test_frame_roundtrip_with_repetition() {
+        for (bits_per_block, repetition) in [(1, 3), (2, 4), (4, 16)] {
+            let cfg = Yts3Config {
+                frame_width: 128,
+                frame_height: 64,
+                bits_per_block,
+                repetition,
+                ..Default::default()
+            };
+            let encoder = VideoEncoder::new(&cfg).unwrap();
+            let decoder = VideoDecoder::new(&cfg);
+            assert_eq!(encoder.bytes_per_frame(), 128 * bits_per_block / repetition / 8);
+
+            let data: Vec<u8> = (0..encoder.bytes_per_frame())
+                .map(|i| (i * 53 + 7) as u8)
+                .collect();
+            let pixels = encoder.render_frame(&data);
+            assert_eq!(decoder.extract_frame(&pixels), data, "repetition={repetition}");
+        }
+    }
+
+    #[test]
+    fn test_robust_preset_survives_noise() {
+        let cfg = Yts3Config {
+            frame_width: 512,
+            frame_height: 256,
+            ..Yts3Config::robust()
+        };
+        let plain = Yts3Config {
+            repetition: 1,
+            ..cfg.clone()
+        };
+
+        // Heavy pseudo-random pixel noise, standing in for lossy re-encoding
+        let add_noise = |pixels: &mut [u8]| {
+            let mut state = 0x2545_F491_4F6C_DD1Du64;
+            for p in pixels.iter_mut() {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                let noise = (state % 481) as i32 - 240;
+                *p = (*p as i32 + noise).clamp(0, 255) as u8;
+            }
+        };
+        let bit_errors = |cfg: &Yts3Config| {
+            let encoder = VideoEncoder::new(cfg).unwrap();
+            let data: Vec<u8> = (0..encoder.bytes_per_frame())
+                .map(|i| (i * 101 + 3) as u8)
+                .collect();
+            let mut pixels = encoder.render_frame(&data);
+            add_noise(&mut pixels);
+            VideoDecoder::new(cfg)
+                .extract_frame(&pixels)
+                .iter()
+                .zip(&data)
+                .map(|(a, b)| (a ^ b).count_ones())
+                .sum::<u32>()
+        };
+
+        // Without repetition the same noise flips bits; with it every bit survives
+        assert!(bit_errors(&plain) > 0);
+        assert_eq!(bit_errors(&cfg), 0);
+    }
+
+    #[test]
+    fn test_soft_extraction_is_unsure_of_wrong_bytes() {
+        let cfg = Yts3Config {
+            frame_width: 512,
+            frame_height: 256,
+            ..Default::default()
+        };
+        let encoder = VideoEncoder::new(&cfg).unwrap();
+        let decoder = VideoDecoder::new(&cfg);
+        let data: Vec<u8> = (0..encoder.bytes_per_frame())
+            .map(|i| (i * 101 + 3) as u8)
+            .collect();
+        let mut pixels = encoder.render_frame(&data);
+
+        let clean = decoder.extract_frame_soft(&pixels);
+        assert_eq!(clean.data, data);
+        assert!(clean.confidence.iter().all(|&c| c >= 0.999));
+
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        for p in pixels.iter_mut() {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            *p = (*p as i32 + (state % 401) as i32 - 200).clamp(0, 255) as u8;
+        }
+        let noisy = decoder.extract_frame_soft(&pixels);
+        // Share of the bytes read right or wrong that would be erased
+        let unsure = |wrong: bool| {
+            let picked: Vec<f32> = (0..data.len())
+                .filter(|&i| (noisy.data[i] != data[i]) == wrong)
+                .map(|i| noisy.confidence[i])
+                .collect();
+            assert!(!picked.is_empty());
+            let erased = picked.iter().filter(|&&c| c < config::ERASURE_CONFIDENCE).count();
+            erased as f64 / picked.len() as f64
+        };
+        // Bytes read wrong are far likelier to be erased than those read right
+        assert!(unsure(true) > 3.0 * unsure(false), "{} vs {}", unsure(true), unsure(false));
+    }
+
+    #[test]
+    fn test_calibration_frame_roundtrip() {
+        let cfg = Yts3Config {
+            frame_width: 512,
+            frame_height: 128,
+            bits_per_block: 3,
+            coefficient_strength: 120.0,
+            ..Default::default()
+        };
+        let encoder = VideoEncoder::new(&cfg).unwrap();
+        let pixels = encoder.render_calibration_frame();
+
+        // The decoder reads the header with only the geometry known
+        let calibration = VideoDecoder::new(&Yts3Config {
+            frame_width: 512,
+            frame_height: 128,
+            bits_per_block: config::CALIBRATION_BITS_PER_BLOCK,
+            coefficient_strength: config::CALIBRATION_COEFFICIENT_STRENGTH,
+            ..Default::default()
+        });
+        let header = CalibrationHeader::from_repeated(&calibration.extract_frame(&pixels)).unwrap();
+        assert_eq!(header, CalibrationHeader::from_config(&cfg).unwrap());
+    }
+
+    #[test]
+    fn test_header_with_zero_symbol_size_is_rejected() {
+        let cfg = Yts3Config {
+            frame_width: 512,
+            frame_height: 128,
+            ..Default::default()
+        };
+        let header = CalibrationHeader {
+            symbol_size: 0,
+            ..CalibrationHeader::from_config(&cfg).unwrap()
+        };
+        assert!(VideoDecoder::with_header(header, &cfg).is_err());
+    }
+
+    #[test]
+    fn test_frame_writer_keeps_packets_within_frames() {
+        let cfg = Yts3Config {
+            frame_width: 512,
+            frame_height: 256,
+            bits_per_block: 2,
+            symbol_size: 64,
+            ..Default::default()
+        };
+        let encoder = VideoEncoder::new(&cfg).unwrap();
+        let decoder = VideoDecoder::with_header(CalibrationHeader::from_config(&cfg).unwrap(), &cfg).unwrap();
+        let frame_size = 512 * 256;
+
+        // 512 bytes per frame hold a frame header and three 126-byte packets
+        let packets: Vec<Vec<u8>> = (0..20)
+            .map(|esi| serialize_packet(&[3; 16], 1, 900, 0, 1024, 900, 64, 16, esi, 0, &[esi as u8; 64]))
+            .collect();
+        let mut writer = FrameWriter::new(&encoder, Vec::new(), 42).unwrap();
+        // Packets may arrive split across writes
+        for piece in packets.concat().chunks(37) {
+            writer.write(piece).unwrap();
+        }
+        let output = writer.finish().unwrap();
+
+        // Calibration frame + 7 data frames, the last one holding two packets
+        assert_eq!(output.len(), frame_size * 8);
+        assert_eq!(&output[..frame_size], encoder.render_calibration_frame().as_slice());
+        let frames: Vec<Vec<u8>> = output[frame_size..]
+            .chunks(frame_size)
+            .map(|frame| decoder.extract_frame(frame))
+            .collect();
+        for (i, frame) in frames.iter().enumerate() {
+            let header = FrameHeader::from_bytes(frame).unwrap();
+            assert_eq!((header.stream_id, header.index), (42, i as u32));
+            assert_eq!(header.total_frames, if i == 6 { 7 } else { 0 });
+        }
+
+        // Losing a frame loses only its own packets
+        let mut reader = decoder.packet_reader();
+        let esis: Vec<u32> = frames
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != 2)
+            .flat_map(|(_, frame)| reader.push(frame, &[]))
+            .map(|p| p.header.esi)
+            .collect();
+        assert_eq!(esis, [0, 1, 2, 3, 4, 5, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19]);
+    }
+
+    #[test]
+    fn test_frame_writer_protects_packets_with_parity() {
+        let cfg = Yts3Config {
+            frame_width: 512,
+            frame_height: 256,
+            bits_per_block: 2,
+            symbol_size: 64,
+            ecc_parity: 16,
+            ..Default::default()
+        };
+        let encoder = VideoEncoder::new(&cfg).unwrap();
+        let decoder = VideoDecoder::with_header(CalibrationHeader::from_config(&cfg).unwrap(), &cfg).unwrap();
+        let frame_size = 512 * 256;
+
+        // 512 bytes per frame hold a frame header and three packets, each followed by
+        // 16 parity bytes
+        let packets: Vec<Vec<u8>> = (0..6)
+            .map(|esi| serialize_packet(&[3; 16], 1, 900, 0, 1024, 900, 64, 16, esi, 0, &[esi as u8; 64]))
+            .collect();
+        let mut writer = FrameWriter::new(&encoder, Vec::new(), 42).unwrap();
+        for piece in packets.concat().chunks(37) {
+            writer.write(piece).unwrap();
+        }
+        let output = writer.finish().unwrap();
+        assert_eq!(output.len(), frame_size * 3);
+
+        // Scattered byte errors in every packet are corrected before the CRC check
+        let mut reader = decoder.packet_reader();
+        let mut esis = Vec::new();
+        for frame in output[frame_size..].chunks(frame_size) {
+            let mut data = decoder.extract_frame(frame);
+            // and so are the frame header's
+            data[4] ^= 0x3C;
+            for slot in 0..3 {
+                for offset in [5, 60, 61, 130] {
+                    data[cfg.frame_header_slot_size() + slot * 142 + offset] ^= 0x3C;
+                }
+            }
+            esis.extend(reader.push(&data, &[]).into_iter().map(|p| p.header.esi));
+        }
+        assert_eq!(esis, [0, 1, 2, 3, 4, 5]);
+        let stats = reader.frame_stats().unwrap();
+        assert_eq!((stats.corrected_packets, stats.damaged_headers), (6, 0));
+    }
+
+    #[test]
+    fn test_header_sizes_cover_downscaled_videos() {
+        // A 1920x1080 video downscaled to 720p is tried at its original size
+        let sizes = header_sizes(Some((1280, 720)), (3840, 2160));
+        assert_eq!(sizes, [(1280, 720), (3840, 2160), (1920, 1080), (2560, 1440), (7680, 4320)]);
+        // 854x480 rounds to whole blocks
+        assert!(header_sizes(Some((854, 480)), (3840, 2160)).contains(&(1920, 1080)));
+        assert_eq!(header_sizes(None, (640, 360)), [(640, 360)]);
+    }
+}
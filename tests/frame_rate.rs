@@ -0,0 +1,93 @@
+//! Round-trips through `ffmpeg -r` frame-rate conversions, which drop or repeat
+//! whole frames.
+//!
+//! The conversion re-encodes losslessly with FFV1 so only the frame sequence
+//! changes. These need `ffmpeg` and are ignored by default. Run them with
+//! `cargo test --release -- --ignored`.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::Result;
+use yts3::{decode_file, encode_file, Credentials, FrameStats, PipelineHook, Yts3Config};
+
+/// Resamples the encoded video to `fps` with `ffmpeg -r`.
+struct FrameRateHook {
+    fps: u32,
+}
+
+impl PipelineHook for FrameRateHook {
+    fn after_encode(&self, encoded_path: &Path) -> Result<PathBuf> {
+        let output = encoded_path.with_extension(format!("{}fps.mkv", self.fps));
+        let status = Command::new("ffmpeg")
+            .args(["-y", "-i"])
+            .arg(encoded_path)
+            .args(["-r", &self.fps.to_string(), "-c:v", "ffv1"])
+            .arg(&output)
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .status()?;
+        if !status.success() {
+            anyhow::bail!("ffmpeg exited with status: {status}");
+        }
+        Ok(output)
+    }
+}
+
+/// Encode at 30 fps, convert to `fps` and decode, returning the frame
+/// statistics once the output is checked.
+fn frame_rate_roundtrip(fps: u32) -> FrameStats {
+    let dir = std::env::temp_dir().join(format!("yts3-frame-rate-{}-{fps}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut state = 0x2545_F491_4F6C_DD1Du64;
+    let data: Vec<u8> = (0..100_000)
+        .map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 56) as u8
+        })
+        .collect();
+    let input = dir.join("input.bin");
+    std::fs::write(&input, &data).unwrap();
+
+    let cfg = Yts3Config {
+        frame_width: 640,
+        frame_height: 360,
+        chunk_size: 64 * 1024,
+        ..Yts3Config::default()
+    };
+    let encoded = dir.join("encoded.mkv");
+    let output = dir.join("output.bin");
+    let result = encode_file(&input, encoded.to_str().unwrap(), &Credentials::default(), &cfg)
+        .and_then(|_| FrameRateHook { fps }.after_encode(&encoded))
+        .and_then(|converted| {
+            decode_file(converted.to_str().unwrap(), &output, &Credentials::default(), &cfg)
+        })
+        .map(|report| (report, std::fs::read(&output).ok()));
+
+    std::fs::remove_dir_all(&dir).ok();
+    let (report, decoded) = result.unwrap();
+    assert!(report.is_complete(), "{fps} fps: {report}");
+    assert!(decoded == Some(data), "{fps} fps: output differs");
+    report.frames.expect("video has frame headers")
+}
+
+#[test]
+#[ignore = "needs ffmpeg"]
+fn test_survives_30_to_24_fps() {
+    let frames = frame_rate_roundtrip(24);
+    // One frame in five is dropped, and never reported as damaged
+    assert!(!frames.missing.is_empty(), "{frames}");
+    assert_eq!(frames.damaged_headers, 0, "{frames}");
+    assert_eq!(frames.damaged_packets, 0, "{frames}");
+}
+
+#[test]
+#[ignore = "needs ffmpeg"]
+fn test_survives_30_to_60_fps() {
+    let frames = frame_rate_roundtrip(60);
+    // Every frame is shown twice and none is lost
+    assert!(frames.duplicates > 0, "{frames}");
+    assert!(frames.missing.is_empty(), "{frames}");
+    assert!(frames.total_frames.is_some(), "{frames}");
+}
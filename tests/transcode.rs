@@ -0,0 +1,102 @@
+//! Robust-mode round-trips through a local simulation of video platform processing.
+//!
+//! These run [`TranscodeHook`] and need an `ffmpeg` build with libx264 and
+//! libvpx-vp9, so they are ignored by default. Run them with `cargo test --release -- --ignored`.
+
+use yts3::{roundtrip, Credentials, TranscodeCodec, TranscodeHook, Yts3Config};
+
+fn robust_roundtrip(name: &str, width: u32, height: u32, ecc_parity: usize, hook: TranscodeHook) {
+    let dir = std::env::temp_dir().join(format!("yts3-transcode-{}-{name}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // Pseudo-random contents so nothing compresses away
+    let mut state = 0x9E37_79B9_7F4A_7C15u64;
+    let data: Vec<u8> = (0..200_000)
+        .map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 56) as u8
+        })
+        .collect();
+    let input = dir.join("input.bin");
+    std::fs::write(&input, &data).unwrap();
+
+    // Frames hold whole packets, so small robust frames need short symbols
+    let cfg = Yts3Config {
+        frame_width: width,
+        frame_height: height,
+        chunk_size: 64 * 1024,
+        ecc_parity,
+        ..Yts3Config::robust()
+    }
+    .fit_symbol_size();
+    let encoded = dir.join("encoded.mkv");
+    let result = roundtrip(
+        &input,
+        encoded.to_str().unwrap(),
+        &dir.join("output.bin"),
+        &Credentials::default(),
+        &cfg,
+        &hook,
+    );
+
+    std::fs::remove_dir_all(&dir).ok();
+    let result = result.unwrap();
+    assert!(result.matched, "{name}: {} != {}", result.original_hash, result.decoded_hash);
+}
+
+#[test]
+#[ignore = "needs ffmpeg with libx264"]
+fn test_survives_h264_crf18() {
+    let hook = TranscodeHook { crf: 18, ..TranscodeHook::default() };
+    robust_roundtrip("h264-crf18", 640, 360, 0, hook);
+}
+
+#[test]
+#[ignore = "needs ffmpeg with libx264"]
+fn test_survives_h264_crf23() {
+    robust_roundtrip("h264-crf23", 640, 360, 0, TranscodeHook::default());
+}
+
+#[test]
+#[ignore = "needs ffmpeg with libx264"]
+fn test_survives_h264_crf28() {
+    let hook = TranscodeHook { crf: 28, ..TranscodeHook::default() };
+    robust_roundtrip("h264-crf28", 640, 360, 0, hook);
+}
+
+#[test]
+#[ignore = "needs ffmpeg with libx264"]
+fn test_survives_h264_crf28_with_parity() {
+    let hook = TranscodeHook { crf: 28, ..TranscodeHook::default() };
+    robust_roundtrip("h264-crf28-ecc", 1280, 720, 32, hook);
+}
+
+#[test]
+#[ignore = "needs ffmpeg with libvpx-vp9"]
+fn test_survives_vp9_crf31() {
+    let hook = TranscodeHook {
+        codec: TranscodeCodec::Vp9,
+        crf: 31,
+        ..TranscodeHook::default()
+    };
+    robust_roundtrip("vp9-crf31", 640, 360, 0, hook);
+}
+
+#[test]
+#[ignore = "needs ffmpeg with libx264"]
+fn test_survives_youtube_1080p() {
+    robust_roundtrip("youtube-1080p", 1920, 1080, 0, TranscodeHook::youtube_1080p());
+}
+
+#[test]
+#[ignore = "needs ffmpeg with libx264"]
+fn test_survives_youtube_720p_downscale() {
+    robust_roundtrip("youtube-720p", 1920, 1080, 0, TranscodeHook::youtube_720p());
+}
+
+#[test]
+#[ignore = "needs ffmpeg with libx264"]
+fn test_survives_frame_rate_change() {
+    let hook = TranscodeHook { fps: Some(24), ..TranscodeHook::default() };
+    robust_roundtrip("fps-24", 640, 360, 0, hook);
+}